@@ -1,27 +1,67 @@
-use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::path::Path;
+
+/// The `e_machine` of the ELF shared object at `path`, following symlinks, or `None` if it
+/// isn't one or isn't there.
+fn elf_machine(path: &Path) -> Option<u16> {
+    let bytes = fs::read(path).ok()?;
+    // e_type ET_DYN at 16, e_machine at 18; both in the file's own byte order
+    if bytes.len() < 20 || &bytes[0..4] != b"\x7fELF" {
+        return None;
+    }
+    let half = |offset: usize| match bytes[5] {
+        1 => Some(u16::from_le_bytes([bytes[offset], bytes[offset + 1]])),
+        2 => Some(u16::from_be_bytes([bytes[offset], bytes[offset + 1]])),
+        _ => None
+    };
+    if half(16)? != 3 {
+        return None;
+    }
+    half(18)
+}
 
 fn main() {
     let target = env::var("TARGET").unwrap();
-    if !target.contains("linux") {
-        panic!("Non-linux build targets are not tested and may not have a corresponding driver at the moment.");
+    let asi = env::var("CARGO_FEATURE_ASI").is_ok();
+    let qhy = env::var("CARGO_FEATURE_QHY").is_ok();
+    if !target.contains("linux") && (asi || qhy) {
+        panic!("Non-linux build targets are not tested and may not have a corresponding driver at the moment. Build with --no-default-features --features sim to use the simulated camera.");
     }
 
-    let mut archmap: HashMap<&'static str, &'static str> = HashMap::new();
-    archmap.insert("x86_64", "x64");
-    archmap.insert("x86", "x86");
-    archmap.insert("armv5", "armv5");
-    archmap.insert("armv6", "armv6");
-    archmap.insert("armv7", "armv7");
-    archmap.insert("armv8", "armv8");
-    for (arch, dir) in archmap.iter() {
-        if target.contains(arch) {
-            println!("cargo:rustc-link-search={}/lib/{}/", env::var("CARGO_MANIFEST_DIR").unwrap(), dir);
+    // The SDKs are loaded at runtime (see src/dynlib.rs), so they aren't linked. Tell the
+    // binary where the vendored ASI SDK for this target is, as a fallback for when it isn't
+    // installed. x86_64 has to come before x86, which it also contains. Each directory's
+    // library has to be an ELF shared object for the target's machine type, since some of
+    // the vendored directories only have a static library or a dangling symlink to go on.
+    let archmap: &[(&str, &str, u16)] = &[
+        ("x86_64", "x64", 0x3e),
+        ("x86", "x86", 0x03),
+        ("armv5", "armv5", 0x28),
+        ("armv6", "armv6", 0x28),
+        ("armv7", "armv7", 0x28),
+        ("armv8", "armv8", 0xb7),
+        ("aarch64", "armv8", 0xb7)
+    ];
+    if asi {
+        println!("cargo:rerun-if-changed=lib");
+        if let Some((_, dir, machine)) = archmap.iter().find(|(arch, _, _)| target.contains(arch)) {
+            let dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("lib").join(dir);
+            match elf_machine(&dir.join("libASICamera2.so")) {
+                Some(found) if found == *machine => {
+                    println!("cargo:rustc-env=ASI_SDK_DIR={}", dir.display());
+                }
+                Some(found) => {
+                    println!("cargo:warning={}/libASICamera2.so is for ELF machine {:#x}, not {:#x}; the ASI SDK has to be installed", dir.display(), found, machine);
+                }
+                None => {
+                    println!("cargo:warning={} has no usable libASICamera2.so; the ASI SDK has to be installed", dir.display());
+                }
+            }
         }
     }
-    println!("cargo:rustc-link-lib=ASICamera2");
-    println!("cargo:rustc-link-lib=qhyccd");
-    // qhyccd needs libstdc++
-    println!("cargo:rustc-flags=-l dylib=stdc++");
-    println!("cargo:rustc-flags=-l dylib=usb-1.0");
+    if asi || qhy {
+        // dlopen and friends, which newer glibc has in libc proper
+        println!("cargo:rustc-link-lib=dylib=dl");
+    }
 }
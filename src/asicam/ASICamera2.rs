@@ -1,4 +1,6 @@
 /* automatically generated by rust-bindgen */
+use crate::dynlib::dynamic_library;
+
 use std::os;
 
 pub struct CameraHandle(i32);
@@ -15,6 +17,7 @@ pub enum BayerPattern {
 
 #[repr(u32)]
 #[derive(Copy, Clone, Debug)]
+#[allow(clippy::upper_case_acronyms)] // the SDK's names
 pub enum ImageType {
     RAW8 = 0,
     RGB24 = 1,
@@ -78,6 +81,12 @@ impl From<AsiBool> for bool {
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SerialNumber {
+    pub id: [os::raw::c_uchar; 8usize]
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct CameraInfo {
@@ -151,23 +160,89 @@ pub enum ExposureStatus {
     Failed = 3
 }
 
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraMode {
+    Normal = 0,
+    TrigSoftEdge = 1,
+    TrigRiseEdge = 2,
+    TrigFallEdge = 3,
+    TrigSoftLevel = 4,
+    TrigHighLevel = 5,
+    TrigLowLevel = 6,
+    End = -1
+}
+
+impl CameraMode {
+    pub fn from_raw(mode: os::raw::c_int) -> Option<CameraMode> {
+        match mode {
+            0 => Some(CameraMode::Normal),
+            1 => Some(CameraMode::TrigSoftEdge),
+            2 => Some(CameraMode::TrigRiseEdge),
+            3 => Some(CameraMode::TrigFallEdge),
+            4 => Some(CameraMode::TrigSoftLevel),
+            5 => Some(CameraMode::TrigHighLevel),
+            6 => Some(CameraMode::TrigLowLevel),
+            -1 => Some(CameraMode::End),
+            _ => None
+        }
+    }
+
+    /// The trigger modes by the names the command line uses for them.
+    pub fn from_name(name: &str) -> Option<CameraMode> {
+        match name {
+            "normal" => Some(CameraMode::Normal),
+            "soft-edge" => Some(CameraMode::TrigSoftEdge),
+            "rise" => Some(CameraMode::TrigRiseEdge),
+            "fall" => Some(CameraMode::TrigFallEdge),
+            "soft-level" => Some(CameraMode::TrigSoftLevel),
+            "high" => Some(CameraMode::TrigHighLevel),
+            "low" => Some(CameraMode::TrigLowLevel),
+            _ => None
+        }
+    }
+
+    /// A mode by name for waiting on the trigger input. The soft modes are left out, since
+    /// their frames only come when `send_soft_trigger` is called, as is normal, which isn't
+    /// triggered at all.
+    pub fn external_trigger(name: &str) -> Result<CameraMode, String> {
+        match CameraMode::from_name(name) {
+            Some(mode @ CameraMode::TrigSoftEdge) | Some(mode @ CameraMode::TrigSoftLevel) => {
+                Err(format!("{} frames are fired by software, not the trigger input ({:?})", name, mode))
+            }
+            Some(CameraMode::Normal) => Err("normal isn't a trigger mode".to_owned()),
+            Some(mode) => Ok(mode),
+            None => Err(format!("{} isn't an ASI trigger mode", name))
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SupportedMode {
+    // raw `CameraMode`s, terminated by `CameraMode::End`
+    pub supported_camera_mode: [os::raw::c_int; 16usize]
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct ID {
     pub id: [os::raw::c_uchar; 8usize ]
 }
 
-extern "C" {
+dynamic_library! {
+    "ZWO ASI SDK";
+    // serial numbers came in with SDK 1.14
+    optional: [ASIGetSerialNumber];
+
     #[doc = "this should be the first API to be called"]
     #[doc = "get number of connected ASI cameras"]
     pub fn ASIGetNumOfConnectedCameras() -> os::raw::c_int;
-}
-extern "C" {
+
 #[doc = "get the product ID of each supported camera, at first set pPIDs as 0 and get length and then malloc a buffer to contain the PIDs"]
 #[doc = "Return: length of the array."]
     pub fn ASIGetProductIDs(pPIDs: *mut os::raw::c_int) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "get the property of the connected cameras, you can do this without open the camera." ]
 # [ doc = "Paras\u{fffd}\u{fffd}" ]
 # [ doc = "ASI_CAMERA_INFO *pASICameraInfo: Pointer to structure containing the property of camera" ]
@@ -178,8 +253,7 @@ extern "C" {
 # [ doc = "ASI_SUCCESS: Operation is successful" ]
 # [ doc = "ASI_ERROR_INVALID_INDEX  :no camera connected or index value out of boundary" ]
     pub fn ASIGetCameraProperty(pASICameraInfo: *mut CameraInfo, iCameraIndex: os::raw::c_int ) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "open the camera before any operation to the camera, this will not affect the camera which is capturing" ]
 # [ doc = "All APIs below need to open the camera at first." ]
@@ -192,8 +266,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_INVALID_ID  : no camera of this ID is connected or ID value is out of boundary" ]
 # [ doc = "ASI_ERROR_CAMERA_REMOVED: failed to find the camera, maybe camera has been removed" ]
     pub fn ASIOpenCamera(iCameraID : os::raw::c_int) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions" ]
 # [ doc = "" ]
 # [ doc = "Initialise the camera after open, this function may take some while, this will affect the camera which is capturing" ]
@@ -206,8 +279,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
 # [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
     pub fn ASIInitCamera (iCameraID: os::raw::c_int) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "you need to close the camera to free all the resource" ]
 # [ doc = "" ]
@@ -219,8 +291,7 @@ extern "C" {
 # [ doc = "ASI_SUCCESS :it will return success even the camera already closed" ]
 # [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
     pub fn ASICloseCamera (iCameraID : os::raw::c_int) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "Get number of controls available for this camera. the camera need be opened at first." ]
 # [ doc = "" ]
@@ -235,8 +306,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
 # [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
     pub fn ASIGetNumOfControls ( iCameraID: os::raw::c_int , piNumberOfControls : * mut os::raw::c_int ) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "Get controls property available for this camera. the camera need be opened at first." ]
 # [ doc = "user need to malloc and maintain the buffer." ]
@@ -254,8 +324,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
 # [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
     pub fn ASIGetControlCaps ( iCameraID: os::raw::c_int , iControlIndex: os::raw::c_int , pControlCaps : * mut ControlCaps ) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "Get controls property value and auto value" ]
 # [ doc = "note:the value of the temperature is the float value * 10 to convert it to long type, control name is \"Temperature\"" ]
@@ -273,8 +342,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
 # [ doc = "ASI_ERROR_INVALID_CONTROL_TYPE, //invalid Control type" ]
     pub fn ASIGetControlValue ( iCameraID: os::raw::c_int , ControlType: os::raw::c_int , plValue : * mut os::raw::c_long , pbAuto : * mut os::raw::c_int ) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "Set controls property value and auto value" ]
 # [ doc = "it will return success and set the max value or min value if the value is beyond the boundary" ]
@@ -293,8 +361,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_INVALID_CONTROL_TYPE, //invalid Control type" ]
 # [ doc = "ASI_ERROR_GENERAL_ERROR,//general error, eg: value is out of valid range; operate to camera hareware failed" ]
     pub fn ASISetControlValue ( iCameraID: os::raw::c_int , ControlType: os::raw::c_int , lValue: os::raw::c_long , bAuto: os::raw::c_int ) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "set the ROI area before capture." ]
 # [ doc = "you must stop capture before call it." ]
@@ -316,8 +383,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_INVALID_SIZE, //wrong video format size" ]
 # [ doc = "ASI_ERROR_INVALID_IMGTYPE, //unsupported image format, make sure iWidth and iHeight and binning is set correct" ]
     pub fn ASISetROIFormat ( iCameraID: os::raw::c_int , iWidth: os::raw::c_int , iHeight: os::raw::c_int , iBin: os::raw::c_int , Img_type: os::raw::c_int ) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "Start camera exposure. the following 4 API is usually used when long exposure required" ]
 # [ doc = "start exposure  and check the exposure status then get the data" ]
@@ -333,8 +399,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
 # [ doc = "ASI_ERROR_VIDEO_MODE_ACTIVE: video mode is working, you need to stop video capture first" ]
     pub fn ASIStartExposure ( iCameraID: os::raw::c_int , bIsDark: os::raw::c_int ) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "to cancel the long exposure which is on." ]
 # [ doc = "" ]
@@ -348,8 +413,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
 # [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
     pub fn ASIStopExposure ( iCameraID: os::raw::c_int ) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "to get the exposure status, work with ASIStartExposure." ]
 # [ doc = "you can read the data if get ASI_EXP_SUCCESS. or have to restart exposure again" ]
@@ -365,8 +429,7 @@ extern "C" {
 # [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
 # [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
     pub fn ASIGetExpStatus ( iCameraID: os::raw::c_int , pExpStatus : * mut ExposureStatus ) -> ErrorCode;
-}
-extern "C" {
+
 # [ doc = "Descriptions\u{fffd}\u{fffd}" ]
 # [ doc = "get data after exposure." ]
 # [ doc = "please make sure the buffer size is biger enough to hold one image" ]
@@ -388,7 +451,125 @@ extern "C" {
 # [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
 # [ doc = "ASI_ERROR_TIMEOUT: no image get and timeout" ]
     pub fn ASIGetDataAfterExp ( iCameraID: os::raw::c_int , pBuffer : * mut os::raw::c_uchar , lBuffSize: os::raw::c_long ) -> ErrorCode;
+
+# [ doc = "Descriptions\u{fffd}\u{fffd}" ]
+# [ doc = "Start video capture" ]
+# [ doc = "then you can get the data from the API ASIGetVideoData" ]
+# [ doc = "" ]
+# [ doc = "" ]
+# [ doc = "Paras\u{fffd}\u{fffd}" ]
+# [ doc = "int CameraID: this is get from the camera property use the API ASIGetCameraProperty" ]
+# [ doc = "" ]
+# [ doc = "return:" ]
+# [ doc = "ASI_SUCCESS : Operation is successful, it will return success if already started" ]
+# [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
+# [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
+# [ doc = "ASI_ERROR_EXPOSURE_IN_PROGRESS: snap mode is working, you need to stop snap first" ]
+    pub fn ASIStartVideoCapture ( iCameraID: os::raw::c_int ) -> ErrorCode;
+
+# [ doc = "Descriptions\u{fffd}\u{fffd}" ]
+# [ doc = "Stop video capture" ]
+# [ doc = "" ]
+# [ doc = "" ]
+# [ doc = "Paras\u{fffd}\u{fffd}" ]
+# [ doc = "int CameraID: this is get from the camera property use the API ASIGetCameraProperty" ]
+# [ doc = "" ]
+# [ doc = "return:" ]
+# [ doc = "ASI_SUCCESS : Operation is successful, it will return success if already stopped" ]
+# [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
+# [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
+    pub fn ASIStopVideoCapture ( iCameraID: os::raw::c_int ) -> ErrorCode;
+
+# [ doc = "Descriptions\u{fffd}\u{fffd}" ]
+# [ doc = "get data from the video buffer.the buffer is very small" ]
+# [ doc = "you need to call this API as fast as possible, otherwise frame will be discarded" ]
+# [ doc = "so the best way is maintain one buffer loop and call this API in a loop" ]
+# [ doc = "please make sure the buffer size is biger enough to hold one image" ]
+# [ doc = "otherwise the this API will crash" ]
+# [ doc = "" ]
+# [ doc = "" ]
+# [ doc = "Paras\u{fffd}\u{fffd}" ]
+# [ doc = "int CameraID: this is get from the camera property use the API ASIGetCameraProperty" ]
+# [ doc = "unsigned char* pBuffer, caller need to malloc the buffer, make sure the size is big enough" ]
+# [ doc = "the size in byte:" ]
+# [ doc = "8bit mono:width*height" ]
+# [ doc = "16bit mono:width*height*2" ]
+# [ doc = "RGB24:width*height*3" ]
+# [ doc = "" ]
+# [ doc = "int iWaitms, this API will block and wait iWaitms to get one image. the unit is ms" ]
+# [ doc = "-1 means wait forever. this value is recommend set to exposure*2+500ms" ]
+# [ doc = "" ]
+# [ doc = "return:" ]
+# [ doc = "ASI_SUCCESS : Operation is successful" ]
+# [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
+# [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
+# [ doc = "ASI_ERROR_TIMEOUT: no image get and timeout" ]
+    pub fn ASIGetVideoData ( iCameraID: os::raw::c_int , pBuffer : * mut os::raw::c_uchar , lBuffSize: os::raw::c_long , iWaitms: os::raw::c_int ) -> ErrorCode;
+
+# [ doc = "Description:" ]
+# [ doc = "Get the camera supported mode, only need to call when the IsTriggerCam in the CameraInfo is true." ]
+# [ doc = "Paras:" ]
+# [ doc = "int CameraID: this is get from the camera property use the API ASIGetCameraProperty" ]
+# [ doc = "ASI_SUPPORTED_MODE: the camera supported mode" ]
+# [ doc = "" ]
+# [ doc = "return:" ]
+# [ doc = "ASI_SUCCESS : Operation is successful" ]
+# [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
+# [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
+    pub fn ASIGetCameraSupportMode ( iCameraID: os::raw::c_int , pSupportedMode : * mut SupportedMode ) -> ErrorCode;
+
+# [ doc = "Description:" ]
+# [ doc = "Get the camera current mode, only need to call when the IsTriggerCam in the CameraInfo is true" ]
+# [ doc = "Paras:" ]
+# [ doc = "int CameraID: this is get from the camera property use the API ASIGetCameraProperty" ]
+# [ doc = "ASI_CAMERA_MODE: the current camera mode" ]
+# [ doc = "" ]
+# [ doc = "return:" ]
+# [ doc = "ASI_SUCCESS : Operation is successful" ]
+# [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
+# [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
+    pub fn ASIGetCameraMode ( iCameraID: os::raw::c_int , mode : * mut os::raw::c_int ) -> ErrorCode;
+
+# [ doc = "Description:" ]
+# [ doc = "Set the camera mode, only need to call when the IsTriggerCam in the CameraInfo is true" ]
+# [ doc = "Paras:" ]
+# [ doc = "int CameraID: this is get from the camera property use the API ASIGetCameraProperty" ]
+# [ doc = "ASI_CAMERA_MODE: this is get from the camera property use the API ASIGetCameraProperty" ]
+# [ doc = "" ]
+# [ doc = "return:" ]
+# [ doc = "ASI_SUCCESS : Operation is successful" ]
+# [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
+# [ doc = "ASI_ERROR_INVALID_SEQUENCE : camera is in capture now, need to stop capture first." ]
+# [ doc = "ASI_ERROR_INVALID_MODE  : mode is out of boundary or this camera do not support this mode" ]
+    pub fn ASISetCameraMode ( iCameraID: os::raw::c_int , mode : os::raw::c_int ) -> ErrorCode;
+
+# [ doc = "Description:" ]
+# [ doc = "Send out a softTrigger. For edge trigger, it only need to set true which means send a" ]
+# [ doc = "rising trigger to start exposure. For level trigger, it need to set true first means" ]
+# [ doc = "start exposure, and set false means stop exposure.it only need to call when the" ]
+# [ doc = "IsTriggerCam in the CameraInfo is true" ]
+# [ doc = "Paras:" ]
+# [ doc = "int CameraID: this is get from the camera property use the API ASIGetCameraProperty" ]
+# [ doc = "AsiBool starts:send a softTrigger start/stop signal" ]
+    pub fn ASISendSoftTrigger ( iCameraID: os::raw::c_int , bStart: os::raw::c_int ) -> ErrorCode;
+
+# [ doc = "Descriptions\u{fffd}\u{fffd}" ]
+# [ doc = "Set the start position of the ROI area." ]
+# [ doc = "you can call this API to move the ROI area when video is streaming" ]
+# [ doc = "the camera will set the ROI area to the center of the full image as default" ]
+# [ doc = "at bin2 or bin3 mode, the position is relative to the image after binning" ]
+# [ doc = "" ]
+# [ doc = "return:" ]
+# [ doc = "ASI_SUCCESS : Operation is successful" ]
+# [ doc = "ASI_ERROR_CAMERA_CLOSED : camera didn\'t open" ]
+# [ doc = "ASI_ERROR_INVALID_ID  :no camera of this ID is connected or ID value is out of boundary" ]
+# [ doc = "ASI_ERROR_OUTOF_BOUNDARY: the start x and start y make the image out of boundary" ]
+    pub fn ASISetStartPos ( iCameraID: os::raw::c_int , iStartX: os::raw::c_int , iStartY: os::raw::c_int ) -> ErrorCode;
+
+#[doc = "get the camera's serial number, only in SDK 1.14 and later"]
+    pub fn ASIGetSerialNumber(iCameraID: os::raw::c_int, pSN: *mut SerialNumber) -> ErrorCode;
 }
+
 /*
 # [ repr ( C ) ]
 # [ derive ( Debug , Copy , Clone ) ]
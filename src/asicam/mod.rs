@@ -1,16 +1,22 @@
 pub mod ASICamera2;
 
-use self::ASICamera2::{CameraInfo, ControlCaps, ControlType, ExposureStatus, ImageType};
+use self::ASICamera2::{BayerPattern, CameraInfo, CameraMode, ControlCaps, ControlType, ExposureStatus, ImageType, SerialNumber, SupportedMode};
 
-use std::alloc::{alloc, dealloc, Layout};
+use crate::dynlib::{self, LoadError};
+use crate::frame::{Cfa, Frame, FrameType, HeaderValue, Setup, Shutter};
+use crate::hotplug::Presence;
+use crate::imaging::{Imager, Reopen, Subframe};
+use crate::preview::Preview;
+use crate::usb;
+
+use std::cell::{Cell, Ref, RefCell, RefMut};
 use std::collections::HashMap;
 use std::ffi::CStr;
+use std::fmt;
+use std::mem::MaybeUninit;
 use std::os;
-use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
-
-use png::HasParameters;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug)]
 pub struct Control {
@@ -24,30 +30,96 @@ pub struct Control {
     pub control_type: ASICamera2::ControlType
 }
 
+/// Where the SDK writes frames. The allocation is only ever grown, so it fits the largest ROI
+/// and format the camera has been set to, but only the current frame's length of it is handed
+/// to the SDK or read back.
+struct ImageBuffer {
+    data: RefCell<Vec<u8>>,
+    len: Cell<usize>
+}
+
+impl ImageBuffer {
+    fn new() -> ImageBuffer {
+        ImageBuffer { data: RefCell::new(Vec::new()), len: Cell::new(0) }
+    }
+
+    /// Make room for frames of `len` bytes, which is what's used from now on.
+    fn reserve(&self, len: usize) {
+        let mut data = self.data.borrow_mut();
+        if data.len() < len {
+            data.resize(len, 0);
+        }
+        self.len.set(len);
+    }
+
+    /// The current frame's bytes, for the SDK to write into.
+    fn frame_mut(&self) -> RefMut<'_, [u8]> {
+        let len = self.len.get();
+        RefMut::map(self.data.borrow_mut(), |data| &mut data[..len])
+    }
+
+    /// The current frame's bytes, as the SDK last wrote them.
+    fn frame(&self) -> Ref<'_, [u8]> {
+        let len = self.len.get();
+        Ref::map(self.data.borrow(), |data| &data[..len])
+    }
+}
+
+impl fmt::Debug for ImageBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ImageBuffer({} of {} bytes)", self.len.get(), self.data.borrow().len())
+    }
+}
+
 #[derive(Debug)]
 pub struct Camera {
     id: i32,
+    /// The serial number, or the model and ID on SDKs too old to report it.
+    pub serial: String,
+    /// The model name, as the SDK lists it before the camera is opened.
+    pub model: String,
     pub width: u32,
     pub height: u32,
     curr_width: u32,
     curr_height: u32,
     bin: u8,
+    /// Where `set_start_pos` put the ROI; the SDK centres it otherwise.
+    start: Option<(u32, u32)>,
     color_format: ASICamera2::ImageType,
-    image_buffer: *mut u8,
-    controls: HashMap<ASICamera2::ControlType, Control>
+    image_buffer: ImageBuffer,
+    trigger_cam: bool,
+    mechanical_shutter: bool,
+    /// The Bayer pattern of a color camera, for raw formats.
+    bayer: Option<Cfa>,
+    mode: CameraMode,
+    capturing: bool,
+    presence: Presence,
+    controls: HashMap<ASICamera2::ControlType, Control>,
+    // everything set through `set_control_value`, to restore in `reopen`
+    values: HashMap<ASICamera2::ControlType, i64>
 }
 
 impl Camera {
     pub fn new(id: i32) -> Camera {
         Camera {
             id: id,
+            serial: String::new(),
+            model: String::new(),
             controls: HashMap::new(),
             width: 0,
             height: 0,
             curr_width: 0,
             curr_height: 0,
             bin: 1,
-            image_buffer: std::ptr::null_mut(),
+            start: None,
+            image_buffer: ImageBuffer::new(),
+            trigger_cam: false,
+            mechanical_shutter: false,
+            bayer: None,
+            mode: CameraMode::Normal,
+            capturing: false,
+            presence: Presence::new(),
+            values: HashMap::new(),
             color_format: ASICamera2::ImageType::END
         }
     }
@@ -78,6 +150,7 @@ impl Camera {
                 )
             };
         build_result((), res)?;
+        self.values.insert(control, value);
         match control {
             ControlType::HardwareBin => {
                 if value == 0 {
@@ -102,41 +175,274 @@ impl Camera {
         self.set_control_value(ControlType::Exposure, ms as i64 * 1000)
     }
 
+    /// Take a frame and save it at `path`, as FITS for a `.fits` path, with a preview next
+    /// to it.
     pub fn take_image(&self, path: &str) -> Result<()> {
+        let frame = self.capture_frame()?;
+        frame.save(path).unwrap();
+        Preview::thumbnail().write_for(&frame, path).unwrap();
+        Ok(())
+    }
+
+    /// Take a single exposure in snap mode and read it out.
+    pub fn capture_frame(&self) -> Result<Frame> {
+        self.snap(false)
+    }
+
+    /// Take a frame of the given type. Darks and bias frames close the shutter if the camera
+    /// has one, and bias frames are taken at the shortest exposure the camera allows. The
+    /// frame records its type and whether the shutter was actually closed.
+    pub fn capture(&mut self, frame_type: FrameType) -> Result<Frame> {
+        let close_shutter = frame_type.wants_dark() && self.mechanical_shutter;
+        let shutter = if close_shutter {
+            Shutter::Closed
+        } else if self.mechanical_shutter {
+            Shutter::Open
+        } else {
+            Shutter::None
+        };
+        let exposure = self.get_control_value(ControlType::Exposure)?;
+        let (mut frame, exposure) = if frame_type == FrameType::Bias {
+            let min_exposure = self.controls.get(&ControlType::Exposure).map(|c| c.min).unwrap_or(32);
+            self.set_control_value(ControlType::Exposure, min_exposure)?;
+            let frame = self.snap(close_shutter);
+            self.set_control_value(ControlType::Exposure, exposure)?;
+            (frame?, min_exposure)
+        } else {
+            (self.snap(close_shutter)?, exposure)
+        };
+        frame.set_frame_type(frame_type, shutter);
+        frame.set_header("EXPTIME", HeaderValue::Float(exposure as f64 / 1e6));
+        frame.set_setup(&self.setup());
+        Ok(frame)
+    }
+
+    /// How frames are being taken, for matching them with masters.
+    pub fn setup(&self) -> Setup {
+        let bin = self.bin.max(1) as u32;
+        // the SDK centres the ROI unless it's been moved
+        let origin = self.start.unwrap_or((
+            (self.width / bin).saturating_sub(self.curr_width) / 2,
+            (self.height / bin).saturating_sub(self.curr_height) / 2
+        ));
+        Setup {
+            serial: self.serial.clone(),
+            gain: self.get_control_value(ControlType::Gain).ok().map(|gain| gain as f64),
+            offset: self.get_control_value(ControlType::Offset).ok().map(|offset| offset as f64),
+            binning: bin,
+            origin: origin,
+            temperature: self.get_control_value(ControlType::Temperature).ok().map(|t| t as f64 / 10.0)
+        }
+    }
+
+    fn snap(&self, dark: bool) -> Result<Frame> {
         let exposure_duration = self.get_control_value(ControlType::Exposure).unwrap();
         let exposure_ms = exposure_duration / 1000;
+        self.check_present()?;
+        let started = SystemTime::now();
         unsafe {
-            let res = ASICamera2::ASIStartExposure(self.id, 0); // isDark == false, doesnt matter really
+            // isDark only does anything on cameras with a mechanical shutter, which it closes
+            let res = ASICamera2::ASIStartExposure(self.id, dark as i32);
             build_result((), res)?;
         }
 
         println!("Sleeping {}ms", exposure_ms + 2500);
         std::thread::sleep(std::time::Duration::from_millis(exposure_ms as u64 + 2500));
+        self.check_present()?;
+
+        self.image_buffer.reserve(self.frame_size());
+        let res = {
+            let mut buffer = self.image_buffer.frame_mut();
+            unsafe {
+                ASICamera2::ASIGetDataAfterExp(self.id, buffer.as_mut_ptr(), buffer.len() as i64)
+            }
+        };
+        build_result((), res)?;
+        let mut frame = self.buffered_frame();
+        frame.started = Some(started);
+        Ok(frame)
+    }
 
+    fn bytes_per_pixel(&self) -> usize {
+        match self.color_format {
+            ImageType::RAW8 | ImageType::Y8 => 1,
+            ImageType::RAW16 => 2,
+            ImageType::RGB24 | ImageType::END => 3
+        }
+    }
+
+    fn frame_size(&self) -> usize {
+        self.curr_width as usize * self.curr_height as usize * self.bytes_per_pixel()
+    }
+
+    fn buffered_frame(&self) -> Frame {
+        let (channels, bpp) = match self.color_format {
+            ImageType::RAW16 => (1, 16),
+            ImageType::RGB24 | ImageType::END => (3, 8),
+            _ => (1, 8)
+        };
+        let buffer = self.image_buffer.frame();
+        let mut frame = Frame::from_bytes(self.curr_width, self.curr_height, channels, bpp, &buffer);
+        if matches!(self.color_format, ImageType::RAW8 | ImageType::RAW16) {
+            frame.cfa = self.bayer;
+        }
+        frame.compute_stats();
+        frame
+    }
+
+    /// Camera modes this camera can be put in. Cameras without trigger support only have
+    /// `CameraMode::Normal`.
+    pub fn supported_modes(&self) -> Result<Vec<CameraMode>> {
+        if !self.trigger_cam {
+            return Ok(vec![CameraMode::Normal]);
+        }
+        let mut supported = SupportedMode { supported_camera_mode: [CameraMode::End as i32; 16] };
         let res = unsafe {
-            ASICamera2::ASIGetDataAfterExp(
-                self.id,
-                self.image_buffer,
-                self.curr_width as i64 * self.curr_height as i64 * 3
-            )
+            ASICamera2::ASIGetCameraSupportMode(self.id, &mut supported as *mut SupportedMode)
         };
         build_result((), res)?;
+        Ok(supported.supported_camera_mode.iter()
+            .map(|mode| CameraMode::from_raw(*mode))
+            .take_while(|mode| mode.is_some() && *mode != Some(CameraMode::End))
+            .map(|mode| mode.unwrap())
+            .collect())
+    }
+
+    pub fn camera_mode(&self) -> Result<CameraMode> {
+        if !self.trigger_cam {
+            return Ok(CameraMode::Normal);
+        }
+        let mut mode: os::raw::c_int = 0;
+        let res = unsafe {
+            ASICamera2::ASIGetCameraMode(self.id, &mut mode as *mut os::raw::c_int)
+        };
+        build_result((), res)?;
+        CameraMode::from_raw(mode).ok_or(CameraError::InvalidMode)
+    }
+
+    /// Switch between normal and trigger modes. The camera must support `mode` (see
+    /// `supported_modes`), and any video capture is stopped first since the SDK refuses to
+    /// change modes mid-capture.
+    pub fn set_camera_mode(&mut self, mode: CameraMode) -> Result<()> {
+        if !self.supported_modes()?.contains(&mode) {
+            return Err(CameraError::InvalidMode);
+        }
+        if !self.trigger_cam {
+            // only normal mode is supported, so we're already there.
+            return Ok(());
+        }
+        self.stop_video_capture()?;
+        let res = unsafe {
+            ASICamera2::ASISetCameraMode(self.id, mode as i32)
+        };
+        build_result((), res)?;
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Close and reopen the camera, found again by its serial number, then restore the
+    /// controls, ROI, format and mode set through this handle. This is how to pick a camera
+    /// back up after it fell off the bus and was reset.
+    pub fn reopen(&mut self) -> Result<()> {
+        unsafe {
+            // the camera is likely already gone as far as the SDK is concerned
+            let _ = ASICamera2::ASICloseCamera(self.id);
+        }
+        held().retain(|id| *id != self.id);
+        let mut fresh = acquire_serial(&self.serial, &self.model)?;
+        for (control, value) in self.values.iter() {
+            fresh.set_control_value(*control, *value)?;
+        }
+        fresh.set_roi_format(self.curr_width, self.curr_height, self.bin, self.color_format)?;
+        if let Some((x, y)) = self.start {
+            fresh.set_start_pos(x, y)?;
+        }
+        if self.mode != CameraMode::Normal {
+            fresh.set_camera_mode(self.mode)?;
+        }
+        if self.capturing {
+            fresh.start_video_capture()?;
+        }
+        fresh.presence = self.presence.clone();
+        fresh.presence.set(true);
+        *self = fresh;
+        Ok(())
+    }
+
+    /// Shared with a hotplug `Watcher`, which marks it absent when the camera is unplugged.
+    pub fn presence(&self) -> Presence {
+        self.presence.clone()
+    }
+
+    fn check_present(&self) -> Result<()> {
+        if self.presence.is_present() {
+            Ok(())
+        } else {
+            Err(CameraError::CameraRemoved)
+        }
+    }
 
-        let dest = Path::new(path);
-        let file = File::create(dest).unwrap();
-        let ref mut w = BufWriter::new(file);
-        let mut encoder = png::Encoder::new(w, self.curr_width, self.curr_height);
-        encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(
+    /// Fire the soft trigger. Edge modes only need `start == true`; level modes expose from
+    /// `send_soft_trigger(true)` until `send_soft_trigger(false)`.
+    pub fn send_soft_trigger(&self, start: bool) -> Result<()> {
+        let res = unsafe {
+            ASICamera2::ASISendSoftTrigger(self.id, start as i32)
+        };
+        build_result((), res)
+    }
+
+    pub fn start_video_capture(&mut self) -> Result<()> {
+        if self.capturing {
+            return Ok(());
+        }
+        let res = unsafe {
+            ASICamera2::ASIStartVideoCapture(self.id)
+        };
+        build_result((), res)?;
+        self.capturing = true;
+        Ok(())
+    }
+
+    pub fn stop_video_capture(&mut self) -> Result<()> {
+        if !self.capturing {
+            return Ok(());
+        }
+        let res = unsafe {
+            ASICamera2::ASIStopVideoCapture(self.id)
+        };
+        build_result((), res)?;
+        self.capturing = false;
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the next frame of a video capture started with
+    /// `start_video_capture`.
+    pub fn get_video_frame(&self, timeout: Duration) -> Result<Frame> {
+        if !self.capturing {
+            return Err(CameraError::InvalidSequence);
+        }
+        self.check_present()?;
+        self.image_buffer.reserve(self.frame_size());
+        let res = {
+            let mut buffer = self.image_buffer.frame_mut();
             unsafe {
-                std::slice::from_raw_parts(
-                    self.image_buffer,
-                    self.curr_width as usize * self.curr_height as usize* 3
-                )
+                ASICamera2::ASIGetVideoData(self.id, buffer.as_mut_ptr(), buffer.len() as i64, timeout.as_millis() as i32)
             }
-        ).unwrap();
-        Ok(())
+        };
+        build_result((), res)?;
+        Ok(self.buffered_frame())
+    }
+
+    /// Block until a trigger (hardware or soft) produces a frame, or `timeout` passes. The
+    /// camera must be in one of the trigger modes; frames are read through video capture,
+    /// which is started if it isn't running already. In the soft modes nothing comes until
+    /// `send_soft_trigger` is called.
+    pub fn wait_for_triggered_frame(&mut self, timeout: Duration) -> Result<Frame> {
+        if self.camera_mode()? == CameraMode::Normal {
+            return Err(CameraError::InvalidMode);
+        }
+        self.start_video_capture()?;
+        self.get_video_frame(timeout)
     }
 
     pub fn exposure_status(&self) -> Result<ExposureStatus> {
@@ -151,6 +457,7 @@ impl Camera {
         self.curr_width = width - (width % 8);
         self.curr_height = height - (height % 8);
         self.bin = binning;
+        self.color_format = image_type;
         let res = unsafe {
             ASICamera2::ASISetROIFormat(
                 self.id,
@@ -159,7 +466,18 @@ impl Camera {
                 self.bin as i32,
                 image_type as i32)
         };
-        build_result((), res)
+        build_result((), res)?;
+        self.start = None;
+        self.image_buffer.reserve(self.frame_size());
+        Ok(())
+    }
+
+    /// Move the ROI to start at (`x`, `y`), in binned pixels. `set_roi_format` centres it.
+    pub fn set_start_pos(&mut self, x: u32, y: u32) -> Result<()> {
+        let res = unsafe { ASICamera2::ASISetStartPos(self.id, x as i32, y as i32) };
+        build_result((), res)?;
+        self.start = Some((x, y));
+        Ok(())
     }
 }
 
@@ -182,7 +500,11 @@ pub enum CameraError {
     ExposureInProgress = 15,
     GeneralError = 16,
     InvalidMode = 17,
-    End = 18
+    End = 18,
+    // not from the SDK: the SDK itself couldn't be loaded
+    SdkMissing = 19,
+    // not from the SDK: nothing says which camera this is, to key bad pixel maps and masters on
+    NoSerial = 20
 }
 
 fn build_result<T>(value: T, err: ASICamera2::ErrorCode) -> Result<T> {
@@ -209,18 +531,93 @@ fn build_result<T>(value: T, err: ASICamera2::ErrorCode) -> Result<T> {
     }
 }
 
-type Result<T> = std::result::Result<T, CameraError>;
+pub type Result<T> = std::result::Result<T, CameraError>;
+
+impl Imager for Camera {
+    type Error = CameraError;
+
+    fn set_exposure(&mut self, exposure: Duration) -> Result<()> {
+        self.set_control_value(ControlType::Exposure, exposure.as_micros() as i64)
+    }
+
+    fn set_gain(&mut self, gain: f64) -> Result<()> {
+        self.set_control_value(ControlType::Gain, gain.round() as i64)
+    }
+
+    fn exposure_range(&self) -> Result<(Duration, Duration)> {
+        let control = self.controls.get(&ControlType::Exposure).ok_or(CameraError::InvalidControlType)?;
+        Ok((Duration::from_micros(control.min as u64), Duration::from_micros(control.max as u64)))
+    }
+
+    fn set_subframe(&mut self, subframe: Option<Subframe>) -> Result<()> {
+        let bin = self.bin.max(1) as u32;
+        let format = self.color_format;
+        match subframe {
+            Some(subframe) => {
+                self.set_roi_format(subframe.width / bin, subframe.height / bin, self.bin, format)?;
+                self.set_start_pos(subframe.x / bin, subframe.y / bin)
+            }
+            None => self.set_roi_format(self.width / bin, self.height / bin, self.bin, format)
+        }
+    }
+
+    fn serial(&self) -> String {
+        self.serial.clone()
+    }
+
+    fn binning(&self) -> u32 {
+        self.bin.max(1) as u32
+    }
+
+    fn capture(&mut self, frame_type: FrameType) -> Result<Frame> {
+        Camera::capture(self, frame_type)
+    }
+}
+
+impl Reopen for Camera {
+    fn presence(&self) -> Presence {
+        Camera::presence(self)
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        Camera::reopen(self)
+    }
+}
+
+/// Load the ASI SDK, returning any functions it's missing. `acquire` does this if it hasn't
+/// been done already.
+pub fn load_sdk() -> std::result::Result<Vec<&'static str>, LoadError> {
+    ASICamera2::load(&dynlib::candidates("ASI_SDK", option_env!("ASI_SDK_DIR"), &["libASICamera2.so"]))
+}
+
+/// The camera's serial number in hex, if the SDK is new enough to say.
+fn serial_number(camera_id: i32) -> Option<String> {
+    if !ASICamera2::has("ASIGetSerialNumber") {
+        return None;
+    }
+    let mut serial = SerialNumber { id: [0; 8] };
+    let res = unsafe { ASICamera2::ASIGetSerialNumber(camera_id, &mut serial) };
+    build_result((), res).ok()?;
+    Some(serial.id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The USB serial of the only ASI camera plugged in, for SDKs too old to read the camera's
+/// own. With more than one there's no telling which SDK index is which device.
+fn usb_serial(devices: &[usb::UsbDevice]) -> Option<String> {
+    let mut cameras = devices.iter().filter(|device| device.vid == usb::ASI_VID);
+    match (cameras.next(), cameras.next()) {
+        (Some(camera), None) => camera.serial.clone(),
+        _ => None
+    }
+}
 
 pub fn acquire(camera_id: i32) -> Result<Camera> {
+    if let Err(e) = load_sdk() {
+        println!("ASI SDK: {}", e);
+        return Err(CameraError::SdkMissing);
+    }
     unsafe {
-        let cameracount = ASICamera2::ASIGetNumOfConnectedCameras();
-        if camera_id >= cameracount {
-            panic!("Camera id is invalid (detected {} cameras)", camera_id);
-        }
-        let props_layout = Layout::array::<CameraInfo>(cameracount as usize).unwrap();
-        let props = alloc(props_layout) as *mut CameraInfo;
-        let res = ASICamera2::ASIGetCameraProperty(props, camera_id);
-        build_result((), res)?;
+        let camera_props = properties(camera_id)?;
         println!("Got properties");
 
         let res = ASICamera2::ASIOpenCamera(camera_id);
@@ -236,47 +633,216 @@ pub fn acquire(camera_id: i32) -> Result<Camera> {
         build_result((), res)?;
         println!("Got control count");
 
-        let control_layout = Layout::array::<ControlCaps>(1).unwrap();
-        let control = alloc(control_layout) as *mut ControlCaps;
-
+        let serial = match read_serial(camera_id) {
+            Some(serial) => serial,
+            None => {
+                println!(
+                    "Can't tell which {} this is: the ASI SDK is too old to read its serial number and USB doesn't give one for it alone. Update the SDK.",
+                    model_name(&camera_props)
+                );
+                let _ = ASICamera2::ASICloseCamera(camera_id);
+                return Err(CameraError::NoSerial);
+            }
+        };
         let mut camera = Camera::new(camera_id);
+        camera.serial = serial;
+        camera.model = model_name(&camera_props);
 
-        let camera_props: CameraInfo = *props.offset(camera_id as isize);
         camera.width = camera_props.max_width as u32;
         camera.height = camera_props.max_height as u32;
         camera.curr_width = camera_props.max_width as u32;
         camera.curr_height = camera_props.max_height as u32;
         camera.color_format = ImageType::RGB24;
-        camera.image_buffer = alloc(
-            Layout::from_size_align(camera.curr_width as usize * camera.curr_height as usize * 3, 8).unwrap()
-        );
+        camera.trigger_cam = bool::from(camera_props.is_trigger_cam);
+        camera.mechanical_shutter = bool::from(camera_props.mechanical_shutter);
+        if bool::from(camera_props.is_color_cam) {
+            camera.bayer = Some(match camera_props.bayer_pattern {
+                BayerPattern::RG => Cfa::RGGB,
+                BayerPattern::BG => Cfa::BGGR,
+                BayerPattern::GR => Cfa::GRBG,
+                BayerPattern::GB => Cfa::GBRG
+            });
+        }
+        camera.image_buffer.reserve(camera.frame_size());
 
         let res = ASICamera2::ASISetROIFormat(camera_id, camera.width as i32, camera.height as i32, 1, ImageType::RGB24 as i32);
         build_result((), res)?;
         println!("Set ROI/Format");
 
         for c in 0..control_count {
-            let res = ASICamera2::ASIGetControlCaps(0, c, control);
+            let mut caps = MaybeUninit::<ControlCaps>::uninit();
+            let res = ASICamera2::ASIGetControlCaps(camera_id, c, caps.as_mut_ptr());
             build_result((),  res)?;
+            let caps = caps.assume_init();
             println!("Got control {:?}", c);
 
             let control = Control {
-                name: CStr::from_ptr((*control).name.as_ptr()).to_str().unwrap().to_owned(),
-                description: CStr::from_ptr((*control).description.as_ptr()).to_str().unwrap().to_owned(),
-                max: (*control).max_value,
-                min: (*control).min_value,
-                default: (*control).default_value,
-                can_auto: bool::from((*control).is_auto_supported),
-                is_writable: bool::from((*control).is_writable),
-                control_type: (*control).control_type
+                name: CStr::from_ptr(caps.name.as_ptr()).to_str().unwrap().to_owned(),
+                description: CStr::from_ptr(caps.description.as_ptr()).to_str().unwrap().to_owned(),
+                max: caps.max_value,
+                min: caps.min_value,
+                default: caps.default_value,
+                can_auto: bool::from(caps.is_auto_supported),
+                is_writable: bool::from(caps.is_writable),
+                control_type: caps.control_type
             };
 
             camera.controls.insert(control.control_type, control);
         }
 
-        dealloc(control as *mut u8, control_layout);
-        dealloc(props as *mut u8, props_layout);
-
+        held().push(camera_id);
         Ok(camera)
     }
 }
+
+/// Open the camera with serial number `serial`, whichever ID it has now. IDs shift when
+/// cameras are plugged in or re-enumerate, so this is how to find one again. Only cameras of
+/// the same `model` that this program doesn't already have open are opened to read their
+/// serial, and only the one that matches is initialised.
+pub fn acquire_serial(serial: &str, model: &str) -> Result<Camera> {
+    if let Err(e) = load_sdk() {
+        println!("ASI SDK: {}", e);
+        return Err(CameraError::SdkMissing);
+    }
+    let cameracount = unsafe { ASICamera2::ASIGetNumOfConnectedCameras() };
+    // a camera unplugged while this runs just drops out of the list
+    let cameras: Vec<(i32, String)> = (0..cameracount)
+        .filter_map(|camera_id| properties(camera_id).ok().map(|props| (camera_id, model_name(&props))))
+        .collect();
+    let open = held().clone();
+    for camera_id in candidates(&cameras, model, &open) {
+        let found = unsafe {
+            // most likely open in another program if this fails
+            if build_result((), ASICamera2::ASIOpenCamera(camera_id)).is_err() {
+                continue;
+            }
+            let found = read_serial(camera_id);
+            let _ = ASICamera2::ASICloseCamera(camera_id);
+            found
+        };
+        if found.as_deref() == Some(serial) {
+            return acquire(camera_id);
+        }
+    }
+    Err(CameraError::CameraRemoved)
+}
+
+/// The SDK's description of camera `camera_id`, which it gives without opening the camera.
+fn properties(camera_id: i32) -> Result<CameraInfo> {
+    let cameracount = unsafe { ASICamera2::ASIGetNumOfConnectedCameras() };
+    if camera_id < 0 || camera_id >= cameracount {
+        return Err(CameraError::InvalidIndex);
+    }
+    let mut props = MaybeUninit::<CameraInfo>::uninit();
+    let res = unsafe { ASICamera2::ASIGetCameraProperty(props.as_mut_ptr(), camera_id) };
+    build_result((), res)?;
+    // the SDK fills in the one struct for `camera_id`, not an array indexed by it
+    Ok(unsafe { props.assume_init() })
+}
+
+fn model_name(props: &CameraInfo) -> String {
+    unsafe { CStr::from_ptr(props.name.as_ptr()).to_string_lossy().into_owned() }
+}
+
+/// The serial of the open camera `camera_id`, from the SDK or, failing that, USB.
+fn read_serial(camera_id: i32) -> Option<String> {
+    serial_number(camera_id)
+        .or_else(|| usb_serial(&usb::list_devices(usb::SYSFS_ROOT).unwrap_or_default()))
+}
+
+/// The IDs of the cameras this program has open, through any handle.
+fn held() -> MutexGuard<'static, Vec<i32>> {
+    static HELD: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+    HELD.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Which of `cameras`, listed as SDK ID and model, could be the `model` camera being looked
+/// for. Others are left closed, and those in `open` are already some other handle's, like
+/// the guider's, so opening and closing them to read a serial would pull them out from under it.
+fn candidates(cameras: &[(i32, String)], model: &str, open: &[i32]) -> Vec<i32> {
+    cameras.iter()
+        .filter(|(camera_id, name)| name == model && !open.contains(camera_id))
+        .map(|(camera_id, _)| *camera_id)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(port: &str, vid: u16, serial: Option<&str>) -> usb::UsbDevice {
+        usb::UsbDevice {
+            sysfs_path: std::path::PathBuf::from(port),
+            vid: vid,
+            pid: 0x120a,
+            serial: serial.map(|serial| serial.to_owned()),
+            busnum: 1,
+            devnum: 2
+        }
+    }
+
+    #[test]
+    fn only_a_lone_camera_is_keyed_on_its_usb_serial() {
+        let hub = device("1-1", 0x1d6b, Some("0000:00:14.0"));
+        let camera = device("1-2", usb::ASI_VID, Some("3f2a1c"));
+        assert_eq!(usb_serial(&[hub.clone(), camera.clone()]), Some("3f2a1c".to_owned()));
+        assert_eq!(usb_serial(&[hub.clone(), device("2-1", 0x046d, None)]), None);
+        assert_eq!(usb_serial(&[device("1-2", usb::ASI_VID, None)]), None);
+        // two of them could be either way round
+        assert_eq!(usb_serial(&[camera, device("1-3", usb::ASI_VID, Some("77e0d4"))]), None);
+    }
+
+    #[test]
+    fn only_closed_cameras_of_the_same_model_are_opened_to_find_one() {
+        let cameras = vec![
+            (0, "ZWO ASI120MM Mini".to_owned()),
+            (1, "ZWO ASI294MC Pro".to_owned()),
+            (2, "ZWO ASI294MC Pro".to_owned()),
+            (3, "ZWO ASI294MC Pro".to_owned())
+        ];
+        assert_eq!(candidates(&cameras, "ZWO ASI294MC Pro", &[]), vec![1, 2, 3]);
+        // the guider, and a second imaging camera some other handle has open
+        assert_eq!(candidates(&cameras, "ZWO ASI294MC Pro", &[0, 2]), vec![1, 3]);
+        assert_eq!(candidates(&cameras, "ZWO ASI533MM Pro", &[]), Vec::<i32>::new());
+        // a camera unplugged while the list was read isn't in it
+        assert_eq!(candidates(&cameras[..2], "ZWO ASI294MC Pro", &[]), vec![1]);
+    }
+
+    #[test]
+    fn the_image_buffer_hands_out_the_current_frame_length() {
+        let buffer = ImageBuffer::new();
+        buffer.reserve(16);
+        buffer.frame_mut().copy_from_slice(&[7; 16]);
+        buffer.reserve(4);
+        assert_eq!(buffer.frame_mut().len(), 4);
+        assert_eq!(&*buffer.frame(), &[7; 4]);
+        // growing again keeps what was there and zeroes only the new part
+        buffer.reserve(20);
+        assert_eq!(&buffer.frame()[..], &[[7; 16].as_slice(), &[0; 4]].concat()[..]);
+    }
+
+    #[test]
+    fn trigger_modes_by_name() {
+        assert_eq!(CameraMode::from_name("rise"), Some(CameraMode::TrigRiseEdge));
+        assert_eq!(CameraMode::from_name("soft-level"), Some(CameraMode::TrigSoftLevel));
+        assert_eq!(CameraMode::from_name("normal"), Some(CameraMode::Normal));
+        assert_eq!(CameraMode::from_name("in"), None);
+        for mode in 0..7 {
+            assert_eq!(CameraMode::from_raw(mode).map(|mode| mode as i32), Some(mode));
+        }
+    }
+
+    #[test]
+    fn only_modes_the_trigger_input_fires_are_waited_on() {
+        assert_eq!(CameraMode::external_trigger("rise"), Ok(CameraMode::TrigRiseEdge));
+        assert_eq!(CameraMode::external_trigger("fall"), Ok(CameraMode::TrigFallEdge));
+        assert_eq!(CameraMode::external_trigger("high"), Ok(CameraMode::TrigHighLevel));
+        assert_eq!(CameraMode::external_trigger("low"), Ok(CameraMode::TrigLowLevel));
+        for soft in ["soft-edge", "soft-level"].iter() {
+            let error = CameraMode::external_trigger(soft).unwrap_err();
+            assert!(error.contains("software"), "{}", error);
+        }
+        assert!(CameraMode::external_trigger("normal").unwrap_err().contains("isn't a trigger mode"));
+        assert!(CameraMode::external_trigger("in").unwrap_err().contains("isn't an ASI trigger mode"));
+    }
+}
@@ -0,0 +1,133 @@
+// Which camera backends this machine can use. The vendor SDKs are loaded at runtime, so a
+// missing one only takes that vendor's cameras out of the picture.
+
+#[cfg(feature = "asi")]
+use crate::asicam;
+#[cfg(feature = "qhy")]
+use crate::firmware;
+#[cfg(feature = "qhy")]
+use crate::qhyccd;
+
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+    Asi,
+    Qhy,
+    Sim
+}
+
+pub const ALL: &[Backend] = &[Backend::Asi, Backend::Qhy, Backend::Sim];
+
+/// Why a backend can't be used.
+#[derive(Debug, Clone)]
+pub enum LoadError {
+    /// Its library wouldn't load.
+    #[cfg(any(feature = "asi", feature = "qhy"))]
+    Library(crate::dynlib::LoadError),
+    /// This binary was built without its cargo feature.
+    NotBuilt
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(any(feature = "asi", feature = "qhy"))]
+            LoadError::Library(e) => write!(f, "{}", e),
+            LoadError::NotBuilt => write!(f, "not built with this backend's cargo feature")
+        }
+    }
+}
+
+#[cfg(any(feature = "asi", feature = "qhy"))]
+impl From<crate::dynlib::LoadError> for LoadError {
+    fn from(err: crate::dynlib::LoadError) -> LoadError {
+        LoadError::Library(err)
+    }
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Asi => "asi",
+            Backend::Qhy => "qhy",
+            Backend::Sim => "sim"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Backend> {
+        ALL.iter().cloned().find(|backend| backend.name() == name)
+    }
+
+    /// Load whatever this backend needs, returning the optional functions its library is
+    /// missing.
+    pub fn load(&self) -> Result<Vec<&'static str>, LoadError> {
+        match self {
+            #[cfg(feature = "asi")]
+            Backend::Asi => Ok(asicam::load_sdk()?),
+            #[cfg(feature = "qhy")]
+            Backend::Qhy => {
+                // QHY cameras need libusb too, to load their firmware
+                let mut missing = qhyccd::load_sdk()?;
+                missing.extend(firmware::libusb::load_library()?);
+                Ok(missing)
+            }
+            #[cfg(feature = "sim")]
+            Backend::Sim => Ok(Vec::new()),
+            #[allow(unreachable_patterns)]
+            _ => Err(LoadError::NotBuilt)
+        }
+    }
+}
+
+pub enum Status {
+    Available,
+    /// The library loaded, but is an older version without these optional functions, so
+    /// whatever needs them (serial numbers, GPS tuning) isn't there.
+    Partial(Vec<&'static str>),
+    Unavailable(LoadError)
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Available => write!(f, "available"),
+            Status::Partial(missing) => write!(f, "available, but missing {}", missing.join(", ")),
+            Status::Unavailable(e) => write!(f, "unavailable: {}", e)
+        }
+    }
+}
+
+pub struct Registry {
+    pub backends: Vec<(Backend, Status)>
+}
+
+impl Registry {
+    /// Try to load every backend.
+    pub fn load() -> Registry {
+        Registry {
+            backends: ALL.iter().map(|backend| {
+                let status = match backend.load() {
+                    Ok(ref missing) if missing.is_empty() => Status::Available,
+                    Ok(missing) => Status::Partial(missing),
+                    Err(e) => Status::Unavailable(e)
+                };
+                (*backend, status)
+            }).collect()
+        }
+    }
+
+    pub fn is_available(&self, backend: Backend) -> bool {
+        self.backends.iter().any(|(b, status)| *b == backend && !matches!(status, Status::Unavailable(_)))
+    }
+
+    pub fn available(&self) -> Vec<Backend> {
+        ALL.iter().cloned().filter(|backend| self.is_available(*backend)).collect()
+    }
+
+    pub fn report(&self) {
+        for (backend, status) in self.backends.iter() {
+            println!("{}: {}", backend.name(), status);
+        }
+    }
+}
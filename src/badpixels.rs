@@ -0,0 +1,430 @@
+// Bad pixel maps: hot and cold pixels and hot columns found in a camera's master darks and
+// flats, kept on disk per camera and binning, and patched over in frames with the median of
+// their good neighbours. This is instead of `ASIEnableDarkSubtract`, whose BMP we can neither
+// see into nor control, and it works for QHY cameras too.
+//
+// Neighbours are always the same color on a Bayer sensor, two pixels away, so patching a red
+// pixel never pulls in green.
+
+use crate::frame::{Cfa, Frame};
+use crate::image::{self, Image};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where maps are kept, under the session directory.
+pub const DIR: &str = "badpixels";
+
+/// MAD * this estimates sigma for Gaussian noise.
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BadPixelMap {
+    pub serial: String,
+    pub binning: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels much brighter than their neighbours in the dark or flat.
+    pub hot: Vec<(u32, u32)>,
+    /// Pixels much dimmer than their neighbours in the flat, dead ones included.
+    pub cold: Vec<(u32, u32)>,
+    /// Columns brighter than their neighbours all the way down.
+    pub columns: Vec<u32>
+}
+
+/// Thresholds for calling a pixel bad.
+#[derive(Debug, Clone)]
+pub struct Detection {
+    /// A pixel this many sigmas above the median of its neighbours is hot.
+    pub hot_sigma: f32,
+    /// A pixel this many sigmas below the median of its neighbours in the flat is cold...
+    pub cold_sigma: f32,
+    /// ...if it's also this fraction below it, so the edges of dust shadows aren't.
+    pub cold_depth: f32,
+    /// A column whose median is this many sigmas above its neighbours' is hot.
+    pub column_sigma: f32,
+    /// How many same-color neighbours out to look, each way.
+    pub radius: u32
+}
+
+impl Default for Detection {
+    fn default() -> Detection {
+        Detection {
+            hot_sigma: 5.0,
+            cold_sigma: 5.0,
+            cold_depth: 0.2,
+            column_sigma: 5.0,
+            radius: 2
+        }
+    }
+}
+
+/// Distance to the nearest neighbour of the same color.
+fn step(cfa: Option<Cfa>) -> u32 {
+    if cfa.is_some() { 2 } else { 1 }
+}
+
+/// The median of the same-color neighbours of (`x`, `y`) out to `radius` of them, in an image
+/// `size` (width and height) big, skipping any `skip` says to.
+fn neighbour_median<T, F>(size: (u32, u32), step: u32, radius: u32, (x, y): (u32, u32), sample: F, skip: &dyn Fn(u32, u32) -> bool) -> Option<T>
+where
+    T: Copy + PartialOrd,
+    F: Fn(u32, u32) -> T
+{
+    let (width, height) = size;
+    let reach = (step * radius) as i64;
+    let mut values = Vec::with_capacity(((2 * radius + 1) * (2 * radius + 1)) as usize);
+    for dy in (-reach..=reach).step_by(step as usize) {
+        for dx in (-reach..=reach).step_by(step as usize) {
+            let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+            if (dx == 0 && dy == 0) || nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                continue;
+            }
+            if skip(nx as u32, ny as u32) {
+                continue;
+            }
+            values.push(sample(nx as u32, ny as u32));
+        }
+    }
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    Some(values[values.len() / 2])
+}
+
+/// Each pixel of `image` (its first channel) less the median of its neighbours.
+fn residuals(image: &Image, detection: &Detection) -> (Vec<f32>, Vec<f32>) {
+    let step = step(image.cfa);
+    let mut residuals = Vec::with_capacity(image.width as usize * image.height as usize);
+    let mut locals = Vec::with_capacity(residuals.capacity());
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let local = neighbour_median((image.width, image.height), step, detection.radius, (x, y), |x, y| image.at(x, y, 0), &|_, _| false)
+                .unwrap_or(0.0);
+            residuals.push(image.at(x, y, 0) - local);
+            locals.push(local);
+        }
+    }
+    (residuals, locals)
+}
+
+/// Robust sigma of `values`, from their MAD.
+fn sigma(values: &[f32]) -> f32 {
+    let mut values = values.to_vec();
+    let centre = image::median(&mut values);
+    for value in values.iter_mut() {
+        *value = (*value - centre).abs();
+    }
+    (image::median(&mut values) * MAD_TO_SIGMA).max(f32::EPSILON)
+}
+
+impl Detection {
+    /// Find the bad pixels in a camera's master dark and master flat, which have to be the
+    /// same size. `None` if there's neither, or they don't match.
+    pub fn detect(&self, serial: &str, binning: u32, dark: Option<&Image>, flat: Option<&Image>) -> Option<BadPixelMap> {
+        let shape = dark.or(flat)?;
+        if let (Some(dark), Some(flat)) = (dark, flat) {
+            if !dark.same_shape(flat) {
+                return None;
+            }
+        }
+        let mut map = BadPixelMap {
+            serial: serial.to_owned(),
+            binning: binning,
+            width: shape.width,
+            height: shape.height,
+            hot: Vec::new(),
+            cold: Vec::new(),
+            columns: Vec::new()
+        };
+        if let Some(dark) = dark {
+            map.columns = self.hot_columns(dark);
+            let (residuals, _) = residuals(dark, self);
+            let threshold = self.hot_sigma * sigma(&residuals);
+            for (i, residual) in residuals.iter().enumerate() {
+                let (x, y) = ((i % dark.width as usize) as u32, (i / dark.width as usize) as u32);
+                if *residual > threshold && !map.columns.contains(&x) {
+                    map.hot.push((x, y));
+                }
+            }
+        }
+        if let Some(flat) = flat {
+            let (residuals, locals) = residuals(flat, self);
+            let sigma = sigma(&residuals);
+            for (i, residual) in residuals.iter().enumerate() {
+                let (x, y) = ((i % flat.width as usize) as u32, (i / flat.width as usize) as u32);
+                if map.columns.contains(&x) {
+                    continue;
+                }
+                if *residual < -self.cold_sigma * sigma && *residual < -self.cold_depth * locals[i] {
+                    map.cold.push((x, y));
+                } else if *residual > self.hot_sigma * sigma && !map.hot.contains(&(x, y)) {
+                    map.hot.push((x, y));
+                }
+            }
+        }
+        map.hot.sort_by_key(|(x, y)| (*y, *x));
+        Some(map)
+    }
+
+    /// Columns whose median in the dark stands out from their neighbours'. A hot column can
+    /// be too faint to see pixel by pixel, but its median over the whole height isn't.
+    fn hot_columns(&self, dark: &Image) -> Vec<u32> {
+        let medians: Vec<f32> = (0..dark.width).map(|x| {
+            let mut column: Vec<f32> = (0..dark.height).map(|y| dark.at(x, y, 0)).collect();
+            image::median(&mut column)
+        }).collect();
+        let step = step(dark.cfa);
+        let residuals: Vec<f32> = (0..dark.width).map(|x| {
+            let local = neighbour_median((dark.width, 1), step, self.radius, (x, 0), |x, _| medians[x as usize], &|_, _| false)
+                .unwrap_or(medians[x as usize]);
+            medians[x as usize] - local
+        }).collect();
+        let threshold = self.column_sigma * sigma(&residuals);
+        (0..dark.width).filter(|x| residuals[*x as usize] > threshold).collect()
+    }
+}
+
+impl BadPixelMap {
+    /// Where the map for `serial` at `binning` lives under `dir`.
+    pub fn path(dir: &Path, serial: &str, binning: u32) -> PathBuf {
+        let serial: String = serial.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+        dir.join(format!("{}_bin{}.txt", serial, binning))
+    }
+
+    /// The map for `serial` at `binning` under `dir`, or `None` if there isn't one.
+    pub fn load_for(dir: &Path, serial: &str, binning: u32) -> io::Result<Option<BadPixelMap>> {
+        match fs::read_to_string(BadPixelMap::path(dir, serial, binning)) {
+            Ok(text) => Ok(Some(BadPixelMap::parse(&text)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Write the map under `dir`, creating it if needed, and return where it went.
+    pub fn save(&self, dir: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let path = BadPixelMap::path(dir, &self.serial, self.binning);
+        fs::write(&path, self.to_text())?;
+        Ok(path)
+    }
+
+    /// One defect per line, so maps can be read and diffed by hand.
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("# bad pixel map\n");
+        text.push_str(&format!("serial {}\nbinning {}\nsize {} {}\n", self.serial, self.binning, self.width, self.height));
+        for x in self.columns.iter() {
+            text.push_str(&format!("column {}\n", x));
+        }
+        for (x, y) in self.hot.iter() {
+            text.push_str(&format!("hot {} {}\n", x, y));
+        }
+        for (x, y) in self.cold.iter() {
+            text.push_str(&format!("cold {} {}\n", x, y));
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> io::Result<BadPixelMap> {
+        let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bad line in bad pixel map: {:?}", line));
+        let mut map = BadPixelMap {
+            serial: String::new(),
+            binning: 1,
+            width: 0,
+            height: 0,
+            hot: Vec::new(),
+            cold: Vec::new(),
+            columns: Vec::new()
+        };
+        for line in text.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let mut words = line.split_whitespace();
+            let key = words.next().unwrap_or("");
+            if key == "serial" {
+                map.serial = line["serial".len()..].trim().to_owned();
+                continue;
+            }
+            let numbers: Vec<u32> = words.map(|word| word.parse()).collect::<Result<_, _>>().map_err(|_| invalid(line))?;
+            match (key, numbers.as_slice()) {
+                ("binning", [binning]) => map.binning = *binning,
+                ("size", [width, height]) => {
+                    map.width = *width;
+                    map.height = *height;
+                }
+                ("column", [x]) => map.columns.push(*x),
+                ("hot", [x, y]) => map.hot.push((*x, *y)),
+                ("cold", [x, y]) => map.cold.push((*x, *y)),
+                _ => return Err(invalid(line))
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hot.len() + self.cold.len() + self.columns.len() * self.height as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Which pixels are bad, row-major.
+    fn mask(&self) -> Vec<bool> {
+        let mut mask = vec![false; self.width as usize * self.height as usize];
+        for (x, y) in self.hot.iter().chain(self.cold.iter()) {
+            mask[*y as usize * self.width as usize + *x as usize] = true;
+        }
+        for x in self.columns.iter() {
+            for y in 0..self.height {
+                mask[y as usize * self.width as usize + *x as usize] = true;
+            }
+        }
+        mask
+    }
+
+    /// Replace every bad sample with the median of its good same-color neighbours.
+    fn correct<T: Copy + PartialOrd>(&self, channels: u32, cfa: Option<Cfa>, data: &mut [T]) {
+        let mask = self.mask();
+        let width = self.width;
+        let is_bad = |x: u32, y: u32| mask[y as usize * width as usize + x as usize];
+        // a hot column's neighbours above and below are bad too, so look wide enough to find
+        // good ones either side
+        let radius = 2;
+        let step = step(cfa);
+        for (i, _) in mask.iter().enumerate().filter(|(_, bad)| **bad) {
+            let (x, y) = ((i % width as usize) as u32, (i / width as usize) as u32);
+            for channel in 0..channels {
+                let sample = |x: u32, y: u32| data[(y as usize * width as usize + x as usize) * channels as usize + channel as usize];
+                if let Some(value) = neighbour_median((width, self.height), step, radius, (x, y), sample, &is_bad) {
+                    data[i * channels as usize + channel as usize] = value;
+                }
+            }
+        }
+    }
+
+    /// Patch the bad pixels in a frame from the camera. Returns false, leaving it alone, if
+    /// the frame isn't the size the map was made at.
+    pub fn correct_frame(&self, frame: &mut Frame) -> bool {
+        if frame.width != self.width || frame.height != self.height {
+            return false;
+        }
+        self.correct(frame.channels, frame.cfa, &mut frame.data);
+        if frame.stats.is_some() {
+            frame.compute_stats();
+        }
+        true
+    }
+
+    /// Patch the bad pixels in an image. Returns false, leaving it alone, if the image isn't
+    /// the size the map was made at.
+    pub fn correct_image(&self, image: &mut Image) -> bool {
+        if image.width != self.width || image.height != self.height {
+            return false;
+        }
+        self.correct(image.channels, image.cfa, &mut image.data);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bayer_pixels_are_patched_from_their_own_color() {
+        // RGGB: red 100, greens 200, blue 300, with a hot red and a dead blue
+        let cfa = Cfa::parse("RGGB").unwrap();
+        let mut frame = Frame::from_bytes(8, 8, 1, 16, &[0; 128]);
+        for y in 0..8 {
+            for x in 0..8 {
+                frame.data[(y * 8 + x) as usize] = [100, 200, 300][cfa.color_at(x, y)];
+            }
+        }
+        frame.cfa = Some(cfa);
+        frame.data[4 * 8 + 4] = 60000;
+        frame.data[5 * 8 + 3] = 0;
+        let map = BadPixelMap {
+            serial: "X".to_owned(),
+            binning: 1,
+            width: 8,
+            height: 8,
+            hot: vec![(4, 4)],
+            cold: vec![(3, 5)],
+            columns: Vec::new()
+        };
+        assert!(map.correct_frame(&mut frame));
+        assert_eq!(frame.data[4 * 8 + 4], 100);
+        assert_eq!(frame.data[5 * 8 + 3], 300);
+
+        let mut small = Frame::from_bytes(4, 4, 1, 16, &[0; 32]);
+        assert!(!map.correct_frame(&mut small));
+    }
+
+    #[test]
+    fn maps_are_kept_per_serial_and_binning() {
+        let dir = crate::testing::TempDir::new("badpixels");
+        let map = BadPixelMap {
+            serial: "ASI 294/MC Pro".to_owned(),
+            binning: 2,
+            width: 100,
+            height: 80,
+            hot: vec![(1, 2), (50, 3)],
+            cold: vec![(7, 7)],
+            columns: vec![33]
+        };
+        let path = map.save(dir.path()).unwrap();
+        assert_eq!(path.file_name().unwrap(), "ASI_294_MC_Pro_bin2.txt");
+        assert_eq!(BadPixelMap::load_for(dir.path(), &map.serial, 2).unwrap(), Some(map.clone()));
+        assert_eq!(BadPixelMap::load_for(dir.path(), &map.serial, 1).unwrap(), None);
+        assert_eq!(BadPixelMap::load_for(dir.path(), "another", 2).unwrap(), None);
+        assert_eq!(map.len(), 3 + map.height as usize);
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn the_simulated_cameras_defects_are_found_and_patched() {
+        use crate::frame::FrameType;
+        use crate::imaging::Imager;
+        use crate::sim;
+
+        let mut camera = sim::Camera::new(320, 240);
+        camera.scatter_defects(30, 10, 3);
+        camera.hot_columns.push((123, 0.5));
+        let master = |camera: &mut sim::Camera, frame_type: FrameType| {
+            let images: Vec<Image> = (0..9).map(|_| Image::from_frame(&camera.capture(frame_type))).collect();
+            Image::median_combine(&images).unwrap()
+        };
+        camera.set_exposure_ms(30000);
+        let dark = master(&mut camera, FrameType::Dark);
+        camera.set_exposure_ms(5000);
+        let flat = master(&mut camera, FrameType::Flat);
+
+        let serial = Imager::serial(&camera);
+        let map = Detection::default().detect(&serial, 1, Some(&dark), Some(&flat)).unwrap();
+        assert_eq!((map.serial.as_str(), map.width, map.height), (serial.as_str(), 320, 240));
+        assert_eq!(map.columns, vec![123]);
+        for pixel in camera.dead_pixels.iter() {
+            assert!(map.cold.contains(pixel), "missed dead pixel {:?}", pixel);
+        }
+        // the warmest are easy; the coolest hide in a 30 s dark's shot noise
+        for (x, y, current) in camera.hot_pixels.iter().filter(|(_, _, current)| *current > 20.0) {
+            assert!(map.hot.contains(&(*x, *y)), "missed hot pixel ({}, {}) at {:.0} e/s", x, y, current);
+        }
+        let real_hot = map.hot.iter().filter(|(x, y)| camera.hot_pixels.iter().any(|(hx, hy, _)| hx == x && hy == y)).count();
+        let real_cold = map.cold.iter().filter(|pixel| camera.dead_pixels.contains(pixel)).count();
+        assert!(map.hot.len() - real_hot <= 3, "{} hot pixels that aren't", map.hot.len() - real_hot);
+        assert!(map.cold.len() - real_cold <= 3, "{} cold pixels that aren't", map.cold.len() - real_cold);
+
+        camera.set_exposure_ms(30000);
+        camera.stars.clear();
+        let mut light = camera.capture(FrameType::Light);
+        assert!(map.correct_frame(&mut light));
+        let stats = light.stats.as_ref().unwrap();
+        let limit = stats.all.median + 8.0 * stats.all.mad * 1.4826;
+        for (x, y) in map.hot.iter() {
+            assert!((light.sample(*x, *y, 0) as f64) < limit, "({}, {}) still hot", x, y);
+        }
+        assert!((0..240).all(|y| (light.sample(123, y, 0) as f64) < limit));
+    }
+}
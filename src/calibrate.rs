@@ -0,0 +1,799 @@
+// Calibrating lights ourselves: take off the overscan, subtract the bias or a dark scaled to
+// the light's exposure, divide by the flat normalized to 1, patch the bad pixels and write
+// the result as floats.
+// Masters are picked out of a directory of them by the setup recorded in each frame, so a
+// master from another camera, gain, offset, binning or region is never used.
+//
+// Everything works on raw Bayer frames, before debayering: subtraction is per pixel, and the
+// flat is normalized and bad pixels patched per color so one filter's response never leaks
+// into another's.
+
+use crate::badpixels::BadPixelMap;
+use crate::fits;
+use crate::frame::{Frame, FrameType, HeaderValue, Setup};
+use crate::image::{self, Image};
+use crate::imaging::Subframe;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Exposures within this fraction of each other count as the same.
+const SAME_EXPOSURE: f64 = 0.01;
+
+/// Flat pixels below this fraction of the flat's median are too dim to divide by.
+const MIN_FLAT: f32 = 0.05;
+
+/// Samples further than this many sigmas from the light's background are left out of the
+/// dark's scale.
+const CLIP_SIGMA: f32 = 3.0;
+
+/// MAD * this estimates sigma for Gaussian noise.
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    Io(io::Error),
+    /// The overscan or data region isn't inside the frame.
+    Overscan,
+    /// The frames to combine weren't all taken with the same setup and exposure.
+    MixedFrames,
+    NoFrames,
+    /// No master matches the frame, so there's nothing to calibrate it with.
+    NoMasters
+}
+
+impl From<io::Error> for CalibrationError {
+    fn from(e: io::Error) -> CalibrationError {
+        CalibrationError::Io(e)
+    }
+}
+
+/// A frame as calibration sees it: its samples as floats and its keywords, whether it came
+/// straight off a camera or out of a FITS file.
+#[derive(Debug, Clone)]
+pub struct Raw {
+    pub image: Image,
+    pub headers: Vec<(String, HeaderValue)>
+}
+
+impl Raw {
+    pub fn from_frame(frame: &Frame) -> Raw {
+        let mut headers = frame.headers.clone();
+        if let (Some(exposure), None) = (frame.exposure(), frame.header("EXPTIME")) {
+            headers.push(("EXPTIME".to_owned(), HeaderValue::Float(exposure)));
+        }
+        Raw { image: Image::from_frame(frame), headers: headers }
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Raw> {
+        let (image, headers) = fits::read(path)?;
+        Ok(Raw { image: image, headers: headers })
+    }
+
+    fn header(&self, key: &str) -> Option<&HeaderValue> {
+        self.headers.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn setup(&self) -> Setup {
+        Setup::from_headers(&self.headers)
+    }
+
+    /// The exposure in seconds, if recorded.
+    pub fn exposure(&self) -> Option<f64> {
+        match self.header("EXPTIME") {
+            Some(HeaderValue::Float(f)) => Some(*f),
+            Some(HeaderValue::Int(i)) => Some(*i as f64),
+            _ => None
+        }
+    }
+
+    pub fn filter(&self) -> Option<String> {
+        match self.header("FILTER") {
+            Some(HeaderValue::Str(filter)) => Some(filter.clone()),
+            _ => None
+        }
+    }
+}
+
+/// How much of the overscan to measure the bias from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OverscanMode {
+    /// One level for the whole frame, from the median of the whole overscan.
+    Level,
+    /// A level for each row, from the median of the overscan in that row, for overscan
+    /// columns beside the exposed pixels. This takes out bias that wanders during readout.
+    Rows,
+    /// A level for each column, for overscan rows above or below the exposed pixels.
+    Columns
+}
+
+/// Overscan columns or rows: pixels that are read out but never exposed, so they measure the
+/// bias of each frame as it was read. The bias is taken off the frame, which is then cropped
+/// to the region that was exposed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Overscan {
+    pub bias: Subframe,
+    pub data: Subframe,
+    pub mode: OverscanMode
+}
+
+impl Overscan {
+    /// The overscan a frame's `BIASSEC` and `DATASEC` describe, measured by row if it runs
+    /// alongside the exposed pixels, by column if it runs across them.
+    pub fn from_headers(headers: &[(String, HeaderValue)]) -> Option<Overscan> {
+        let section = |key: &str| match headers.iter().find(|(k, _)| k == key).map(|(_, v)| v) {
+            Some(HeaderValue::Str(section)) => fits::parse_section(section),
+            _ => None
+        };
+        let bias = section("BIASSEC")?;
+        let data = section("DATASEC")?;
+        let mode = if bias.y <= data.y && bias.y + bias.height >= data.y + data.height {
+            OverscanMode::Rows
+        } else if bias.x <= data.x && bias.x + bias.width >= data.x + data.width {
+            OverscanMode::Columns
+        } else {
+            OverscanMode::Level
+        };
+        Some(Overscan { bias: bias, data: data, mode: mode })
+    }
+
+    pub fn apply(&self, image: &Image) -> Result<Image, CalibrationError> {
+        let overscan = image.crop(&self.bias).ok_or(CalibrationError::Overscan)?;
+        let mut data = image.crop(&self.data).ok_or(CalibrationError::Overscan)?;
+        let mut all = overscan.data.clone();
+        let level = image::median(&mut all);
+        let row = (data.width * data.channels) as usize;
+        match self.mode {
+            OverscanMode::Level => {
+                for sample in data.data.iter_mut() {
+                    *sample -= level;
+                }
+            }
+            OverscanMode::Rows => {
+                let overscan_row = (overscan.width * overscan.channels) as usize;
+                for (y, samples) in data.data.chunks_mut(row).enumerate() {
+                    let start = ((self.data.y + y as u32 - self.bias.y) as usize) * overscan_row;
+                    let mut bias = overscan.data[start..start + overscan_row].to_vec();
+                    let level = image::median(&mut bias);
+                    for sample in samples.iter_mut() {
+                        *sample -= level;
+                    }
+                }
+            }
+            OverscanMode::Columns => {
+                let levels: Vec<f32> = (0..data.width).map(|x| {
+                    let mut bias: Vec<f32> = (0..overscan.height)
+                        .flat_map(|y| (0..overscan.channels).map(move |c| (y, c)))
+                        .map(|(y, c)| overscan.at(self.data.x + x - self.bias.x, y, c))
+                        .collect();
+                    image::median(&mut bias)
+                }).collect();
+                for samples in data.data.chunks_mut(row) {
+                    for (i, sample) in samples.iter_mut().enumerate() {
+                        *sample -= levels[i / data.channels as usize];
+                    }
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+fn same_exposure(a: f64, b: f64) -> bool {
+    (a - b).abs() <= SAME_EXPOSURE * a.max(b)
+}
+
+fn frame_type_of(imagetyp: &str) -> Option<FrameType> {
+    [FrameType::Bias, FrameType::Dark, FrameType::Flat, FrameType::Light].iter().cloned()
+        .find(|frame_type| frame_type.imagetyp() == imagetyp)
+}
+
+#[derive(Debug, Clone)]
+pub struct Master {
+    pub frame_type: FrameType,
+    pub setup: Setup,
+    /// In seconds.
+    pub exposure: f64,
+    pub filter: Option<String>,
+    /// How many frames went into it.
+    pub frames: usize,
+    pub image: Image,
+    /// The file it was saved to or loaded from.
+    pub path: Option<PathBuf>
+}
+
+impl Master {
+    /// Median-combine frames of `frame_type` into a master, taking the overscan off each
+    /// first if there's any: `overscan`, or what each frame's headers say.
+    pub fn combine(frame_type: FrameType, frames: &[Raw], overscan: Option<&Overscan>) -> Result<Master, CalibrationError> {
+        let first = frames.first().ok_or(CalibrationError::NoFrames)?;
+        let setup = first.setup();
+        let exposure = first.exposure().unwrap_or(0.0);
+        let filter = first.filter();
+        let mut images = Vec::with_capacity(frames.len());
+        let mut temperature = 0.0;
+        for frame in frames {
+            let frame_setup = frame.setup();
+            if !frame_setup.matches(&setup, f64::INFINITY)
+                || (frame_type != FrameType::Bias && !same_exposure(frame.exposure().unwrap_or(0.0), exposure))
+                || frame.filter() != filter
+            {
+                return Err(CalibrationError::MixedFrames);
+            }
+            temperature += frame_setup.temperature.unwrap_or(0.0);
+            images.push(match overscan.cloned().or_else(|| Overscan::from_headers(&frame.headers)) {
+                Some(overscan) => overscan.apply(&frame.image)?,
+                None => frame.image.clone()
+            });
+        }
+        let image = Image::median_combine(&images).ok_or(CalibrationError::MixedFrames)?;
+        Ok(Master {
+            frame_type: frame_type,
+            setup: Setup {
+                temperature: setup.temperature.map(|_| temperature / frames.len() as f64),
+                ..setup
+            },
+            exposure: exposure,
+            filter: if frame_type == FrameType::Flat { filter } else { None },
+            frames: frames.len(),
+            image: image,
+            path: None
+        })
+    }
+
+    pub fn headers(&self) -> Vec<(String, HeaderValue)> {
+        let mut headers = vec![
+            ("IMAGETYP".to_owned(), HeaderValue::Str(format!("Master {}", self.frame_type.imagetyp()))),
+            ("EXPTIME".to_owned(), HeaderValue::Float(self.exposure)),
+            ("NCOMBINE".to_owned(), HeaderValue::Int(self.frames as i64))
+        ];
+        if let Some(filter) = self.filter.as_ref() {
+            headers.push(("FILTER".to_owned(), HeaderValue::Str(filter.clone())));
+        }
+        headers.extend(self.setup.to_headers());
+        headers
+    }
+
+    /// A file name that keeps masters for different setups apart.
+    pub fn file_name(&self) -> String {
+        let serial: String = self.setup.serial.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+        let mut name = format!("master_{:?}_{}_bin{}", self.frame_type, serial, self.setup.binning).to_lowercase();
+        if let Some(gain) = self.setup.gain {
+            name.push_str(&format!("_gain{}", gain));
+        }
+        if self.frame_type != FrameType::Bias {
+            name.push_str(&format!("_{:.3}s", self.exposure));
+        }
+        if let Some(filter) = self.filter.as_ref() {
+            name.push_str(&format!("_{}", filter));
+        }
+        if let Some(temperature) = self.setup.temperature {
+            name.push_str(&format!("_{:.0}C", temperature));
+        }
+        name + ".fits"
+    }
+
+    /// Write the master under `dir` as 32-bit floats.
+    pub fn save(&mut self, dir: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(self.file_name());
+        fits::write_image(&self.image, &self.headers(), &path)?;
+        self.path = Some(path.clone());
+        Ok(path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Master> {
+        let raw = Raw::read(path)?;
+        let frame_type = match raw.header("IMAGETYP") {
+            Some(HeaderValue::Str(imagetyp)) => imagetyp.strip_prefix("Master ").and_then(frame_type_of),
+            _ => None
+        };
+        let frame_type = frame_type.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a master"))?;
+        let frames = match raw.header("NCOMBINE") {
+            Some(HeaderValue::Int(n)) => *n as usize,
+            _ => 1
+        };
+        Ok(Master {
+            frame_type: frame_type,
+            setup: raw.setup(),
+            exposure: raw.exposure().unwrap_or(0.0),
+            filter: raw.filter(),
+            frames: frames,
+            path: Some(path.to_owned()),
+            image: raw.image
+        })
+    }
+
+    /// What to call the master in a calibrated frame's headers.
+    fn name(&self) -> String {
+        match self.path.as_ref().and_then(|path| path.file_name()) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => self.file_name()
+        }
+    }
+}
+
+/// Every master in a directory.
+#[derive(Debug, Clone)]
+pub struct MasterLibrary {
+    pub masters: Vec<Master>,
+    /// How far, in °C, a master's temperature can be from a frame's and still calibrate it.
+    pub temperature_tolerance: f64
+}
+
+impl Default for MasterLibrary {
+    fn default() -> MasterLibrary {
+        MasterLibrary {
+            masters: Vec::new(),
+            temperature_tolerance: 2.0
+        }
+    }
+}
+
+impl MasterLibrary {
+    /// Load every master in `dir`, skipping FITS files that aren't masters.
+    pub fn load(dir: &Path) -> io::Result<MasterLibrary> {
+        let mut library = MasterLibrary::default();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+            if extension.as_deref() != Some("fits") && extension.as_deref() != Some("fit") {
+                continue;
+            }
+            match Master::load(&path) {
+                Ok(master) => library.masters.push(master),
+                Err(e) => println!("Skipping {}: {}", path.display(), e)
+            }
+        }
+        Ok(library)
+    }
+
+    /// The best master of `frame_type` for a frame taken with `setup` at `size` (width and
+    /// height): the same setup, with the closest exposure then the closest temperature.
+    /// Darks have to match `exposure` exactly unless `exact` is false, and flats have to be
+    /// for `filter`.
+    pub fn find(&self, frame_type: FrameType, setup: &Setup, size: (u32, u32), exposure: Option<f64>, exact: bool, filter: Option<&str>) -> Option<&Master> {
+        // by ratio, so a dark twice as long is as close as one half as long
+        let exposure_miss = |master: &Master| match exposure {
+            Some(exposure) if frame_type == FrameType::Dark && master.exposure > 0.0 && exposure > 0.0 => (master.exposure / exposure).ln().abs(),
+            _ => 0.0
+        };
+        let temperature_miss = |master: &Master| match (master.setup.temperature, setup.temperature) {
+            (Some(a), Some(b)) => (a - b).abs(),
+            _ => 0.0
+        };
+        self.masters.iter()
+            .filter(|master| master.frame_type == frame_type)
+            .filter(|master| master.setup.matches(setup, self.temperature_tolerance))
+            .filter(|master| (master.image.width, master.image.height) == size)
+            .filter(|master| frame_type != FrameType::Flat || master.filter.as_deref() == filter)
+            .filter(|master| match exposure {
+                Some(exposure) if frame_type == FrameType::Dark && exact => same_exposure(master.exposure, exposure),
+                _ => true
+            })
+            .min_by(|a, b| {
+                (exposure_miss(a), temperature_miss(a)).partial_cmp(&(exposure_miss(b), temperature_miss(b)))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Calibrator {
+    pub library: MasterLibrary,
+    /// Maps to patch bad pixels with, picked by camera serial and binning.
+    pub bad_pixels: Vec<BadPixelMap>,
+    /// Overscan to take off every frame, instead of what each frame's headers say.
+    pub overscan: Option<Overscan>,
+    /// Scale the dark to whatever takes the most noise out of the background, rather than by
+    /// exposure. This makes up for dark current drifting with temperature between the darks
+    /// and lights.
+    pub optimize_dark: bool
+}
+
+/// A calibrated light, and what went into it.
+#[derive(Debug, Clone)]
+pub struct Calibrated {
+    pub image: Image,
+    /// The light's keywords, with what was done to it added.
+    pub headers: Vec<(String, HeaderValue)>,
+    pub bias: Option<String>,
+    pub dark: Option<String>,
+    /// What the dark's thermal signal was multiplied by.
+    pub dark_scale: Option<f64>,
+    pub flat: Option<String>,
+    /// Bad pixels patched.
+    pub bad_pixels: usize
+}
+
+impl Calibrated {
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fits::write_image(&self.image, &self.headers, path)
+    }
+}
+
+/// Which samples are below where `data` saturates. Saturated pixels stop growing with the
+/// exposure, so they'd throw out the dark's scale.
+fn unsaturated(data: &[f32]) -> Vec<bool> {
+    let max = data.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    data.iter().map(|sample| *sample < 0.95 * max).collect()
+}
+
+/// The scale for `thermal` that leaves the least variance in `signal - scale * thermal`,
+/// over the samples that are `usable`.
+fn optimal_scale(signal: &[f32], thermal: &[f32], usable: &[bool]) -> Option<f64> {
+    let samples = || signal.iter().zip(thermal.iter()).zip(usable.iter()).filter(|(_, usable)| **usable).map(|(pair, _)| pair);
+    let n = samples().count() as f64;
+    if n == 0.0 {
+        return None;
+    }
+    let mean_signal = samples().map(|(s, _)| *s as f64).sum::<f64>() / n;
+    let mean_thermal = samples().map(|(_, t)| *t as f64).sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (s, t) in samples() {
+        let t = *t as f64 - mean_thermal;
+        covariance += (*s as f64 - mean_signal) * t;
+        variance += t * t;
+    }
+    if variance <= f64::EPSILON {
+        None
+    } else {
+        Some((covariance / variance).max(0.0))
+    }
+}
+
+/// Where the bulk of `values` lies, among those `usable`: its median, and how far from the
+/// median a sample has to be to stand out from it.
+fn spread(values: &[f32], usable: &[bool]) -> Option<(f32, f32)> {
+    let mut kept: Vec<f32> = values.iter().zip(usable.iter()).filter(|(_, usable)| **usable).map(|(v, _)| *v).collect();
+    if kept.is_empty() {
+        return None;
+    }
+    let level = image::median(&mut kept);
+    for value in kept.iter_mut() {
+        *value = (*value - level).abs();
+    }
+    Some((level, CLIP_SIGMA * MAD_TO_SIGMA * image::median(&mut kept)))
+}
+
+/// The dark's scale fitted over the background alone. Stars, nebulae and satellite trails
+/// have nothing to do with the dark, and a star over a hot pixel would pull the scale up, so
+/// anything standing out from the sky in the light's 3x3 median is left out. Hot pixels are
+/// single pixels, which the median passes over, so they stay in, and are what the fit is
+/// mostly made of.
+/// `width` is in samples, so for RGB the median runs along each row across channels too.
+fn background_scale(signal: &[f32], thermal: &[f32], usable: &[bool], width: usize) -> Option<f64> {
+    let height = signal.len() / width;
+    let mut smoothed = signal.to_vec();
+    let mut window = Vec::with_capacity(9);
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            window.clear();
+            for row in y - 1..y + 2 {
+                window.extend_from_slice(&signal[row * width + x - 1..row * width + x + 2]);
+            }
+            smoothed[y * width + x] = image::median(&mut window);
+        }
+    }
+    let (sky, limit) = spread(&smoothed, usable)?;
+    let background: Vec<bool> = smoothed.iter().zip(usable.iter()).map(|(s, usable)| *usable && (s - sky).abs() <= limit).collect();
+    optimal_scale(signal, thermal, &background)
+}
+
+/// Divide `light` by `flat` after normalizing each color of the flat to a median of 1.
+fn divide_by_flat(light: &mut Image, flat: &Image) {
+    let groups = if flat.cfa.is_some() { 3 } else { flat.channels as usize };
+    let group_of = |i: usize| match flat.cfa {
+        Some(cfa) => cfa.color_at((i % flat.width as usize) as u32, (i / flat.width as usize) as u32),
+        None => i % flat.channels as usize
+    };
+    let mut samples = vec![Vec::new(); groups];
+    for (i, sample) in flat.data.iter().enumerate() {
+        samples[group_of(i)].push(*sample);
+    }
+    let medians: Vec<f32> = samples.iter_mut().map(|samples| image::median(samples)).collect();
+    for (i, sample) in light.data.iter_mut().enumerate() {
+        let median = medians[group_of(i)];
+        let normalized = flat.data[i] / median;
+        // leave pixels the flat says see almost nothing for the bad pixel map
+        if median > 0.0 && normalized > MIN_FLAT {
+            *sample /= normalized;
+        }
+    }
+}
+
+impl Calibrator {
+    pub fn new(library: MasterLibrary) -> Calibrator {
+        Calibrator {
+            library: library,
+            bad_pixels: Vec::new(),
+            overscan: None,
+            optimize_dark: true
+        }
+    }
+
+    pub fn calibrate_frame(&self, frame: &Frame) -> Result<Calibrated, CalibrationError> {
+        self.calibrate(&Raw::from_frame(frame))
+    }
+
+    pub fn calibrate(&self, light: &Raw) -> Result<Calibrated, CalibrationError> {
+        let overscan = self.overscan.or_else(|| Overscan::from_headers(&light.headers));
+        let mut image = match overscan.as_ref() {
+            Some(overscan) => overscan.apply(&light.image)?,
+            None => light.image.clone()
+        };
+        let setup = light.setup();
+        let exposure = light.exposure();
+        let filter = light.filter();
+        let (width, height) = (image.width, image.height);
+        let library = &self.library;
+        let bias = library.find(FrameType::Bias, &setup, (width, height), None, false, None);
+        // without a bias there's no telling the dark's thermal signal from its bias, so
+        // there's no scaling it
+        let dark = library.find(FrameType::Dark, &setup, (width, height), exposure, bias.is_none(), None);
+        let flat = library.find(FrameType::Flat, &setup, (width, height), None, false, filter.as_deref());
+        if bias.is_none() && dark.is_none() && flat.is_none() {
+            return Err(CalibrationError::NoMasters);
+        }
+
+        let mut dark_scale = None;
+        match (dark, bias) {
+            (Some(dark), Some(bias)) => {
+                let thermal: Vec<f32> = dark.image.data.iter().zip(bias.image.data.iter()).map(|(d, b)| d - b).collect();
+                let usable: Vec<bool> = unsaturated(&image.data).iter().zip(unsaturated(&dark.image.data).iter())
+                    .map(|(light, dark)| *light && *dark)
+                    .collect();
+                for (sample, bias) in image.data.iter_mut().zip(bias.image.data.iter()) {
+                    *sample -= bias;
+                }
+                let by_exposure = match exposure {
+                    Some(exposure) if dark.exposure > 0.0 => exposure / dark.exposure,
+                    _ => 1.0
+                };
+                let scale = if self.optimize_dark {
+                    background_scale(&image.data, &thermal, &usable, (image.width * image.channels) as usize).unwrap_or(by_exposure)
+                } else {
+                    by_exposure
+                };
+                for (sample, thermal) in image.data.iter_mut().zip(thermal.iter()) {
+                    *sample -= scale as f32 * thermal;
+                }
+                dark_scale = Some(scale);
+            }
+            (Some(dark), None) => {
+                for (sample, dark) in image.data.iter_mut().zip(dark.image.data.iter()) {
+                    *sample -= dark;
+                }
+                dark_scale = Some(1.0);
+            }
+            (None, Some(bias)) => {
+                for (sample, bias) in image.data.iter_mut().zip(bias.image.data.iter()) {
+                    *sample -= bias;
+                }
+            }
+            (None, None) => {}
+        }
+
+        if let Some(flat) = flat {
+            // the flat's own dark-flat if there is one, or at least its bias
+            let under = library.find(FrameType::Dark, &flat.setup, (width, height), Some(flat.exposure), true, None).or(bias);
+            let mut signal = flat.image.clone();
+            if let Some(under) = under {
+                for (sample, under) in signal.data.iter_mut().zip(under.image.data.iter()) {
+                    *sample -= under;
+                }
+            }
+            divide_by_flat(&mut image, &signal);
+        }
+
+        let map = self.bad_pixels.iter()
+            .find(|map| map.serial == setup.serial && map.binning == setup.binning && map.width == width && map.height == height);
+        let bad_pixels = match map {
+            Some(map) if map.correct_image(&mut image) => map.len(),
+            _ => 0
+        };
+
+        let mut headers = light.headers.clone();
+        if overscan.is_some() {
+            // cropped off
+            headers.retain(|(key, _)| key != "BIASSEC" && key != "DATASEC");
+        }
+        let mut calibrated = Calibrated {
+            image: image,
+            headers: headers,
+            bias: bias.map(|master| master.name()),
+            dark: dark.map(|master| master.name()),
+            dark_scale: dark_scale,
+            flat: flat.map(|master| master.name()),
+            bad_pixels: bad_pixels
+        };
+        calibrated.record();
+        Ok(calibrated)
+    }
+}
+
+impl Calibrated {
+    /// Note what was done in the headers: `CALSTAT` lists the steps as the usual letters.
+    fn record(&mut self) {
+        let mut steps = String::new();
+        let mut notes = Vec::new();
+        if let Some(bias) = self.bias.as_ref() {
+            steps.push('B');
+            notes.push(("CALBIAS", HeaderValue::Str(bias.clone())));
+        }
+        if let Some(dark) = self.dark.as_ref() {
+            steps.push('D');
+            notes.push(("CALDARK", HeaderValue::Str(dark.clone())));
+        }
+        if let Some(scale) = self.dark_scale {
+            notes.push(("DARKSCAL", HeaderValue::Float(scale)));
+        }
+        if let Some(flat) = self.flat.as_ref() {
+            steps.push('F');
+            notes.push(("CALFLAT", HeaderValue::Str(flat.clone())));
+        }
+        if self.bad_pixels > 0 {
+            notes.push(("BADPIXEL", HeaderValue::Int(self.bad_pixels as i64)));
+        }
+        notes.push(("CALSTAT", HeaderValue::Str(steps)));
+        for (key, value) in notes {
+            match self.headers.iter_mut().find(|(k, _)| k == key) {
+                Some(header) => header.1 = value,
+                None => self.headers.push((key.to_owned(), value))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::badpixels::Detection;
+    use crate::imaging::Imager;
+    use crate::sim;
+    use crate::testing::TempDir;
+
+    /// Median-combine nine frames of `frame_type` at `ms` into a master.
+    fn master(camera: &mut sim::Camera, frame_type: FrameType, ms: u32) -> Master {
+        camera.set_exposure_ms(ms);
+        let frames: Vec<Raw> = (0..9).map(|_| Raw::from_frame(&camera.capture(frame_type))).collect();
+        Master::combine(frame_type, &frames, None).unwrap()
+    }
+
+    /// The median of a 40 pixel square with its corner at (`x`, `y`).
+    fn level(image: &Image, x: u32, y: u32) -> f32 {
+        image.crop(&Subframe { x: x, y: y, width: 40, height: 40 }).unwrap().median()
+    }
+
+    #[test]
+    fn lights_lose_their_vignetting_dark_current_and_hot_pixels() {
+        let dir = TempDir::new("calibrate");
+        let mut camera = sim::Camera::new(320, 240);
+        camera.scatter_defects(30, 10, 3);
+        camera.vignetting = 0.3;
+        camera.dark_current = 0.5;
+        // just sky, so anything bright left over is a hot pixel
+        camera.stars.clear();
+        master(&mut camera, FrameType::Bias, 0).save(dir.path()).unwrap();
+        let mut dark = master(&mut camera, FrameType::Dark, 120000);
+        dark.save(dir.path()).unwrap();
+        master(&mut camera, FrameType::Dark, 5000).save(dir.path()).unwrap();
+        let mut flat = master(&mut camera, FrameType::Flat, 5000);
+        flat.save(dir.path()).unwrap();
+
+        let mut calibrator = Calibrator::new(MasterLibrary::load(dir.path()).unwrap());
+        assert_eq!(calibrator.library.masters.len(), 4);
+        let map = Detection::default().detect(&Imager::serial(&camera), 1, Some(&dark.image), Some(&flat.image)).unwrap();
+        calibrator.bad_pixels.push(map);
+
+        // a different exposure from the dark's, so it has to be scaled
+        camera.set_exposure_ms(60000);
+        let light = camera.capture(FrameType::Light);
+        let calibrated = calibrator.calibrate_frame(&light).unwrap();
+        assert_eq!(calibrated.dark.as_deref(), Some(dark.name().as_str()));
+        assert_eq!(calibrated.flat.as_deref(), Some(flat.name().as_str()));
+        let scale = calibrated.dark_scale.unwrap();
+        assert!((scale - 0.5).abs() < 0.03, "dark scaled by {:.3}", scale);
+        assert!(calibrated.bad_pixels > 0);
+        assert_eq!(calibrated.headers.iter().find(|(key, _)| key == "CALSTAT").map(|(_, value)| value), Some(&HeaderValue::Str("BDF".to_owned())));
+
+        // sky in a corner against sky in the middle, which vignetting dims
+        let mut raw = Image::from_frame(&light);
+        for sample in raw.data.iter_mut() {
+            *sample -= camera.offset as f32;
+        }
+        let before = level(&raw, 0, 0) / level(&raw, 140, 100);
+        let after = level(&calibrated.image, 0, 0) / level(&calibrated.image, 140, 100);
+        assert!(before < 0.85, "corner/centre {:.3} before", before);
+        assert!((after - 1.0).abs() < 0.03, "corner/centre {:.3} after", after);
+
+        let hot = |image: &Image| camera.hot_pixels.iter().filter(|(x, y, _)| image.at(*x, *y, 0) > image.median() + 100.0).count();
+        assert!(hot(&raw) > 10, "{} hot pixels before", hot(&raw));
+        assert_eq!(hot(&calibrated.image), 0);
+
+        let path = dir.join("light_cal.fits");
+        calibrated.write(&path).unwrap();
+        let (read_back, headers) = fits::read(&path).unwrap();
+        assert_eq!(read_back, calibrated.image);
+        assert!(headers.iter().any(|(key, value)| key == "DARKSCAL" && matches!(value, HeaderValue::Float(written) if (written - scale).abs() < 1e-6)));
+
+        let uncalibrated = Calibrator::new(MasterLibrary::default());
+        assert!(matches!(uncalibrated.calibrate_frame(&light), Err(CalibrationError::NoMasters)));
+    }
+
+    #[test]
+    fn the_dark_scale_follows_drifting_dark_current_and_ignores_the_stars() {
+        let mut camera = sim::Camera::new(320, 240);
+        camera.dark_current = 0.5;
+        camera.stars = sim::scatter_stars(320, 240, 150, 9);
+        for star in camera.stars.iter_mut() {
+            star.flux *= 20.0;
+        }
+        // warm pixels, half of them under stars, which a fit over the whole frame would take
+        // for dark current
+        let mut rng = sim::Rng::new(5);
+        camera.hot_pixels = camera.stars.iter().take(40).map(|star| (star.x as u32, star.y as u32, 10.0 + 30.0 * rng.uniform())).collect();
+        camera.hot_pixels.extend((0..40).map(|_| ((rng.uniform() * 320.0) as u32, (rng.uniform() * 240.0) as u32, 10.0 + 30.0 * rng.uniform())));
+        let mut library = MasterLibrary::default();
+        library.masters.push(master(&mut camera, FrameType::Bias, 0));
+        library.masters.push(master(&mut camera, FrameType::Dark, 60000));
+
+        // warmer for the lights: 30% more dark current than the darks had
+        camera.dark_current *= 1.3;
+        for pixel in camera.hot_pixels.iter_mut() {
+            pixel.2 *= 1.3;
+        }
+        camera.set_exposure_ms(60000);
+        let light = camera.capture(FrameType::Light);
+        let calibrator = Calibrator::new(library);
+        let scale = calibrator.calibrate_frame(&light).unwrap().dark_scale.unwrap();
+        assert!((scale - 1.3).abs() < 0.05, "dark scaled by {:.3}", scale);
+
+        let by_exposure = Calibrator { optimize_dark: false, ..calibrator };
+        assert_eq!(by_exposure.calibrate_frame(&light).unwrap().dark_scale, Some(1.0));
+    }
+
+    /// How much the median level scatters from row to row.
+    fn row_scatter(image: &Image) -> f32 {
+        let mut levels: Vec<f32> = image.data.chunks(image.width as usize).map(|row| image::median(&mut row.to_vec())).collect();
+        let mean = levels.iter().sum::<f32>() / levels.len() as f32;
+        for level in levels.iter_mut() {
+            *level = (*level - mean) * (*level - mean);
+        }
+        (levels.iter().sum::<f32>() / levels.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn overscan_takes_out_bias_wandering_from_row_to_row() {
+        let mut camera = sim::Camera::new(320, 240);
+        camera.overscan = 32;
+        camera.row_noise = 8.0;
+        camera.stars.clear();
+        let mut library = MasterLibrary::default();
+        library.masters.push(master(&mut camera, FrameType::Bias, 0));
+        library.masters.push(master(&mut camera, FrameType::Flat, 5000));
+        camera.set_exposure_ms(30000);
+        let light = Raw::from_frame(&camera.capture(FrameType::Light));
+        assert_eq!((light.image.width, light.image.height), (352, 240));
+
+        let overscan = Overscan::from_headers(&light.headers).unwrap();
+        assert_eq!(overscan.bias, Subframe { x: 320, y: 0, width: 32, height: 240 });
+        assert_eq!(overscan.data, Subframe { x: 0, y: 0, width: 320, height: 240 });
+        assert!(matches!(overscan.mode, OverscanMode::Rows));
+
+        let mut calibrator = Calibrator::new(library);
+        let by_row = calibrator.calibrate(&light).unwrap();
+        assert_eq!((by_row.image.width, by_row.image.height), (320, 240));
+        assert!(!by_row.headers.iter().any(|(key, _)| key == "BIASSEC" || key == "DATASEC"));
+        calibrator.overscan = Some(Overscan { mode: OverscanMode::Level, ..overscan });
+        let by_frame = calibrator.calibrate(&light).unwrap();
+
+        let raw = row_scatter(&light.image.crop(&overscan.data).unwrap());
+        let (by_frame, by_row) = (row_scatter(&by_frame.image), row_scatter(&by_row.image));
+        assert!(raw > 6.0, "{:.2} ADU raw", raw);
+        // a level for the frame can't follow the rows; one for each row can
+        assert!(by_frame > 0.7 * raw, "{:.2} ADU with one level against {:.2} raw", by_frame, raw);
+        assert!(by_row < 0.25 * raw, "{:.2} ADU with a level per row against {:.2} raw", by_row, raw);
+    }
+}
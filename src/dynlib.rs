@@ -0,0 +1,232 @@
+// Loading shared libraries at runtime, so a missing vendor SDK means that vendor's cameras
+// are unavailable instead of the binary refusing to start.
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os;
+
+const RTLD_NOW: os::raw::c_int = 2;
+const RTLD_GLOBAL: os::raw::c_int = 0x100;
+
+extern "C" {
+    fn dlopen(filename: *const os::raw::c_char, flags: os::raw::c_int) -> *mut os::raw::c_void;
+    fn dlsym(handle: *mut os::raw::c_void, symbol: *const os::raw::c_char) -> *mut os::raw::c_void;
+    fn dlclose(handle: *mut os::raw::c_void) -> os::raw::c_int;
+    fn dlerror() -> *mut os::raw::c_char;
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    /// What each candidate file failed with, in the order they were tried.
+    pub attempts: Vec<(String, String)>
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "couldn't load")?;
+        for (i, (name, err)) in self.attempts.iter().enumerate() {
+            write!(f, "{} {} ({})", if i == 0 { "" } else { ";" }, name, err)?;
+        }
+        Ok(())
+    }
+}
+
+fn last_error() -> String {
+    unsafe {
+        let err = dlerror();
+        if err.is_null() {
+            "unknown error".to_owned()
+        } else {
+            CStr::from_ptr(err).to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// An open shared library. Once its functions are in use it's never closed: the SDKs start
+/// threads of their own and unloading them out from under those isn't worth the trouble.
+pub struct Library {
+    handle: *mut os::raw::c_void
+}
+
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+impl Library {
+    /// Open the library `name`. A plain file name is looked up the way the dynamic linker
+    /// does; anything with a `/` is a path. The error is the linker's.
+    pub fn open(name: &str) -> Result<Library, String> {
+        let cname = CString::new(name).map_err(|_| "name has a NUL in it".to_owned())?;
+        // the SDKs' own dependencies (libusb, libstdc++) get pulled in along with them
+        let handle = unsafe { dlopen(cname.as_ptr(), RTLD_NOW | RTLD_GLOBAL) };
+        if handle.is_null() {
+            return Err(last_error());
+        }
+        Ok(Library { handle: handle })
+    }
+
+    /// The address of `name`, which must end in a NUL, or null if it isn't there.
+    pub fn symbol(&self, name: &str) -> *mut os::raw::c_void {
+        unsafe { dlsym(self.handle, name.as_ptr() as *const os::raw::c_char) }
+    }
+
+    /// Unload a library that turned out not to be the one wanted, so its symbols, opened
+    /// with `RTLD_GLOBAL`, don't shadow those of the one that is.
+    pub fn close(self) {
+        unsafe {
+            dlclose(self.handle);
+        }
+    }
+}
+
+/// Where to look for a library: `$<env>` if it's set, then `names` on the linker's search
+/// path, then each of `names` in `dir` (where `build.rs` says the vendored copies are).
+pub fn candidates(env: &str, dir: Option<&str>, names: &[&str]) -> Vec<String> {
+    let mut candidates = Vec::new();
+    if let Ok(path) = std::env::var(env) {
+        candidates.push(path);
+    }
+    candidates.extend(names.iter().map(|name| name.to_string()));
+    if let Some(dir) = dir {
+        candidates.extend(names.iter().map(|name| format!("{}/{}", dir, name)));
+    }
+    candidates
+}
+
+/// Declare a library's functions, to be looked up at runtime instead of linked against.
+/// This generates `load(candidates)`, which opens the first candidate that has every function
+/// not listed as `optional` and returns the optional ones it's missing, `is_loaded()`,
+/// `has(name)` for checking an optional function is there before calling it, and an
+/// `unsafe fn` for each function that calls through to the library, panicking if it isn't
+/// loaded or lacks that function.
+macro_rules! dynamic_library {
+    (
+        $library:literal;
+        $(optional: [$($optional:ident),* $(,)?];)?
+        $(
+            $(#[$meta:meta])*
+            pub fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;
+        )*
+    ) => {
+        #[allow(non_snake_case)]
+        struct Functions {
+            $( $name: Option<unsafe extern "C" fn($($ty),*) $(-> $ret)?>, )*
+        }
+
+        static FUNCTIONS: std::sync::OnceLock<Functions> = std::sync::OnceLock::new();
+
+        /// Functions only newer versions of the library have, which callers check for.
+        const OPTIONAL: &[&str] = &[$($(stringify!($optional)),*)?];
+
+        /// Open the first of `candidates` that has every required function, returning the
+        /// optional ones it doesn't have.
+        pub fn load(candidates: &[String]) -> std::result::Result<Vec<&'static str>, crate::dynlib::LoadError> {
+            if let Some(functions) = FUNCTIONS.get() {
+                return Ok(functions.missing());
+            }
+            let mut attempts = Vec::new();
+            for candidate in candidates {
+                let library = match crate::dynlib::Library::open(candidate) {
+                    Ok(library) => library,
+                    Err(e) => {
+                        attempts.push((candidate.to_owned(), e));
+                        continue;
+                    }
+                };
+                let functions = Functions {
+                    $( $name: unsafe {
+                        let address = library.symbol(concat!(stringify!($name), "\0"));
+                        if address.is_null() {
+                            None
+                        } else {
+                            Some(std::mem::transmute::<*mut std::os::raw::c_void, unsafe extern "C" fn($($ty),*) $(-> $ret)?>(address))
+                        }
+                    }, )*
+                };
+                let missing = functions.missing();
+                let required: Vec<&str> = missing.iter().cloned().filter(|name| !OPTIONAL.contains(name)).collect();
+                if !required.is_empty() {
+                    attempts.push((candidate.to_owned(), format!("not a usable {}, it lacks {}", $library, required.join(", "))));
+                    library.close();
+                    continue;
+                }
+                let _ = FUNCTIONS.set(functions);
+                return Ok(missing);
+            }
+            Err(crate::dynlib::LoadError { attempts: attempts })
+        }
+
+        pub fn is_loaded() -> bool {
+            FUNCTIONS.get().is_some()
+        }
+
+        /// Whether the library is loaded and has the function `name`.
+        pub fn has(name: &str) -> bool {
+            FUNCTIONS.get().map(|functions| !functions.missing().contains(&name)).unwrap_or(false)
+        }
+
+        impl Functions {
+            fn missing(&self) -> Vec<&'static str> {
+                let mut missing = Vec::new();
+                $( if self.$name.is_none() { missing.push(stringify!($name)); } )*
+                missing
+            }
+        }
+
+        $(
+            $(#[$meta])*
+            #[allow(clippy::too_many_arguments)] // as many as the library's function takes
+            pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                let functions = FUNCTIONS.get().expect(concat!($library, " isn't loaded"));
+                let f = functions.$name.expect(concat!(stringify!($name), " isn't in the loaded ", $library));
+                f($($arg),*)
+            }
+        )*
+    }
+}
+
+pub(crate) use dynamic_library;
+
+#[cfg(test)]
+mod tests {
+    mod libm {
+        dynamic_library! {
+            "libm";
+            optional: [not_in_libm];
+            pub fn cos(x: f64) -> f64;
+            pub fn not_in_libm(x: f64) -> f64;
+        }
+    }
+
+    mod nothing {
+        dynamic_library! {
+            "nothing";
+            pub fn not_anywhere(x: f64) -> f64;
+        }
+    }
+
+    #[test]
+    fn libraries_without_the_required_symbols_are_passed_over() {
+        let candidates = vec!["libnot-a-library.so".to_owned(), "libc.so.6".to_owned(), "libm.so.6".to_owned()];
+        assert_eq!(libm::load(&candidates).unwrap(), vec!["not_in_libm"]);
+        assert!(libm::has("cos"));
+        assert!(!libm::has("not_in_libm"));
+        assert_eq!(unsafe { libm::cos(0.0) }, 1.0);
+    }
+
+    #[test]
+    fn load_errors_name_each_library_and_what_it_lacks() {
+        let err = nothing::load(&["libnot-a-library.so".to_owned(), "libm.so.6".to_owned()]).err().unwrap();
+        assert_eq!(err.attempts.len(), 2);
+        assert_eq!(err.attempts[0].0, "libnot-a-library.so");
+        assert_eq!(err.attempts[1], ("libm.so.6".to_owned(), "not a usable nothing, it lacks not_anywhere".to_owned()));
+        assert!(!nothing::is_loaded());
+    }
+
+    #[test]
+    fn candidates_go_environment_then_search_path_then_directory() {
+        assert_eq!(
+            super::candidates("CALIBRATION_COLLECTOR_UNSET_FOR_TESTS", Some("/opt/sdk"), &["liba.so", "liba.so.1"]),
+            vec!["liba.so", "liba.so.1", "/opt/sdk/liba.so", "/opt/sdk/liba.so.1"]
+        );
+    }
+}
@@ -0,0 +1,56 @@
+// The parts of ZWO's EAF_focuser.h we use, for their Electronic Automatic Focuser.
+use crate::dynlib::dynamic_library;
+
+use std::os;
+
+pub const SUCCESS: os::raw::c_int = 0;
+pub const ERROR_INVALID_INDEX: os::raw::c_int = 1;
+pub const ERROR_INVALID_ID: os::raw::c_int = 2;
+pub const ERROR_INVALID_VALUE: os::raw::c_int = 3;
+pub const ERROR_REMOVED: os::raw::c_int = 4;
+pub const ERROR_MOVING: os::raw::c_int = 5;
+pub const ERROR_ERROR_STATE: os::raw::c_int = 6;
+pub const ERROR_GENERAL_ERROR: os::raw::c_int = 7;
+pub const ERROR_NOT_SUPPORTED: os::raw::c_int = 8;
+pub const ERROR_CLOSED: os::raw::c_int = 9;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Info {
+    pub id: os::raw::c_int,
+    pub name: [os::raw::c_char; 64usize],
+    pub max_step: os::raw::c_int
+}
+
+// The functions return an EAF_ERROR_CODE, taken as a plain int so a code newer than these
+// can't make an invalid enum.
+dynamic_library! {
+    "ZWO EAF SDK";
+
+    #[doc = "the number of EAFs connected; call this first, and again to pick up replugged ones"]
+    pub fn EAFGetNum() -> os::raw::c_int;
+
+    #[doc = "the ID of the EAF at `index`, which stays the same while it's plugged in"]
+    pub fn EAFGetID(index: os::raw::c_int, ID: *mut os::raw::c_int) -> os::raw::c_int;
+
+    #[doc = "open the EAF before anything else is done with it"]
+    pub fn EAFOpen(ID: os::raw::c_int) -> os::raw::c_int;
+
+    #[doc = "the EAF's name and how far it can go; this doesn't need it to be open"]
+    pub fn EAFGetProperty(ID: os::raw::c_int, pInfo: *mut Info) -> os::raw::c_int;
+
+    #[doc = "start moving to the absolute position `iStep`"]
+    pub fn EAFMove(ID: os::raw::c_int, iStep: os::raw::c_int) -> os::raw::c_int;
+
+    pub fn EAFStop(ID: os::raw::c_int) -> os::raw::c_int;
+
+    #[doc = "whether it's moving, and whether that's from its hand controller"]
+    pub fn EAFIsMoving(ID: os::raw::c_int, pbVal: *mut bool, pbHandControl: *mut bool) -> os::raw::c_int;
+
+    pub fn EAFGetPosition(ID: os::raw::c_int, piStep: *mut os::raw::c_int) -> os::raw::c_int;
+
+    #[doc = "the temperature probe's reading; -273 and ERROR_GENERAL_ERROR when there's no probe reading"]
+    pub fn EAFGetTemp(ID: os::raw::c_int, pfTemp: *mut f32) -> os::raw::c_int;
+
+    pub fn EAFClose(ID: os::raw::c_int) -> os::raw::c_int;
+}
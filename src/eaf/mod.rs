@@ -0,0 +1,144 @@
+// ZWO's EAF focuser, through their SDK, loaded at runtime like the camera SDKs.
+pub mod EAF_focuser;
+
+use self::EAF_focuser::Info;
+
+use crate::dynlib::{self, LoadError};
+use crate::focus;
+
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+use std::os;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EafError {
+    InvalidIndex,
+    InvalidId,
+    InvalidValue,
+    Removed,
+    Moving,
+    ErrorState,
+    GeneralError,
+    NotSupported,
+    Closed,
+    /// A code this doesn't know.
+    Unknown(i32),
+    // not from the SDK: the SDK itself couldn't be loaded
+    SdkMissing
+}
+
+type Result<T> = std::result::Result<T, EafError>;
+
+fn build_result<T>(value: T, code: os::raw::c_int) -> Result<T> {
+    match code {
+        EAF_focuser::SUCCESS => Ok(value),
+        EAF_focuser::ERROR_INVALID_INDEX => Err(EafError::InvalidIndex),
+        EAF_focuser::ERROR_INVALID_ID => Err(EafError::InvalidId),
+        EAF_focuser::ERROR_INVALID_VALUE => Err(EafError::InvalidValue),
+        EAF_focuser::ERROR_REMOVED => Err(EafError::Removed),
+        EAF_focuser::ERROR_MOVING => Err(EafError::Moving),
+        EAF_focuser::ERROR_ERROR_STATE => Err(EafError::ErrorState),
+        EAF_focuser::ERROR_GENERAL_ERROR => Err(EafError::GeneralError),
+        EAF_focuser::ERROR_NOT_SUPPORTED => Err(EafError::NotSupported),
+        EAF_focuser::ERROR_CLOSED => Err(EafError::Closed),
+        other => Err(EafError::Unknown(other))
+    }
+}
+
+/// Load the EAF SDK, returning any functions it's missing. `open` does this if it hasn't
+/// been done already.
+pub fn load_sdk() -> std::result::Result<Vec<&'static str>, LoadError> {
+    // ZWO don't ship it with the camera SDK, so nothing's vendored to fall back on
+    EAF_focuser::load(&dynlib::candidates("EAF_SDK", None, &["libEAFFocuser.so"]))
+}
+
+/// An open EAF. It's left open when dropped, like the cameras, since the SDK keeps moving
+/// it to where it was last sent either way.
+#[derive(Debug)]
+pub struct Focuser {
+    id: i32,
+    pub name: String,
+    /// The furthest out it can go, in steps.
+    pub max_step: i32
+}
+
+/// Open the EAF at `index` among those plugged in.
+pub fn open(index: i32) -> Result<Focuser> {
+    if let Err(e) = load_sdk() {
+        println!("EAF SDK: {}", e);
+        return Err(EafError::SdkMissing);
+    }
+    unsafe {
+        if index < 0 || index >= EAF_focuser::EAFGetNum() {
+            return Err(EafError::InvalidIndex);
+        }
+        let mut id = 0;
+        build_result((), EAF_focuser::EAFGetID(index, &mut id))?;
+        let mut info = MaybeUninit::<Info>::uninit();
+        build_result((), EAF_focuser::EAFGetProperty(id, info.as_mut_ptr()))?;
+        let info = info.assume_init();
+        build_result((), EAF_focuser::EAFOpen(id))?;
+        Ok(Focuser {
+            id: id,
+            name: CStr::from_ptr(info.name.as_ptr()).to_string_lossy().into_owned(),
+            max_step: info.max_step
+        })
+    }
+}
+
+impl Focuser {
+    /// Stop wherever it's got to.
+    pub fn stop(&mut self) -> Result<()> {
+        build_result((), unsafe { EAF_focuser::EAFStop(self.id) })
+    }
+}
+
+impl focus::Focuser for Focuser {
+    type Error = EafError;
+
+    fn position(&self) -> Result<i32> {
+        let mut position = 0;
+        build_result((), unsafe { EAF_focuser::EAFGetPosition(self.id, &mut position) })?;
+        Ok(position)
+    }
+
+    fn move_to(&mut self, position: i32) -> Result<()> {
+        if position < 0 || position > self.max_step {
+            return Err(EafError::InvalidValue);
+        }
+        build_result((), unsafe { EAF_focuser::EAFMove(self.id, position) })
+    }
+
+    fn is_moving(&self) -> Result<bool> {
+        let mut moving = false;
+        let mut hand_control = false;
+        build_result((), unsafe { EAF_focuser::EAFIsMoving(self.id, &mut moving, &mut hand_control) })?;
+        Ok(moving)
+    }
+
+    fn temperature(&self) -> Result<Option<f64>> {
+        let mut temperature = 0f32;
+        match build_result((), unsafe { EAF_focuser::EAFGetTemp(self.id, &mut temperature) }) {
+            // no probe, or no reading from it
+            Err(EafError::GeneralError) => Ok(None),
+            Err(e) => Err(e),
+            Ok(()) if temperature <= -273.0 => Ok(None),
+            Ok(()) => Ok(Some(temperature as f64))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sdk_codes_map_to_errors() {
+        assert_eq!(build_result(5, EAF_focuser::SUCCESS), Ok(5));
+        assert_eq!(build_result((), EAF_focuser::ERROR_REMOVED), Err(EafError::Removed));
+        assert_eq!(build_result((), EAF_focuser::ERROR_CLOSED), Err(EafError::Closed));
+        // the enum's END is -1, and newer SDKs may add more
+        assert_eq!(build_result((), -1), Err(EafError::Unknown(-1)));
+        assert_eq!(build_result((), 42), Err(EafError::Unknown(42)));
+    }
+}
@@ -0,0 +1,147 @@
+// Cypress FX3 boot images (`firmware/qhy/*.img`): a "CY" signature and two control bytes,
+// then sections of (length in words, address, data), ended by a zero-length section whose
+// address is the entry point, then a checksum over every section's data words.
+
+use super::{FirmwareError, Segment};
+
+/// `bImageType` of a plain firmware image with a checksum, the only kind the boot loader
+/// takes over USB.
+const IMAGE_TYPE_FIRMWARE: u8 = 0xb0;
+
+pub struct Image {
+    /// `bImageCTL`: bit 0 set means the image is data rather than executable, bits 1-3 give
+    /// the SPI/I2C speed and size used when booting from flash.
+    pub image_ctl: u8,
+    pub sections: Vec<Segment>,
+    pub entry: u32,
+    pub checksum: u32
+}
+
+fn word(bytes: &[u8], offset: usize) -> Result<u32, FirmwareError> {
+    if offset + 4 > bytes.len() {
+        return Err(FirmwareError::Parse(format!("image truncated at offset {:#x}", offset)));
+    }
+    Ok(bytes[offset] as u32 |
+       (bytes[offset + 1] as u32) << 8 |
+       (bytes[offset + 2] as u32) << 16 |
+       (bytes[offset + 3] as u32) << 24)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Image, FirmwareError> {
+    if bytes.len() < 4 || &bytes[0..2] != b"CY" {
+        return Err(FirmwareError::Parse("missing CY signature".to_owned()));
+    }
+    let image_ctl = bytes[2];
+    if image_ctl & 1 != 0 {
+        return Err(FirmwareError::Parse("image is marked as data, not executable".to_owned()));
+    }
+    if bytes[3] != IMAGE_TYPE_FIRMWARE {
+        return Err(FirmwareError::Parse(format!("unsupported image type {:#04x}", bytes[3])));
+    }
+    let mut offset = 4;
+    let mut sections: Vec<Segment> = Vec::new();
+    let mut sum: u32 = 0;
+    let entry = loop {
+        let words = word(bytes, offset)? as usize;
+        let address = word(bytes, offset + 4)?;
+        offset += 8;
+        if words == 0 {
+            break address;
+        }
+        let len = words * 4;
+        if offset + len > bytes.len() {
+            return Err(FirmwareError::Parse(format!("section at {:#x} runs past the end of the image", address)));
+        }
+        for i in 0..words {
+            sum = sum.wrapping_add(word(bytes, offset + i * 4)?);
+        }
+        sections.push(Segment {
+            address: address,
+            data: bytes[offset..offset + len].to_vec()
+        });
+        offset += len;
+    };
+
+    let checksum = word(bytes, offset)?;
+    if checksum != sum {
+        return Err(FirmwareError::Parse(format!("checksum is {:#010x}, but the sections sum to {:#010x}", checksum, sum)));
+    }
+    if offset + 4 != bytes.len() {
+        return Err(FirmwareError::Parse(format!("{} trailing bytes after the checksum", bytes.len() - offset - 4)));
+    }
+
+    let mut sorted: Vec<&Segment> = sections.iter().collect();
+    sorted.sort_by_key(|section| section.address);
+    for pair in sorted.windows(2) {
+        if pair[0].end() > pair[1].address {
+            return Err(FirmwareError::Parse(format!(
+                "sections at {:#010x} and {:#010x} overlap", pair[0].address, pair[1].address
+            )));
+        }
+    }
+    if !sections.iter().any(|section| section.address <= entry && entry < section.end()) {
+        return Err(FirmwareError::Parse(format!("entry point {:#010x} isn't in any section", entry)));
+    }
+
+    Ok(Image {
+        image_ctl: image_ctl,
+        sections: sections,
+        entry: entry,
+        checksum: checksum
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firmware::tests::fx3_image;
+
+    fn parse_error(bytes: &[u8]) -> String {
+        match parse(bytes) {
+            Err(FirmwareError::Parse(msg)) => msg,
+            Err(other) => panic!("expected a parse error, got {:?}", other),
+            Ok(_) => panic!("expected a parse error, got an image")
+        }
+    }
+
+    #[test]
+    fn bad_checksums_are_rejected() {
+        let mut bytes = fx3_image(&[(0x4000_0000, &[1, 0, 0, 0])], 0x4000_0000);
+        let last = bytes.len() - 4;
+        bytes[last] = 2;
+        assert_eq!(parse_error(&bytes), "checksum is 0x00000002, but the sections sum to 0x00000001");
+    }
+
+    #[test]
+    fn overlapping_sections_are_rejected() {
+        let bytes = fx3_image(&[(0x4000_0100, &[0; 8]), (0x4000_0000, &[0; 4]), (0x4000_0104, &[0; 4])], 0x4000_0000);
+        assert_eq!(parse_error(&bytes), "sections at 0x40000100 and 0x40000104 overlap");
+    }
+
+    #[test]
+    fn broken_images_are_rejected() {
+        let good = fx3_image(&[(0x4000_0000, &[0; 4])], 0x4000_0000);
+        assert_eq!(parse_error(b"QY\x1c\xb0"), "missing CY signature");
+
+        let mut data = good.clone();
+        data[2] |= 1;
+        assert_eq!(parse_error(&data), "image is marked as data, not executable");
+
+        let mut other_type = good.clone();
+        other_type[3] = 0xb1;
+        assert_eq!(parse_error(&other_type), "unsupported image type 0xb1");
+
+        assert_eq!(parse_error(&good[..good.len() - 2]), "image truncated at offset 0x18");
+
+        let mut trailing = good.clone();
+        trailing.push(0);
+        assert_eq!(parse_error(&trailing), "1 trailing bytes after the checksum");
+
+        let elsewhere = fx3_image(&[(0x4000_0000, &[0; 4])], 0x4000_0004);
+        assert_eq!(parse_error(&elsewhere), "entry point 0x40000004 isn't in any section");
+
+        let mut long = good.clone();
+        long[4] = 9;
+        assert_eq!(parse_error(&long), "section at 0x40000000 runs past the end of the image");
+    }
+}
@@ -0,0 +1,131 @@
+// Intel HEX, as used for the FX2-based cameras' firmware (`firmware/qhy/*.HEX`).
+
+use super::{FirmwareError, Segment};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+
+fn parse_error(line: usize, msg: &str) -> FirmwareError {
+    FirmwareError::Parse(format!("line {}: {}", line, msg))
+}
+
+fn decode(line: usize, text: &str) -> Result<Vec<u8>, FirmwareError> {
+    if !text.len().is_multiple_of(2) {
+        return Err(parse_error(line, "odd number of hex digits"));
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| parse_error(line, "bad hex digit"))
+        })
+        .collect()
+}
+
+/// Parse a HEX file into the segments it writes, with contiguous records merged and sorted
+/// by address. Every record's checksum is checked, and records that overlap are an error
+/// rather than silently overwriting each other.
+pub fn parse(text: &str) -> Result<Vec<Segment>, FirmwareError> {
+    let mut segments: Vec<Segment> = Vec::new();
+    // upper address bits from the last extended address record
+    let mut base: u32 = 0;
+    for (idx, line) in text.lines().enumerate() {
+        let lineno = idx + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(':') {
+            return Err(parse_error(lineno, "record doesn't start with ':'"));
+        }
+        let bytes = decode(lineno, &line[1..])?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(parse_error(lineno, "record length doesn't match its byte count"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(parse_error(lineno, "bad checksum"));
+        }
+        let offset = ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            DATA => {
+                let address = base + offset;
+                match segments.last_mut() {
+                    Some(last) if last.end() == address => {
+                        last.data.extend_from_slice(data);
+                    }
+                    _ => {
+                        segments.push(Segment { address: address, data: data.to_vec() });
+                    }
+                }
+            }
+            END_OF_FILE => {
+                segments.sort_by_key(|segment| segment.address);
+                for pair in segments.windows(2) {
+                    if pair[0].end() > pair[1].address {
+                        return Err(FirmwareError::Parse(format!(
+                            "segments at {:#06x} and {:#06x} overlap", pair[0].address, pair[1].address
+                        )));
+                    }
+                }
+                return Ok(segments);
+            }
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS => {
+                if data.len() != 2 {
+                    return Err(parse_error(lineno, "extended address record must have two bytes of data"));
+                }
+                let value = ((data[0] as u32) << 8) | data[1] as u32;
+                base = if bytes[3] == EXTENDED_SEGMENT_ADDRESS { value << 4 } else { value << 16 };
+            }
+            other => {
+                return Err(parse_error(lineno, &format!("unsupported record type {:02x}", other)));
+            }
+        }
+    }
+    Err(FirmwareError::Parse("no end-of-file record".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firmware::tests::{end_of_file, record};
+
+    fn parse_error(text: &str) -> String {
+        match parse(text) {
+            Err(FirmwareError::Parse(msg)) => msg,
+            other => panic!("expected a parse error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn bad_checksums_are_reported_with_their_line() {
+        let good = record(0x00, 0x0000, &[1, 2, 3]);
+        // flip the last data byte without fixing the checksum
+        let bad = good.replacen("010203", "010204", 1);
+        let msg = parse_error(&[good.clone(), bad, end_of_file()].concat());
+        assert_eq!(msg, "line 2: bad checksum");
+    }
+
+    #[test]
+    fn overlapping_records_are_an_error() {
+        let text = [
+            record(0x00, 0x0100, &[1, 2, 3, 4]),
+            record(0x00, 0x0200, &[5]),
+            record(0x00, 0x0102, &[6, 7]),
+            end_of_file()
+        ].concat();
+        assert_eq!(parse_error(&text), "segments at 0x0100 and 0x0102 overlap");
+    }
+
+    #[test]
+    fn malformed_records_are_rejected() {
+        assert_eq!(parse_error("0000000001FF\n"), "line 1: record doesn't start with ':'");
+        assert_eq!(parse_error(":00000001F\n"), "line 1: odd number of hex digits");
+        assert_eq!(parse_error(":0000000GFF\n"), "line 1: bad hex digit");
+        assert_eq!(parse_error(":0200000001FD\n"), "line 1: record length doesn't match its byte count");
+        assert_eq!(parse_error(&record(0x00, 0, &[1])), "no end-of-file record");
+        assert_eq!(parse_error(&record(0x05, 0, &[0, 0, 0, 0])), "line 1: unsupported record type 05");
+    }
+}
@@ -0,0 +1,174 @@
+// Just enough of libusb-1.0 to find devices and send them vendor requests.
+
+use super::{ControlOut, FirmwareError};
+use crate::dynlib::{self, dynamic_library};
+
+use std::os;
+
+pub enum libusb_context {}
+pub enum libusb_device {}
+pub enum libusb_device_handle {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct libusb_device_descriptor {
+    pub bLength: u8,
+    pub bDescriptorType: u8,
+    pub bcdUSB: u16,
+    pub bDeviceClass: u8,
+    pub bDeviceSubClass: u8,
+    pub bDeviceProtocol: u8,
+    pub bMaxPacketSize0: u8,
+    pub idVendor: u16,
+    pub idProduct: u16,
+    pub bcdDevice: u16,
+    pub iManufacturer: u8,
+    pub iProduct: u8,
+    pub iSerialNumber: u8,
+    pub bNumConfigurations: u8
+}
+
+pub const LIBUSB_REQUEST_TYPE_VENDOR: u8 = 0x02 << 5;
+pub const LIBUSB_RECIPIENT_DEVICE: u8 = 0x00;
+pub const LIBUSB_ENDPOINT_OUT: u8 = 0x00;
+
+dynamic_library! {
+    "libusb";
+    pub fn libusb_init(ctx: *mut *mut libusb_context) -> os::raw::c_int;
+    pub fn libusb_exit(ctx: *mut libusb_context);
+    pub fn libusb_get_device_list(ctx: *mut libusb_context, list: *mut *mut *mut libusb_device) -> isize;
+    pub fn libusb_free_device_list(list: *mut *mut libusb_device, unref_devices: os::raw::c_int);
+    pub fn libusb_get_device_descriptor(dev: *mut libusb_device, desc: *mut libusb_device_descriptor) -> os::raw::c_int;
+    pub fn libusb_get_bus_number(dev: *mut libusb_device) -> u8;
+    pub fn libusb_get_device_address(dev: *mut libusb_device) -> u8;
+    pub fn libusb_open(dev: *mut libusb_device, handle: *mut *mut libusb_device_handle) -> os::raw::c_int;
+    pub fn libusb_close(handle: *mut libusb_device_handle);
+    pub fn libusb_control_transfer(
+        handle: *mut libusb_device_handle,
+        request_type: u8, request: u8, value: u16, index: u16,
+        data: *mut os::raw::c_uchar, length: u16,
+        timeout: os::raw::c_uint) -> os::raw::c_int;
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInfo {
+    pub bus: u8,
+    pub address: u8,
+    pub vid: u16,
+    pub pid: u16
+}
+
+pub struct Context {
+    ctx: *mut libusb_context
+}
+
+/// Load libusb, returning any functions it's missing.
+pub fn load_library() -> Result<Vec<&'static str>, dynlib::LoadError> {
+    load(&dynlib::candidates("LIBUSB", None, &["libusb-1.0.so.0", "libusb-1.0.so"]))
+}
+
+impl Context {
+    pub fn new() -> Result<Context, FirmwareError> {
+        if let Err(e) = load_library() {
+            println!("{}", e);
+            return Err(FirmwareError::NoLibrary);
+        }
+        let mut ctx: *mut libusb_context = std::ptr::null_mut();
+        let res = unsafe { libusb_init(&mut ctx) };
+        if res < 0 {
+            return Err(FirmwareError::Usb(res));
+        }
+        Ok(Context { ctx: ctx })
+    }
+
+    /// Call `f` with each attached device, stopping early if it returns `Some`.
+    fn find_device<T, F: FnMut(*mut libusb_device, DeviceInfo) -> Option<T>>(&self, mut f: F) -> Result<Option<T>, FirmwareError> {
+        unsafe {
+            let mut list: *mut *mut libusb_device = std::ptr::null_mut();
+            let count = libusb_get_device_list(self.ctx, &mut list);
+            if count < 0 {
+                return Err(FirmwareError::Usb(count as i32));
+            }
+            let mut found = None;
+            for i in 0..count as usize {
+                let dev = *list.add(i);
+                let mut desc = libusb_device_descriptor::default();
+                if libusb_get_device_descriptor(dev, &mut desc) < 0 {
+                    continue;
+                }
+                let info = DeviceInfo {
+                    bus: libusb_get_bus_number(dev),
+                    address: libusb_get_device_address(dev),
+                    vid: desc.idVendor,
+                    pid: desc.idProduct
+                };
+                found = f(dev, info);
+                if found.is_some() {
+                    break;
+                }
+            }
+            libusb_free_device_list(list, 1);
+            Ok(found)
+        }
+    }
+
+    pub fn devices(&self) -> Result<Vec<DeviceInfo>, FirmwareError> {
+        let mut devices = Vec::new();
+        self.find_device(|_, info| -> Option<()> {
+            devices.push(info);
+            None
+        })?;
+        Ok(devices)
+    }
+
+    pub fn open(&self, bus: u8, address: u8) -> Result<Handle, FirmwareError> {
+        let handle = self.find_device(|dev, info| {
+            if info.bus != bus || info.address != address {
+                return None;
+            }
+            let mut handle: *mut libusb_device_handle = std::ptr::null_mut();
+            let res = unsafe { libusb_open(dev, &mut handle) };
+            Some(if res < 0 { Err(FirmwareError::Usb(res)) } else { Ok(handle) })
+        })?;
+        match handle {
+            Some(handle) => Ok(Handle { handle: handle? }),
+            None => Err(FirmwareError::NoDevice)
+        }
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe { libusb_exit(self.ctx); }
+    }
+}
+
+pub struct Handle {
+    handle: *mut libusb_device_handle
+}
+
+impl ControlOut for Handle {
+    fn vendor_write(&mut self, request: u8, value: u16, index: u16, data: &[u8]) -> Result<(), FirmwareError> {
+        let res = unsafe {
+            libusb_control_transfer(
+                self.handle,
+                LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_DEVICE | LIBUSB_ENDPOINT_OUT,
+                request, value, index,
+                data.as_ptr() as *mut os::raw::c_uchar, data.len() as u16,
+                1000)
+        };
+        if res < 0 {
+            return Err(FirmwareError::Usb(res));
+        }
+        if res as usize != data.len() {
+            return Err(FirmwareError::ShortWrite);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { libusb_close(self.handle); }
+    }
+}
@@ -0,0 +1,379 @@
+// Firmware loading for QHY cameras, which enumerate with a bare Cypress FX2/FX3 and need
+// their firmware pushed into RAM before they show up as a camera.
+
+pub mod fx3;
+pub mod ihex;
+pub mod libusb;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum FirmwareError {
+    Io(io::Error),
+    Parse(String),
+    Usb(i32),
+    ShortWrite,
+    NoDevice,
+    NoLibrary
+}
+
+impl From<io::Error> for FirmwareError {
+    fn from(err: io::Error) -> FirmwareError {
+        FirmwareError::Io(err)
+    }
+}
+
+type Result<T> = std::result::Result<T, FirmwareError>;
+
+/// A run of bytes to be written at `address` in device memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.address + self.data.len() as u32
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Chip {
+    Fx2,
+    Fx3
+}
+
+/// A device that needs firmware, as it appears on the bus before the firmware is loaded.
+#[derive(Debug, Copy, Clone)]
+pub struct KnownDevice {
+    pub vid: u16,
+    pub pid: u16,
+    pub chip: Chip,
+    pub image: &'static str,
+    pub name: &'static str
+}
+
+pub const QHY_VID: u16 = 0x1618;
+
+/// Loader IDs of the cameras we've loaded firmware into. Others can be loaded by naming the
+/// image explicitly; add them here once they're known to work.
+pub const DEVICES: &[KnownDevice] = &[
+    KnownDevice { vid: QHY_VID, pid: 0xc367, chip: Chip::Fx3, image: "QHY367.img", name: "QHY367C" }
+];
+
+pub fn lookup(vid: u16, pid: u16) -> Option<&'static KnownDevice> {
+    DEVICES.iter().find(|dev| dev.vid == vid && dev.pid == pid)
+}
+
+/// Something that takes vendor-specific control OUT requests: a USB device, or a log of
+/// what would have been sent to one.
+pub trait ControlOut {
+    fn vendor_write(&mut self, request: u8, value: u16, index: u16, data: &[u8]) -> Result<()>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub data: Vec<u8>
+}
+
+/// Records transfers instead of sending them, for dry runs.
+#[derive(Debug, Default)]
+pub struct TransferLog {
+    pub transfers: Vec<Transfer>
+}
+
+impl ControlOut for TransferLog {
+    fn vendor_write(&mut self, request: u8, value: u16, index: u16, data: &[u8]) -> Result<()> {
+        self.transfers.push(Transfer {
+            request: request,
+            value: value,
+            index: index,
+            data: data.to_vec()
+        });
+        Ok(())
+    }
+}
+
+/// "Firmware load": the vendor request both the FX2 and FX3 boot ROMs accept to write RAM.
+const FIRMWARE_LOAD: u8 = 0xa0;
+
+/// The FX2's CPU control register; writing 1 holds the 8051 in reset, 0 lets it run.
+const FX2_CPUCS: u16 = 0xe600;
+
+const FX2_CHUNK: usize = 1024;
+const FX3_CHUNK: usize = 4096;
+
+/// Hold the FX2 in reset, write each segment to its RAM, then let it run the new code.
+pub fn upload_fx2<D: ControlOut>(dev: &mut D, segments: &[Segment]) -> Result<()> {
+    dev.vendor_write(FIRMWARE_LOAD, FX2_CPUCS, 0, &[1])?;
+    for segment in segments {
+        for (i, chunk) in segment.data.chunks(FX2_CHUNK).enumerate() {
+            let address = segment.address as usize + i * FX2_CHUNK;
+            dev.vendor_write(FIRMWARE_LOAD, address as u16, 0, chunk)?;
+        }
+    }
+    dev.vendor_write(FIRMWARE_LOAD, FX2_CPUCS, 0, &[0])
+}
+
+/// Write each section through the FX3 boot loader, then jump to the entry point with an
+/// empty write to it. Addresses are 32 bits, split across wValue (low) and wIndex (high).
+pub fn upload_fx3<D: ControlOut>(dev: &mut D, image: &fx3::Image) -> Result<()> {
+    for section in image.sections.iter() {
+        for (i, chunk) in section.data.chunks(FX3_CHUNK).enumerate() {
+            let address = section.address + (i * FX3_CHUNK) as u32;
+            dev.vendor_write(FIRMWARE_LOAD, address as u16, (address >> 16) as u16, chunk)?;
+        }
+    }
+    dev.vendor_write(FIRMWARE_LOAD, image.entry as u16, (image.entry >> 16) as u16, &[])
+}
+
+pub fn upload_file<D: ControlOut, P: AsRef<Path>>(dev: &mut D, chip: Chip, path: P) -> Result<()> {
+    match chip {
+        Chip::Fx2 => upload_fx2(dev, &read_fx2(path)?),
+        Chip::Fx3 => upload_fx3(dev, &fx3::parse(&fs::read(path)?)?)
+    }
+}
+
+fn read_fx2<P: AsRef<Path>>(path: P) -> Result<Vec<Segment>> {
+    let segments = ihex::parse(&fs::read_to_string(path)?)?;
+    for segment in segments.iter() {
+        // the boot ROM can only write the FX2's internal RAM
+        if segment.end() > 0x4000 {
+            return Err(FirmwareError::Parse(format!("segment at {:#06x} is outside internal RAM", segment.address)));
+        }
+    }
+    Ok(segments)
+}
+
+/// What a firmware file would load, for `firmware verify`.
+#[derive(Debug)]
+pub struct Summary {
+    pub chip: Chip,
+    pub segments: usize,
+    pub bytes: usize,
+    pub lowest: u32,
+    pub highest: u32,
+    /// FX3 only: where the boot loader jumps once the image is written.
+    pub entry: Option<u32>
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}, {} bytes in {} segments, {:#010x}..{:#010x}",
+            self.chip, self.bytes, self.segments, self.lowest, self.highest)?;
+        if let Some(entry) = self.entry {
+            write!(f, ", entry {:#010x}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+fn summarize(chip: Chip, segments: &[Segment], entry: Option<u32>) -> Summary {
+    Summary {
+        chip: chip,
+        segments: segments.len(),
+        bytes: segments.iter().map(|s| s.data.len()).sum(),
+        lowest: segments.iter().map(|s| s.address).min().unwrap_or(0),
+        highest: segments.iter().map(|s| s.end()).max().unwrap_or(0),
+        entry: entry
+    }
+}
+
+/// The chip a firmware file is for, going by its extension.
+pub fn chip_for<P: AsRef<Path>>(path: P) -> Option<Chip> {
+    match path.as_ref().extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_ascii_lowercase()) {
+        Some(ref ext) if ext == "hex" => Some(Chip::Fx2),
+        Some(ref ext) if ext == "img" => Some(Chip::Fx3),
+        _ => None
+    }
+}
+
+/// Fully parse a firmware file, checking checksums and layout.
+pub fn verify_file<P: AsRef<Path>>(path: P) -> Result<Summary> {
+    match chip_for(&path) {
+        Some(Chip::Fx2) => {
+            Ok(summarize(Chip::Fx2, &read_fx2(path)?, None))
+        }
+        Some(Chip::Fx3) => {
+            let image = fx3::parse(&fs::read(path)?)?;
+            Ok(summarize(Chip::Fx3, &image.sections, Some(image.entry)))
+        }
+        None => Err(FirmwareError::Parse("not a .HEX or .img file".to_owned()))
+    }
+}
+
+/// Verify every firmware file in `dir`, returning each file's name and result.
+pub fn verify_dir<P: AsRef<Path>>(dir: P) -> Result<Vec<(String, Result<Summary>)>> {
+    let mut results = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if chip_for(&path).is_none() {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        results.push((name, verify_file(&path)));
+    }
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(results)
+}
+
+/// Load firmware from `dir` into every attached device that's waiting for it, returning the
+/// devices that were loaded.
+pub fn load_attached<P: AsRef<Path>>(dir: P) -> Result<Vec<&'static KnownDevice>> {
+    let ctx = libusb::Context::new()?;
+    let mut loaded = Vec::new();
+    for info in ctx.devices()? {
+        let known = match lookup(info.vid, info.pid) {
+            Some(known) => known,
+            None => continue
+        };
+        println!(
+            "Loading {} into {} at bus {:03} device {:03}",
+            known.image, known.name, info.bus, info.address
+        );
+        let mut handle = ctx.open(info.bus, info.address)?;
+        upload_file(&mut handle, known.chip, dir.as_ref().join(known.image))?;
+        loaded.push(known);
+    }
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// One Intel HEX record, checksum and all.
+    pub(super) fn record(kind: u8, offset: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, kind];
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        let digits: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(":{}\n", digits)
+    }
+
+    pub(super) fn end_of_file() -> String {
+        record(0x01, 0, &[])
+    }
+
+    /// An FX3 boot image of `sections`, each a whole number of words, jumping to `entry`.
+    pub(super) fn fx3_image(sections: &[(u32, &[u8])], entry: u32) -> Vec<u8> {
+        let mut bytes = vec![b'C', b'Y', 0x1c, 0xb0];
+        let mut sum = 0u32;
+        for (address, data) in sections {
+            bytes.extend_from_slice(&(data.len() as u32 / 4).to_le_bytes());
+            bytes.extend_from_slice(&address.to_le_bytes());
+            bytes.extend_from_slice(data);
+            for word in data.chunks(4) {
+                sum = sum.wrapping_add(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+            }
+        }
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&entry.to_le_bytes());
+        bytes.extend_from_slice(&sum.to_le_bytes());
+        bytes
+    }
+
+    fn transfer(value: u16, index: u16, data: &[u8]) -> Transfer {
+        Transfer { request: FIRMWARE_LOAD, value: value, index: index, data: data.to_vec() }
+    }
+
+    #[test]
+    fn hex_records_are_merged_and_placed_by_extended_addresses() {
+        let text = [
+            record(0x00, 0x0200, &[5, 6]),
+            record(0x00, 0x0202, &[7]),
+            record(0x00, 0x0100, &[1, 2, 3, 4]),
+            record(0x04, 0, &[0x00, 0x01]),
+            record(0x00, 0x0010, &[8, 9]),
+            end_of_file()
+        ].concat();
+        let segments = ihex::parse(&text).unwrap();
+        assert_eq!(segments, vec![
+            Segment { address: 0x0100, data: vec![1, 2, 3, 4] },
+            Segment { address: 0x0200, data: vec![5, 6, 7] },
+            Segment { address: 0x10010, data: vec![8, 9] }
+        ]);
+    }
+
+    #[test]
+    fn fx3_images_give_their_sections_and_entry_point() {
+        let code = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        let data = [1, 0, 0, 0];
+        let image = fx3::parse(&fx3_image(&[(0x4000_0000, &code), (0x4000_1000, &data)], 0x4000_0004)).unwrap();
+        assert_eq!(image.image_ctl, 0x1c);
+        assert_eq!(image.entry, 0x4000_0004);
+        assert_eq!(image.sections, vec![
+            Segment { address: 0x4000_0000, data: code.to_vec() },
+            Segment { address: 0x4000_1000, data: data.to_vec() }
+        ]);
+        assert_eq!(image.checksum, 0x4433_2211u32.wrapping_add(0x8877_6655).wrapping_add(1));
+    }
+
+    #[test]
+    fn fx2_uploads_hold_the_cpu_in_reset_around_chunked_writes() {
+        let long: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        let segments = vec![
+            Segment { address: 0x0100, data: long.clone() },
+            Segment { address: 0x3000, data: vec![0xaa, 0xbb, 0xcc] }
+        ];
+        let mut log = TransferLog::default();
+        upload_fx2(&mut log, &segments).unwrap();
+        assert_eq!(log.transfers, vec![
+            transfer(FX2_CPUCS, 0, &[1]),
+            transfer(0x0100, 0, &long[..FX2_CHUNK]),
+            transfer(0x0100 + FX2_CHUNK as u16, 0, &long[FX2_CHUNK..]),
+            transfer(0x3000, 0, &[0xaa, 0xbb, 0xcc]),
+            transfer(FX2_CPUCS, 0, &[0])
+        ]);
+    }
+
+    #[test]
+    fn fx3_uploads_split_addresses_across_value_and_index_then_jump_to_the_entry() {
+        let long: Vec<u8> = (0..FX3_CHUNK + 8).map(|i| i as u8).collect();
+        let image = fx3::parse(&fx3_image(&[(0x4003_f000, &long)], 0x4004_0000)).unwrap();
+        let mut log = TransferLog::default();
+        upload_fx3(&mut log, &image).unwrap();
+        assert_eq!(log.transfers, vec![
+            transfer(0xf000, 0x4003, &long[..FX3_CHUNK]),
+            transfer(0x0000, 0x4004, &long[FX3_CHUNK..]),
+            transfer(0x0000, 0x4004, &[])
+        ]);
+    }
+
+    #[test]
+    fn fx2_images_outside_internal_ram_are_refused_before_anything_is_sent() {
+        let dir = TempDir::new("fx2_outside_ram");
+        let path = dir.join("big.hex");
+        fs::write(&path, [record(0x00, 0x3ffe, &[1, 2, 3]), end_of_file()].concat()).unwrap();
+        let mut log = TransferLog::default();
+        match upload_file(&mut log, Chip::Fx2, &path) {
+            Err(FirmwareError::Parse(msg)) => assert!(msg.contains("outside internal RAM"), "{}", msg),
+            other => panic!("expected a parse error, got {:?}", other)
+        }
+        assert!(log.transfers.is_empty());
+    }
+
+    #[test]
+    fn firmware_directories_are_verified_by_extension() {
+        let dir = TempDir::new("firmware_verify");
+        fs::write(dir.join("QHY5.HEX"), [record(0x00, 0x0000, &[1, 2]), end_of_file()].concat()).unwrap();
+        fs::write(dir.join("QHY367.img"), fx3_image(&[(0x4000_0000, &[0, 0, 0, 0])], 0x4000_0000)).unwrap();
+        fs::write(dir.join("readme.txt"), "not firmware").unwrap();
+
+        let results = verify_dir(dir.path()).unwrap();
+        let names: Vec<&str> = results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["QHY367.img", "QHY5.HEX"]);
+        let fx3 = results[0].1.as_ref().unwrap();
+        assert_eq!((fx3.chip, fx3.bytes, fx3.entry), (Chip::Fx3, 4, Some(0x4000_0000)));
+        let fx2 = results[1].1.as_ref().unwrap();
+        assert_eq!((fx2.chip, fx2.segments, fx2.lowest, fx2.highest), (Chip::Fx2, 1, 0, 2));
+    }
+}
@@ -0,0 +1,287 @@
+// Minimal FITS writer: a single primary HDU holding one frame, plus whatever keywords the
+// capture path attached to it. Calibrated frames and masters go out as 32-bit floats, and can
+// be read back in along with their keywords.
+
+use crate::frame::{Cfa, Frame, HeaderValue};
+use crate::gps;
+use crate::image::Image;
+use crate::imaging::Subframe;
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const BLOCK: usize = 2880;
+const CARD: usize = 80;
+
+fn card(key: &str, value: &HeaderValue, comment: &str) -> String {
+    let value = match value {
+        HeaderValue::Str(s) => format!("'{:<8}'", s.replace('\'', "''")),
+        HeaderValue::Int(i) => format!("{:>20}", i),
+        HeaderValue::Float(f) => format!("{:>20}", format!("{:.10E}", f)),
+        HeaderValue::Bool(b) => format!("{:>20}", if *b { "T" } else { "F" })
+    };
+    let mut card = format!("{:<8}= {}", key, value);
+    if !comment.is_empty() {
+        card.push_str(" / ");
+        card.push_str(comment);
+    }
+    card.truncate(CARD);
+    format!("{:<80}", card)
+}
+
+/// An IRAF-style section, `[x1:x2,y1:y2]`, one-based and inclusive, as in `BIASSEC` and
+/// `DATASEC`.
+pub fn section(region: &Subframe) -> String {
+    format!("[{}:{},{}:{}]", region.x + 1, region.x + region.width, region.y + 1, region.y + region.height)
+}
+
+pub fn parse_section(section: &str) -> Option<Subframe> {
+    let inner = section.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let mut ranges = inner.split(',').map(|range| {
+        let mut ends = range.split(':').map(|end| end.trim().parse::<u32>().ok());
+        match (ends.next().flatten(), ends.next().flatten()) {
+            (Some(start), Some(end)) if start >= 1 && end >= start => Some((start - 1, end - start + 1)),
+            _ => None
+        }
+    });
+    let (x, width) = ranges.next()??;
+    let (y, height) = ranges.next()??;
+    Some(Subframe { x: x, y: y, width: width, height: height })
+}
+
+/// Keywords for the frame's own properties, before anything attached in `Frame::headers`.
+fn standard_keywords(frame: &Frame) -> Vec<(String, HeaderValue, &'static str)> {
+    let mut keywords = vec![
+        ("DATE-RD".to_owned(), HeaderValue::Str(gps::iso8601(frame.timestamp)), "UTC time the frame was read out")
+    ];
+    if let Some(started) = frame.started {
+        keywords.push(("DATE-OBS".to_owned(), HeaderValue::Str(gps::iso8601(started)), "UTC start of exposure"));
+    }
+    if let Some(cfa) = frame.cfa {
+        keywords.push(("BAYERPAT".to_owned(), HeaderValue::Str(cfa.as_str().to_owned()), "Bayer color filter array pattern"));
+    }
+    if let Some(gps) = frame.gps.as_ref() {
+        keywords.push(("DATE-END".to_owned(), HeaderValue::Str(gps::iso8601(gps.shutter_close_time())), "UTC end of exposure"));
+        keywords.push(("EXPTIME".to_owned(), HeaderValue::Float(gps.exposure().as_secs_f64()), "[s] exposure, from GPS"));
+        keywords.push(("GPS_SEQ".to_owned(), HeaderValue::Int(gps.sequence as i64), "GPS frame sequence number"));
+        keywords.push(("GPS_SFLG".to_owned(), HeaderValue::Int(gps.shutter_open.flag as i64), "GPS status at shutter open"));
+        keywords.push(("GPS_EFLG".to_owned(), HeaderValue::Int(gps.shutter_close.flag as i64), "GPS status at shutter close"));
+        keywords.push(("GPS_PPSC".to_owned(), HeaderValue::Int(gps.pps_counter as i64), "clock ticks per PPS"));
+        keywords.push(("SITELAT".to_owned(), HeaderValue::Float(gps.latitude), "[deg] GPS latitude"));
+        keywords.push(("SITELONG".to_owned(), HeaderValue::Float(gps.longitude), "[deg] GPS longitude"));
+    }
+    keywords
+}
+
+/// The cards every header starts with, for an image of `bitpix`.
+fn structural_cards(bitpix: i64, width: u32, height: u32, channels: u32) -> String {
+    let mut header = String::new();
+    header.push_str(&card("SIMPLE", &HeaderValue::Bool(true), "conforms to FITS standard"));
+    header.push_str(&card("BITPIX", &HeaderValue::Int(bitpix), "bits per data value"));
+    header.push_str(&card("NAXIS", &HeaderValue::Int(if channels > 1 { 3 } else { 2 }), ""));
+    header.push_str(&card("NAXIS1", &HeaderValue::Int(width as i64), ""));
+    header.push_str(&card("NAXIS2", &HeaderValue::Int(height as i64), ""));
+    if channels > 1 {
+        header.push_str(&card("NAXIS3", &HeaderValue::Int(channels as i64), ""));
+    }
+    header
+}
+
+fn end_header(header: &mut String) {
+    header.push_str(&format!("{:<80}", "END"));
+    while !header.len().is_multiple_of(BLOCK) {
+        header.push(' ');
+    }
+}
+
+fn write_hdu<P: AsRef<Path>>(path: P, header: &str, mut data: Vec<u8>) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut w = BufWriter::new(file);
+    w.write_all(header.as_bytes())?;
+    while !data.len().is_multiple_of(BLOCK) {
+        data.push(0);
+    }
+    w.write_all(&data)?;
+    w.flush()
+}
+
+pub fn write<P: AsRef<Path>>(frame: &Frame, path: P) -> io::Result<()> {
+    let bitpix = if frame.bpp <= 8 { 8 } else { 16 };
+    let mut header = structural_cards(bitpix, frame.width, frame.height, frame.channels);
+    if bitpix == 16 {
+        // FITS integers are signed; offset so the full unsigned range survives
+        header.push_str(&card("BZERO", &HeaderValue::Int(32768), "offset data range to that of unsigned short"));
+        header.push_str(&card("BSCALE", &HeaderValue::Int(1), ""));
+    }
+    for (key, value, comment) in standard_keywords(frame) {
+        if frame.header(&key).is_none() {
+            header.push_str(&card(&key, &value, comment));
+        }
+    }
+    for (key, value) in frame.headers.iter() {
+        header.push_str(&card(key, value, ""));
+    }
+    end_header(&mut header);
+
+    // FITS wants planes, frames are interleaved
+    let mut data: Vec<u8> = Vec::with_capacity(frame.data.len() * (bitpix as usize / 8));
+    for channel in 0..frame.channels as usize {
+        for sample in frame.data.iter().skip(channel).step_by(frame.channels as usize) {
+            if bitpix == 8 {
+                data.push(*sample as u8);
+            } else {
+                let signed = (*sample as i32 - 32768) as i16;
+                data.extend_from_slice(&signed.to_be_bytes());
+            }
+        }
+    }
+    write_hdu(path, &header, data)
+}
+
+/// Write an image as 32-bit floats, with `headers` as its keywords.
+pub fn write_image<P: AsRef<Path>>(image: &Image, headers: &[(String, HeaderValue)], path: P) -> io::Result<()> {
+    let mut header = structural_cards(-32, image.width, image.height, image.channels);
+    if let Some(cfa) = image.cfa {
+        if !headers.iter().any(|(key, _)| key == "BAYERPAT") {
+            header.push_str(&card("BAYERPAT", &HeaderValue::Str(cfa.as_str().to_owned()), "Bayer color filter array pattern"));
+        }
+    }
+    for (key, value) in headers.iter() {
+        if !["SIMPLE", "BITPIX", "NAXIS", "NAXIS1", "NAXIS2", "NAXIS3", "BZERO", "BSCALE"].contains(&key.as_str()) {
+            header.push_str(&card(key, value, ""));
+        }
+    }
+    end_header(&mut header);
+
+    let mut data: Vec<u8> = Vec::with_capacity(image.data.len() * 4);
+    for channel in 0..image.channels as usize {
+        for sample in image.data.iter().skip(channel).step_by(image.channels as usize) {
+            data.extend_from_slice(&sample.to_be_bytes());
+        }
+    }
+    write_hdu(path, &header, data)
+}
+
+/// Parse the value of a card, leaving off any comment.
+fn parse_value(text: &str) -> Option<HeaderValue> {
+    let text = text.trim_start();
+    if let Some(quoted) = text.strip_prefix('\'') {
+        // '' is an escaped quote; the string ends at the first lone one
+        let mut value = String::new();
+        let mut chars = quoted.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\'' {
+                if chars.peek() == Some(&'\'') {
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            value.push(c);
+        }
+        return Some(HeaderValue::Str(value.trim_end().to_owned()));
+    }
+    let text = text.split('/').next().unwrap_or("").trim();
+    match text {
+        "T" => Some(HeaderValue::Bool(true)),
+        "F" => Some(HeaderValue::Bool(false)),
+        _ => text.parse().map(HeaderValue::Int).ok()
+            .or_else(|| text.replace('D', "E").parse().map(HeaderValue::Float).ok())
+    }
+}
+
+/// Read the primary HDU of a FITS file as an image, along with its keywords. Handles 8 and
+/// 16 bit integers and 32 and 64 bit floats, which covers what this and most capture
+/// programs write.
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<(Image, Vec<(String, HeaderValue)>)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+    let bytes = fs::read(path)?;
+    let mut headers = Vec::new();
+    let mut offset = 0;
+    loop {
+        let card = bytes.get(offset..offset + CARD).ok_or_else(|| invalid("no END card"))?;
+        offset += CARD;
+        let card: String = card.iter().map(|b| if b.is_ascii() { *b as char } else { '?' }).collect();
+        let key = card[..8].trim();
+        if key == "END" {
+            break;
+        }
+        if &card[8..10] == "= " {
+            if let Some(value) = parse_value(&card[10..]) {
+                headers.push((key.to_owned(), value));
+            }
+        }
+    }
+    let data_start = offset.div_ceil(BLOCK) * BLOCK;
+
+    let number = |key: &str| match headers.iter().find(|(k, _)| k == key).map(|(_, v)| v) {
+        Some(HeaderValue::Int(i)) => Some(*i as f64),
+        Some(HeaderValue::Float(f)) => Some(*f),
+        _ => None
+    };
+    let bitpix = number("BITPIX").ok_or_else(|| invalid("no BITPIX"))? as i64;
+    let axes = number("NAXIS").ok_or_else(|| invalid("no NAXIS"))? as u32;
+    if !(2..=3).contains(&axes) {
+        return Err(invalid("not a 2D image"));
+    }
+    let width = number("NAXIS1").ok_or_else(|| invalid("no NAXIS1"))? as u32;
+    let height = number("NAXIS2").ok_or_else(|| invalid("no NAXIS2"))? as u32;
+    let channels = if axes == 3 { number("NAXIS3").ok_or_else(|| invalid("no NAXIS3"))? as u32 } else { 1 };
+    let zero = number("BZERO").unwrap_or(0.0) as f32;
+    let scale = number("BSCALE").unwrap_or(1.0) as f32;
+
+    let size = (bitpix.unsigned_abs() / 8) as usize;
+    let plane = width as usize * height as usize;
+    let count = plane * channels as usize;
+    let data = bytes.get(data_start..data_start + count * size).ok_or_else(|| invalid("data ends early"))?;
+    let mut image = Image::new(width, height, channels);
+    for (i, raw) in data.chunks(size).enumerate() {
+        let value = match bitpix {
+            8 => raw[0] as f32,
+            16 => i16::from_be_bytes([raw[0], raw[1]]) as f32,
+            32 => i32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) as f32,
+            -32 => f32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]),
+            -64 => f64::from_be_bytes([raw[0], raw[1], raw[2], raw[3], raw[4], raw[5], raw[6], raw[7]]) as f32,
+            _ => return Err(invalid("unsupported BITPIX"))
+        };
+        // planes in the file, interleaved in the image
+        let (channel, pixel) = (i / plane, i % plane);
+        image.data[pixel * channels as usize + channel] = zero + scale * value;
+    }
+    image.cfa = match headers.iter().find(|(k, _)| k == "BAYERPAT").map(|(_, v)| v) {
+        Some(HeaderValue::Str(name)) if channels == 1 => Cfa::parse(name),
+        _ => None
+    };
+    Ok((image, headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn keyword<'a>(headers: &'a [(String, HeaderValue)], key: &str) -> Option<&'a HeaderValue> {
+        headers.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    #[test]
+    fn date_obs_is_only_written_when_the_start_is_known() {
+        let dir = TempDir::new("fits_dates");
+        let mut frame = Frame::from_bytes(4, 2, 1, 16, &[0u8; 16]);
+        frame.timestamp = UNIX_EPOCH + Duration::from_secs(1_713_283_205);
+        write(&frame, dir.join("video.fits")).unwrap();
+        let (_, headers) = read(dir.join("video.fits")).unwrap();
+        assert_eq!(keyword(&headers, "DATE-OBS"), None);
+        assert_eq!(keyword(&headers, "DATE-RD"), Some(&HeaderValue::Str("2024-04-16T16:00:05.000000".to_owned())));
+
+        frame.started = Some(UNIX_EPOCH + Duration::from_millis(1_713_283_200_500));
+        frame.set_header("FILTER", HeaderValue::Str("Ha".to_owned()));
+        write(&frame, dir.join("light.fits")).unwrap();
+        let (image, headers) = read(dir.join("light.fits")).unwrap();
+        assert_eq!(keyword(&headers, "DATE-OBS"), Some(&HeaderValue::Str("2024-04-16T16:00:00.500000".to_owned())));
+        assert_eq!(keyword(&headers, "FILTER"), Some(&HeaderValue::Str("Ha".to_owned())));
+        assert_eq!((image.width, image.height), (4, 2));
+    }
+}
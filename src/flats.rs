@@ -0,0 +1,307 @@
+// Working out flat exposures instead of guessing them. Test frames find the exposure that puts
+// the median at a target fraction of full scale, then the flats are taken, following the sky as
+// it brightens or fades for twilight flats, and dark-flats are taken to match.
+//
+// Full scale is the frame's, from the depth the SDK transfers at (`get_dimensions`' bpp on QHY,
+// RAW8 or RAW16 on ASI), not the ADC's `CameraInfo::bit_depth`: both SDKs scale samples up to
+// the transfer depth, so a 12-bit ASI camera's RAW16 frames go all the way to 65535.
+
+use crate::frame::{Frame, FrameType};
+use crate::imaging::Imager;
+use crate::stats::FrameStats;
+
+use std::time::Duration;
+
+/// Above this fraction of full scale a frame is treated as saturated, and its median says
+/// nothing about how much brighter the light really is.
+const SATURATED: f64 = 0.95;
+
+/// Below this fraction of full scale over the bias, there's too little signal to scale from.
+const NO_SIGNAL: f64 = 0.01;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Source {
+    /// A flat panel or lightbox, steady enough that one exposure does for every flat.
+    Panel,
+    /// The twilight sky, which brightens or fades from one flat to the next.
+    Sky
+}
+
+#[derive(Debug)]
+pub enum FlatError<E> {
+    Camera(E),
+    /// Even the shortest exposure put the median at this fraction of full scale.
+    TooBright(f64),
+    /// Even the longest exposure only got the median up to this fraction of full scale.
+    TooDark(f64),
+    /// The test exposures kept missing the target.
+    NotConverging,
+    /// This many flats in a row came out off target and were thrown away.
+    TooManyRejected(u32)
+}
+
+#[derive(Debug, Clone)]
+pub struct FlatWizard {
+    pub source: Source,
+    /// Where to put the median, as a fraction of full scale.
+    pub target: f64,
+    /// How far off `target` a flat's median can be, as a fraction of full scale, and still be
+    /// kept.
+    pub tolerance: f64,
+    /// Limits on the exposure, on top of the camera's own.
+    pub min_exposure: Duration,
+    pub max_exposure: Duration,
+    /// The first test exposure.
+    pub first_exposure: Duration,
+    /// Test exposures to take before giving up on the target, and flats in a row that can
+    /// miss it before giving up on the run.
+    pub max_tries: u32
+}
+
+impl Default for FlatWizard {
+    fn default() -> FlatWizard {
+        FlatWizard {
+            source: Source::Panel,
+            target: 0.45,
+            tolerance: 0.05,
+            min_exposure: Duration::from_millis(1),
+            max_exposure: Duration::from_secs(60),
+            first_exposure: Duration::from_secs(1),
+            max_tries: 8
+        }
+    }
+}
+
+/// What a flat run took.
+#[derive(Debug, Clone, Default)]
+pub struct FlatRun {
+    /// The bias level flat medians were measured from, in ADU.
+    pub bias: f64,
+    /// The exposure of each flat kept, in the order they were taken.
+    pub exposures: Vec<Duration>,
+    /// Flats thrown away for missing the target.
+    pub rejected: u32,
+    /// Whether the sky got too bright or too dark to finish the flats.
+    pub light_ran_out: bool,
+    pub dark_flats: u32
+}
+
+fn median(frame: &Frame) -> f64 {
+    match frame.stats.as_ref() {
+        Some(stats) => stats.all.median,
+        None => FrameStats::of(frame).all.median
+    }
+}
+
+impl FlatWizard {
+    /// Take `count` flats and a dark-flat at the exposure of each, handing every frame kept to
+    /// `save` along with its type and exposure.
+    pub fn run<C, F>(&self, camera: &mut C, count: u32, mut save: F) -> Result<FlatRun, FlatError<C::Error>>
+    where
+        C: Imager,
+        F: FnMut(&Frame, FrameType, Duration)
+    {
+        let range = camera.exposure_range().map_err(FlatError::Camera)?;
+        let bias = camera.capture(FrameType::Bias).map_err(FlatError::Camera)?;
+        let mut run = FlatRun { bias: median(&bias), ..FlatRun::default() };
+        println!("Bias level is {:.1} ADU", run.bias);
+
+        let mut exposure = self.find_exposure(camera, range, run.bias)?;
+        let mut previous_rate = None;
+        let mut misses = 0;
+        while run.exposures.len() < count as usize {
+            let (frame, level, full) = self.flat(camera, exposure)?;
+            let kept = (level / full - self.target).abs() <= self.tolerance;
+            if kept {
+                save(&frame, FrameType::Flat, exposure);
+                run.exposures.push(exposure);
+                misses = 0;
+            } else {
+                println!("Flat at {:?} came out at {:.0}% of full scale, dropping it", exposure, level / full * 100.0);
+                run.rejected += 1;
+                misses += 1;
+                if misses >= self.max_tries {
+                    return Err(FlatError::TooManyRejected(misses));
+                }
+            }
+            // a panel stays put, so its exposure only changes if it's drifted off target
+            if self.source == Source::Sky || !kept {
+                let rate = self.rate(level, full, run.bias, exposure);
+                let predicted = match previous_rate {
+                    // the next flat is about as far on as the last was from the one before, so
+                    // the sky should change by about the same factor again
+                    Some(previous) if self.source == Source::Sky => rate * rate / previous,
+                    _ => rate
+                };
+                previous_rate = Some(rate);
+                let wanted = self.exposure_for(predicted, run.bias, full);
+                let next = self.clamp(wanted, range);
+                if next != wanted && self.source == Source::Sky {
+                    println!("The sky needs {:?} now, which is out of range", wanted);
+                    run.light_ran_out = true;
+                    break;
+                }
+                exposure = next;
+            }
+        }
+
+        let mut exposures = run.exposures.clone();
+        exposures.sort();
+        let mut current = None;
+        for exposure in exposures {
+            if current != Some(exposure) {
+                camera.set_exposure(exposure).map_err(FlatError::Camera)?;
+                current = Some(exposure);
+            }
+            let frame = camera.capture(FrameType::Dark).map_err(FlatError::Camera)?;
+            save(&frame, FrameType::Dark, exposure);
+            run.dark_flats += 1;
+        }
+        Ok(run)
+    }
+
+    /// Take test flats until one lands within `tolerance` of the target, returning its
+    /// exposure.
+    fn find_exposure<C: Imager>(&self, camera: &mut C, range: (Duration, Duration), bias: f64) -> Result<Duration, FlatError<C::Error>> {
+        let mut exposure = self.clamp(self.first_exposure, range);
+        for _ in 0..self.max_tries {
+            let (_, level, full) = self.flat(camera, exposure)?;
+            let fraction = level / full;
+            println!("Test flat at {:?} is at {:.0}% of full scale", exposure, fraction * 100.0);
+            if (fraction - self.target).abs() <= self.tolerance {
+                return Ok(exposure);
+            }
+            let next = self.clamp(self.exposure_for(self.rate(level, full, bias, exposure), bias, full), range);
+            if next == exposure {
+                // already at the limit, and still off target
+                return Err(if fraction > self.target { FlatError::TooBright(fraction) } else { FlatError::TooDark(fraction) });
+            }
+            exposure = next;
+        }
+        Err(FlatError::NotConverging)
+    }
+
+    /// Take a flat, returning it with its median and full scale.
+    fn flat<C: Imager>(&self, camera: &mut C, exposure: Duration) -> Result<(Frame, f64, f64), FlatError<C::Error>> {
+        camera.set_exposure(exposure).map_err(FlatError::Camera)?;
+        let frame = camera.capture(FrameType::Flat).map_err(FlatError::Camera)?;
+        let level = median(&frame);
+        let full = frame.full_scale() as f64;
+        Ok((frame, level, full))
+    }
+
+    /// How fast the light is filling pixels, in ADU per second over the bias, judging by a
+    /// frame with median `level` at `exposure`.
+    fn rate(&self, level: f64, full: f64, bias: f64, exposure: Duration) -> f64 {
+        let seconds = exposure.as_secs_f64();
+        let signal = level - bias;
+        if level >= SATURATED * full {
+            // at least this bright, and likely a lot brighter
+            4.0 * signal / seconds
+        } else {
+            signal.max(NO_SIGNAL * full) / seconds
+        }
+    }
+
+    /// The exposure that brings the median to the target at `rate`, to the microsecond.
+    fn exposure_for(&self, rate: f64, bias: f64, full: f64) -> Duration {
+        let seconds = ((self.target * full - bias) / rate).clamp(0.0, 1e6);
+        Duration::from_micros((seconds * 1e6).round() as u64)
+    }
+
+    fn clamp(&self, exposure: Duration, range: (Duration, Duration)) -> Duration {
+        exposure.max(self.min_exposure.max(range.0)).min(self.max_exposure.min(range.1))
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim;
+
+    /// A frame handed over by a run: its type, exposure and median as a fraction of full scale.
+    type Saved = (FrameType, Duration, f64);
+
+    /// Run `wizard` for `count` flats, returning the run and every frame handed over.
+    fn take(camera: &mut sim::Camera, wizard: &FlatWizard, count: u32) -> (Result<FlatRun, FlatError<std::convert::Infallible>>, Vec<Saved>) {
+        let mut saved = Vec::new();
+        let run = wizard.run(camera, count, |frame, frame_type, exposure| {
+            assert_eq!(frame.header("IMAGETYP"), Some(&crate::frame::HeaderValue::Str(frame_type.imagetyp().to_owned())));
+            saved.push((frame_type, exposure, median(frame) / frame.full_scale() as f64));
+        });
+        (run, saved)
+    }
+
+    #[test]
+    fn panel_flats_land_on_target_with_a_dark_flat_each() {
+        let mut camera = sim::Camera::new(160, 120);
+        let wizard = FlatWizard::default();
+        let (run, saved) = take(&mut camera, &wizard, 5);
+        let run = run.unwrap();
+        assert!((run.bias - camera.offset).abs() < 2.0, "bias {}", run.bias);
+        assert_eq!(run.exposures.len(), 5);
+        assert_eq!((run.rejected, run.dark_flats, run.light_ran_out), (0, 5, false));
+        // a steady panel gets the same exposure every time
+        assert!(run.exposures.iter().all(|exposure| *exposure == run.exposures[0]));
+
+        let flats: Vec<_> = saved.iter().filter(|(frame_type, _, _)| *frame_type == FrameType::Flat).collect();
+        let darks: Vec<_> = saved.iter().filter(|(frame_type, _, _)| *frame_type == FrameType::Dark).collect();
+        assert_eq!((flats.len(), darks.len()), (5, 5));
+        for (_, _, level) in flats.iter() {
+            assert!((level - wizard.target).abs() <= wizard.tolerance, "flat at {:.3}", level);
+        }
+        for ((_, flat, _), (_, dark, level)) in flats.iter().zip(darks.iter()) {
+            assert_eq!(flat, dark);
+            assert!(*level < 0.02, "dark-flat at {:.3}", level);
+        }
+    }
+
+    #[test]
+    fn sky_flats_lengthen_as_dusk_fades() {
+        let mut camera = sim::Camera::new(160, 120);
+        // bright to start with, fading by 1% a second
+        camera.flat_level = 60000.0;
+        camera.twilight = -0.01;
+        let wizard = FlatWizard { source: Source::Sky, ..FlatWizard::default() };
+        let (run, saved) = take(&mut camera, &wizard, 10);
+        let run = run.unwrap();
+        assert_eq!(run.exposures.len(), 10);
+        assert!(!run.light_ran_out);
+        assert!(run.exposures.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", run.exposures);
+        assert!(run.exposures[9] > run.exposures[0] * 6 / 5, "{:?}", run.exposures);
+        for (_, _, level) in saved.iter().filter(|(frame_type, _, _)| *frame_type == FrameType::Flat) {
+            assert!((level - wizard.target).abs() <= wizard.tolerance, "flat at {:.3}", level);
+        }
+        // dark-flats come shortest first, one for every flat
+        let darks: Vec<Duration> = saved.iter().filter(|(frame_type, _, _)| *frame_type == FrameType::Dark).map(|(_, exposure, _)| *exposure).collect();
+        let mut sorted = run.exposures.clone();
+        sorted.sort();
+        assert_eq!(darks, sorted);
+    }
+
+    #[test]
+    fn the_sky_running_out_stops_the_run_early() {
+        let mut camera = sim::Camera::new(160, 120);
+        camera.flat_level = 60000.0;
+        camera.twilight = -0.02;
+        let wizard = FlatWizard { source: Source::Sky, max_exposure: Duration::from_secs(2), ..FlatWizard::default() };
+        let (run, _) = take(&mut camera, &wizard, 40);
+        let run = run.unwrap();
+        assert!(run.light_ran_out);
+        assert!(!run.exposures.is_empty() && run.exposures.len() < 40, "{} flats", run.exposures.len());
+        assert_eq!(run.dark_flats as usize, run.exposures.len());
+    }
+
+    #[test]
+    fn a_panel_too_bright_or_too_dark_is_reported() {
+        let mut camera = sim::Camera::new(160, 120);
+        camera.flat_level = 1e9;
+        let (run, saved) = take(&mut camera, &FlatWizard::default(), 3);
+        assert!(matches!(run, Err(FlatError::TooBright(fraction)) if fraction > SATURATED), "{:?}", run);
+        assert!(saved.is_empty());
+
+        camera.flat_level = 10.0;
+        let (run, _) = take(&mut camera, &FlatWizard::default(), 3);
+        assert!(matches!(run, Err(FlatError::TooDark(fraction)) if fraction < 0.05), "{:?}", run);
+    }
+}
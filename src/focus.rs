@@ -0,0 +1,380 @@
+// Autofocus: step a focuser through positions either side of where it is, measure the stars'
+// HFR at each, fit a hyperbola through them and move to its minimum. Any focuser will do, as
+// long as it can say where it is and move.
+//
+// Moves into a position always finish going out, so the gears' play is always taken up the
+// same way and a position means the same thing for every sample and the final move.
+
+use crate::frame::FrameType;
+use crate::imaging::{Imager, Subframe};
+use crate::stars::StarFinder;
+
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Focuser {
+    type Error: fmt::Debug;
+
+    /// Where the focuser is, in steps.
+    fn position(&self) -> Result<i32, Self::Error>;
+
+    /// Start moving to `position`, in steps.
+    fn move_to(&mut self, position: i32) -> Result<(), Self::Error>;
+
+    fn is_moving(&self) -> Result<bool, Self::Error>;
+
+    /// The focuser's temperature probe, if it has one.
+    fn temperature(&self) -> Result<Option<f64>, Self::Error>;
+}
+
+/// Whatever a focuser's errors were, for driving any kind through one `AnyFocuser`.
+pub type BoxedError = Box<dyn fmt::Debug>;
+
+/// A focuser of any kind, as something like a sequence that doesn't care which holds it.
+pub type AnyFocuser = dyn Focuser<Error = BoxedError>;
+
+/// A focuser with its errors boxed, to be handed around as an `AnyFocuser`.
+#[derive(Debug)]
+pub struct Boxed<F>(pub F);
+
+impl<F: Focuser> Focuser for Boxed<F> where F::Error: 'static {
+    type Error = BoxedError;
+
+    fn position(&self) -> Result<i32, BoxedError> {
+        self.0.position().map_err(|e| Box::new(e) as BoxedError)
+    }
+
+    fn move_to(&mut self, position: i32) -> Result<(), BoxedError> {
+        self.0.move_to(position).map_err(|e| Box::new(e) as BoxedError)
+    }
+
+    fn is_moving(&self) -> Result<bool, BoxedError> {
+        self.0.is_moving().map_err(|e| Box::new(e) as BoxedError)
+    }
+
+    fn temperature(&self) -> Result<Option<f64>, BoxedError> {
+        self.0.temperature().map_err(|e| Box::new(e) as BoxedError)
+    }
+}
+
+/// Move `focuser` to `position`, going `backlash` steps past it first if that's what it takes
+/// to finish going out, and wait up to `timeout` for each move.
+pub fn move_to<F: Focuser + ?Sized, C>(focuser: &mut F, position: i32, backlash: i32, timeout: Duration) -> Result<(), FocusError<F::Error, C>> {
+    if backlash > 0 && focuser.position().map_err(FocusError::Focuser)? > position - backlash {
+        focuser.move_to(position - backlash).map_err(FocusError::Focuser)?;
+        wait(focuser, timeout)?;
+    }
+    focuser.move_to(position).map_err(FocusError::Focuser)?;
+    wait(focuser, timeout)
+}
+
+fn wait<F: Focuser + ?Sized, C>(focuser: &F, timeout: Duration) -> Result<(), FocusError<F::Error, C>> {
+    let deadline = Instant::now() + timeout;
+    while focuser.is_moving().map_err(FocusError::Focuser)? {
+        if Instant::now() > deadline {
+            return Err(FocusError::Timeout);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum FocusError<F, C> {
+    Focuser(F),
+    Camera(C),
+    /// The focuser was still moving when `move_timeout` ran out.
+    Timeout,
+    /// Too few positions had enough stars to fit a curve through.
+    TooFewSamples(usize),
+    /// The HFRs don't make a curve with a minimum, so focus is probably well outside the
+    /// positions tried.
+    NoMinimum,
+    /// The curve's minimum is at this position, outside the positions tried. Running again
+    /// from closer to it should find it.
+    MinimumOutside(i32)
+}
+
+#[derive(Debug, Clone)]
+pub struct Autofocus {
+    /// Focuser steps between positions.
+    pub step: i32,
+    /// Positions to try either side of the starting one.
+    pub steps_out: u32,
+    /// Steps past a position to go before moving in to it from below.
+    pub backlash: i32,
+    pub exposure: Duration,
+    /// Region to read out for focusing, or the whole sensor.
+    pub subframe: Option<Subframe>,
+    /// Frames to take at each position, whose HFRs are averaged.
+    pub frames: u32,
+    /// Positions with fewer stars than this are left out of the fit.
+    pub min_stars: usize,
+    pub finder: StarFinder,
+    pub move_timeout: Duration
+}
+
+impl Default for Autofocus {
+    fn default() -> Autofocus {
+        Autofocus {
+            step: 100,
+            steps_out: 4,
+            backlash: 100,
+            exposure: Duration::from_secs(3),
+            subframe: None,
+            frames: 1,
+            min_stars: 3,
+            finder: StarFinder::default(),
+            move_timeout: Duration::from_secs(60)
+        }
+    }
+}
+
+/// HFR(position) = a * sqrt(1 + ((position - centre) / b)^2), which is what a star's size does
+/// either side of focus.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Hyperbola {
+    /// HFR at best focus.
+    pub a: f64,
+    /// How sharply the HFR rises away from focus, in steps.
+    pub b: f64,
+    pub centre: f64
+}
+
+impl Hyperbola {
+    /// Least-squares fit through (position, HFR) samples. Squaring the HFR makes it a
+    /// parabola, `a^2 + (a/b)^2 (position - centre)^2`, which is a linear fit. Squaring also
+    /// blows up the scatter of the big HFRs far from focus, so samples are weighted by
+    /// 1/HFR^4 to keep those from dragging the minimum towards them.
+    pub fn fit(samples: &[(i32, f64)]) -> Option<Hyperbola> {
+        if samples.len() < 3 {
+            return None;
+        }
+        // around the mean position, to keep the sums small
+        let mean = samples.iter().map(|(p, _)| *p as f64).sum::<f64>() / samples.len() as f64;
+        let mut sums = [0f64; 5];
+        let mut rhs = [0f64; 3];
+        for (position, hfr) in samples {
+            let x = *position as f64 - mean;
+            let y = hfr * hfr;
+            let mut power = 1.0 / (y * y);
+            for (i, sum) in sums.iter_mut().enumerate() {
+                *sum += power;
+                if i < 3 {
+                    rhs[i] += power * y;
+                }
+                power *= x;
+            }
+        }
+        // normal equations for y = c0 + c1 x + c2 x^2
+        let m = [
+            [sums[0], sums[1], sums[2]],
+            [sums[1], sums[2], sums[3]],
+            [sums[2], sums[3], sums[4]]
+        ];
+        let [c0, c1, c2] = solve3(m, rhs)?;
+        if c2 <= 0.0 {
+            return None;
+        }
+        let offset = -c1 / (2.0 * c2);
+        let a2 = c0 - c1 * c1 / (4.0 * c2);
+        if a2 <= 0.0 {
+            return None;
+        }
+        Some(Hyperbola { a: a2.sqrt(), b: (a2 / c2).sqrt(), centre: mean + offset })
+    }
+
+    pub fn hfr_at(&self, position: f64) -> f64 {
+        let x = (position - self.centre) / self.b;
+        self.a * (1.0 + x * x).sqrt()
+    }
+}
+
+/// Solve `m x = rhs` by Cramer's rule, or `None` if `m` is singular.
+fn solve3(m: [[f64; 3]; 3], rhs: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: &[[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(&m);
+    if d.abs() < 1e-12 {
+        return None;
+    }
+    let mut x = [0f64; 3];
+    for (column, value) in x.iter_mut().enumerate() {
+        let mut replaced = m;
+        for row in 0..3 {
+            replaced[row][column] = rhs[row];
+        }
+        *value = det(&replaced) / d;
+    }
+    Some(x)
+}
+
+#[derive(Debug, Clone)]
+pub struct FocusResult {
+    /// Where the focuser was left.
+    pub position: i32,
+    /// HFR measured there.
+    pub hfr: f64,
+    pub curve: Hyperbola,
+    /// (position, HFR) of every position with enough stars.
+    pub samples: Vec<(i32, f64)>,
+    pub temperature: Option<f64>
+}
+
+impl Autofocus {
+    /// Find best focus around where `focuser` is and leave it there. The camera is left
+    /// reading out the whole sensor, at `exposure`.
+    pub fn run<F, C>(&self, focuser: &mut F, camera: &mut C) -> Result<FocusResult, FocusError<F::Error, C::Error>>
+    where
+        F: Focuser + ?Sized,
+        C: Imager
+    {
+        camera.set_exposure(self.exposure).map_err(FocusError::Camera)?;
+        camera.set_subframe(self.subframe).map_err(FocusError::Camera)?;
+        let result = self.focus(focuser, camera);
+        camera.set_subframe(None).map_err(FocusError::Camera)?;
+        result
+    }
+
+    fn focus<F: Focuser + ?Sized, C: Imager>(&self, focuser: &mut F, camera: &mut C) -> Result<FocusResult, FocusError<F::Error, C::Error>> {
+        let start = focuser.position().map_err(FocusError::Focuser)?;
+        let out = self.steps_out as i32;
+        let positions: Vec<i32> = (-out..=out).map(|i| start + i * self.step).collect();
+
+        let mut samples = Vec::new();
+        for position in positions.iter() {
+            self.move_to(focuser, *position)?;
+            match self.measure(camera)? {
+                Some(hfr) => {
+                    println!("Focus {}: HFR {:.2}", position, hfr);
+                    samples.push((*position, hfr));
+                }
+                None => println!("Focus {}: too few stars", position)
+            }
+        }
+        if samples.len() < 3 {
+            return Err(FocusError::TooFewSamples(samples.len()));
+        }
+        let curve = Hyperbola::fit(&samples).ok_or(FocusError::NoMinimum)?;
+        let best = curve.centre.round() as i32;
+        if best < positions[0] || best > positions[positions.len() - 1] {
+            self.move_to(focuser, start)?;
+            return Err(FocusError::MinimumOutside(best));
+        }
+        println!("Best focus at {} with HFR {:.2}", best, curve.a);
+
+        self.move_to(focuser, best)?;
+        let hfr = self.measure(camera)?.unwrap_or(0.0);
+        Ok(FocusResult {
+            position: best,
+            hfr: hfr,
+            curve: curve,
+            samples: samples,
+            temperature: focuser.temperature().map_err(FocusError::Focuser)?
+        })
+    }
+
+    /// `move_to` with this run's backlash and move timeout.
+    fn move_to<F: Focuser + ?Sized, C>(&self, focuser: &mut F, position: i32) -> Result<(), FocusError<F::Error, C>> {
+        move_to(focuser, position, self.backlash, self.move_timeout)
+    }
+
+    /// The mean HFR over `frames` frames, or `None` if any had too few stars.
+    fn measure<F, C: Imager>(&self, camera: &mut C) -> Result<Option<f64>, FocusError<F, C::Error>> {
+        let mut total = 0.0;
+        for _ in 0..self.frames.max(1) {
+            let frame = camera.capture(FrameType::Light).map_err(FocusError::Camera)?;
+            let field = self.finder.find(&frame);
+            if field.stars.len() < self.min_stars {
+                return Ok(None);
+            }
+            total += field.hfr;
+        }
+        Ok(Some(total / self.frames.max(1) as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hyperbolas_are_fitted_back_from_their_own_samples() {
+        let curve = Hyperbola { a: 1.8, b: 150.0, centre: 5230.0 };
+        let samples: Vec<(i32, f64)> = (0..9).map(|i| 4800 + 100 * i).map(|p| (p, curve.hfr_at(p as f64))).collect();
+        let fitted = Hyperbola::fit(&samples).unwrap();
+        assert!((fitted.a - curve.a).abs() < 1e-6, "{:?}", fitted);
+        assert!((fitted.b - curve.b).abs() < 1e-3, "{:?}", fitted);
+        assert!((fitted.centre - curve.centre).abs() < 1e-3, "{:?}", fitted);
+
+        // a flat line or a curve upside down has no minimum to go to
+        assert_eq!(Hyperbola::fit(&[(0, 2.0), (100, 2.0), (200, 2.0)]), None);
+        assert_eq!(Hyperbola::fit(&[(0, 2.0), (100, 3.0), (200, 2.0)]), None);
+        assert_eq!(Hyperbola::fit(&[(0, 2.0), (100, 3.0)]), None);
+    }
+
+    #[cfg(feature = "sim")]
+    mod sim {
+        use super::super::*;
+        use crate::sim;
+
+        /// Autofocus the simulated camera from `from` steps out, with `backlash` steps of play
+        /// in the focuser, returning the result and where the motor has to be for the optics
+        /// to be at best focus after moving out.
+        fn focus_from(from: i32, backlash: i32) -> (Result<FocusResult, FocusError<std::convert::Infallible, std::convert::Infallible>>, i32) {
+            let mut camera = sim::Camera::new(640, 480);
+            let mut focuser = camera.focuser(backlash);
+            focuser.move_to(camera.best_focus + from).unwrap();
+            let autofocus = Autofocus {
+                subframe: Some(Subframe::centre(camera.width, camera.height, 2)),
+                ..Autofocus::default()
+            };
+            let result = autofocus.run(&mut focuser, &mut camera);
+            (result, camera.best_focus + backlash)
+        }
+
+        #[test]
+        fn the_fitted_minimum_is_best_focus() {
+            let (result, best) = focus_from(250, 0);
+            let result = result.unwrap();
+            assert_eq!(result.samples.len(), 9);
+            assert!((result.position - best).abs() <= 25, "focused at {}, best focus at {}", result.position, best);
+            // the stars at the end are as small as the curve says they get
+            assert!(result.hfr < result.curve.a * 1.1, "HFR {:.2} against {:.2}", result.hfr, result.curve.a);
+            assert_eq!(result.temperature, Some(5.0));
+        }
+
+        #[test]
+        fn backlash_shifts_the_minimum_by_the_play_in_the_gears() {
+            // every move finishes going out, leaving the optics `backlash` behind the motor
+            let (result, best) = focus_from(-250, 40);
+            let result = result.unwrap();
+            assert!((result.position - best).abs() <= 25, "focused at {}, best focus at {}", result.position, best);
+            assert!(result.hfr < result.curve.a * 1.1, "HFR {:.2} against {:.2}", result.hfr, result.curve.a);
+        }
+
+        #[test]
+        fn focus_far_outside_the_positions_tried_is_reported() {
+            // tried from 5200 to 6000, with best focus below them all
+            let (result, best) = focus_from(600, 0);
+            match result {
+                Err(FocusError::MinimumOutside(position)) => assert!(position < 5200 && (position - best).abs() < 150, "minimum at {}", position),
+                Err(FocusError::NoMinimum) => {}
+                other => panic!("{:?}", other.map(|result| result.position))
+            }
+        }
+
+        #[test]
+        fn a_starless_sky_gives_too_few_samples() {
+            let mut camera = sim::Camera::new(320, 240);
+            camera.stars.clear();
+            let mut focuser = camera.focuser(0);
+            let result = Autofocus::default().run(&mut focuser, &mut camera);
+            assert!(matches!(result, Err(FocusError::TooFewSamples(0))));
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use crate::fits;
+use crate::gps::{self, GpsHeader};
+use crate::jpeg;
+use crate::stats::FrameStats;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use png::HasParameters;
+
+/// The JPEG quality frames are saved at, from 1 to 100: high enough that a stretched sky's
+/// noise doesn't turn into blocks.
+pub const JPEG_QUALITY: u8 = 90;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FrameType {
+    Light,
+    Dark,
+    Bias,
+    Flat
+}
+
+impl FrameType {
+    /// Whether this kind of frame should be taken with the shutter closed.
+    pub fn wants_dark(&self) -> bool {
+        match self {
+            FrameType::Dark | FrameType::Bias => true,
+            FrameType::Light | FrameType::Flat => false
+        }
+    }
+
+    /// The conventional FITS `IMAGETYP` value.
+    pub fn imagetyp(&self) -> &'static str {
+        match self {
+            FrameType::Light => "Light Frame",
+            FrameType::Dark => "Dark Frame",
+            FrameType::Bias => "Bias Frame",
+            FrameType::Flat => "Flat Field"
+        }
+    }
+}
+
+/// What the shutter was doing during an exposure, recorded as the `SHUTTER` keyword.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Shutter {
+    Open,
+    Closed,
+    /// The camera has no mechanical shutter; darks were taken however the user covered it.
+    None
+}
+
+impl Shutter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Shutter::Open => "OPEN",
+            Shutter::Closed => "CLOSED",
+            Shutter::None => "NONE"
+        }
+    }
+}
+
+/// The layout of a Bayer color filter array, named by its top-left 2x2 block read across
+/// then down.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)] // the patterns' usual names, as BAYERPAT spells them
+pub enum Cfa {
+    RGGB,
+    BGGR,
+    GRBG,
+    GBRG
+}
+
+impl Cfa {
+    /// The color at (`x`, `y`): 0 for red, 1 for green, 2 for blue.
+    pub fn color_at(&self, x: u32, y: u32) -> usize {
+        let pattern: [usize; 4] = match self {
+            Cfa::RGGB => [0, 1, 1, 2],
+            Cfa::BGGR => [2, 1, 1, 0],
+            Cfa::GRBG => [1, 0, 2, 1],
+            Cfa::GBRG => [1, 2, 0, 1]
+        };
+        pattern[((y & 1) * 2 + (x & 1)) as usize]
+    }
+
+    /// A name for each of the four sites in the 2x2 block, keeping the two greens apart:
+    /// the one sharing a row with red is G1.
+    pub fn site_name(&self, x: u32, y: u32) -> &'static str {
+        match self.color_at(x, y) {
+            0 => "R",
+            2 => "B",
+            _ => if self.color_at(x ^ 1, y) == 0 { "G1" } else { "G2" }
+        }
+    }
+
+    /// The pattern of the part of the sensor starting at (`x`, `y`).
+    pub fn offset(&self, x: u32, y: u32) -> Cfa {
+        let wanted = [self.color_at(x, y), self.color_at(x + 1, y), self.color_at(x, y + 1), self.color_at(x + 1, y + 1)];
+        [Cfa::RGGB, Cfa::BGGR, Cfa::GRBG, Cfa::GBRG].iter().cloned()
+            .find(|cfa| [cfa.color_at(0, 0), cfa.color_at(1, 0), cfa.color_at(0, 1), cfa.color_at(1, 1)] == wanted)
+            .unwrap_or(*self)
+    }
+
+    /// The pattern named by `name`, as in a `BAYERPAT` keyword.
+    pub fn parse(name: &str) -> Option<Cfa> {
+        [Cfa::RGGB, Cfa::BGGR, Cfa::GRBG, Cfa::GBRG].iter().cloned().find(|cfa| cfa.as_str() == name.trim())
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Cfa::RGGB => "RGGB",
+            Cfa::BGGR => "BGGR",
+            Cfa::GRBG => "GRBG",
+            Cfa::GBRG => "GBRG"
+        }
+    }
+}
+
+/// How a frame was taken, as far as calibration cares: a master only calibrates frames from
+/// the same camera at the same gain, offset, binning and region. Kept in the frame's headers.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Setup {
+    pub serial: String,
+    pub gain: Option<f64>,
+    pub offset: Option<f64>,
+    pub binning: u32,
+    /// Where the region read out starts, in binned pixels.
+    pub origin: (u32, u32),
+    /// Sensor temperature during the exposure, in °C.
+    pub temperature: Option<f64>
+}
+
+fn header_f64(headers: &[(String, HeaderValue)], key: &str) -> Option<f64> {
+    match headers.iter().find(|(k, _)| k == key).map(|(_, v)| v) {
+        Some(HeaderValue::Float(f)) => Some(*f),
+        Some(HeaderValue::Int(i)) => Some(*i as f64),
+        _ => None
+    }
+}
+
+impl Setup {
+    /// Read a setup back from the keywords `record` writes; anything missing is left unknown.
+    pub fn from_headers(headers: &[(String, HeaderValue)]) -> Setup {
+        let serial = match headers.iter().find(|(k, _)| k == "SERIALNO").map(|(_, v)| v) {
+            Some(HeaderValue::Str(s)) => s.clone(),
+            _ => String::new()
+        };
+        Setup {
+            serial: serial,
+            gain: header_f64(headers, "GAIN"),
+            offset: header_f64(headers, "OFFSET"),
+            binning: header_f64(headers, "XBINNING").map(|b| b as u32).unwrap_or(1),
+            origin: (
+                header_f64(headers, "XORGSUBF").unwrap_or(0.0) as u32,
+                header_f64(headers, "YORGSUBF").unwrap_or(0.0) as u32
+            ),
+            temperature: header_f64(headers, "CCD-TEMP")
+        }
+    }
+
+    pub fn to_headers(&self) -> Vec<(String, HeaderValue)> {
+        let mut headers = vec![
+            ("SERIALNO".to_owned(), HeaderValue::Str(self.serial.clone())),
+            ("XBINNING".to_owned(), HeaderValue::Int(self.binning as i64)),
+            ("YBINNING".to_owned(), HeaderValue::Int(self.binning as i64)),
+            ("XORGSUBF".to_owned(), HeaderValue::Int(self.origin.0 as i64)),
+            ("YORGSUBF".to_owned(), HeaderValue::Int(self.origin.1 as i64))
+        ];
+        if let Some(gain) = self.gain {
+            headers.push(("GAIN".to_owned(), HeaderValue::Float(gain)));
+        }
+        if let Some(offset) = self.offset {
+            headers.push(("OFFSET".to_owned(), HeaderValue::Float(offset)));
+        }
+        if let Some(temperature) = self.temperature {
+            headers.push(("CCD-TEMP".to_owned(), HeaderValue::Float(temperature)));
+        }
+        headers
+    }
+
+    /// Whether a master taken with this setup can calibrate a frame taken with `other`:
+    /// everything the same but the temperature, which only has to be within `tolerance` °C
+    /// where both are known.
+    pub fn matches(&self, other: &Setup, tolerance: f64) -> bool {
+        let temperature = match (self.temperature, other.temperature) {
+            (Some(a), Some(b)) => (a - b).abs() <= tolerance,
+            _ => true
+        };
+        self.serial == other.serial
+            && self.gain == other.gain
+            && self.offset == other.offset
+            && self.binning == other.binning
+            && self.origin == other.origin
+            && temperature
+    }
+}
+
+/// An image read off a camera. Samples are row-major with channels interleaved, widened to
+/// `u16` whatever the camera's transfer depth; `bpp` says how many bits of each are real.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub channels: u32,
+    pub bpp: u32,
+    pub data: Vec<u16>,
+    /// When the frame was read out.
+    pub timestamp: SystemTime,
+    /// When the exposure started: the GPS shutter open time if the camera has one, otherwise
+    /// as the capture path noted it. `None` for video and triggered frames, whose start isn't
+    /// known.
+    pub started: Option<SystemTime>,
+    pub gps: Option<GpsHeader>,
+    /// Extra FITS keywords describing how the frame was taken.
+    pub headers: Vec<(String, HeaderValue)>,
+    /// The Bayer pattern of a raw frame from a color camera.
+    pub cfa: Option<Cfa>,
+    pub stats: Option<FrameStats>
+}
+
+impl Frame {
+    /// Build a frame from an SDK buffer: one byte per sample for 8bpp, little-endian pairs for
+    /// anything deeper.
+    pub fn from_bytes(width: u32, height: u32, channels: u32, bpp: u32, bytes: &[u8]) -> Frame {
+        let samples = width as usize * height as usize * channels as usize;
+        let data = if bpp <= 8 {
+            bytes[..samples].iter().map(|b| *b as u16).collect()
+        } else {
+            bytes[..samples * 2].chunks(2).map(|b| b[0] as u16 | ((b[1] as u16) << 8)).collect()
+        };
+        Frame {
+            width: width,
+            height: height,
+            channels: channels,
+            bpp: bpp,
+            data: data,
+            timestamp: SystemTime::now(),
+            started: None,
+            gps: None,
+            headers: Vec::new(),
+            cfa: None,
+            stats: None
+        }
+    }
+
+    /// The largest value a sample can take.
+    pub fn full_scale(&self) -> u16 {
+        if self.bpp >= 16 { u16::MAX } else { (1u16 << self.bpp) - 1 }
+    }
+
+    /// Work out this frame's statistics, keeping them in `stats`.
+    pub fn compute_stats(&mut self) -> &FrameStats {
+        self.stats = Some(FrameStats::of(self));
+        self.stats.as_ref().unwrap()
+    }
+
+    /// Attach the GPS header the camera embedded in this frame, taking its start time from it.
+    /// The header was written over the first pixels, which are patched with the ones below
+    /// them so it doesn't show up in the stats or the picture.
+    pub fn set_gps(&mut self, gps: GpsHeader) {
+        self.started = Some(gps.shutter_open_time());
+        self.gps = Some(gps);
+        let bytes_per_sample = if self.bpp <= 8 { 1 } else { 2 };
+        let covered = gps::HEADER_LEN.div_ceil(bytes_per_sample);
+        let row = self.width as usize * self.channels as usize;
+        if self.height > 1 {
+            for i in 0..covered.min(row) {
+                self.data[i] = self.data[i + row];
+            }
+        }
+        if self.stats.is_some() {
+            self.compute_stats();
+        }
+    }
+
+    pub fn set_header(&mut self, key: &str, value: HeaderValue) {
+        match self.headers.iter_mut().find(|(k, _)| k == key) {
+            Some(header) => { header.1 = value; }
+            None => { self.headers.push((key.to_owned(), value)); }
+        }
+    }
+
+    /// Record what kind of frame this is and what the shutter did while taking it.
+    pub fn set_frame_type(&mut self, frame_type: FrameType, shutter: Shutter) {
+        self.set_header("IMAGETYP", HeaderValue::Str(frame_type.imagetyp().to_owned()));
+        self.set_header("SHUTTER", HeaderValue::Str(shutter.as_str().to_owned()));
+    }
+
+    pub fn header(&self, key: &str) -> Option<&HeaderValue> {
+        self.headers.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Record how the frame was taken, for matching it up with masters.
+    pub fn set_setup(&mut self, setup: &Setup) {
+        for (key, value) in setup.to_headers() {
+            self.set_header(&key, value);
+        }
+    }
+
+    pub fn setup(&self) -> Setup {
+        Setup::from_headers(&self.headers)
+    }
+
+    /// The exposure in seconds, from the GPS if the camera has one, otherwise as recorded
+    /// in `EXPTIME`.
+    pub fn exposure(&self) -> Option<f64> {
+        match self.gps.as_ref() {
+            Some(gps) => Some(gps.exposure().as_secs_f64()),
+            None => header_f64(&self.headers, "EXPTIME")
+        }
+    }
+
+    pub fn sample(&self, x: u32, y: u32, channel: u32) -> u16 {
+        self.data[((y as usize * self.width as usize + x as usize) * self.channels as usize) + channel as usize]
+    }
+
+    /// Save the frame at `path`: FITS with its headers for `.fits`, `.fit` or `.fts`, JPEG
+    /// for `.jpg` or `.jpeg`, png for anything else.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let extension = path.as_ref().extension().map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("fits") | Some("fit") | Some("fts") => fits::write(self, path),
+            Some("jpg") | Some("jpeg") => self.write_jpeg(path, JPEG_QUALITY),
+            _ => self.write_png(path)
+        }
+    }
+
+    pub fn write_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.encode_png(BufWriter::new(file))
+    }
+
+    /// The frame as a png, into `w`: a file, or a buffer for serving.
+    pub fn encode_png<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(w, self.width, self.height);
+        let color = match self.channels {
+            1 => png::ColorType::Grayscale,
+            3 => png::ColorType::RGB,
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("can't write a {}-channel png", self.channels)));
+            }
+        };
+        let to_io = |e: png::EncodingError| io::Error::other(e.to_string());
+        if self.bpp <= 8 {
+            encoder.set(color).set(png::BitDepth::Eight);
+            let bytes: Vec<u8> = self.data.iter().map(|s| *s as u8).collect();
+            encoder.write_header().map_err(to_io)?.write_image_data(&bytes).map_err(to_io)
+        } else {
+            encoder.set(color).set(png::BitDepth::Sixteen);
+            // png wants big-endian samples
+            let mut bytes: Vec<u8> = Vec::with_capacity(self.data.len() * 2);
+            for s in self.data.iter() {
+                bytes.push((s >> 8) as u8);
+                bytes.push(*s as u8);
+            }
+            encoder.write_header().map_err(to_io)?.write_image_data(&bytes).map_err(to_io)
+        }
+    }
+
+    pub fn write_jpeg<P: AsRef<Path>>(&self, path: P, quality: u8) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.encode_jpeg(BufWriter::new(file), quality)
+    }
+
+    /// The frame as a JPEG of `quality` from 1 to 100, into `w`. JPEG only has 8 bits, so
+    /// deeper frames lose their low bits.
+    pub fn encode_jpeg<W: Write>(&self, w: W, quality: u8) -> io::Result<()> {
+        let shift = self.bpp.clamp(8, 16) - 8;
+        let bytes: Vec<u8> = self.data.iter().map(|s| (s >> shift) as u8).collect();
+        jpeg::encode(w, self.width, self.height, self.channels, &bytes, quality)
+    }
+}
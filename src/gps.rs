@@ -0,0 +1,216 @@
+// GPS-equipped cameras (the QHY174-GPS) overwrite the start of each image with a header
+// recording when the shutter opened and closed, as measured against GPS time.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Size of the header at the start of the image data, in bytes.
+pub const HEADER_LEN: usize = 44;
+
+/// GPS seconds count from 1995-10-10 00:00:00 UTC (JD 2450000.5).
+const GPS_EPOCH_UNIX: u64 = 813283200;
+
+/// Nominal rate of the camera's sub-second counter; the counter is driven by a 10MHz VCXO
+/// that the PPS signal disciplines, so the measured count per second (`pps_counter`) is the
+/// better divisor when we have it.
+const NOMINAL_CLOCK_HZ: u32 = 10_000_000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GpsStamp {
+    /// GPS status flag as the camera reported it with this timestamp.
+    pub flag: u8,
+    pub seconds: u32,
+    /// Sub-second clock ticks, see `NOMINAL_CLOCK_HZ`.
+    pub ticks: u32
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GpsHeader {
+    pub sequence: u32,
+    pub width: u16,
+    pub height: u16,
+    /// Degrees, positive north.
+    pub latitude: f64,
+    /// Degrees, positive east.
+    pub longitude: f64,
+    pub shutter_open: GpsStamp,
+    pub shutter_close: GpsStamp,
+    /// When the camera assembled the header.
+    pub now: GpsStamp,
+    /// Sub-second ticks counted between the last two PPS pulses.
+    pub pps_counter: u32
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) | bytes[3] as u32
+}
+
+fn be24(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32
+}
+
+fn be16(bytes: &[u8]) -> u16 {
+    ((bytes[0] as u16) << 8) | bytes[1] as u16
+}
+
+/// Coordinates are NMEA-style `DDDMM.MMMM` scaled to an integer (degrees * 1e6 + minutes *
+/// 1e4), with 1e9 added for the southern/western hemisphere.
+fn coordinate(raw: u32) -> f64 {
+    let (negative, raw) = if raw >= 1_000_000_000 {
+        (true, raw - 1_000_000_000)
+    } else {
+        (false, raw)
+    };
+    let degrees = (raw / 1_000_000) as f64 + (raw % 1_000_000) as f64 / 10_000.0 / 60.0;
+    if negative { -degrees } else { degrees }
+}
+
+fn stamp(bytes: &[u8]) -> GpsStamp {
+    GpsStamp {
+        flag: bytes[0],
+        seconds: be32(&bytes[1..5]),
+        ticks: be24(&bytes[5..8])
+    }
+}
+
+impl GpsHeader {
+    /// Parse the header from the first bytes of a frame as read from the camera. Returns `None`
+    /// if there aren't enough bytes, or if the header is blank (GPS not enabled).
+    pub fn parse(bytes: &[u8]) -> Option<GpsHeader> {
+        if bytes.len() < HEADER_LEN || bytes[..HEADER_LEN].iter().all(|b| *b == 0) {
+            return None;
+        }
+        Some(GpsHeader {
+            sequence: be32(&bytes[0..4]),
+            // byte 4 is reserved
+            width: be16(&bytes[5..7]),
+            height: be16(&bytes[7..9]),
+            latitude: coordinate(be32(&bytes[9..13])),
+            longitude: coordinate(be32(&bytes[13..17])),
+            shutter_open: stamp(&bytes[17..25]),
+            shutter_close: stamp(&bytes[25..33]),
+            now: stamp(&bytes[33..41]),
+            pps_counter: be24(&bytes[41..44])
+        })
+    }
+
+    fn clock_hz(&self) -> u32 {
+        // a counter far from nominal means the PPS hasn't been seen yet
+        if self.pps_counter > NOMINAL_CLOCK_HZ - NOMINAL_CLOCK_HZ / 100 &&
+           self.pps_counter < NOMINAL_CLOCK_HZ + NOMINAL_CLOCK_HZ / 100 {
+            self.pps_counter
+        } else {
+            NOMINAL_CLOCK_HZ
+        }
+    }
+
+    pub fn time_of(&self, stamp: &GpsStamp) -> SystemTime {
+        let nanos = stamp.ticks as u64 * 1_000_000_000 / self.clock_hz() as u64;
+        UNIX_EPOCH +
+            Duration::from_secs(GPS_EPOCH_UNIX + stamp.seconds as u64) +
+            Duration::from_nanos(std::cmp::min(nanos, 999_999_999))
+    }
+
+    pub fn shutter_open_time(&self) -> SystemTime {
+        self.time_of(&self.shutter_open)
+    }
+
+    pub fn shutter_close_time(&self) -> SystemTime {
+        self.time_of(&self.shutter_close)
+    }
+
+    pub fn exposure(&self) -> Duration {
+        self.shutter_close_time()
+            .duration_since(self.shutter_open_time())
+            .unwrap_or(Duration::from_secs(0))
+    }
+}
+
+/// Format `time` as an ISO-8601 UTC timestamp with microseconds, as FITS `DATE-OBS` wants it.
+pub fn iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}",
+        year, month, day,
+        secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+/// Days since 1970-01-01 to a (year, month, day) in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+
+    /// A header as the camera writes it: frame 258 of 1920x1200, at 52°30.5'N 13°24'W, the
+    /// shutter open from 16:00:00.5 to 16:00:02.25 UTC on 2024-04-16.
+    const HEADER: [u8; HEADER_LEN] = [
+        0x00, 0x00, 0x01, 0x02, // sequence
+        0x00, // reserved
+        0x07, 0x80, 0x04, 0xb0, // width, height
+        0x03, 0x1e, 0x1c, 0x68, // latitude 52_305_000
+        0x3c, 0x64, 0xd0, 0xc0, // longitude 1_013_240_000
+        0x33, 0x35, 0xa4, 0xe9, 0x00, 0x4c, 0x4b, 0x40, // shutter open: flag, seconds, ticks
+        0x33, 0x35, 0xa4, 0xe9, 0x02, 0x26, 0x25, 0xa0, // shutter close
+        0x33, 0x35, 0xa4, 0xe9, 0x03, 0x00, 0x00, 0x00, // now
+        0x98, 0x96, 0x80 // PPS counter
+    ];
+
+    #[test]
+    fn parses_a_known_header() {
+        let gps = GpsHeader::parse(&HEADER).unwrap();
+        assert_eq!(gps.sequence, 258);
+        assert_eq!((gps.width, gps.height), (1920, 1200));
+        assert!((gps.latitude - 52.508333).abs() < 1e-6);
+        assert!((gps.longitude + 13.4).abs() < 1e-6);
+        assert_eq!(gps.shutter_open, GpsStamp { flag: 0x33, seconds: 900_000_000, ticks: 5_000_000 });
+        assert_eq!(gps.shutter_close.seconds, 900_000_002);
+        assert_eq!(gps.pps_counter, 10_000_000);
+        assert_eq!(iso8601(gps.shutter_open_time()), "2024-04-16T16:00:00.500000");
+        assert_eq!(iso8601(gps.shutter_close_time()), "2024-04-16T16:00:02.250000");
+        assert_eq!(gps.exposure(), Duration::from_millis(1750));
+    }
+
+    #[test]
+    fn blank_and_short_headers_are_not_parsed() {
+        assert_eq!(GpsHeader::parse(&[0u8; 100]), None);
+        assert_eq!(GpsHeader::parse(&HEADER[..HEADER_LEN - 1]), None);
+    }
+
+    #[test]
+    fn an_off_nominal_pps_count_falls_back_to_the_nominal_clock() {
+        let mut bytes = HEADER;
+        bytes[41..44].copy_from_slice(&[0x00, 0x00, 0x10]);
+        let gps = GpsHeader::parse(&bytes).unwrap();
+        assert_eq!(iso8601(gps.shutter_open_time()), "2024-04-16T16:00:00.500000");
+    }
+
+    #[test]
+    fn frames_are_patched_where_the_header_was() {
+        // a 16-bit 32x4 frame of 1000s with the header over the start of its first row
+        let mut bytes: Vec<u8> = (0..32 * 4).flat_map(|_| 1000u16.to_le_bytes()).collect();
+        bytes[..HEADER_LEN].copy_from_slice(&HEADER);
+        let mut frame = Frame::from_bytes(32, 4, 1, 16, &bytes);
+        frame.compute_stats();
+        frame.set_gps(GpsHeader::parse(&bytes).unwrap());
+        assert!(frame.data.iter().all(|sample| *sample == 1000));
+        assert_eq!(frame.stats.as_ref().unwrap().all.max, 1000);
+        assert_eq!(frame.started, Some(frame.gps.unwrap().shutter_open_time()));
+    }
+}
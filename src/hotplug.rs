@@ -0,0 +1,438 @@
+// Watching for cameras being plugged in and unplugged, from the kernel's uevents. Events
+// come from an `EventSource` so they can be fed in by hand instead of from netlink.
+
+use crate::usb::{self, UsbDevice, ASI_VID, QHY_VID};
+
+use std::collections::VecDeque;
+use std::io;
+use std::os;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    Add,
+    Remove
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub action: Action,
+    /// The device's port, e.g. `4-1.2`. It's the same when a camera is unplugged and plugged
+    /// back into the same socket, unlike the device number.
+    pub port: String,
+    pub vid: u16,
+    pub pid: u16,
+    pub busnum: u32,
+    pub devnum: u32
+}
+
+/// Parse a kernel uevent: a `<action>@<devpath>` line then `KEY=value` fields, each ended by
+/// a NUL. Only whole USB devices are returned; their interfaces get events of their own.
+pub fn parse_uevent(msg: &[u8]) -> Option<Event> {
+    let mut action = None;
+    let mut devpath = None;
+    let mut product = None;
+    let mut busnum = None;
+    let mut devnum = None;
+    let mut is_device = false;
+    for field in msg.split(|b| *b == 0) {
+        let field = std::str::from_utf8(field).ok()?;
+        let mut kv = field.splitn(2, '=');
+        let (key, value) = match (kv.next(), kv.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => continue
+        };
+        match key {
+            "ACTION" => action = match value {
+                "add" => Some(Action::Add),
+                "remove" => Some(Action::Remove),
+                _ => return None
+            },
+            "DEVPATH" => devpath = Some(value),
+            "DEVTYPE" => is_device = value == "usb_device",
+            "PRODUCT" => product = Some(value),
+            "BUSNUM" => busnum = value.parse().ok(),
+            "DEVNUM" => devnum = value.parse().ok(),
+            _ => {}
+        }
+    }
+    if !is_device {
+        return None;
+    }
+    // PRODUCT is vid/pid/bcdDevice, in hex without leading zeros
+    let mut product = product?.split('/');
+    Some(Event {
+        action: action?,
+        port: devpath?.rsplit('/').next()?.to_owned(),
+        vid: u16::from_str_radix(product.next()?, 16).ok()?,
+        pid: u16::from_str_radix(product.next()?, 16).ok()?,
+        busnum: busnum?,
+        devnum: devnum?
+    })
+}
+
+/// Where raw uevents come from.
+pub trait EventSource {
+    /// The next uevent, or `None` if there wasn't one within `timeout`.
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>>;
+}
+
+const AF_NETLINK: os::raw::c_int = 16;
+const SOCK_DGRAM: os::raw::c_int = 2;
+const SOCK_CLOEXEC: os::raw::c_int = 0o2000000;
+const NETLINK_KOBJECT_UEVENT: os::raw::c_int = 15;
+const POLLIN: os::raw::c_short = 1;
+/// The multicast group the kernel sends uevents to (udev re-sends them on group 2).
+const KERNEL_GROUP: u32 = 1;
+
+#[repr(C)]
+struct sockaddr_nl {
+    nl_family: u16,
+    nl_pad: u16,
+    nl_pid: u32,
+    nl_groups: u32
+}
+
+#[repr(C)]
+struct pollfd {
+    fd: os::raw::c_int,
+    events: os::raw::c_short,
+    revents: os::raw::c_short
+}
+
+extern "C" {
+    fn socket(domain: os::raw::c_int, ty: os::raw::c_int, protocol: os::raw::c_int) -> os::raw::c_int;
+    fn bind(fd: os::raw::c_int, addr: *const sockaddr_nl, len: u32) -> os::raw::c_int;
+    fn poll(fds: *mut pollfd, nfds: os::raw::c_ulong, timeout: os::raw::c_int) -> os::raw::c_int;
+    fn recv(fd: os::raw::c_int, buf: *mut os::raw::c_void, len: usize, flags: os::raw::c_int) -> isize;
+    fn close(fd: os::raw::c_int) -> os::raw::c_int;
+}
+
+/// Uevents straight from the kernel over netlink.
+pub struct Netlink {
+    fd: os::raw::c_int
+}
+
+impl Netlink {
+    pub fn open() -> io::Result<Netlink> {
+        unsafe {
+            let fd = socket(AF_NETLINK, SOCK_DGRAM | SOCK_CLOEXEC, NETLINK_KOBJECT_UEVENT);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let addr = sockaddr_nl {
+                nl_family: AF_NETLINK as u16,
+                nl_pad: 0,
+                nl_pid: 0,
+                nl_groups: KERNEL_GROUP
+            };
+            if bind(fd, &addr, std::mem::size_of::<sockaddr_nl>() as u32) < 0 {
+                let err = io::Error::last_os_error();
+                close(fd);
+                return Err(err);
+            }
+            Ok(Netlink { fd: fd })
+        }
+    }
+}
+
+impl EventSource for Netlink {
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let mut fds = pollfd { fd: self.fd, events: POLLIN, revents: 0 };
+        let res = unsafe { poll(&mut fds, 1, timeout.as_millis() as os::raw::c_int) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if res == 0 {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; 8192];
+        let len = unsafe { recv(self.fd, buf.as_mut_ptr() as *mut os::raw::c_void, buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+        Ok(Some(buf))
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { close(self.fd); }
+    }
+}
+
+/// Uevents queued up by hand, for trying things out without plugging cables in.
+#[derive(Debug, Default)]
+pub struct FakeSource {
+    pub messages: VecDeque<Vec<u8>>
+}
+
+impl FakeSource {
+    /// Queue the uevent the kernel would send for `event`.
+    pub fn push(&mut self, event: &Event) {
+        let action = match event.action {
+            Action::Add => "add",
+            Action::Remove => "remove"
+        };
+        let devpath = format!("/devices/pci0000:00/0000:00:14.0/usb{}/{}", event.busnum, event.port);
+        let fields = [
+            format!("{}@{}", action, devpath),
+            format!("ACTION={}", action),
+            format!("DEVPATH={}", devpath),
+            "SUBSYSTEM=usb".to_owned(),
+            "DEVTYPE=usb_device".to_owned(),
+            format!("PRODUCT={:x}/{:x}/0", event.vid, event.pid),
+            format!("BUSNUM={:03}", event.busnum),
+            format!("DEVNUM={:03}", event.devnum)
+        ];
+        let mut msg = Vec::new();
+        for field in fields.iter() {
+            msg.extend_from_slice(field.as_bytes());
+            msg.push(0);
+        }
+        self.messages.push_back(msg);
+    }
+}
+
+impl EventSource for FakeSource {
+    fn recv(&mut self, _timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.messages.pop_front())
+    }
+}
+
+/// Whether a camera is still plugged in, shared between the camera and the watcher.
+#[derive(Debug, Clone)]
+pub struct Presence {
+    inner: Arc<(Mutex<bool>, Condvar)>
+}
+
+impl Presence {
+    pub fn new() -> Presence {
+        Presence { inner: Arc::new((Mutex::new(true), Condvar::new())) }
+    }
+
+    pub fn is_present(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    pub fn set(&self, present: bool) {
+        *self.inner.0.lock().unwrap() = present;
+        self.inner.1.notify_all();
+    }
+
+    /// Block until the camera is plugged back in, returning false if `timeout` passes first.
+    pub fn wait_present(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut present = self.inner.0.lock().unwrap();
+        while !*present {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            present = self.inner.1.wait_timeout(present, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+/// Turns uevents into connect/disconnect events for ASI and QHY devices, and keeps the
+/// `Presence` of each watched port up to date.
+pub struct Watcher<S: EventSource> {
+    source: S,
+    watched: Vec<(String, Presence)>
+}
+
+impl<S: EventSource> Watcher<S> {
+    pub fn new(source: S) -> Watcher<S> {
+        Watcher {
+            source: source,
+            watched: Vec::new()
+        }
+    }
+
+    /// Track the camera on `port` (see `UsbDevice::port`).
+    pub fn watch(&mut self, port: &str, presence: Presence) {
+        self.watched.push((port.to_owned(), presence));
+    }
+
+    /// The next event for an ASI or QHY device, or `None` if there wasn't one within
+    /// `timeout`.
+    pub fn next_event(&mut self, timeout: Duration) -> io::Result<Option<Event>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            let msg = match self.source.recv(if now < deadline { deadline - now } else { Duration::from_millis(0) })? {
+                Some(msg) => msg,
+                None => return Ok(None)
+            };
+            let event = match parse_uevent(&msg) {
+                Some(event) => event,
+                None => continue
+            };
+            if event.vid != ASI_VID && event.vid != QHY_VID {
+                continue;
+            }
+            for (port, presence) in self.watched.iter() {
+                if *port == event.port {
+                    presence.set(event.action == Action::Add);
+                }
+            }
+            return Ok(Some(event));
+        }
+    }
+}
+
+impl<S: EventSource + Send + 'static> Watcher<S> {
+    /// Watch on a thread of its own, calling `f` with every event.
+    pub fn spawn<F: FnMut(&Event) + Send + 'static>(mut self, mut f: F) -> std::thread::JoinHandle<io::Result<()>> {
+        std::thread::spawn(move || {
+            loop {
+                if let Some(event) = self.next_event(Duration::from_secs(1))? {
+                    f(&event);
+                }
+            }
+        })
+    }
+}
+
+/// The port of the camera the SDK knows by `serial` among `devices`: the device from `vid`
+/// whose USB serial number is part of it, or else the only device from `vid` there is.
+/// `None` if that doesn't pick out one device.
+pub fn port_of(devices: &[UsbDevice], vid: u16, serial: &str) -> Option<String> {
+    let serial = serial.to_ascii_lowercase();
+    let from_vid: Vec<&UsbDevice> = devices.iter().filter(|device| device.vid == vid).collect();
+    let named: Vec<&UsbDevice> = from_vid.iter().cloned()
+        .filter(|device| device.serial.as_ref().map(|s| !s.is_empty() && serial.contains(&s.to_ascii_lowercase())).unwrap_or(false))
+        .collect();
+    match (named.as_slice(), from_vid.as_slice()) {
+        ([device], _) | ([], [device]) => Some(device.port()),
+        _ => None
+    }
+}
+
+/// Keep `presence` up to date for the camera from `vid` the SDK knows by `serial`, from a
+/// thread watching the kernel's uevents. Returns the port being watched, or `None` if the
+/// camera couldn't be told apart from others on the bus.
+pub fn watch_camera<P: AsRef<Path>>(sysfs_root: P, vid: u16, serial: &str, presence: Presence) -> io::Result<Option<String>> {
+    let port = match port_of(&usb::list_devices(sysfs_root)?, vid, serial) {
+        Some(port) => port,
+        None => return Ok(None)
+    };
+    let mut watcher = Watcher::new(Netlink::open()?);
+    watcher.watch(&port, presence);
+    let watched = port.clone();
+    watcher.spawn(move |event| {
+        if event.port == watched {
+            match event.action {
+                Action::Remove => println!("Camera on port {} was unplugged", watched),
+                Action::Add => println!("Camera on port {} was plugged back in", watched)
+            }
+        }
+    });
+    Ok(Some(port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn event(action: Action, port: &str, vid: u16) -> Event {
+        Event { action: action, port: port.to_owned(), vid: vid, pid: 0x120a, busnum: 4, devnum: 9 }
+    }
+
+    fn device(port: &str, vid: u16, serial: Option<&str>) -> UsbDevice {
+        UsbDevice {
+            sysfs_path: PathBuf::from(usb::SYSFS_ROOT).join(port),
+            vid: vid,
+            pid: 0x120a,
+            serial: serial.map(|serial| serial.to_owned()),
+            busnum: 4,
+            devnum: 9
+        }
+    }
+
+    #[test]
+    fn fake_uevents_parse_back_to_their_events() {
+        let mut source = FakeSource::default();
+        let plugged = event(Action::Add, "4-1.2", ASI_VID);
+        let unplugged = event(Action::Remove, "4-1.2", ASI_VID);
+        source.push(&plugged);
+        source.push(&unplugged);
+        let first = source.recv(Duration::from_millis(0)).unwrap().unwrap();
+        assert_eq!(parse_uevent(&first), Some(plugged));
+        let second = source.recv(Duration::from_millis(0)).unwrap().unwrap();
+        assert_eq!(parse_uevent(&second), Some(unplugged));
+        assert_eq!(source.recv(Duration::from_millis(0)).unwrap(), None);
+    }
+
+    #[test]
+    fn interface_events_are_not_devices() {
+        let mut source = FakeSource::default();
+        source.push(&event(Action::Add, "4-1.2", ASI_VID));
+        let msg = source.recv(Duration::from_millis(0)).unwrap().unwrap();
+        let text = String::from_utf8(msg).unwrap().replace("DEVTYPE=usb_device", "DEVTYPE=usb_interface");
+        assert_eq!(parse_uevent(text.as_bytes()), None);
+    }
+
+    #[test]
+    fn only_camera_vendors_come_through() {
+        let mut source = FakeSource::default();
+        source.push(&event(Action::Add, "1-1", 0x046d));
+        source.push(&event(Action::Add, "1-2", QHY_VID));
+        source.push(&event(Action::Remove, "1-3", 0x8087));
+        let mut watcher = Watcher::new(source);
+        let seen = watcher.next_event(Duration::from_millis(0)).unwrap();
+        assert_eq!(seen.map(|event| event.port), Some("1-2".to_owned()));
+        assert_eq!(watcher.next_event(Duration::from_millis(0)).unwrap(), None);
+    }
+
+    #[test]
+    fn presence_follows_the_watched_port() {
+        let mut source = FakeSource::default();
+        source.push(&event(Action::Remove, "4-1.3", ASI_VID));
+        source.push(&event(Action::Remove, "4-1.2", ASI_VID));
+        source.push(&event(Action::Add, "4-1.2", ASI_VID));
+        let presence = Presence::new();
+        let mut watcher = Watcher::new(source);
+        watcher.watch("4-1.2", presence.clone());
+
+        // another camera going away doesn't count
+        watcher.next_event(Duration::from_millis(0)).unwrap().unwrap();
+        assert!(presence.is_present());
+        watcher.next_event(Duration::from_millis(0)).unwrap().unwrap();
+        assert!(!presence.is_present());
+        assert!(!presence.wait_present(Duration::from_millis(10)));
+        watcher.next_event(Duration::from_millis(0)).unwrap().unwrap();
+        assert!(presence.is_present());
+    }
+
+    #[test]
+    fn waiting_wakes_when_the_camera_comes_back() {
+        let presence = Presence::new();
+        presence.set(false);
+        let plugger = presence.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            plugger.set(true);
+        });
+        assert!(presence.wait_present(Duration::from_secs(10)));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn cameras_are_matched_to_ports_by_serial_or_being_the_only_one() {
+        let devices = vec![
+            device("1-1", ASI_VID, None),
+            device("1-2", QHY_VID, Some("3F2A")),
+            device("1-3", QHY_VID, Some("77bc"))
+        ];
+        assert_eq!(port_of(&devices, ASI_VID, "0123456789abcdef"), Some("1-1".to_owned()));
+        assert_eq!(port_of(&devices, QHY_VID, "QHY600M-3f2a"), Some("1-2".to_owned()));
+        assert_eq!(port_of(&devices, QHY_VID, "QHY600M-0000"), None);
+        assert_eq!(port_of(&devices[..2], QHY_VID, "QHY600M-0000"), Some("1-2".to_owned()));
+    }
+}
@@ -0,0 +1,177 @@
+// Frames as floats, for working on once they're off the camera: combining masters,
+// calibrating and stacking, where u16 would clip negative values and round away the
+// fractions that averaging many frames buys.
+
+use crate::frame::{Cfa, Frame};
+use crate::imaging::Subframe;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub channels: u32,
+    /// The Bayer pattern of a raw frame from a color camera.
+    pub cfa: Option<Cfa>,
+    /// Row-major with channels interleaved, in ADU.
+    pub data: Vec<f32>
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, channels: u32) -> Image {
+        Image {
+            width: width,
+            height: height,
+            channels: channels,
+            cfa: None,
+            data: vec![0.0; width as usize * height as usize * channels as usize]
+        }
+    }
+
+    pub fn from_frame(frame: &Frame) -> Image {
+        Image {
+            width: frame.width,
+            height: frame.height,
+            channels: frame.channels,
+            cfa: frame.cfa,
+            data: frame.data.iter().map(|s| *s as f32).collect()
+        }
+    }
+
+    fn index(&self, x: u32, y: u32, channel: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * self.channels as usize + channel as usize
+    }
+
+    pub fn at(&self, x: u32, y: u32, channel: u32) -> f32 {
+        self.data[self.index(x, y, channel)]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, channel: u32, value: f32) {
+        let i = self.index(x, y, channel);
+        self.data[i] = value;
+    }
+
+    pub fn same_shape(&self, other: &Image) -> bool {
+        self.width == other.width && self.height == other.height && self.channels == other.channels
+    }
+
+    /// The part of the image in `region`, or `None` if it doesn't fit inside.
+    pub fn crop(&self, region: &Subframe) -> Option<Image> {
+        if region.x + region.width > self.width || region.y + region.height > self.height {
+            return None;
+        }
+        let mut cropped = Image::new(region.width, region.height, self.channels);
+        cropped.cfa = self.cfa.map(|cfa| cfa.offset(region.x, region.y));
+        let row = (region.width * self.channels) as usize;
+        for y in 0..region.height {
+            let from = self.index(region.x, region.y + y, 0);
+            let to = (y * region.width * self.channels) as usize;
+            cropped.data[to..to + row].copy_from_slice(&self.data[from..from + row]);
+        }
+        Some(cropped)
+    }
+
+    /// RGB from a raw Bayer image, each missing color the mean of its neighbours of that
+    /// color, which is bilinear interpolation. Anything else comes back as it is.
+    pub fn debayer(&self) -> Image {
+        let cfa = match self.cfa {
+            Some(cfa) if self.channels == 1 => cfa,
+            _ => return self.clone()
+        };
+        let mut rgb = Image::new(self.width, self.height, 3);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let mut sums = [0f32; 3];
+                let mut counts = [0u32; 3];
+                for ny in y.saturating_sub(1)..(y + 2).min(self.height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(self.width) {
+                        let color = cfa.color_at(nx, ny);
+                        sums[color] += self.at(nx, ny, 0);
+                        counts[color] += 1;
+                    }
+                }
+                let own = cfa.color_at(x, y);
+                for color in 0..3 {
+                    let value = if color == own {
+                        self.at(x, y, 0)
+                    } else {
+                        sums[color] / counts[color].max(1) as f32
+                    };
+                    rgb.set(x, y, color as u32, value);
+                }
+            }
+        }
+        rgb
+    }
+
+    /// The mean of each `factor` by `factor` block of pixels, leaving out NaNs, for a smaller
+    /// image. Pixels left over at the right and bottom edges are dropped. Colors get mixed
+    /// up, so a raw Bayer image comes back without its pattern.
+    pub fn downsample(&self, factor: u32) -> Image {
+        if factor <= 1 {
+            return self.clone();
+        }
+        let width = (self.width / factor).max(1).min(self.width);
+        let height = (self.height / factor).max(1).min(self.height);
+        let mut small = Image::new(width, height, self.channels);
+        for y in 0..height {
+            for x in 0..width {
+                for channel in 0..self.channels {
+                    let (mut sum, mut count) = (0f32, 0u32);
+                    for by in y * factor..((y + 1) * factor).min(self.height) {
+                        for bx in x * factor..((x + 1) * factor).min(self.width) {
+                            let value = self.at(bx, by, channel);
+                            if !value.is_nan() {
+                                sum += value;
+                                count += 1;
+                            }
+                        }
+                    }
+                    small.set(x, y, channel, if count > 0 { sum / count as f32 } else { f32::NAN });
+                }
+            }
+        }
+        small
+    }
+
+    /// The median of every sample.
+    pub fn median(&self) -> f32 {
+        let mut values = self.data.clone();
+        median(&mut values)
+    }
+
+    /// The median of each sample across `images`, which is how masters are made: hot pixels,
+    /// cosmic rays and satellites in any one frame drop out. `None` if there are no images or
+    /// they aren't all the same shape.
+    pub fn median_combine(images: &[Image]) -> Option<Image> {
+        let first = images.first()?;
+        if images.iter().any(|image| !image.same_shape(first)) {
+            return None;
+        }
+        let mut combined = first.clone();
+        let mut values = Vec::with_capacity(images.len());
+        for (i, sample) in combined.data.iter_mut().enumerate() {
+            values.clear();
+            values.extend(images.iter().map(|image| image.data[i]));
+            *sample = median(&mut values);
+        }
+        Some(combined)
+    }
+}
+
+/// The median of `values`, averaging the middle two of an even count. Reorders `values`.
+pub fn median(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let len = values.len();
+    let middle = len / 2;
+    let (lower, upper, _) = values.select_nth_unstable_by(middle, |a, b| a.total_cmp(b));
+    let upper = *upper;
+    if middle * 2 == len {
+        // the other middle value is the largest of the lower half
+        let below = lower.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        (below + upper) / 2.0
+    } else {
+        upper
+    }
+}
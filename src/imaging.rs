@@ -0,0 +1,61 @@
+// What routines that drive a camera's exposures themselves (working out flat exposures and
+// the like) need from it, so they run the same on any backend, the simulator included.
+
+use crate::frame::{Frame, FrameType};
+use crate::hotplug::Presence;
+
+use std::fmt;
+use std::time::Duration;
+
+/// A region of the sensor, in unbinned pixels.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Subframe {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
+
+impl Subframe {
+    /// The middle `1/fraction` of a `width` by `height` sensor on each side.
+    pub fn centre(width: u32, height: u32, fraction: u32) -> Subframe {
+        let (w, h) = (width / fraction, height / fraction);
+        Subframe { x: (width - w) / 2, y: (height - h) / 2, width: w, height: h }
+    }
+}
+
+pub trait Imager {
+    type Error: fmt::Debug;
+
+    /// Set the exposure time of the frames that follow.
+    fn set_exposure(&mut self, exposure: Duration) -> Result<(), Self::Error>;
+
+    /// Set the gain of the frames that follow, in the camera's own units.
+    fn set_gain(&mut self, gain: f64) -> Result<(), Self::Error>;
+
+    /// The shortest and longest exposures the camera can take.
+    fn exposure_range(&self) -> Result<(Duration, Duration), Self::Error>;
+
+    /// Read out only `subframe` from now on, or the whole sensor for `None`. Cameras may
+    /// round it to the sizes they support.
+    fn set_subframe(&mut self, subframe: Option<Subframe>) -> Result<(), Self::Error>;
+
+    /// What identifies this particular camera, for things kept per camera like its bad pixel
+    /// map.
+    fn serial(&self) -> String;
+
+    /// The binning frames are read out at.
+    fn binning(&self) -> u32;
+
+    /// Take a frame of `frame_type`, with its stats computed.
+    fn capture(&mut self, frame_type: FrameType) -> Result<Frame, Self::Error>;
+}
+
+/// Cameras that can be picked back up after being unplugged and plugged back in.
+pub trait Reopen: Imager {
+    /// Shared with a hotplug `Watcher`, which marks it absent while the camera is unplugged.
+    fn presence(&self) -> Presence;
+
+    /// Open the camera again and restore the settings made through this handle.
+    fn reopen(&mut self) -> Result<(), Self::Error>;
+}
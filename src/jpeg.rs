@@ -0,0 +1,541 @@
+// A baseline JPEG encoder, for previews and live stack snapshots small enough to open over a
+// phone's connection. Grey images are written as one component and color ones as YCbCr with
+// the chroma kept at full resolution: a stretched sky's noise is mostly in the chroma, and
+// subsampling smears it into blotches.
+//
+// The quantization and Huffman tables are the example ones from Annex K of the standard,
+// with the quantization scaled for quality the way libjpeg does it.
+
+use std::io::{self, Write};
+
+/// The natural (row by row) index of each coefficient, in the zigzag order they're written in.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63
+];
+
+const LUMINANCE_QUANTIZATION: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99
+];
+
+const CHROMINANCE_QUANTIZATION: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99
+];
+
+/// A Huffman table as it's written in the file: how many codes there are of each length from
+/// 1 to 16 bits, and the symbols they stand for, shortest first.
+struct HuffmanSpec {
+    counts: [u8; 16],
+    symbols: &'static [u8]
+}
+
+const DC_LUMINANCE: HuffmanSpec = HuffmanSpec {
+    counts: [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+    symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]
+};
+
+const DC_CHROMINANCE: HuffmanSpec = HuffmanSpec {
+    counts: [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+    symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]
+};
+
+const AC_LUMINANCE: HuffmanSpec = HuffmanSpec {
+    counts: [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d],
+    symbols: &[
+        0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+        0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+        0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+        0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+        0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+        0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+        0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+        0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+        0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+        0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+        0xf9, 0xfa
+    ]
+};
+
+const AC_CHROMINANCE: HuffmanSpec = HuffmanSpec {
+    counts: [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+    symbols: &[
+        0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+        0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+        0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+        0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+        0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+        0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+        0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+        0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+        0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+        0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+        0xf9, 0xfa
+    ]
+};
+
+/// Each symbol's code and its length in bits; symbols the table doesn't have are 0 bits long.
+struct HuffmanTable {
+    codes: [(u16, u8); 256]
+}
+
+impl HuffmanTable {
+    fn new(spec: &HuffmanSpec) -> HuffmanTable {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0u16;
+        let mut symbols = spec.symbols.iter();
+        for (length, count) in spec.counts.iter().enumerate() {
+            for _ in 0..*count {
+                codes[*symbols.next().unwrap() as usize] = (code, length as u8 + 1);
+                code += 1;
+            }
+            code <<= 1;
+        }
+        HuffmanTable { codes: codes }
+    }
+}
+
+/// Scale a base quantization table for `quality` from 1 to 100, as libjpeg does: 50 leaves
+/// it as it is and 100 makes every entry 1.
+fn quantization(base: &[u16; 64], quality: u8) -> [u16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 { 5000 / quality } else { 200 - 2 * quality };
+    let mut table = [0; 64];
+    for (entry, base) in table.iter_mut().zip(base.iter()) {
+        *entry = ((*base as u32 * scale + 50) / 100).clamp(1, 255) as u16;
+    }
+    table
+}
+
+/// Writes the entropy-coded data, with a 0 stuffed after each 0xff so it can't be taken for
+/// a marker.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u32
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u16, length: u8) {
+        let length = length as u32;
+        self.buffer = (self.buffer << length) | (value as u32 & ((1 << length) - 1));
+        self.bits += length;
+        while self.bits >= 8 {
+            let byte = (self.buffer >> (self.bits - 8)) as u8;
+            self.bits -= 8;
+            self.buffer &= (1 << self.bits) - 1;
+            self.bytes.push(byte);
+            if byte == 0xff {
+                self.bytes.push(0);
+            }
+        }
+    }
+
+    /// Pad the last byte out with 1s.
+    fn flush(&mut self) {
+        if self.bits > 0 {
+            let padding = 8 - self.bits;
+            self.write((1 << padding) - 1, padding as u8);
+        }
+    }
+}
+
+/// How many bits `value` takes, and those bits as the standard has negative numbers: one
+/// less than the value, in that many bits.
+fn category(value: i32) -> (u8, u16) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 { value - 1 } else { value };
+    (size as u8, (bits & ((1 << size) - 1)) as u16)
+}
+
+/// One component's tables, and its DC coefficient from the block before.
+struct Component {
+    quantization: [u16; 64],
+    dc: HuffmanTable,
+    ac: HuffmanTable,
+    previous_dc: i32
+}
+
+impl Component {
+    fn new(base: &[u16; 64], dc: &HuffmanSpec, ac: &HuffmanSpec, quality: u8) -> Component {
+        Component {
+            quantization: quantization(base, quality),
+            dc: HuffmanTable::new(dc),
+            ac: HuffmanTable::new(ac),
+            previous_dc: 0
+        }
+    }
+
+    /// Transform, quantize and write one block of samples, level shifted to be around 0.
+    fn encode(&mut self, block: &[f32; 64], cosines: &[[f32; 8]; 8], out: &mut BitWriter) {
+        let coefficients = dct(block, cosines);
+        let mut quantized = [0i32; 64];
+        for (k, natural) in ZIGZAG.iter().enumerate() {
+            let value = (coefficients[*natural] / self.quantization[*natural] as f32).round() as i32;
+            quantized[k] = value.clamp(-1023, 1023);
+        }
+
+        let (size, bits) = category(quantized[0] - self.previous_dc);
+        self.previous_dc = quantized[0];
+        let (code, length) = self.dc.codes[size as usize];
+        out.write(code, length);
+        out.write(bits, size);
+
+        let mut run = 0;
+        for value in quantized[1..].iter() {
+            if *value == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                let (code, length) = self.ac.codes[0xf0];
+                out.write(code, length);
+                run -= 16;
+            }
+            let (size, bits) = category(*value);
+            let (code, length) = self.ac.codes[(run << 4) | size as usize];
+            out.write(code, length);
+            out.write(bits, size);
+            run = 0;
+        }
+        if run > 0 {
+            let (code, length) = self.ac.codes[0x00];
+            out.write(code, length);
+        }
+    }
+}
+
+/// `cosines[u][x]` is the weight of sample `x` in coefficient `u` of the 1-D DCT.
+fn cosines() -> [[f32; 8]; 8] {
+    let mut cosines = [[0.0; 8]; 8];
+    for (u, row) in cosines.iter_mut().enumerate() {
+        let scale = if u == 0 { 0.5 / 2f32.sqrt() } else { 0.5 };
+        for (x, weight) in row.iter_mut().enumerate() {
+            *weight = scale * ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.0).cos();
+        }
+    }
+    cosines
+}
+
+/// The 2-D forward DCT of a block, rows first and then columns.
+fn dct(block: &[f32; 64], cosines: &[[f32; 8]; 8]) -> [f32; 64] {
+    let mut rows = [0.0; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| cosines[u][x] * block[y * 8 + x]).sum();
+        }
+    }
+    let mut coefficients = [0.0; 64];
+    for u in 0..8 {
+        for v in 0..8 {
+            coefficients[v * 8 + u] = (0..8).map(|y| cosines[v][y] * rows[y * 8 + u]).sum();
+        }
+    }
+    coefficients
+}
+
+fn segment(out: &mut Vec<u8>, marker: u8, contents: &[u8]) {
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&(contents.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(contents);
+}
+
+/// Write 8-bit samples, grey for 1 channel or RGB for 3, as a JPEG of `quality` from 1 to
+/// 100 into `w`.
+pub fn encode<W: Write>(mut w: W, width: u32, height: u32, channels: u32, pixels: &[u8], quality: u8) -> io::Result<()> {
+    if channels != 1 && channels != 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("can't write a {}-channel jpeg", channels)));
+    }
+    if width == 0 || height == 0 || width > 0xffff || height > 0xffff {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("can't write a {}x{} jpeg", width, height)));
+    }
+    if pixels.len() < (width * height * channels) as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too few samples for the size"));
+    }
+
+    let mut components = vec![Component::new(&LUMINANCE_QUANTIZATION, &DC_LUMINANCE, &AC_LUMINANCE, quality)];
+    if channels == 3 {
+        components.push(Component::new(&CHROMINANCE_QUANTIZATION, &DC_CHROMINANCE, &AC_CHROMINANCE, quality));
+        components.push(Component::new(&CHROMINANCE_QUANTIZATION, &DC_CHROMINANCE, &AC_CHROMINANCE, quality));
+    }
+
+    let mut out = vec![0xff, 0xd8];
+    segment(&mut out, 0xe0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+    for (id, component) in components.iter().take(2).enumerate() {
+        let mut table = vec![id as u8];
+        table.extend(ZIGZAG.iter().map(|natural| component.quantization[*natural] as u8));
+        segment(&mut out, 0xdb, &table);
+    }
+    let mut frame = vec![8];
+    frame.extend_from_slice(&(height as u16).to_be_bytes());
+    frame.extend_from_slice(&(width as u16).to_be_bytes());
+    frame.push(channels as u8);
+    for id in 0..channels as u8 {
+        // no subsampling, and the luminance tables for Y and the chrominance ones for the rest
+        frame.extend_from_slice(&[id + 1, 0x11, id.min(1)]);
+    }
+    segment(&mut out, 0xc0, &frame);
+    let specs = [(0x00, &DC_LUMINANCE), (0x10, &AC_LUMINANCE), (0x01, &DC_CHROMINANCE), (0x11, &AC_CHROMINANCE)];
+    for (class, spec) in specs.iter().take(if channels == 3 { 4 } else { 2 }) {
+        let mut table = vec![*class];
+        table.extend_from_slice(&spec.counts);
+        table.extend_from_slice(spec.symbols);
+        segment(&mut out, 0xc4, &table);
+    }
+    let mut scan = vec![channels as u8];
+    for id in 0..channels as u8 {
+        scan.extend_from_slice(&[id + 1, id.min(1) * 0x11]);
+    }
+    scan.extend_from_slice(&[0, 63, 0]);
+    segment(&mut out, 0xda, &scan);
+
+    let cosines = cosines();
+    let mut bits = BitWriter { bytes: out, buffer: 0, bits: 0 };
+    let mut blocks = vec![[0.0f32; 64]; channels as usize];
+    for block_y in 0..height.div_ceil(8) {
+        for block_x in 0..width.div_ceil(8) {
+            for i in 0..64 {
+                // blocks hanging off the edge repeat the last row and column
+                let x = (block_x * 8 + i % 8).min(width - 1);
+                let y = (block_y * 8 + i / 8).min(height - 1);
+                let at = ((y * width + x) * channels) as usize;
+                if channels == 1 {
+                    blocks[0][i as usize] = pixels[at] as f32 - 128.0;
+                } else {
+                    let (r, g, b) = (pixels[at] as f32, pixels[at + 1] as f32, pixels[at + 2] as f32);
+                    blocks[0][i as usize] = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+                    blocks[1][i as usize] = -0.168736 * r - 0.331264 * g + 0.5 * b;
+                    blocks[2][i as usize] = 0.5 * r - 0.418688 * g - 0.081312 * b;
+                }
+            }
+            for (component, block) in components.iter_mut().zip(blocks.iter()) {
+                component.encode(block, &cosines, &mut bits);
+            }
+        }
+    }
+    bits.flush();
+    let mut out = bits.bytes;
+    out.extend_from_slice(&[0xff, 0xd9]);
+    w.write_all(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Just enough of a baseline decoder to read back what `encode` writes, from the tables
+    /// in the file rather than the encoder's. Returns the width, height, channels and
+    /// samples.
+    fn decode(data: &[u8]) -> (u32, u32, u32, Vec<u8>) {
+        assert_eq!(&data[..2], &[0xff, 0xd8]);
+        let mut quantization = [[0u16; 64]; 4];
+        // (length, code, symbol) for each table, by class and id
+        let mut huffman: Vec<Vec<(u8, u16, u8)>> = vec![Vec::new(); 4];
+        let (mut width, mut height) = (0, 0);
+        let mut components: Vec<(usize, usize, usize)> = Vec::new();
+        let mut at = 2;
+        loop {
+            assert_eq!(data[at], 0xff);
+            let marker = data[at + 1];
+            let length = u16::from_be_bytes([data[at + 2], data[at + 3]]) as usize;
+            let contents = &data[at + 4..at + 2 + length];
+            at += 2 + length;
+            match marker {
+                0xdb => {
+                    for (k, natural) in ZIGZAG.iter().enumerate() {
+                        quantization[contents[0] as usize][*natural] = contents[1 + k] as u16;
+                    }
+                }
+                0xc0 => {
+                    height = u16::from_be_bytes([contents[1], contents[2]]) as u32;
+                    width = u16::from_be_bytes([contents[3], contents[4]]) as u32;
+                    for c in 0..contents[5] as usize {
+                        assert_eq!(contents[7 + 3 * c], 0x11);
+                        components.push((contents[8 + 3 * c] as usize, 0, 0));
+                    }
+                }
+                0xc4 => {
+                    let table = &mut huffman[(contents[0] >> 4) as usize * 2 + (contents[0] & 15) as usize];
+                    let mut code = 0u16;
+                    let mut symbol = 17;
+                    for length in 0..16 {
+                        for _ in 0..contents[1 + length] {
+                            table.push((length as u8 + 1, code, contents[symbol]));
+                            code += 1;
+                            symbol += 1;
+                        }
+                        code <<= 1;
+                    }
+                }
+                0xda => {
+                    for c in 0..contents[0] as usize {
+                        let tables = contents[2 + 2 * c];
+                        components[c].1 = (tables >> 4) as usize;
+                        components[c].2 = 2 + (tables & 15) as usize;
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        // unstuff the entropy-coded data up to the end marker
+        let mut bytes = Vec::new();
+        while !(data[at] == 0xff && data[at + 1] == 0xd9) {
+            bytes.push(data[at]);
+            at += if data[at] == 0xff { 2 } else { 1 };
+        }
+        let mut position = 0;
+        let mut bit = || {
+            let value = bytes.get(position / 8).map(|byte| (byte >> (7 - position % 8)) & 1).unwrap_or(1);
+            position += 1;
+            value as u16
+        };
+        let read = |length: u8, bit: &mut dyn FnMut() -> u16| (0..length).fold(0u16, |value, _| (value << 1) | bit());
+        let extend = |bits: u16, size: u8| {
+            if size == 0 {
+                0
+            } else if bits >> (size - 1) == 0 {
+                bits as i32 - (1 << size) + 1
+            } else {
+                bits as i32
+            }
+        };
+        let symbol = |table: &Vec<(u8, u16, u8)>, bit: &mut dyn FnMut() -> u16| {
+            let (mut code, mut length) = (0u16, 0u8);
+            loop {
+                code = (code << 1) | bit();
+                length += 1;
+                if let Some((_, _, symbol)) = table.iter().find(|(l, c, _)| *l == length && *c == code) {
+                    return *symbol;
+                }
+                assert!(length < 16, "no such code");
+            }
+        };
+
+        let channels = components.len() as u32;
+        let cosines = cosines();
+        let mut planes = vec![vec![0.0f32; (width * height) as usize]; components.len()];
+        let mut previous = vec![0i32; components.len()];
+        for block_y in 0..height.div_ceil(8) {
+            for block_x in 0..width.div_ceil(8) {
+                for (c, (q, dc, ac)) in components.iter().enumerate() {
+                    let mut coefficients = [0.0f32; 64];
+                    let size = symbol(&huffman[*dc], &mut bit);
+                    let bits = read(size, &mut bit);
+                    previous[c] += extend(bits, size);
+                    coefficients[0] = previous[c] as f32 * quantization[*q][0] as f32;
+                    let mut k = 1;
+                    while k < 64 {
+                        let rs = symbol(&huffman[*ac], &mut bit);
+                        if rs == 0 {
+                            break;
+                        }
+                        k += (rs >> 4) as usize;
+                        let size = rs & 15;
+                        if size > 0 {
+                            let bits = read(size, &mut bit);
+                            coefficients[ZIGZAG[k]] = extend(bits, size) as f32 * quantization[*q][ZIGZAG[k]] as f32;
+                        }
+                        k += 1;
+                    }
+                    for i in 0..64 {
+                        let (x, y) = (block_x * 8 + i % 8, block_y * 8 + i / 8);
+                        if x < width && y < height {
+                            // the inverse of an orthonormal transform is its transpose
+                            let value: f32 = (0..64).map(|j| {
+                                cosines[j % 8][i as usize % 8] * cosines[j / 8][i as usize / 8] * coefficients[j]
+                            }).sum();
+                            planes[c][(y * width + x) as usize] = value;
+                        }
+                    }
+                }
+            }
+        }
+
+        let samples = (0..(width * height) as usize).flat_map(|i| {
+            if channels == 1 {
+                vec![planes[0][i] + 128.0]
+            } else {
+                let (y, cb, cr) = (planes[0][i] + 128.0, planes[1][i], planes[2][i]);
+                vec![y + 1.402 * cr, y - 0.344136 * cb - 0.714136 * cr, y + 1.772 * cb]
+            }
+        }).map(|value| value.round().clamp(0.0, 255.0) as u8).collect();
+        (width, height, channels, samples)
+    }
+
+    /// A smooth gradient with some stars on it, `channels` deep, and every channel different.
+    fn picture(width: u32, height: u32, channels: u32) -> Vec<u8> {
+        let mut pixels = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..channels {
+                    let star = if (x + 3 * c) % 13 == 0 && y % 11 == 0 { 120.0 } else { 0.0 };
+                    let value = 30.0 + 100.0 * x as f32 / width as f32 + 40.0 * c as f32 + 20.0 * y as f32 / height as f32;
+                    pixels.push((value + star).min(255.0) as u8);
+                }
+            }
+        }
+        pixels
+    }
+
+    fn mean_error(a: &[u8], b: &[u8]) -> f64 {
+        a.iter().zip(b.iter()).map(|(a, b)| (*a as f64 - *b as f64).abs()).sum::<f64>() / a.len() as f64
+    }
+
+    #[test]
+    fn the_huffman_tables_have_a_symbol_for_every_code() {
+        for spec in [&DC_LUMINANCE, &DC_CHROMINANCE, &AC_LUMINANCE, &AC_CHROMINANCE].iter() {
+            assert_eq!(spec.counts.iter().map(|count| *count as usize).sum::<usize>(), spec.symbols.len());
+        }
+        assert_eq!(category(0), (0, 0));
+        assert_eq!(category(5), (3, 0b101));
+        assert_eq!(category(-5), (3, 0b010));
+        assert_eq!(quantization(&LUMINANCE_QUANTIZATION, 50), LUMINANCE_QUANTIZATION);
+        assert!(quantization(&LUMINANCE_QUANTIZATION, 100).iter().all(|q| *q == 1));
+    }
+
+    #[test]
+    fn grey_and_color_images_read_back_close_to_what_went_in() {
+        // sizes that aren't a whole number of blocks, to check the edges
+        for channels in [1, 3].iter() {
+            let (width, height) = (45, 30);
+            let pixels = picture(width, height, *channels);
+            let mut jpeg = Vec::new();
+            encode(&mut jpeg, width, height, *channels, &pixels, 95).unwrap();
+            assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+            let (w, h, c, decoded) = decode(&jpeg);
+            assert_eq!((w, h, c), (width, height, *channels));
+            let error = mean_error(&pixels, &decoded);
+            assert!(error < 2.5, "{} channels off by {:.2} on average", channels, error);
+
+            let mut small = Vec::new();
+            encode(&mut small, width, height, *channels, &pixels, 30).unwrap();
+            assert!(small.len() < jpeg.len());
+            assert!(mean_error(&pixels, &decode(&small).3) < 8.0);
+        }
+    }
+
+    #[test]
+    fn only_grey_and_rgb_fit() {
+        let mut jpeg = Vec::new();
+        assert!(encode(&mut jpeg, 4, 4, 2, &[0; 32], 90).is_err());
+        assert!(encode(&mut jpeg, 4, 4, 3, &[0; 16], 90).is_err());
+        assert!(encode(&mut jpeg, 0, 4, 1, &[], 90).is_err());
+    }
+}
@@ -0,0 +1,517 @@
+// Live stacking, for outreach nights: frames are taken one after another, calibrated,
+// lined up on the first good one and folded into a running mean, so the picture on the
+// screen gets deeper while people watch. There's no keeping every frame around for a
+// median, so outliers are rejected against each pixel's running mean and spread instead,
+// and frames spoiled by cloud, wind or a bumped tripod are dropped before they're added.
+//
+// The stack is published every so often as a png or JPEG, written to a file and, if asked,
+// served over HTTP for a browser on the same network.
+
+use crate::calibrate::{Calibrator, Raw};
+use crate::frame::FrameType;
+use crate::image::{self, Image};
+use crate::imaging::Imager;
+use crate::preview::{Format, Preview};
+use crate::stack::{self, Interpolation, Registered, Registration};
+use crate::stars::{Star, StarFinder};
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Why a frame was left out of the stack.
+#[derive(Debug, Clone)]
+pub enum Dropped {
+    /// Too few stars, in the frame or against the reference: cloud, dew, or the mount
+    /// pointing somewhere else.
+    TooFewStars(usize),
+    /// Stars this much wider than the reference's, in pixels: wind, bad seeing, or focus.
+    Blurry(f64),
+    /// Its stars couldn't be matched to the reference's.
+    NotRegistered,
+    /// A different size than the reference.
+    MixedFrames
+}
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    /// The first good frame, which everything else is lined up on.
+    Reference { stars: usize, fwhm: f64 },
+    Added { registered: Registered, stars: usize, fwhm: f64 },
+    Dropped(Dropped)
+}
+
+#[derive(Debug, Clone)]
+struct Reference {
+    stars: Vec<Star>,
+    fwhm: f64,
+    sky: f32,
+    noise: f32,
+    width: u32,
+    height: u32,
+    channels: u32
+}
+
+#[derive(Debug, Clone)]
+pub struct LiveStack {
+    pub finder: StarFinder,
+    pub registration: Registration,
+    pub interpolation: Interpolation,
+    /// Frames with fewer stars than this are dropped, as are frames with fewer than
+    /// `min_star_ratio` of the reference's.
+    pub min_stars: usize,
+    pub min_star_ratio: f64,
+    /// Frames whose FWHM is more than this times the reference's are dropped.
+    pub max_fwhm_ratio: f64,
+    /// A sample further than this many sigmas from its pixel's running mean is left out,
+    /// once the pixel has three samples to judge by.
+    pub reject_sigma: f32,
+    reference: Option<Reference>,
+    mean: Vec<f32>,
+    m2: Vec<f32>,
+    counts: Vec<u32>,
+    /// Frames in the stack, the reference included.
+    pub stacked: u32,
+    pub dropped: u32,
+    /// Samples left out as outliers.
+    pub rejected: u64
+}
+
+impl Default for LiveStack {
+    fn default() -> LiveStack {
+        LiveStack {
+            finder: StarFinder::default(),
+            registration: Registration::default(),
+            interpolation: Interpolation::Bilinear,
+            min_stars: 10,
+            min_star_ratio: 0.5,
+            max_fwhm_ratio: 1.5,
+            reject_sigma: 3.0,
+            reference: None,
+            mean: Vec::new(),
+            m2: Vec::new(),
+            counts: Vec::new(),
+            stacked: 0,
+            dropped: 0,
+            rejected: 0
+        }
+    }
+}
+
+/// The median of the samples that aren't NaN.
+fn sky(image: &Image) -> f32 {
+    let mut values: Vec<f32> = image.data.iter().cloned().filter(|v| !v.is_nan()).collect();
+    image::median(&mut values)
+}
+
+impl LiveStack {
+    /// Start over, with the next good frame as the reference: after a slew to another
+    /// target, say.
+    pub fn reset(&mut self) {
+        self.reference = None;
+        self.mean.clear();
+        self.m2.clear();
+        self.counts.clear();
+        self.stacked = 0;
+        self.dropped = 0;
+        self.rejected = 0;
+    }
+
+    /// Add a calibrated frame to the stack, or say why not. Raw Bayer images are debayered
+    /// first.
+    pub fn add(&mut self, image: &Image) -> Outcome {
+        let outcome = self.add_debayered(&image.debayer());
+        match outcome {
+            Outcome::Dropped(_) => self.dropped += 1,
+            _ => self.stacked += 1
+        }
+        outcome
+    }
+
+    fn add_debayered(&mut self, image: &Image) -> Outcome {
+        let field = self.finder.find_image(image);
+        if field.stars.len() < self.min_stars {
+            return Outcome::Dropped(Dropped::TooFewStars(field.stars.len()));
+        }
+        let reference = match self.reference.as_ref() {
+            Some(reference) => reference,
+            None => {
+                let stars = field.stars.len();
+                self.reference = Some(Reference {
+                    stars: field.stars,
+                    fwhm: field.fwhm,
+                    sky: sky(image),
+                    noise: field.noise as f32,
+                    width: image.width,
+                    height: image.height,
+                    channels: image.channels
+                });
+                self.mean = image.data.clone();
+                self.m2 = vec![0.0; image.data.len()];
+                self.counts = vec![1; image.data.len()];
+                return Outcome::Reference { stars: stars, fwhm: field.fwhm };
+            }
+        };
+
+        if image.width != reference.width || image.height != reference.height || image.channels != reference.channels {
+            return Outcome::Dropped(Dropped::MixedFrames);
+        }
+        if (field.stars.len() as f64) < reference.stars.len() as f64 * self.min_star_ratio {
+            return Outcome::Dropped(Dropped::TooFewStars(field.stars.len()));
+        }
+        if reference.fwhm > 0.0 && field.fwhm > reference.fwhm * self.max_fwhm_ratio {
+            return Outcome::Dropped(Dropped::Blurry(field.fwhm));
+        }
+        let registered = match self.registration.register(&reference.stars, &field.stars) {
+            Some(registered) => registered,
+            None => return Outcome::Dropped(Dropped::NotRegistered)
+        };
+
+        let layer = stack::resample(image, &registered.transform, self.interpolation);
+        let offset = reference.sky - sky(&layer);
+        // a few samples can't say much about a pixel's spread, so it's never taken to be
+        // less than the reference's sky noise
+        let floor = reference.noise.max(f32::EPSILON);
+        for (i, value) in layer.data.iter().enumerate() {
+            if value.is_nan() {
+                continue;
+            }
+            let value = value + offset;
+            let count = self.counts[i];
+            if count >= 3 {
+                let sigma = (self.m2[i] / (count - 1) as f32).sqrt().max(floor);
+                if (value - self.mean[i]).abs() > self.reject_sigma * sigma {
+                    self.rejected += 1;
+                    continue;
+                }
+            }
+            // Welford's running mean and sum of squared deviations
+            let count = count + 1;
+            let delta = value - self.mean[i];
+            self.mean[i] += delta / count as f32;
+            self.m2[i] += delta * (value - self.mean[i]);
+            self.counts[i] = count;
+        }
+        Outcome::Added { registered: registered, stars: field.stars.len(), fwhm: field.fwhm }
+    }
+
+    /// The stack so far, or `None` before the reference.
+    pub fn image(&self) -> Option<Image> {
+        let reference = self.reference.as_ref()?;
+        Some(Image {
+            width: reference.width,
+            height: reference.height,
+            channels: reference.channels,
+            cfa: None,
+            data: self.mean.clone()
+        })
+    }
+}
+
+/// Where the stack is published, and how often.
+pub struct Snapshots {
+    pub path: PathBuf,
+    pub interval: Duration,
+    pub preview: Preview,
+    latest: Arc<Mutex<Vec<u8>>>,
+    last: Option<Instant>
+}
+
+impl Snapshots {
+    pub fn new(path: PathBuf, interval: Duration) -> Snapshots {
+        Snapshots {
+            preview: Preview { format: Format::for_path(&path), ..Preview::default() },
+            path: path,
+            interval: interval,
+            latest: Arc::new(Mutex::new(Vec::new())),
+            last: None
+        }
+    }
+
+    pub fn due(&self) -> bool {
+        self.last.map(|last| last.elapsed() >= self.interval).unwrap_or(true)
+    }
+
+    /// Render `image` and publish it as `preview.format`, which `new` picks from the path,
+    /// replacing the file in one go so a viewer watching it never sees half a picture.
+    pub fn publish(&mut self, image: &Image) -> io::Result<()> {
+        let rendered = self.preview.render(image);
+        let mut encoded = Vec::new();
+        self.preview.format.encode(&rendered, &mut encoded)?;
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".part");
+        fs::write(&partial, &encoded)?;
+        fs::rename(&partial, &self.path)?;
+        *self.latest.lock().unwrap() = encoded;
+        self.last = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Serve the latest snapshot at `/stack.png` or `/stack.jpg`, whichever the file is, and
+    /// a page showing it that reloads itself at `/`, on `address` (`127.0.0.1:8080`, or
+    /// `0.0.0.0:8080` for other machines), from a thread of its own. Each request gets a
+    /// thread too, so a slow phone on the edge of the Wi-Fi doesn't hold up everyone else's.
+    /// Returns the address it's listening on.
+    pub fn serve(&self, address: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let local = listener.local_addr()?;
+        let latest = Arc::clone(&self.latest);
+        let refresh = self.interval.as_secs().max(1);
+        let format = self.preview.format;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let latest = Arc::clone(&latest);
+                thread::spawn(move || {
+                    let result = stream.and_then(|stream| respond(stream, &latest, refresh, format));
+                    if let Err(e) = result {
+                        println!("Live stack request failed: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(local)
+    }
+}
+
+/// Where the snapshot is served, and what as.
+fn served_as(format: Format) -> (&'static str, &'static str) {
+    match format {
+        Format::Png => ("/stack.png", "image/png"),
+        Format::Jpeg(_) => ("/stack.jpg", "image/jpeg")
+    }
+}
+
+fn respond(mut stream: TcpStream, latest: &Mutex<Vec<u8>>, refresh: u64, format: Format) -> io::Result<()> {
+    // a client that stops reading or writing gives up its thread after this
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new((&stream).take(8192));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // read the rest of the request, or closing on it unread would reset the connection
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (snapshot, snapshot_type) = served_as(format);
+    let (status, content_type, body) = match path {
+        "/" => {
+            let page = format!(
+                "<!DOCTYPE html><html><head><meta http-equiv=\"refresh\" content=\"{}\"><title>Live stack</title></head>\
+                 <body style=\"margin:0;background:#000\"><img src=\"{}\" style=\"width:100%\"></body></html>",
+                refresh, snapshot
+            );
+            ("200 OK", "text/html", page.into_bytes())
+        }
+        _ if path == snapshot => {
+            let encoded = latest.lock().unwrap().clone();
+            if encoded.is_empty() {
+                ("503 Service Unavailable", "text/plain", b"no stack yet\n".to_vec())
+            } else {
+                ("200 OK", snapshot_type, encoded)
+            }
+        }
+        _ => ("404 Not Found", "text/plain", b"not found\n".to_vec())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+/// Take lights from `camera` and stack them live, calibrated by `calibrator` if given,
+/// publishing to `snapshots` as it goes. Stops after `count` frames, or never for `None`.
+pub fn run<C: Imager>(
+    camera: &mut C,
+    calibrator: Option<&Calibrator>,
+    live: &mut LiveStack,
+    snapshots: &mut Snapshots,
+    count: Option<u32>
+) -> Result<(), C::Error> {
+    let mut taken = 0;
+    while count.map(|count| taken < count).unwrap_or(true) {
+        let frame = camera.capture(FrameType::Light)?;
+        taken += 1;
+        let number = live.stacked + live.dropped + 1;
+        let raw = Raw::from_frame(&frame);
+        let image = match calibrator.map(|calibrator| calibrator.calibrate(&raw)) {
+            Some(Ok(calibrated)) => calibrated.image,
+            Some(Err(e)) => {
+                println!("Couldn't calibrate frame {}, stacking it as it is: {:?}", number, e);
+                raw.image
+            }
+            None => raw.image
+        };
+        match live.add(&image) {
+            Outcome::Reference { stars, fwhm } => {
+                println!("Frame {}: reference, {} stars, FWHM {:.2}", number, stars, fwhm);
+            }
+            Outcome::Added { registered, stars, fwhm } => {
+                println!(
+                    "Frame {}: added, {} stars, FWHM {:.2}, {} matched at RMS {:.2} px; {} stacked",
+                    number, stars, fwhm, registered.matches, registered.rms, live.stacked
+                );
+            }
+            Outcome::Dropped(why) => println!("Frame {}: dropped, {:?}", number, why)
+        }
+        if snapshots.due() {
+            publish(live, snapshots);
+        }
+    }
+    publish(live, snapshots);
+    Ok(())
+}
+
+fn publish(live: &LiveStack, snapshots: &mut Snapshots) {
+    if let Some(image) = live.image() {
+        if let Err(e) = snapshots.publish(&image) {
+            println!("Couldn't publish the stack to {}: {}", snapshots.path.display(), e);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Camera, Star as SimStar};
+    use crate::testing::TempDir;
+
+    /// The simulated night: dithered frames, with frame 5 blurred by wind, frame 9 under
+    /// cloud and a satellite across frame 12.
+    fn set_up(camera: &mut Camera, i: u32, rng: &mut sim::Rng, sigma: f64, stars: &[SimStar]) {
+        camera.shift = (20.0 * rng.uniform() - 10.0, 20.0 * rng.uniform() - 10.0);
+        camera.psf_sigma = if i == 5 { sigma * 3.0 } else { sigma };
+        camera.stars = stars.to_vec();
+        if i == 9 {
+            for star in camera.stars.iter_mut() {
+                star.flux *= 0.002;
+            }
+        }
+        // a satellite trail, in sensor pixels like the real thing
+        camera.hot_pixels = if i == 12 {
+            (0..camera.width).map(|x| (x, 150 + x / 3, 300.0)).collect()
+        } else {
+            Vec::new()
+        };
+    }
+
+    fn camera() -> Camera {
+        let mut camera = Camera::new(640, 480);
+        camera.set_exposure_ms(10000);
+        camera
+    }
+
+    /// Send a GET for `path` and return the status line, headers and body.
+    fn get(address: SocketAddr, path: &str) -> (String, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, address).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..end]).into_owned();
+        let (status, headers) = head.split_once("\r\n").unwrap_or((&head, ""));
+        (status.to_string(), headers.to_string(), response[end + 4..].to_vec())
+    }
+
+    #[test]
+    fn spoiled_frames_are_dropped_and_satellites_rejected() {
+        let mut camera = camera();
+        let mut stack = LiveStack::default();
+        let mut rng = sim::Rng::new(11);
+        let (sigma, stars) = (camera.psf_sigma, camera.stars.clone());
+        let mut rejected_before = 0;
+        for i in 0..16 {
+            set_up(&mut camera, i, &mut rng, sigma, &stars);
+            let frame = camera.capture(FrameType::Light);
+            if i == 12 {
+                rejected_before = stack.rejected;
+            }
+            let outcome = stack.add(&Raw::from_frame(&frame).image);
+            match (i, outcome) {
+                (0, Outcome::Reference { stars, .. }) => assert!(stars >= 10),
+                (5, Outcome::Dropped(Dropped::Blurry(_))) => {}
+                (9, Outcome::Dropped(Dropped::TooFewStars(_))) => {}
+                (i, Outcome::Added { registered, .. }) if i != 5 && i != 9 => {
+                    assert!(registered.rms < 0.5, "frame {} registered at RMS {:.2}", i, registered.rms);
+                }
+                (i, outcome) => panic!("frame {}: {:?}", i, outcome)
+            }
+            if i == 12 {
+                // most of the trail's samples are thrown out, nearly all of them the trail's
+                let rejected = stack.rejected - rejected_before;
+                assert!(rejected > camera.width as u64 / 2, "{} samples rejected", rejected);
+            }
+        }
+        assert_eq!((stack.stacked, stack.dropped), (14, 2));
+
+        // what's left of the trail in the stack is sky
+        let image = stack.image().unwrap();
+        let sky = sky(&image);
+        let field = stack.finder.find_image(&image);
+        let under = |x: u32, y: u32| {
+            let at = (y * image.width + x) as usize;
+            let near_star = field.stars.iter().any(|star| (star.x - x as f64).hypot(star.y - y as f64) < 8.0);
+            if near_star || image.data[at].is_nan() { None } else { Some(image.data[at]) }
+        };
+        let trail: Vec<f32> = (20..620).filter_map(|x| under(x, 150 + x / 3)).collect();
+        let mean = trail.iter().sum::<f32>() / trail.len() as f32;
+        assert!((mean - sky).abs() < 3.0 * field.noise as f32, "the trail is at {:.1} on a sky of {:.1}", mean, sky);
+
+        stack.reset();
+        assert!(stack.image().is_none());
+        assert_eq!((stack.stacked, stack.dropped, stack.rejected), (0, 0, 0));
+    }
+
+    #[test]
+    fn the_stack_is_published_and_served_as_png_or_jpeg() {
+        let dir = TempDir::new("live");
+        for (name, content_type, magic) in [("stack.png", "image/png", &b"\x89PNG"[..]), ("stack.jpg", "image/jpeg", &b"\xff\xd8"[..])].iter() {
+            let path = dir.join(name);
+            let mut snapshots = Snapshots::new(path.clone(), Duration::from_secs(60));
+            let address = snapshots.serve("127.0.0.1:0").unwrap();
+            let served = format!("/{}", name);
+            assert!(get(address, &served).0.contains("503"));
+
+            let mut camera = camera();
+            let mut stack = LiveStack::default();
+            run(&mut camera, None, &mut stack, &mut snapshots, Some(3)).unwrap();
+            assert_eq!(stack.stacked, 3);
+            let file = fs::read(&path).unwrap();
+            assert!(file.starts_with(magic), "{} isn't what it says", name);
+            assert!(!dir.join(format!("{}.part", name)).exists());
+
+            let (status, headers, body) = get(address, &served);
+            assert!(status.contains("200"), "{}: {}", served, status);
+            assert!(headers.contains(&format!("Content-Type: {}", content_type)), "{}", headers);
+            assert!(body == file, "{} isn't the file", served);
+            let (status, _, page) = get(address, "/");
+            assert!(status.contains("200"));
+            assert!(String::from_utf8(page).unwrap().contains(&format!("src=\"{}\"", served)));
+            assert!(get(address, "/elsewhere").0.contains("404"));
+        }
+    }
+
+    #[test]
+    fn an_idle_client_doesnt_hold_up_the_others() {
+        let dir = TempDir::new("live_idle");
+        let mut snapshots = Snapshots::new(dir.join("stack.png"), Duration::from_secs(60));
+        let address = snapshots.serve("127.0.0.1:0").unwrap();
+        run(&mut camera(), None, &mut LiveStack::default(), &mut snapshots, Some(1)).unwrap();
+
+        // connected, but saying nothing, for longer than the others are prepared to wait
+        let idle = TcpStream::connect(address).unwrap();
+        let started = Instant::now();
+        let (status, _, _) = get(address, "/stack.png");
+        assert!(status.contains("200"));
+        assert!(started.elapsed() < Duration::from_secs(2), "waited {:?}", started.elapsed());
+        drop(idle);
+    }
+}
@@ -3,21 +3,801 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 #![allow(dead_code)]
-#![feature(alloc_layout_extra)]
+#[cfg(feature = "asi")]
 mod asicam;
+mod backend;
+mod badpixels;
+mod calibrate;
+#[cfg(any(feature = "asi", feature = "qhy"))]
+mod dynlib;
+#[cfg(feature = "asi")]
+mod eaf;
+#[cfg(feature = "qhy")]
+mod firmware;
+mod fits;
+mod flats;
+mod focus;
+mod frame;
+mod gps;
+mod hotplug;
+mod image;
+mod imaging;
+mod jpeg;
+mod live;
+mod phd2;
+mod preview;
+#[cfg(feature = "qhy")]
 mod qhyccd;
+mod recovery;
+mod ser;
+mod sequence;
+mod session;
+mod setup;
+mod stack;
+mod stars;
+#[cfg(feature = "sim")]
+mod sim;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod usb;
 
+#[cfg(feature = "asi")]
 use crate::asicam::ASICamera2::{ControlType, ImageType};
+#[cfg(feature = "asi")]
 use crate::asicam::Camera;
+use crate::frame::FrameType;
+use crate::session::SessionLog;
 
 fn main() {
-    operate_qhy();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|arg| arg.as_str()) {
+        #[cfg(feature = "qhy")]
+        Some("firmware") => firmware_command(&args[2..]),
+        Some("usb") => usb_command(&args[2..]),
+        Some("setup") => setup_command(&args[2..]),
+        Some("calibrate") => calibrate_command(&args[2..]),
+        Some("stack") => stack_command(&args[2..]),
+        Some("preview") => preview_command(&args[2..]),
+        #[cfg(any(feature = "asi", feature = "qhy", feature = "sim"))]
+        Some("flats") => flats_command(&args[2..]),
+        #[cfg(any(feature = "asi", feature = "qhy", feature = "sim"))]
+        Some("lights") => lights_command(&args[2..]),
+        #[cfg(any(feature = "asi", feature = "qhy", feature = "sim"))]
+        Some("sequence") => sequence_command(&args[2..]),
+        #[cfg(any(feature = "asi", feature = "sim"))]
+        Some("autofocus") => autofocus_command(&args[2..]),
+        #[cfg(any(feature = "asi", feature = "qhy"))]
+        Some("trigger") => trigger_command(&args[2..]),
+        #[cfg(any(feature = "asi", feature = "qhy"))]
+        Some("live") => live_command(&args[2..]),
+        Some("backends") => backend::Registry::load().report(),
+        #[cfg(feature = "qhy")]
+        _ => {
+            let registry = backend::Registry::load();
+            if !registry.is_available(backend::Backend::Qhy) {
+                registry.report();
+                println!("The QHY SDK isn't available, set QHY_SDK to the path of libqhyccd.so");
+                std::process::exit(1);
+            }
+            operate_qhy()
+        }
+        #[cfg(not(feature = "qhy"))]
+        _ => {
+            backend::Registry::load().report();
+            println!("usage: backends | setup | usb | calibrate | stack | preview | flats | lights | sequence | autofocus | trigger | live");
+        }
+    }
+}
+
+#[cfg(feature = "qhy")]
+fn firmware_command(args: &[String]) {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("load") => {
+            let dir = args.get(1).map(|dir| dir.as_str()).unwrap_or("firmware/qhy");
+            let loaded = firmware::load_attached(dir).unwrap();
+            if loaded.is_empty() {
+                println!("No cameras waiting for firmware, it may have been loaded already");
+            }
+        }
+        Some("dry-run") if args.len() == 2 => {
+            let path = &args[1];
+            let chip = firmware::chip_for(path).expect("firmware images are .HEX or .img files");
+            let mut log = firmware::TransferLog::default();
+            firmware::upload_file(&mut log, chip, path).unwrap();
+            for transfer in log.transfers.iter() {
+                println!(
+                    "request {:02x} value {:04x} index {:04x}: {} bytes",
+                    transfer.request, transfer.value, transfer.index, transfer.data.len()
+                );
+            }
+        }
+        Some("verify") => {
+            let dir = args.get(1).map(|dir| dir.as_str()).unwrap_or("firmware/qhy");
+            let mut failed = 0;
+            for (name, result) in firmware::verify_dir(dir).unwrap() {
+                match result {
+                    Ok(summary) => println!("ok   {}: {}", name, summary),
+                    Err(e) => {
+                        println!("FAIL {}: {:?}", name, e);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                println!("{} firmware files failed verification", failed);
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            println!("usage: firmware load [firmware dir]");
+            println!("       firmware verify [firmware dir]");
+            println!("       firmware dry-run <image>");
+        }
+    }
+}
+
+fn setup_command(args: &[String]) {
+    let roots = setup::roots_from_args(args);
+    match args.first().map(|arg| arg.as_str()) {
+        Some("rules") => print!("{}", setup::rules()),
+        Some("install") => {
+            let path = setup::install(&roots).unwrap();
+            println!("Wrote {}; run `udevadm control --reload` and replug the cameras", path.display());
+        }
+        Some("check") => {
+            let problems = setup::check(&roots).unwrap();
+            for problem in problems.iter() {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+            println!("Everything looks fine");
+        }
+        _ => {
+            println!("usage: setup rules");
+            println!("       setup install [--udev <root>]");
+            println!("       setup check [--sys <root>] [--dev <root>] [--udev <root>]");
+        }
+    }
+}
+
+fn calibrate_command(args: &[String]) {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("master") if args.len() >= 4 => {
+            let frame_type = match args[1].as_str() {
+                "bias" => FrameType::Bias,
+                "dark" => FrameType::Dark,
+                "flat" => FrameType::Flat,
+                other => panic!("masters are bias, dark or flat, not {}", other)
+            };
+            let frames: Vec<calibrate::Raw> = args[3..].iter().map(|path| calibrate::Raw::read(path).unwrap()).collect();
+            let mut master = calibrate::Master::combine(frame_type, &frames, None).unwrap();
+            let path = master.save(std::path::Path::new(&args[2])).unwrap();
+            println!("Wrote {} from {} frames", path.display(), master.frames);
+        }
+        Some("lights") if args.len() >= 3 => {
+            let library = calibrate::MasterLibrary::load(std::path::Path::new(&args[1])).unwrap();
+            let mut calibrator = calibrate::Calibrator::new(library);
+            for path in args[2..].iter() {
+                let light = calibrate::Raw::read(path).unwrap();
+                let setup = light.setup();
+                let have_map = calibrator.bad_pixels.iter().any(|map| map.serial == setup.serial && map.binning == setup.binning);
+                if !have_map {
+                    let dir = std::path::Path::new(badpixels::DIR);
+                    if let Some(map) = badpixels::BadPixelMap::load_for(dir, &setup.serial, setup.binning).unwrap() {
+                        calibrator.bad_pixels.push(map);
+                    }
+                }
+                match calibrator.calibrate(&light) {
+                    Ok(calibrated) => {
+                        let out = format!("{}_cal.fits", path.trim_end_matches(".fits").trim_end_matches(".fit"));
+                        calibrated.write(&out).unwrap();
+                        println!(
+                            "Wrote {} (bias {}, dark {}, flat {})", out,
+                            calibrated.bias.as_deref().unwrap_or("-"), calibrated.dark.as_deref().unwrap_or("-"),
+                            calibrated.flat.as_deref().unwrap_or("-")
+                        );
+                    }
+                    Err(e) => println!("Couldn't calibrate {}: {:?}", path, e)
+                }
+            }
+        }
+        Some("badpixels") if args.len() == 2 => {
+            // a map per camera and binning, from its longest dark, where hot pixels stand out
+            // most, and a flat the same size
+            let library = calibrate::MasterLibrary::load(std::path::Path::new(&args[1])).unwrap();
+            let mut done: Vec<(&str, u32)> = Vec::new();
+            for master in library.masters.iter() {
+                let (serial, binning) = (master.setup.serial.as_str(), master.setup.binning);
+                if done.contains(&(serial, binning)) {
+                    continue;
+                }
+                done.push((serial, binning));
+                let of_camera = |frame_type: FrameType| library.masters.iter().filter(move |other| {
+                    other.frame_type == frame_type && other.setup.serial == serial && other.setup.binning == binning
+                });
+                let dark = of_camera(FrameType::Dark).max_by(|a, b| a.exposure.total_cmp(&b.exposure)).map(|dark| &dark.image);
+                let flat = of_camera(FrameType::Flat).find(|flat| dark.map(|dark| dark.same_shape(&flat.image)).unwrap_or(true)).map(|flat| &flat.image);
+                if let Some(map) = badpixels::Detection::default().detect(serial, binning, dark, flat) {
+                    let path = map.save(std::path::Path::new(badpixels::DIR)).unwrap();
+                    println!(
+                        "Wrote {}: {} hot and {} cold pixels, {} hot columns",
+                        path.display(), map.hot.len(), map.cold.len(), map.columns.len()
+                    );
+                }
+            }
+        }
+        _ => {
+            println!("usage: calibrate master <bias|dark|flat> <masters dir> <frame.fits>...");
+            println!("       calibrate badpixels <masters dir>");
+            println!("       calibrate lights <masters dir> <light.fits>...");
+        }
+    }
+}
+
+fn stack_command(args: &[String]) {
+    if args.len() < 3 {
+        println!("usage: stack <out.fits> <light.fits>...");
+        return;
+    }
+    let images: Vec<image::Image> = args[1..].iter().map(|path| fits::read(path).unwrap().0).collect();
+    let stack = stack::Stacker::default().stack(&images).unwrap();
+    report_stack(&stack, &args[1..]);
+    fits::write_image(&stack.image, &[], &args[0]).unwrap();
+    println!("Wrote {}", args[0]);
+}
+
+/// Write a preview next to each FITS file, with the channels stretched on their own for
+/// `--unlinked`, and as a JPEG for `--jpeg`.
+fn preview_command(args: &[String]) {
+    let mut preview = preview::Preview::thumbnail();
+    let mut paths = Vec::new();
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--unlinked" => preview.linked = false,
+            "--jpeg" => preview.format = preview::Format::Jpeg(frame::JPEG_QUALITY),
+            _ => paths.push(arg)
+        }
+    }
+    if paths.is_empty() {
+        println!("usage: preview [--unlinked] [--jpeg] <image.fits>...");
+        return;
+    }
+    for path in paths {
+        let (image, _) = fits::read(path).unwrap();
+        let out = preview::path_for(std::path::Path::new(path), preview.format);
+        preview.format.write(&preview.render(&image), &out).unwrap();
+        println!("Wrote {}", out.display());
+    }
 }
 
+/// Connect to PHD2 at `<host>[:<instance>]`, the instance being 1 for the first PHD2 running
+/// on the host.
+fn connect_guider(at: &str) -> phd2::Guider {
+    let (host, instance) = match at.rsplit_once(':') {
+        Some((host, instance)) => (host, instance.parse().expect("the PHD2 instance is a number")),
+        None => (at, 1)
+    };
+    let guider = phd2::connect(host, instance).unwrap();
+    println!("PHD2 on {} is {}", at, guider.app_state());
+    guider
+}
+
+/// Run a sequence planned in a file (see `Sequence::parse`) with the first camera of a kind,
+/// then flats through the filters that have a flat exposure if `--flats` is given. QHY
+/// cameras use the wheel on their CFW port if they have one; otherwise filters are changed
+/// by hand. Focus offsets are made with the first ZWO EAF given `--eaf`, or the simulated
+/// camera's own focuser.
+#[cfg(any(feature = "asi", feature = "qhy", feature = "sim"))]
+fn sequence_command(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut flats = None;
+    #[cfg(feature = "qhy")]
+    let mut filter_names = None;
+    #[cfg(feature = "qhy")]
+    let mut keep_overscan = false;
+    let mut phd2_at = None;
+    let mut reconnect = false;
+    let mut previews = preview::Format::Png;
+    #[cfg(feature = "asi")]
+    let mut use_eaf = false;
+    let mut focus_backlash = 0;
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--flats" => flats = Some(options.next().and_then(|count| count.parse().ok()).expect("--flats takes a number of flats")),
+            #[cfg(feature = "asi")]
+            "--eaf" => use_eaf = true,
+            "--focus-backlash" => focus_backlash = options.next().and_then(|steps| steps.parse().ok()).expect("--focus-backlash takes a number of steps"),
+            "--reconnect" => reconnect = true,
+            "--jpeg-previews" => previews = preview::Format::Jpeg(frame::JPEG_QUALITY),
+            #[cfg(feature = "qhy")]
+            "--filters" => filter_names = options.next(),
+            #[cfg(feature = "qhy")]
+            "--keep-overscan" => keep_overscan = true,
+            "--phd2" => phd2_at = options.next(),
+            _ => positional.push(arg.as_str())
+        }
+    }
+    if positional.len() != 3 {
+        println!("usage: sequence <asi|qhy|sim> <plan> <prefix> [--flats <count>] [--filters <filter names>] [--keep-overscan] [--phd2 <host>[:<instance>]] [--reconnect] [--jpeg-previews] [--eaf] [--focus-backlash <steps>]");
+        println!("       --reconnect waits for an unplugged camera to come back and carries on");
+        println!("       --eaf makes the plan's focus offsets with the first ZWO EAF, finishing each move going out after --focus-backlash steps");
+        println!("       --keep-overscan keeps a QHY camera's overscan in frames, for calibrate to take the bias from");
+        println!("       --jpeg-previews writes the previews next to each frame as JPEG instead of png");
+        return;
+    }
+    let text = std::fs::read_to_string(positional[1]).unwrap();
+    let plan = sequence::Sequence::parse(positional[2], &text).unwrap();
+    let mut guider = phd2_at.map(|at| connect_guider(at));
+    #[cfg(any(feature = "asi", feature = "qhy"))]
+    #[allow(unused_mut)]
+    let mut focuser: Option<Box<focus::AnyFocuser>> = None;
+    #[cfg(feature = "asi")]
+    if use_eaf {
+        let eaf = eaf::open(0).unwrap();
+        println!("Focusing with {}", eaf.name);
+        focuser = Some(Box::new(focus::Boxed(eaf)));
+    }
+    match positional[0] {
+        #[cfg(feature = "asi")]
+        "asi" => {
+            let mut camera = asicam::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::ASI_VID);
+            run_sequence(&mut camera, &mut sequence::ManualFilters::default(), &plan, flats, guider.as_mut(), reconnect, previews, focusing(&mut focuser, focus_backlash));
+        }
+        #[cfg(feature = "qhy")]
+        "qhy" => {
+            let mut camera = qhyccd::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::QHY_VID);
+            if keep_overscan {
+                camera.set_keep_overscan(true).expect("this camera can't keep its overscan");
+            }
+            if camera.has_filter_wheel() {
+                let mut wheel = qhyccd::FilterWheel::new(&camera).unwrap();
+                if let Some(path) = filter_names {
+                    wheel.load_names(path).unwrap();
+                }
+                run_sequence(&mut camera, &mut wheel, &plan, flats, guider.as_mut(), reconnect, previews, focusing(&mut focuser, focus_backlash));
+            } else {
+                run_sequence(&mut camera, &mut sequence::ManualFilters::default(), &plan, flats, guider.as_mut(), reconnect, previews, focusing(&mut focuser, focus_backlash));
+            }
+        }
+        #[cfg(feature = "sim")]
+        "sim" => {
+            let mut camera = sim::Camera::new(640, 480);
+            let mut focuser: Option<Box<focus::AnyFocuser>> = Some(Box::new(focus::Boxed(camera.focuser(focus_backlash))));
+            run_sequence(&mut camera, &mut sequence::ManualFilters::default(), &plan, flats, guider.as_mut(), reconnect, previews, focusing(&mut focuser, focus_backlash));
+        }
+        other => println!("no {} cameras here", other)
+    }
+}
+
+#[cfg(any(feature = "asi", feature = "qhy", feature = "sim"))]
+#[allow(clippy::too_many_arguments)]
+fn run_sequence<C, W>(
+    camera: &mut C,
+    wheel: &mut W,
+    plan: &sequence::Sequence,
+    flats: Option<u32>,
+    guider: Option<&mut phd2::Guider>,
+    reconnect: bool,
+    previews: preview::Format,
+    focusing: Option<(&mut focus::AnyFocuser, i32)>
+)
+    where C: imaging::Reopen, W: sequence::FilterChanger<C>
+{
+    let mut log = SessionLog::open("session.log").unwrap();
+    let mut runner = sequence::Runner::new(&mut log);
+    runner.guider = guider;
+    runner.preview.format = previews;
+    if let Some((focuser, backlash)) = focusing {
+        runner.focuser = Some(focuser);
+        runner.focus_backlash = backlash;
+    }
+    runner.bad_pixels = badpixels::BadPixelMap::load_for(std::path::Path::new(badpixels::DIR), &camera.serial(), camera.binning()).unwrap();
+    let mut run = |runner: &mut sequence::Runner, plan: &sequence::Sequence| {
+        if reconnect {
+            runner.run_reconnecting(camera, wheel, plan)
+        } else {
+            runner.run(camera, wheel, plan)
+        }
+    };
+    run(&mut runner, plan).unwrap();
+    if let Some(count) = flats {
+        run(&mut runner, &plan.flats(count)).unwrap();
+    }
+}
+
+/// The focuser for a sequence's focus offsets, if there is one, and its backlash.
+#[cfg(any(feature = "asi", feature = "qhy", feature = "sim"))]
+fn focusing(focuser: &mut Option<Box<focus::AnyFocuser>>, backlash: i32) -> Option<(&mut focus::AnyFocuser, i32)> {
+    focuser.as_deref_mut().map(|focuser| (focuser, backlash))
+}
+
+/// Autofocus the first camera of a kind with the first ZWO EAF, or the simulated camera with
+/// its own focuser, and leave the focuser at best focus.
+#[cfg(any(feature = "asi", feature = "sim"))]
+fn autofocus_command(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut autofocus = focus::Autofocus::default();
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--exposure" => autofocus.exposure = std::time::Duration::from_millis(options.next().and_then(|ms| ms.parse().ok()).expect("--exposure takes milliseconds")),
+            "--step" => autofocus.step = options.next().and_then(|steps| steps.parse().ok()).expect("--step takes a number of steps"),
+            "--steps-out" => autofocus.steps_out = options.next().and_then(|count| count.parse().ok()).expect("--steps-out takes a number of positions"),
+            "--backlash" => autofocus.backlash = options.next().and_then(|steps| steps.parse().ok()).expect("--backlash takes a number of steps"),
+            "--frames" => autofocus.frames = options.next().and_then(|count| count.parse().ok()).expect("--frames takes a number of frames"),
+            _ => positional.push(arg.as_str())
+        }
+    }
+    if positional.len() != 1 {
+        println!("usage: autofocus <asi|qhy|sim> [--exposure <ms>] [--step <steps>] [--steps-out <positions>] [--backlash <steps>] [--frames <count>]");
+        println!("       tries --steps-out positions --step apart either side of where the focuser is, {} of each by default", autofocus.steps_out);
+        return;
+    }
+    match positional[0] {
+        #[cfg(feature = "asi")]
+        "asi" => {
+            let mut camera = asicam::acquire(0).unwrap();
+            let mut focuser = eaf::open(0).unwrap();
+            report_focus(autofocus.run(&mut focuser, &mut camera));
+        }
+        #[cfg(all(feature = "asi", feature = "qhy"))]
+        "qhy" => {
+            let mut camera = qhyccd::acquire(0).unwrap();
+            let mut focuser = eaf::open(0).unwrap();
+            report_focus(autofocus.run(&mut focuser, &mut camera));
+        }
+        #[cfg(feature = "sim")]
+        "sim" => {
+            let mut camera = sim::Camera::new(640, 480);
+            let mut focuser = camera.focuser(autofocus.backlash);
+            report_focus(autofocus.run(&mut focuser, &mut camera));
+        }
+        other => println!("no {} cameras here", other)
+    }
+}
+
+#[cfg(any(feature = "asi", feature = "sim"))]
+fn report_focus<F: std::fmt::Debug, C: std::fmt::Debug>(result: Result<focus::FocusResult, focus::FocusError<F, C>>) {
+    match result {
+        Ok(result) => {
+            println!("Focused at {} with HFR {:.2}, from {} positions", result.position, result.hfr, result.samples.len());
+            if let Some(temperature) = result.temperature {
+                println!("Focuser temperature {:.1}C", temperature);
+            }
+        }
+        Err(focus::FocusError::MinimumOutside(position)) =>
+            println!("Best focus looks to be around {}, outside the positions tried; move closer and run again", position),
+        Err(e) => println!("Autofocus failed: {:?}", e)
+    }
+}
+
+/// Keep the camera's `Presence` up to date from hotplug events, so unplugging it fails
+/// captures straight away rather than hanging, and `--reconnect` knows when it's back.
+#[cfg(any(feature = "asi", feature = "qhy"))]
+fn watch_for_unplugging<C: imaging::Reopen>(camera: &C, vid: u16) {
+    match hotplug::watch_camera(usb::SYSFS_ROOT, vid, &camera.serial(), camera.presence()) {
+        Ok(Some(port)) => println!("Watching USB port {} for the camera being unplugged", port),
+        Ok(None) => println!("Couldn't tell which USB device the camera is, so unplugging it won't be noticed"),
+        Err(e) => println!("Couldn't watch for the camera being unplugged: {}", e)
+    }
+}
+
+/// Take lights with the first camera of a kind, dithering through PHD2 between them with
+/// `--phd2`. Its instance, after the host, is 1 for the first PHD2 running there.
+#[cfg(any(feature = "asi", feature = "qhy", feature = "sim"))]
+fn lights_command(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut phd2_at = None;
+    let mut amount = 5.0;
+    let mut ra_only = false;
+    #[cfg(feature = "qhy")]
+    let mut keep_overscan = false;
+    let mut preview = preview::Preview::thumbnail();
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--phd2" => phd2_at = options.next(),
+            "--jpeg-previews" => preview.format = preview::Format::Jpeg(frame::JPEG_QUALITY),
+            "--dither" => amount = options.next().and_then(|pixels| pixels.parse().ok()).expect("--dither takes a number of pixels"),
+            "--ra-only" => ra_only = true,
+            #[cfg(feature = "qhy")]
+            "--keep-overscan" => keep_overscan = true,
+            _ => positional.push(arg.as_str())
+        }
+    }
+    if positional.len() != 4 {
+        println!("usage: lights <asi|qhy|sim> <count> <exposure ms> <prefix> [--phd2 <host>[:<instance>]] [--dither <pixels>] [--ra-only] [--keep-overscan] [--jpeg-previews]");
+        return;
+    }
+    let count: u32 = positional[1].parse().unwrap();
+    let exposure = std::time::Duration::from_millis(positional[2].parse().unwrap());
+    let prefix = positional[3];
+    let mut dithering = phd2_at.map(|at| {
+        Dithering { guider: connect_guider(at), amount: amount, ra_only: ra_only, settle: phd2::Settle::default() }
+    });
+    match positional[0] {
+        #[cfg(feature = "asi")]
+        "asi" => {
+            let mut camera = asicam::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::ASI_VID);
+            imaging::Imager::set_exposure(&mut camera, exposure).unwrap();
+            take_light_images(&mut camera, count, prefix, dithering.as_mut(), &preview);
+        }
+        #[cfg(feature = "qhy")]
+        "qhy" => {
+            let mut camera = qhyccd::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::QHY_VID);
+            if keep_overscan {
+                camera.set_keep_overscan(true).expect("this camera can't keep its overscan");
+            }
+            imaging::Imager::set_exposure(&mut camera, exposure).unwrap();
+            take_light_images(&mut camera, count, prefix, dithering.as_mut(), &preview);
+        }
+        #[cfg(feature = "sim")]
+        "sim" => {
+            let mut camera = sim::Camera::new(640, 480);
+            imaging::Imager::set_exposure(&mut camera, exposure).unwrap();
+            take_light_images(&mut camera, count, prefix, dithering.as_mut(), &preview);
+        }
+        other => println!("no {} cameras here", other)
+    }
+}
+
+/// Take flats and matching dark-flats with the first camera of a kind, working out the
+/// exposure from test frames. `--sky` follows the twilight sky instead of a steady panel.
+#[cfg(any(feature = "asi", feature = "qhy", feature = "sim"))]
+fn flats_command(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut wizard = flats::FlatWizard::default();
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--sky" => wizard.source = flats::Source::Sky,
+            "--target" => wizard.target = options.next().and_then(|fraction| fraction.parse().ok()).expect("--target takes a fraction of full scale"),
+            _ => positional.push(arg.as_str())
+        }
+    }
+    if positional.len() != 3 {
+        println!("usage: flats <asi|qhy|sim> <count> <prefix> [--sky] [--target <fraction of full scale>]");
+        return;
+    }
+    let count: u32 = positional[1].parse().unwrap();
+    let prefix = positional[2];
+    match positional[0] {
+        #[cfg(feature = "asi")]
+        "asi" => {
+            let mut camera = asicam::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::ASI_VID);
+            take_flats(&mut camera, &wizard, count, prefix);
+        }
+        #[cfg(feature = "qhy")]
+        "qhy" => {
+            let mut camera = qhyccd::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::QHY_VID);
+            take_flats(&mut camera, &wizard, count, prefix);
+        }
+        #[cfg(feature = "sim")]
+        "sim" => take_flats(&mut sim::Camera::new(640, 480), &wizard, count, prefix),
+        other => println!("no {} cameras here", other)
+    }
+}
+
+/// Save frames from the first camera of a kind as its external trigger fires them, for
+/// occultations and the like, giving up after `--timeout` seconds without one.
+#[cfg(any(feature = "asi", feature = "qhy"))]
+fn trigger_command(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut mode = None;
+    let mut timeout = std::time::Duration::from_secs(60);
+    let mut video = false;
+    let mut preview = preview::Preview::thumbnail();
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--mode" => mode = options.next(),
+            "--ser" => video = true,
+            "--jpeg-previews" => preview.format = preview::Format::Jpeg(frame::JPEG_QUALITY),
+            "--timeout" => timeout = std::time::Duration::from_secs(options.next().and_then(|seconds| seconds.parse().ok()).expect("--timeout takes seconds")),
+            _ => positional.push(arg.as_str())
+        }
+    }
+    if positional.len() != 3 {
+        println!("usage: trigger <asi|qhy> <count> <prefix> [--mode <mode>] [--timeout <seconds>] [--ser] [--jpeg-previews]");
+        println!("       frames are saved as FITS with a png preview each, or a JPEG one with --jpeg-previews,");
+        println!("       or all in one SER video with --ser");
+        println!("       ASI modes are rise (the default), fall, high and low;");
+        println!("       QHY modes are in (the default) or a model-specific mode's number");
+        return;
+    }
+    let count: u32 = positional[1].parse().unwrap();
+    let prefix = positional[2];
+    let mut ser: Option<ser::SerWriter> = None;
+    let mut save = |frame: &frame::Frame, i: u32| {
+        if !video {
+            let path = format!("{}_{:06}.fits", prefix, i);
+            frame.save(&path).unwrap();
+            preview.write_for(frame, &path).unwrap();
+            println!("Wrote {}", path);
+            return;
+        }
+        let writer = ser.get_or_insert_with(|| ser::SerWriter::create(format!("{}.ser", prefix), frame, positional[0]).unwrap());
+        writer.write_frame(frame).unwrap();
+        println!("Frame {} written to {}.ser", i, prefix);
+    };
+    match positional[0] {
+        #[cfg(feature = "asi")]
+        "asi" => {
+            use crate::asicam::ASICamera2::CameraMode;
+            let mode = match mode {
+                Some(name) => match CameraMode::external_trigger(name) {
+                    Ok(mode) => mode,
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                },
+                None => CameraMode::TrigRiseEdge
+            };
+            let mut camera = asicam::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::ASI_VID);
+            camera.set_camera_mode(mode).unwrap();
+            for i in 0..count {
+                match camera.wait_for_triggered_frame(timeout) {
+                    Ok(frame) => save(&frame, i),
+                    Err(e) => {
+                        println!("No triggered frame: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            camera.set_camera_mode(CameraMode::Normal).unwrap();
+        }
+        #[cfg(feature = "qhy")]
+        "qhy" => {
+            let mode = match mode {
+                Some(name) => qhyccd::TriggerMode::from_name(name).expect("not a QHY trigger mode"),
+                None => qhyccd::TriggerMode::TriggerIn
+            };
+            let mut camera = qhyccd::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::QHY_VID);
+            camera.enable_trigger(mode).unwrap();
+            for i in 0..count {
+                match camera.wait_for_triggered_frame(timeout) {
+                    Ok(frame) => save(&frame, i),
+                    Err(e) => {
+                        println!("No triggered frame: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            camera.disable_trigger().unwrap();
+        }
+        other => println!("no {} cameras here", other)
+    }
+    if let Some(writer) = ser {
+        writer.finish().unwrap();
+    }
+}
+
+/// Stack lights live from the first camera of a kind, for outreach nights, until killed.
+#[cfg(any(feature = "asi", feature = "qhy"))]
+fn live_command(args: &[String]) {
+    if args.len() < 3 {
+        println!("usage: live <asi|qhy> <exposure ms> <snapshot.png|snapshot.jpg> [masters dir | -] [listen address]");
+        return;
+    }
+    let exposure = std::time::Duration::from_millis(args[1].parse().unwrap());
+    let library = match args.get(3).map(|dir| dir.as_str()) {
+        Some("-") | None => None,
+        Some(dir) => Some(calibrate::MasterLibrary::load(std::path::Path::new(dir)).unwrap())
+    };
+    let mut snapshots = live::Snapshots::new(args[2].clone().into(), std::time::Duration::from_secs(10));
+    if let Some(address) = args.get(4) {
+        println!("Serving the stack at http://{}/", snapshots.serve(address).unwrap());
+    }
+    match args[0].as_str() {
+        #[cfg(feature = "asi")]
+        "asi" => {
+            let mut camera = asicam::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::ASI_VID);
+            live_stack(&mut camera, exposure, library, &mut snapshots);
+        }
+        #[cfg(feature = "qhy")]
+        "qhy" => {
+            let mut camera = qhyccd::acquire(0).unwrap();
+            watch_for_unplugging(&camera, usb::QHY_VID);
+            live_stack(&mut camera, exposure, library, &mut snapshots);
+        }
+        other => println!("no {} cameras here", other)
+    }
+}
+
+#[cfg(any(feature = "asi", feature = "qhy"))]
+fn live_stack<C: imaging::Imager>(camera: &mut C, exposure: std::time::Duration, library: Option<calibrate::MasterLibrary>, snapshots: &mut live::Snapshots) {
+    camera.set_exposure(exposure).unwrap();
+    let calibrator = library.map(|library| {
+        let mut calibrator = calibrate::Calibrator::new(library);
+        let dir = std::path::Path::new(badpixels::DIR);
+        if let Some(map) = badpixels::BadPixelMap::load_for(dir, &camera.serial(), camera.binning()).unwrap() {
+            calibrator.bad_pixels.push(map);
+        }
+        calibrator
+    });
+    live::run(camera, calibrator.as_ref(), &mut live::LiveStack::default(), snapshots, None).unwrap();
+}
+
+fn report_stack<S: std::fmt::Display>(stack: &stack::Stack, names: &[S]) {
+    for (name, frame) in names.iter().zip(stack.frames.iter()) {
+        match frame.registered.as_ref() {
+            Some(registered) => println!(
+                "{}: {} stars, FWHM {:.2}, SNR {:.1}, weight {:.3}, {} matched with RMS {:.2}px",
+                name, frame.stars, frame.fwhm, frame.snr, frame.weight, registered.matches, registered.rms
+            ),
+            None => println!("{}: {} stars, couldn't be registered, left out", name, frame.stars)
+        }
+    }
+    println!("Stacked on {} with {:.2}% of samples clipped", names[stack.reference], stack.rejected * 100.0);
+}
+
+/// Parse `vvvv:pppp` or just `vvvv`, in hex.
+fn parse_usb_id(id: &str) -> Option<(u16, Option<u16>)> {
+    let mut parts = id.splitn(2, ':');
+    let vid = u16::from_str_radix(parts.next()?, 16).ok()?;
+    match parts.next() {
+        Some(pid) => Some((vid, Some(u16::from_str_radix(pid, 16).ok()?))),
+        None => Some((vid, None))
+    }
+}
+
+fn usb_command(args: &[String]) {
+    let recovery = recovery::Recovery::default();
+    match args.first().map(|arg| arg.as_str()) {
+        Some("watch") => {
+            let mut watcher = hotplug::Watcher::new(hotplug::Netlink::open().unwrap());
+            loop {
+                if let Some(event) = watcher.next_event(std::time::Duration::from_secs(60)).unwrap() {
+                    println!("{:?} {:04x}:{:04x} on port {}", event.action, event.vid, event.pid, event.port);
+                }
+            }
+        }
+        Some("list") => {
+            for device in usb::list_devices(&recovery.sysfs_root).unwrap() {
+                if device.vid != usb::ASI_VID && device.vid != usb::QHY_VID {
+                    continue;
+                }
+                println!(
+                    "{:04x}:{:04x} at {} serial {}",
+                    device.vid, device.pid,
+                    device.dev_node(&recovery.dev_root).display(),
+                    device.serial.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        Some("reset") if args.len() >= 2 => {
+            let (vid, pid) = parse_usb_id(&args[1]).expect("device is given as vid:pid in hex");
+            let serial = args.get(2).map(|serial| serial.as_str());
+            let device = recovery.reset_and_wait(vid, pid, serial).unwrap();
+            println!("{:04x}:{:04x} is back at {}", device.vid, device.pid, device.dev_node(&recovery.dev_root).display());
+        }
+        _ => {
+            println!("usage: usb list");
+            println!("       usb watch");
+            println!("       usb reset <vid:pid> [serial]");
+        }
+    }
+}
+
+#[cfg(feature = "qhy")]
 fn operate_qhy() {
     use crate::qhyccd::Control;
     println!("Operating on qhy camera ... or i'll die trying");
-    let mut camera = qhyccd::acquire(0).unwrap();
+    let camera = qhyccd::acquire(0).unwrap();
     camera.set_exposure_ms(40000).unwrap();
     camera.set_param(Control::Gain, 64.0).unwrap();
     camera.set_param(Control::Offset, 00.0).unwrap();
@@ -31,12 +811,17 @@ fn operate_qhy() {
     println!("4x4: {}", camera.has_param(Control::Bin4x4Mode));
 //    camera.set_param(Control::Speed, 1.0).unwrap();
     println!("current temp: {}", camera.get_param(Control::CurTemp));
+    if camera.has_filter_wheel() {
+        let wheel = qhyccd::FilterWheel::new(&camera).unwrap();
+        println!("Filter wheel with {} slots, currently at {:?}", wheel.slot_count(), wheel.position(&camera).unwrap());
+    }
     camera.set_defaults().unwrap();
 //    camera.set_bin_mode(2).unwrap();
-    camera.take_image("../../asdf.png");
+    camera.take_image("../../asdf.fits").unwrap();
     camera.release().unwrap();
 }
 
+#[cfg(feature = "asi")]
 fn operate_asi() {
     println!("Operating on asi camera ... or i'll die trying");
     let mut camera = asicam::acquire(0).unwrap();
@@ -46,6 +831,7 @@ fn operate_asi() {
     camera.set_control_value(ControlType::CoolerOn, 1).unwrap();
     std::thread::sleep(std::time::Duration::from_millis(500));
     println!("Camera temperature is currently {:?}", camera.get_control_value(ControlType::Temperature).unwrap());
+    let mut log = SessionLog::open("session.log").unwrap();
 
     /*
     for exposure in [2000, 5000, 10000, 30000].iter() {
@@ -54,7 +840,7 @@ fn operate_asi() {
             camera.set_control_value(ControlType::Gain, *gain).unwrap();
             for offset in [100, 80, 60, 40, 20, 0].iter() {
                 camera.set_control_value(ControlType::Offset, *offset).unwrap();
-                take_calibration_images(&camera, 1, &format!("roof_gain_{:03}_offset_{:03}_exposure_{:06}", gain, offset, exposure));
+                take_calibration_images(&mut camera, FrameType::Dark, 1, &mut log, &format!("roof_gain_{:03}_offset_{:03}_exposure_{:06}", gain, offset, exposure));
             }
         }
     }
@@ -65,7 +851,7 @@ fn operate_asi() {
     camera.set_control_value(ControlType::Offset, 0).unwrap();
     camera.set_control_value(ControlType::HardwareBin, 0).unwrap();
     camera.set_roi_format(camera.width, camera.height, 1, ImageType::RGB24).unwrap();
-    take_calibration_images(&camera, 40, "dark_gain_350_exposure_45000");
+    take_calibration_images(&mut camera, FrameType::Dark, 40, &mut log, "dark_gain_350_exposure_45000");
     /*
     for exposure in [1000 * 1000 * 10].iter() {
         camera.set_control_value(ControlType::Exposure, *exposure).unwrap();
@@ -74,8 +860,10 @@ fn operate_asi() {
             for offset in [100, 80, 70, 60, 40, 0].iter() {
                 camera.set_control_value(ControlType::Offset, *offset).unwrap();
                 take_calibration_images(
-                    &camera,
+                    &mut camera,
+                    FrameType::Dark,
                     30,
+                    &mut log,
                     &format!("images/gain_{:03}_offset_{:03}_exposure_{:06}", gain, offset, exposure));
             }
         }
@@ -85,11 +873,132 @@ fn operate_asi() {
     println!("Done!");
 }
 
-fn take_calibration_images(camera: &Camera, count: u32, path_fragment: &str) {
+/// Take `count` calibration frames, logging each one's statistics. Darks are compared to the
+/// first good dark of the series and flats checked for exposure, and any that look bad are
+/// flagged in the log and on the console so they can be retaken.
+#[cfg(feature = "asi")]
+fn take_calibration_images(camera: &mut Camera, frame_type: FrameType, count: u32, log: &mut SessionLog, path_fragment: &str) {
+    let limits = stats::QualityLimits::default();
+    let mut reference: Option<stats::FrameStats> = None;
     for i in 0..count {
         println!("{} image {:06}", path_fragment,  i);
         let temp = camera.get_control_value(ControlType::Temperature).unwrap();
         println!("Camera temperature is currently {:?}", temp);
-        camera.take_image(&format!("{}_{:06}_temp_{:03}.png", path_fragment, i, temp)).unwrap();
+        let frame = match camera.capture(frame_type) {
+            Ok(frame) => frame,
+            Err(ref e) if recovery::asi_recoverable(e) => {
+                println!("Camera dropped out ({:?}), resetting it", e);
+                recovery::Recovery::default().recover_asi(camera).unwrap();
+                camera.capture(frame_type).unwrap()
+            }
+            Err(e) => panic!("capture failed: {:?}", e)
+        };
+        println!("Shutter: {:?}", frame.header("SHUTTER"));
+        let path = format!("{}_{:06}_temp_{:03}.fits", path_fragment, i, temp);
+        frame.save(&path).unwrap();
+        preview::Preview::thumbnail().write_for(&frame, &path).unwrap();
+
+        let stats = frame.stats.as_ref().expect("frames from the camera come with stats");
+        let problems = match frame_type {
+            FrameType::Dark => limits.check_dark(stats, reference.as_ref()),
+            FrameType::Flat => limits.check_flat(stats),
+            FrameType::Light | FrameType::Bias => Vec::new()
+        };
+        for problem in problems.iter() {
+            println!("{} looks bad: {}", path, problem);
+        }
+        if frame_type == FrameType::Dark && problems.is_empty() && reference.is_none() {
+            reference = Some(stats.clone());
+        }
+        let mut fields = vec![
+            ("IMAGETYP".to_owned(), frame_type.imagetyp().to_owned()),
+            ("CCD-TEMP".to_owned(), format!("{}", temp))
+        ];
+        fields.extend(stats.log_fields());
+        if !problems.is_empty() {
+            fields.push(("FLAG".to_owned(), problems.join("; ")));
+        }
+        if let Err(e) = log.record(&path, &fields) {
+            println!("Failed to record {} in the session log: {}", path, e);
+        }
+    }
+}
+
+/// Run the flat wizard on `camera`, writing the flats and dark-flats it takes and recording
+/// them in the session log.
+fn take_flats<C: imaging::Imager>(camera: &mut C, wizard: &flats::FlatWizard, count: u32, path_fragment: &str) {
+    let mut log = SessionLog::open("session.log").unwrap();
+    let mut taken = 0;
+    let result = wizard.run(camera, count, |frame, frame_type, exposure| {
+        let kind = if frame_type == FrameType::Flat { "flat" } else { "darkflat" };
+        let path = format!("{}_{}_{:06}_exposure_{:06}.fits", path_fragment, kind, taken, exposure.as_millis());
+        taken += 1;
+        frame.save(&path).unwrap();
+        preview::Preview::thumbnail().write_for(frame, &path).unwrap();
+        let mut fields = vec![
+            ("IMAGETYP".to_owned(), frame_type.imagetyp().to_owned()),
+            ("EXPTIME".to_owned(), format!("{:.6}", exposure.as_secs_f64()))
+        ];
+        if let Some(stats) = frame.stats.as_ref() {
+            fields.extend(stats.log_fields());
+        }
+        if let Err(e) = log.record(&path, &fields) {
+            println!("Failed to record {} in the session log: {}", path, e);
+        }
+    });
+    match result {
+        Ok(run) => {
+            println!(
+                "Took {} flats ({} dropped) and {} dark-flats over a bias of {:.1}",
+                run.exposures.len(), run.rejected, run.dark_flats, run.bias
+            );
+            if run.light_ran_out {
+                println!("The light ran out before all {} flats were taken", count);
+            }
+        }
+        Err(e) => println!("Flats failed: {:?}", e)
+    }
+}
+
+/// Dithering between lights: `amount` is the most PHD2 may move the guide star, in guide
+/// camera pixels.
+struct Dithering {
+    guider: phd2::Guider,
+    amount: f64,
+    ra_only: bool,
+    settle: phd2::Settle
+}
+
+/// Take `count` lights, dithering between them if `dithering` is given, and record them in the
+/// session log.
+fn take_light_images<C: imaging::Imager>(camera: &mut C, count: u32, path_fragment: &str, mut dithering: Option<&mut Dithering>, preview: &preview::Preview) {
+    let mut log = SessionLog::open("session.log").unwrap();
+    for i in 0..count {
+        if let Some(dithering) = dithering.as_mut().filter(|_| i > 0) {
+            println!("Dithering by up to {} pixels...", dithering.amount);
+            let result = dithering.guider.dither(dithering.amount, dithering.ra_only, &dithering.settle);
+            for notice in dithering.guider.take_notices() {
+                println!("PHD2 {}", notice);
+            }
+            if let Err(e) = result {
+                println!("Dither did not settle ({:?}), continuing anyway", e);
+            }
+        }
+        println!("{} image {:06}", path_fragment, i);
+        let frame = camera.capture(FrameType::Light).unwrap();
+        let temp = frame.setup().temperature.map(|temp| temp.round() as i32).unwrap_or(0);
+        let path = format!("{}_{:06}_temp_{:03}.fits", path_fragment, i, temp);
+        frame.save(&path).unwrap();
+        preview.write_for(&frame, &path).unwrap();
+        let mut fields = vec![("IMAGETYP".to_owned(), FrameType::Light.imagetyp().to_owned())];
+        if let Some(exposure) = frame.exposure() {
+            fields.push(("EXPTIME".to_owned(), format!("{:.6}", exposure)));
+        }
+        if let Some(stats) = frame.stats.as_ref() {
+            fields.extend(stats.log_fields());
+        }
+        if let Err(e) = log.record(&path, &fields) {
+            println!("Failed to record {} in the session log: {}", path, e);
+        }
     }
 }
@@ -0,0 +1,455 @@
+// Client for PHD2's event server. PHD2 speaks newline-delimited JSON over TCP: it pushes
+// events (`AppState`, `GuideStep`, `SettleDone`, ...) unprompted and answers JSON-RPC
+// requests on the same socket, so responses have to be fished out from between events.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+pub const DEFAULT_PORT: u16 = 4400;
+
+/// How much longer than its own settle timeout PHD2 gets to say settling failed.
+const SETTLE_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum GuiderError {
+    Io(io::Error),
+    Protocol(String),
+    Rpc { code: i64, message: String },
+    SettleFailed(String),
+    Timeout
+}
+
+impl From<io::Error> for GuiderError {
+    fn from(err: io::Error) -> GuiderError {
+        match err.kind() {
+            io::ErrorKind::WouldBlock |
+            io::ErrorKind::TimedOut => GuiderError::Timeout,
+            _ => GuiderError::Io(err)
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, GuiderError>;
+
+/// Settling criteria handed to PHD2 along with `dither` and `guide`: guiding is settled once
+/// the error stays below `pixels` for `time` seconds, or fails after `timeout` seconds.
+#[derive(Debug, Copy, Clone)]
+pub struct Settle {
+    pub pixels: f64,
+    pub time: u32,
+    pub timeout: u32
+}
+
+impl Default for Settle {
+    fn default() -> Settle {
+        Settle {
+            pixels: 1.5,
+            time: 10,
+            timeout: 60
+        }
+    }
+}
+
+impl Settle {
+    fn to_json(self) -> Value {
+        json!({
+            "pixels": self.pixels,
+            "time": self.time,
+            "timeout": self.timeout
+        })
+    }
+}
+
+pub struct Guider {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    // bytes of a message that was only partially read when a read timed out
+    partial: Vec<u8>,
+    next_id: u64,
+    app_state: String,
+    settle_result: Option<std::result::Result<(), String>>,
+    /// Things PHD2 mentioned that the caller should hear about, see `take_notices`.
+    notices: Vec<String>,
+    /// How much longer than a `Settle`'s timeout to wait for PHD2's `SettleDone`.
+    pub settle_grace: Duration
+}
+
+/// Connect to the PHD2 instance `instance` (1 for the first) on `host`.
+pub fn connect(host: &str, instance: u16) -> Result<Guider> {
+    let port = instance.checked_sub(1).and_then(|offset| DEFAULT_PORT.checked_add(offset));
+    match port {
+        Some(port) => connect_to((host, port)),
+        None => Err(GuiderError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput, format!("there's no PHD2 instance {}, they're numbered from 1", instance)
+        )))
+    }
+}
+
+/// Connect to a PHD2 event server at `address`.
+pub fn connect_to<A: ToSocketAddrs>(address: A) -> Result<Guider> {
+    let stream = TcpStream::connect(address)?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut guider = Guider {
+        stream: stream,
+        reader: reader,
+        partial: Vec::new(),
+        next_id: 1,
+        app_state: "Unknown".to_owned(),
+        settle_result: None,
+        notices: Vec::new(),
+        settle_grace: SETTLE_GRACE
+    };
+    // PHD2 leads with `Version` and `AppState`, but ask anyway in case we raced it.
+    let state = guider.call("get_app_state", Value::Null)?;
+    if let Some(state) = state.as_str() {
+        guider.app_state = state.to_owned();
+    }
+    Ok(guider)
+}
+
+impl Guider {
+    /// The most recent application state PHD2 reported, e.g. "Guiding", "Paused", "Stopped".
+    pub fn app_state(&self) -> &str {
+        &self.app_state
+    }
+
+    pub fn is_guiding(&self) -> bool {
+        self.app_state == "Guiding" || self.app_state == "LostLock"
+    }
+
+    /// Alerts, lost stars and anything unexpected PHD2 sent since the last call, for the
+    /// caller to show or log.
+    pub fn take_notices(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.notices)
+    }
+
+    /// Start guiding (calibrating first if needed) and block until PHD2 reports settling.
+    pub fn guide(&mut self, settle: &Settle, recalibrate: bool) -> Result<()> {
+        self.settle_result = None;
+        self.call("guide", json!({
+            "settle": settle.to_json(),
+            "recalibrate": recalibrate
+        }))?;
+        self.wait_for_settle(settle)
+    }
+
+    /// Dither by up to `amount` pixels and block until guiding has settled again.
+    pub fn dither(&mut self, amount: f64, ra_only: bool, settle: &Settle) -> Result<()> {
+        self.settle_result = None;
+        self.call("dither", json!({
+            "amount": amount,
+            "raOnly": ra_only,
+            "settle": settle.to_json()
+        }))?;
+        self.wait_for_settle(settle)
+    }
+
+    /// Pause guiding entirely (including looping exposures), e.g. across a meridian flip or
+    /// filter change where the guide star is expected to move.
+    pub fn pause(&mut self) -> Result<()> {
+        self.call("set_paused", json!([true, "full"]))?;
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        self.call("set_paused", json!([false]))?;
+        Ok(())
+    }
+
+    pub fn is_paused(&mut self) -> Result<bool> {
+        self.call("get_paused", Value::Null)?
+            .as_bool()
+            .ok_or_else(|| GuiderError::Protocol("get_paused did not return a bool".to_owned()))
+    }
+
+    pub fn stop_capture(&mut self) -> Result<()> {
+        self.call("stop_capture", Value::Null)?;
+        Ok(())
+    }
+
+    fn wait_for_settle(&mut self, settle: &Settle) -> Result<()> {
+        // give PHD2 a little longer than its own timeout so its SettleDone (with the reason
+        // settling failed) arrives before we give up on it.
+        let deadline = Instant::now() + Duration::from_secs(settle.timeout as u64) + self.settle_grace;
+        loop {
+            if let Some(result) = self.settle_result.take() {
+                return result.map_err(GuiderError::SettleFailed);
+            }
+            let message = self.read_message(deadline)?;
+            self.handle_message(&message);
+        }
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut request = json!({
+            "method": method,
+            "id": id
+        });
+        if !params.is_null() {
+            request["params"] = params;
+        }
+        let mut line = request.to_string();
+        line.push_str("\r\n");
+        self.stream.write_all(line.as_bytes())?;
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            let message = self.read_message(deadline)?;
+            if message.get("jsonrpc").is_none() {
+                self.handle_message(&message);
+                continue;
+            }
+            if message["id"].as_u64() != Some(id) {
+                self.notices.push(format!("ignored a response to an unknown request: {}", message));
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                return Err(GuiderError::Rpc {
+                    code: error["code"].as_i64().unwrap_or(0),
+                    message: error["message"].as_str().unwrap_or("").to_owned()
+                });
+            }
+            return Ok(message["result"].clone());
+        }
+    }
+
+    fn handle_message(&mut self, message: &Value) {
+        let event = match message["Event"].as_str() {
+            Some(event) => event,
+            None => {
+                self.notices.push(format!("ignored an unexpected message: {}", message));
+                return;
+            }
+        };
+        match event {
+            "AppState" => {
+                if let Some(state) = message["State"].as_str() {
+                    self.app_state = state.to_owned();
+                }
+            }
+            "SettleDone" => {
+                self.settle_result = if message["Status"].as_i64() == Some(0) {
+                    Some(Ok(()))
+                } else {
+                    Some(Err(message["Error"].as_str().unwrap_or("unknown error").to_owned()))
+                };
+            }
+            "StartGuiding" | "GuideStep" | "SettleBegin" | "Settling" => {
+                self.app_state = "Guiding".to_owned();
+            }
+            "Paused" => { self.app_state = "Paused".to_owned(); }
+            "Resumed" => { self.app_state = "Guiding".to_owned(); }
+            "LoopingExposures" => { self.app_state = "Looping".to_owned(); }
+            "LoopingExposuresStopped" | "GuidingStopped" => { self.app_state = "Stopped".to_owned(); }
+            "StarLost" => {
+                self.notices.push(format!("lost the guide star: {}", message["Status"]));
+                self.app_state = "LostLock".to_owned();
+            }
+            "Alert" => {
+                self.notices.push(format!("alert: {}", message["Msg"].as_str().unwrap_or("")));
+            }
+            _ => {}
+        }
+    }
+
+    fn read_message(&mut self, deadline: Instant) -> Result<Value> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(GuiderError::Timeout);
+            }
+            self.stream.set_read_timeout(Some(deadline - now))?;
+            // on a timeout `read_until` leaves what it did read in `partial`, to be picked
+            // up by the next call.
+            let read = self.reader.read_until(b'\n', &mut self.partial)?;
+            if read == 0 {
+                return Err(GuiderError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof, "PHD2 closed the connection"
+                )));
+            }
+            let line = std::mem::take(&mut self.partial);
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            return serde_json::from_str(line)
+                .map_err(|e| GuiderError::Protocol(format!("bad message {:?}: {}", line, e)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    /// One client's worth of a scripted PHD2: sends the usual greeting, then hands each
+    /// request to `answer`, which returns the lines to send back.
+    fn fake_phd2<F>(mut answer: F) -> (std::net::SocketAddr, JoinHandle<()>)
+        where F: FnMut(&Value) -> Vec<Value> + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            let greeting = [
+                json!({"Event": "Version", "PHDVersion": "2.6.13", "MsgVersion": 1}),
+                json!({"Event": "AppState", "State": "Guiding"})
+            ];
+            for message in greeting.iter() {
+                writeln!(stream, "{}\r", message).unwrap();
+            }
+            for line in reader.lines() {
+                let request: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                for message in answer(&request) {
+                    writeln!(stream, "{}\r", message).unwrap();
+                }
+            }
+        });
+        (address, server)
+    }
+
+    fn result(request: &Value, result: Value) -> Value {
+        json!({"jsonrpc": "2.0", "result": result, "id": request["id"]})
+    }
+
+    fn get_app_state(request: &Value) -> Option<Vec<Value>> {
+        if request["method"] == "get_app_state" {
+            Some(vec![result(request, json!("Guiding"))])
+        } else {
+            None
+        }
+    }
+
+    fn quick_settle() -> Settle {
+        Settle { pixels: 1.5, time: 1, timeout: 0 }
+    }
+
+    #[test]
+    fn instance_zero_is_rejected() {
+        match connect("127.0.0.1", 0) {
+            Err(GuiderError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("connected to instance 0")
+        }
+    }
+
+    #[test]
+    fn dither_waits_for_settle_done() {
+        let (address, server) = fake_phd2(|request| {
+            if let Some(reply) = get_app_state(request) {
+                return reply;
+            }
+            assert_eq!(request["method"], "dither");
+            assert_eq!(request["params"]["amount"], 3.0);
+            assert_eq!(request["params"]["raOnly"], true);
+            vec![
+                result(request, json!(0)),
+                json!({"Event": "SettleBegin"}),
+                json!({"Event": "Alert", "Msg": "star mass changed", "Type": "warning"}),
+                json!({"Event": "Settling", "Distance": 0.8}),
+                json!({"Event": "SettleDone", "Status": 0, "TotalFrames": 4, "DroppedFrames": 0})
+            ]
+        });
+        let mut guider = connect_to(address).unwrap();
+        assert!(guider.is_guiding());
+        guider.dither(3.0, true, &quick_settle()).unwrap();
+        assert_eq!(guider.take_notices(), vec!["alert: star mass changed".to_owned()]);
+        assert!(guider.take_notices().is_empty());
+        drop(guider);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn failed_settle_reports_why() {
+        let (address, server) = fake_phd2(|request| {
+            if let Some(reply) = get_app_state(request) {
+                return reply;
+            }
+            vec![
+                result(request, json!(0)),
+                json!({"Event": "StarLost", "Status": -1}),
+                json!({"Event": "SettleDone", "Status": 1, "Error": "timed-out waiting for guider to settle"})
+            ]
+        });
+        let mut guider = connect_to(address).unwrap();
+        match guider.dither(5.0, false, &quick_settle()) {
+            Err(GuiderError::SettleFailed(reason)) => assert_eq!(reason, "timed-out waiting for guider to settle"),
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert_eq!(guider.app_state(), "LostLock");
+        drop(guider);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn missing_settle_done_times_out() {
+        let (address, server) = fake_phd2(|request| {
+            if let Some(reply) = get_app_state(request) {
+                return reply;
+            }
+            vec![result(request, json!(0)), json!({"Event": "SettleBegin"})]
+        });
+        let mut guider = connect_to(address).unwrap();
+        guider.settle_grace = Duration::from_millis(200);
+        let started = Instant::now();
+        match guider.dither(5.0, false, &quick_settle()) {
+            Err(GuiderError::Timeout) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(guider);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn error_replies_become_rpc_errors() {
+        let (address, server) = fake_phd2(|request| {
+            if let Some(reply) = get_app_state(request) {
+                return reply;
+            }
+            vec![
+                json!({"jsonrpc": "2.0", "error": {"code": 1, "message": "cannot dither when not guiding"}, "id": request["id"]})
+            ]
+        });
+        let mut guider = connect_to(address).unwrap();
+        match guider.dither(5.0, false, &quick_settle()) {
+            Err(GuiderError::Rpc { code, message }) => {
+                assert_eq!(code, 1);
+                assert_eq!(message, "cannot dither when not guiding");
+            }
+            other => panic!("unexpected result {:?}", other)
+        }
+        drop(guider);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn stray_responses_are_noticed_and_skipped() {
+        let (address, server) = fake_phd2(|request| {
+            if let Some(reply) = get_app_state(request) {
+                return reply;
+            }
+            vec![
+                json!({"jsonrpc": "2.0", "result": 0, "id": 999}),
+                json!({"Event": "Paused"}),
+                result(request, json!(true))
+            ]
+        });
+        let mut guider = connect_to(address).unwrap();
+        assert!(guider.is_paused().unwrap());
+        assert_eq!(guider.app_state(), "Paused");
+        assert_eq!(guider.take_notices().len(), 1);
+        drop(guider);
+        server.join().unwrap();
+    }
+}
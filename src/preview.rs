@@ -0,0 +1,319 @@
+// 8-bit previews of raw frames and stacks, so the session directory can be checked with an
+// image viewer instead of a FITS viewer: linear raw data is nearly all black on a screen.
+//
+// The stretch is the screen transfer function PixInsight's auto-stretch uses. The shadows
+// are clipped a few MADs below the sky's median, and a midtones transfer function is picked
+// that puts the median at a fixed background brightness, which brings up faint signal
+// without blowing out the stars.
+//
+// Previews are png, or JPEG for a session that will be looked through over a slow connection.
+
+use crate::frame::{Frame, JPEG_QUALITY};
+use crate::image::{self, Image};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// MAD * this estimates sigma for Gaussian noise.
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+/// Previews written next to raw frames are no larger than this on either side.
+pub const THUMBNAIL_SIZE: u32 = 800;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Downsample {
+    /// Average blocks of this many pixels on a side; 1 leaves the image as it is.
+    Factor(u32),
+    /// The smallest factor that gets both sides down to this many pixels or fewer.
+    Fit(u32)
+}
+
+impl Downsample {
+    fn factor(&self, width: u32, height: u32) -> u32 {
+        match *self {
+            Downsample::Factor(factor) => factor.max(1),
+            Downsample::Fit(size) => {
+                let size = size.max(1);
+                width.max(height).div_ceil(size)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Png,
+    /// At this quality, from 1 to 100.
+    Jpeg(u8)
+}
+
+impl Format {
+    /// JPEG for a `.jpg` or `.jpeg` path, png for anything else.
+    pub fn for_path(path: &Path) -> Format {
+        let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("jpg") | Some("jpeg") => Format::Jpeg(JPEG_QUALITY),
+            _ => Format::Png
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg(_) => "jpg"
+        }
+    }
+
+    /// Write a rendered preview into `w`.
+    pub fn encode<W: Write>(&self, rendered: &Frame, w: W) -> io::Result<()> {
+        match *self {
+            Format::Png => rendered.encode_png(w),
+            Format::Jpeg(quality) => rendered.encode_jpeg(w, quality)
+        }
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, rendered: &Frame, path: P) -> io::Result<()> {
+        self.encode(rendered, BufWriter::new(File::create(path)?))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Preview {
+    /// Where the shadows are clipped, in sigmas from the median: negative is below it.
+    pub shadows_clip: f32,
+    /// How bright the median comes out, from 0 to 1.
+    pub target_background: f32,
+    /// Stretch a color image's channels together, keeping its color balance, or each on its
+    /// own, which makes the sky neutral grey whatever the light pollution.
+    pub linked: bool,
+    /// Make raw Bayer frames color, rather than showing them grey with the pattern in.
+    pub debayer: bool,
+    pub downsample: Downsample,
+    /// What `write_for` writes.
+    pub format: Format
+}
+
+impl Default for Preview {
+    fn default() -> Preview {
+        Preview {
+            shadows_clip: -2.8,
+            target_background: 0.25,
+            linked: true,
+            debayer: true,
+            downsample: Downsample::Factor(1),
+            format: Format::Png
+        }
+    }
+}
+
+/// The midtones transfer function: 0 and 1 stay put and `midtones` goes to 0.5.
+fn mtf(x: f32, midtones: f32) -> f32 {
+    if x <= 0.0 {
+        0.0
+    } else if x >= 1.0 {
+        1.0
+    } else {
+        (midtones - 1.0) * x / ((2.0 * midtones - 1.0) * x - midtones)
+    }
+}
+
+/// The shadows clipping point and midtones balance for one channel, or several linked.
+#[derive(Debug, Copy, Clone)]
+struct Stf {
+    shadows: f32,
+    midtones: f32
+}
+
+impl Stf {
+    fn apply(&self, x: f32) -> f32 {
+        let clipped = ((x - self.shadows) / (1.0 - self.shadows)).clamp(0.0, 1.0);
+        mtf(clipped, self.midtones)
+    }
+}
+
+/// The median and its MAD as sigma, of the samples that aren't NaN.
+fn median_and_noise(values: &mut Vec<f32>) -> (f32, f32) {
+    values.retain(|v| !v.is_nan());
+    let median = image::median(values);
+    for value in values.iter_mut() {
+        *value = (*value - median).abs();
+    }
+    (median, image::median(values) * MAD_TO_SIGMA)
+}
+
+/// Where a preview of the frame at `path` goes: `light_000001.fits` gets
+/// `light_000001_preview.png`, or `.jpg` for a JPEG.
+pub fn path_for(path: &Path, format: Format) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}_preview.{}", stem, format.extension()))
+}
+
+impl Preview {
+    /// Settings for the previews written next to raw frames.
+    pub fn thumbnail() -> Preview {
+        Preview { downsample: Downsample::Fit(THUMBNAIL_SIZE), ..Preview::default() }
+    }
+
+    /// A preview of a frame off the camera, with its full scale as white.
+    pub fn render_frame(&self, frame: &Frame) -> Frame {
+        self.render_scaled(&Image::from_frame(frame), frame.full_scale() as f32)
+    }
+
+    /// A preview of a calibrated or stacked image, with its brightest sample as white.
+    pub fn render(&self, image: &Image) -> Frame {
+        let white = image.data.iter().cloned().filter(|v| !v.is_nan()).fold(0.0, f32::max);
+        self.render_scaled(image, white)
+    }
+
+    fn render_scaled(&self, image: &Image, white: f32) -> Frame {
+        let image = if self.debayer { image.debayer() } else { image.clone() };
+        let factor = self.downsample.factor(image.width, image.height);
+        let mut image = image.downsample(factor);
+        let white = white.max(f32::EPSILON);
+        for value in image.data.iter_mut() {
+            *value = (*value / white).clamp(0.0, 1.0);
+        }
+
+        let channels = image.channels as usize;
+        let measured: Vec<(f32, f32)> = (0..channels).map(|channel| {
+            let mut values: Vec<f32> = image.data.iter().skip(channel).step_by(channels).cloned().collect();
+            median_and_noise(&mut values)
+        }).collect();
+        let stfs: Vec<Stf> = if self.linked {
+            let median = measured.iter().map(|(median, _)| median).sum::<f32>() / channels as f32;
+            let noise = measured.iter().map(|(_, noise)| noise).sum::<f32>() / channels as f32;
+            vec![self.stf(median, noise); channels]
+        } else {
+            measured.iter().map(|(median, noise)| self.stf(*median, *noise)).collect()
+        };
+
+        let bytes: Vec<u8> = image.data.iter().enumerate().map(|(i, value)| {
+            if value.is_nan() {
+                0
+            } else {
+                (stfs[i % channels].apply(*value) * 255.0).round() as u8
+            }
+        }).collect();
+        Frame::from_bytes(image.width, image.height, image.channels, 8, &bytes)
+    }
+
+    fn stf(&self, median: f32, noise: f32) -> Stf {
+        // never clip past the median, or there would be no sky left to put at the background
+        let shadows = (median + self.shadows_clip * noise).clamp(0.0, median.min(0.999));
+        let background = (median - shadows) / (1.0 - shadows);
+        let midtones = if background > 0.0 { mtf(background, self.target_background) } else { 0.5 };
+        Stf { shadows: shadows, midtones: midtones }
+    }
+
+    /// Write a preview of `frame` next to where it was saved at `path`.
+    pub fn write_for<P: AsRef<Path>>(&self, frame: &Frame, path: P) -> io::Result<PathBuf> {
+        let preview = path_for(path.as_ref(), self.format);
+        self.format.write(&self.render_frame(frame), &preview)?;
+        Ok(preview)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_midtones_balance_goes_to_half_and_the_ends_stay_put() {
+        for midtones in [0.01, 0.1, 0.25, 0.5, 0.9].iter() {
+            assert!((mtf(*midtones, *midtones) - 0.5).abs() < 1e-6);
+            assert_eq!(mtf(0.0, *midtones), 0.0);
+            assert_eq!(mtf(1.0, *midtones), 1.0);
+        }
+        assert!((mtf(0.3, 0.5) - 0.3).abs() < 1e-6);
+        // the stretch that puts a background of 0.02 at 0.25 takes it there
+        let midtones = mtf(0.02, 0.25);
+        assert!((mtf(0.02, midtones) - 0.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn downsampling_fits_the_longer_side() {
+        assert_eq!(Downsample::Fit(800).factor(640, 480), 1);
+        assert_eq!(Downsample::Fit(800).factor(6248, 4176), 8);
+        assert_eq!(Downsample::Fit(200).factor(480, 640), 4);
+        assert_eq!(Downsample::Factor(0).factor(640, 480), 1);
+        assert_eq!(path_for(Path::new("dir/light_000001.fits"), Format::Png), Path::new("dir/light_000001_preview.png"));
+        assert_eq!(path_for(Path::new("light.fits"), Format::Jpeg(90)), Path::new("light_preview.jpg"));
+        assert_eq!(Format::for_path(Path::new("stack.JPEG")), Format::Jpeg(JPEG_QUALITY));
+        assert_eq!(Format::for_path(Path::new("stack.png")), Format::Png);
+    }
+
+    #[cfg(feature = "sim")]
+    mod sim {
+        use super::super::*;
+        use crate::frame::{Cfa, FrameType};
+        use crate::sim::Camera;
+        use crate::testing::TempDir;
+
+        use std::fs;
+
+        /// A light from the simulated camera as if it had a color sensor under a sky brighter
+        /// through the green filters.
+        fn green_sky() -> Frame {
+            let mut camera = Camera::new(640, 480);
+            camera.set_exposure_ms(10000);
+            let mut frame = camera.capture(FrameType::Light);
+            let cfa = Cfa::parse("RGGB").unwrap();
+            frame.cfa = Some(cfa);
+            for y in 0..frame.height {
+                for x in 0..frame.width {
+                    let at = (y * frame.width + x) as usize;
+                    let extra = [100, 300, 50][cfa.color_at(x, y)];
+                    frame.data[at] = frame.data[at].saturating_add(extra);
+                }
+            }
+            frame
+        }
+
+        /// The median of each channel of a rendered preview.
+        fn sky(rendered: &Frame) -> Vec<f32> {
+            let channels = rendered.channels as usize;
+            (0..channels).map(|channel| {
+                let mut values: Vec<f32> = rendered.data.iter().skip(channel).step_by(channels).map(|v| *v as f32).collect();
+                image::median(&mut values)
+            }).collect()
+        }
+
+        #[test]
+        fn linked_stretches_keep_the_skys_color_and_unlinked_ones_neutralize_it() {
+            let frame = green_sky();
+            let linked = Preview::default().render_frame(&frame);
+            assert_eq!((linked.width, linked.height, linked.channels, linked.bpp), (640, 480, 3, 8));
+            let color = sky(&linked);
+            assert!(color[1] > color[0] + 50.0 && color[1] > color[2] + 50.0, "linked sky is {:?}", color);
+
+            let unlinked = Preview { linked: false, ..Preview::default() }.render_frame(&frame);
+            let neutral = sky(&unlinked);
+            // each channel's sky lands on the target background
+            for value in neutral.iter() {
+                assert!((value - 0.25 * 255.0).abs() < 3.0, "unlinked sky is {:?}", neutral);
+            }
+
+            // left as a Bayer frame it's grey, with the pattern in it
+            let raw = Preview { debayer: false, ..Preview::default() }.render_frame(&frame);
+            assert_eq!(raw.channels, 1);
+        }
+
+        #[test]
+        fn thumbnails_are_written_next_to_frames_as_png_or_jpeg() {
+            let dir = TempDir::new("preview");
+            let frame = green_sky();
+            let path = dir.join("light_000001.fits");
+            for (format, magic) in [(Format::Png, &b"\x89PNG"[..]), (Format::Jpeg(80), &b"\xff\xd8"[..])].iter() {
+                let thumbnail = Preview { downsample: Downsample::Fit(200), format: *format, ..Preview::thumbnail() };
+                let written = thumbnail.write_for(&frame, &path).unwrap();
+                assert_eq!(written, path_for(&path, *format));
+                assert!(fs::read(&written).unwrap().starts_with(magic), "{} isn't what it says", written.display());
+                let small = thumbnail.render_frame(&frame);
+                assert_eq!((small.width, small.height), (160, 120));
+            }
+            let (info, _) = png::Decoder::new(File::open(dir.join("light_000001_preview.png")).unwrap()).read_info().unwrap();
+            assert_eq!((info.width, info.height), (160, 120));
+        }
+    }
+}
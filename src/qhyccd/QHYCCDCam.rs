@@ -1,3 +1,5 @@
+use crate::dynlib::dynamic_library;
+
 use std::os;
 
 #[repr(u32)]
@@ -13,12 +15,12 @@ impl From<u32> for QHYResult {
     fn from(u: u32) -> QHYResult {
         match u {
             0 => QHYResult::QHYCCD_SUCCESS,
-            0x2000 => QHYResult::QHYCCD_READ_DIRECTLY,
-            0x2001 => QHYResult::QHYCCD_DELAY_200MS,
+            0x2000 => QHYResult::QHYCCD_DELAY_200MS,
+            0x2001 => QHYResult::QHYCCD_READ_DIRECTLY,
             0xffffffff => QHYResult::QHYCCD_ERROR,
-            _ => {
-                panic!("Unexpected result code from qhy sdk: {:08x}", u);
-            }
+            // an unplugged camera returns all sorts, and that's no reason to bring down the
+            // whole process
+            _ => QHYResult::QHYCCD_ERROR
         }
     }
 }
@@ -84,6 +86,41 @@ pub enum Control {
     OutputDataAlignment = 55                        //55
 }
 
+/// How a trigger capable camera starts exposures, for `SetQHYCCDTrigerMode`. The modes past
+/// plain trigger-in differ from model to model, so they're only known by number.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TriggerMode {
+    /// Expose when the trigger-in line fires; every trigger capable camera has it.
+    TriggerIn,
+    /// A model-specific mode, numbered as the SDK numbers them.
+    Model(u32)
+}
+
+impl TriggerMode {
+    pub fn raw(self) -> os::raw::c_uint {
+        match self {
+            TriggerMode::TriggerIn => 0,
+            TriggerMode::Model(mode) => mode
+        }
+    }
+
+    /// `in` for plain trigger-in, or a model-specific mode's number.
+    pub fn from_name(name: &str) -> Option<TriggerMode> {
+        match name {
+            "in" => Some(TriggerMode::TriggerIn),
+            number => match number.parse() {
+                Ok(0) => Some(TriggerMode::TriggerIn),
+                Ok(mode) => Some(TriggerMode::Model(mode)),
+                Err(_) => None
+            }
+        }
+    }
+}
+
+pub const MACHANICALSHUTTER_OPEN: u8 = 0;
+pub const MACHANICALSHUTTER_CLOSE: u8 = 1;
+pub const MACHANICALSHUTTER_FREE: u8 = 2;
+
 #[repr(u32)]
 #[derive(Copy, Clone, Debug)]
 pub enum Bayer
@@ -95,10 +132,16 @@ pub enum Bayer
 }
 
 
-extern "C" {
+dynamic_library! {
+    "QHYCCD SDK";
+    // the GPS board's tuning only came in with the QHY174-GPS
+    optional: [
+        SetQHYCCDGPSVCOXFreq, SetQHYCCDGPSLedCalMode, SetQHYCCDGPSLedCal, SetQHYCCDGPSPOSA,
+        SetQHYCCDGPSPOSB, SetQHYCCDGPSMasterSlave, SetQHYCCDGPSSlaveModeParameter
+    ];
     pub fn ScanQHYCCD() -> os::raw::c_int;
     pub fn InitQHYCCDResource() -> os::raw::c_int;
-    pub fn GetQHYCCDId(id: os::raw::c_int, id: *mut os::raw::c_char) -> os::raw::c_int;
+    pub fn GetQHYCCDId(index: os::raw::c_int, id: *mut os::raw::c_char) -> os::raw::c_int;
     pub fn GetQHYCCDModel(id: *mut os::raw::c_char, model: *mut os::raw::c_char) -> os::raw::c_int;
     pub fn OpenQHYCCD(id: *mut os::raw::c_char) -> *mut os::raw::c_void;
     pub fn SetQHYCCDStreamMode(handle: *mut os::raw::c_void, mode: os::raw::c_char) -> os::raw::c_int;
@@ -107,6 +150,7 @@ extern "C" {
     pub fn IsQHYCCDControlAvailable(handle: *mut os::raw::c_void, control: os::raw::c_int) -> os::raw::c_int;
     pub fn SetQHYCCDParam(handle: *mut os::raw::c_void, control: os::raw::c_int, value: os::raw::c_double) -> os::raw::c_int;
     pub fn GetQHYCCDParam(handle: *mut os::raw::c_void, control: os::raw::c_int) -> os::raw::c_double;
+    pub fn GetQHYCCDParamMinMaxStep(handle: *mut os::raw::c_void, control: os::raw::c_int, min: *mut os::raw::c_double, max: *mut os::raw::c_double, step: *mut os::raw::c_double) -> os::raw::c_int;
     pub fn GetQHYCCDEffectiveArea(handle: *mut os::raw::c_void, startx: *mut os::raw::c_int, starty: *mut os::raw::c_int, sizex: *mut os::raw::c_int, sizey: *mut os::raw::c_int) -> os::raw::c_int;
     pub fn GetQHYCCDOverScanArea(handle: *mut os::raw::c_void, startx: *mut os::raw::c_int, starty: *mut os::raw::c_int, sizex: *mut os::raw::c_int, sizey: *mut os::raw::c_int) -> os::raw::c_int;
     pub fn GetQHYCCDChipInfo(
@@ -124,6 +168,20 @@ extern "C" {
     pub fn GetQHYCCDExposureRemaining(handle: *mut os::raw::c_void) -> os::raw::c_uint;
     pub fn GetQHYCCDMemLength(handle: *mut os::raw::c_void) -> os::raw::c_int;
     pub fn GetQHYCCDSingleFrame(handle: *mut os::raw::c_void, w: *mut os::raw::c_int, h: *mut os::raw::c_int, bpp: *mut os::raw::c_int, channels: *mut os::raw::c_int, data: *mut os::raw::c_uchar) -> os::raw::c_int;
+    pub fn IsQHYCCDCFWPlugged(handle: *mut os::raw::c_void) -> os::raw::c_int;
+    pub fn SendOrder2QHYCCDCFW(handle: *mut os::raw::c_void, order: *mut os::raw::c_char, length: os::raw::c_uint) -> os::raw::c_int;
+    pub fn GetQHYCCDCFWStatus(handle: *mut os::raw::c_void, status: *mut os::raw::c_char) -> os::raw::c_int;
+    pub fn SetQHYCCDTrigerMode(handle: *mut os::raw::c_void, mode: os::raw::c_uint) -> os::raw::c_int;
+    pub fn SetQHYCCDTrigerFunction(handle: *mut os::raw::c_void, value: bool) -> os::raw::c_int;
+    pub fn SetQHYCCDGPSVCOXFreq(handle: *mut os::raw::c_void, i: u16) -> os::raw::c_int;
+    pub fn SetQHYCCDGPSLedCalMode(handle: *mut os::raw::c_void, i: u8) -> os::raw::c_int;
+    pub fn SetQHYCCDGPSLedCal(handle: *mut os::raw::c_void, pos: os::raw::c_uint, width: u8);
+    pub fn SetQHYCCDGPSPOSA(handle: *mut os::raw::c_void, is_slave: u8, pos: os::raw::c_uint, width: u8);
+    pub fn SetQHYCCDGPSPOSB(handle: *mut os::raw::c_void, is_slave: u8, pos: os::raw::c_uint, width: u8);
+    pub fn SetQHYCCDGPSMasterSlave(handle: *mut os::raw::c_void, i: u8) -> os::raw::c_int;
+    pub fn SetQHYCCDGPSSlaveModeParameter(handle: *mut os::raw::c_void, target_sec: os::raw::c_uint, target_us: os::raw::c_uint, deltaT_sec: os::raw::c_uint, deltaT_us: os::raw::c_uint, expTime: os::raw::c_uint);
+    pub fn ControlQHYCCDShutter(handle: *mut os::raw::c_void, status: u8) -> os::raw::c_int;
+    pub fn GetQHYCCDShutterStatus(handle: *mut os::raw::c_void) -> os::raw::c_int;
     pub fn CloseQHYCCD(handle: *mut os::raw::c_void) -> os::raw::c_int;
     pub fn ReleaseQHYCCDResource() -> os::raw::c_int;
 }
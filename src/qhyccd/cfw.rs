@@ -0,0 +1,235 @@
+use super::{Camera, CameraError, Result};
+use crate::sequence::FilterChanger;
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The wheel is sent to a slot with a single hex digit, as QHY's own software does for their
+/// bigger wheels, so no more slots than this can be named.
+pub const MAX_SLOTS: u32 = 16;
+
+/// The order that sends the wheel to `slot`, or `None` past the slots a digit can name.
+pub(super) fn slot_order(slot: u32) -> Option<u8> {
+    std::char::from_digit(slot, MAX_SLOTS).map(|digit| digit.to_ascii_uppercase() as u8)
+}
+
+/// The slot in the first byte of a CFW status reply, or `None` for the `N` it sends while the
+/// wheel is turning.
+pub(super) fn reported_slot(status: u8) -> Option<u32> {
+    (status as char).to_digit(MAX_SLOTS)
+}
+
+/// A color filter wheel hanging off the CFW port of a QHY camera, with optional names for
+/// each slot so sequences can ask for "Ha" rather than slot 4. It's driven through the
+/// camera, which is handed to each call so the camera can still be reopened underneath it.
+pub struct FilterWheel {
+    slots: u32,
+    names: Vec<String>
+}
+
+impl FilterWheel {
+    pub fn new(camera: &Camera) -> Result<FilterWheel> {
+        if !camera.has_filter_wheel() {
+            return Err(CameraError::NoFilterWheel);
+        }
+        let slots = camera.filter_slot_count()?;
+        Ok(FilterWheel {
+            slots: slots,
+            names: (0..slots).map(|slot| format!("slot{}", slot)).collect()
+        })
+    }
+
+    /// Name slots from a filter config file, see `parse_filter_names`. Slots the file doesn't
+    /// mention keep their default `slotN` names.
+    pub fn load_names<P: AsRef<Path>>(&mut self, path: P) -> std::result::Result<(), String> {
+        let text = fs::read_to_string(path.as_ref())
+            .map_err(|e| format!("can't read {}: {}", path.as_ref().display(), e))?;
+        self.names = rename_slots(&self.names, parse_filter_names(&text)?)?;
+        Ok(())
+    }
+
+    pub fn slot_count(&self) -> u32 {
+        self.slots
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn slot_of(&self, name: &str) -> Option<u32> {
+        self.names.iter().position(|n| n == name).map(|slot| slot as u32)
+    }
+
+    pub fn name_of(&self, slot: u32) -> Option<&str> {
+        self.names.get(slot as usize).map(|name| name.as_str())
+    }
+
+    /// The slot the wheel is in, or `None` while it is moving.
+    pub fn position(&self, camera: &Camera) -> Result<Option<u32>> {
+        camera.filter_position()
+    }
+
+    /// The name of the filter in the light path, or `None` while the wheel is moving.
+    pub fn current_filter(&self, camera: &Camera) -> Result<Option<&str>> {
+        Ok(self.position(camera)?.and_then(|slot| self.name_of(slot)))
+    }
+
+    pub fn move_to(&self, camera: &Camera, slot: u32, timeout: Duration) -> Result<()> {
+        if self.position(camera)? == Some(slot) {
+            return Ok(());
+        }
+        println!("Moving filter wheel to slot {} ({})", slot, self.name_of(slot).unwrap_or("?"));
+        camera.move_filter_wheel(slot)?;
+        self.wait_for_position(camera, slot, timeout)
+    }
+
+    pub fn move_to_filter(&self, camera: &Camera, name: &str, timeout: Duration) -> Result<()> {
+        match self.slot_of(name) {
+            Some(slot) => self.move_to(camera, slot, timeout),
+            None => Err(CameraError::InvalidFilter)
+        }
+    }
+
+    fn wait_for_position(&self, camera: &Camera, slot: u32, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        // sleep before the first poll; the wheel can report its old position for a moment
+        // after taking the order.
+        while Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(250));
+            match self.position(camera)? {
+                Some(pos) if pos == slot => { return Ok(()); }
+                _ => {}
+            }
+        }
+        Err(CameraError::Timeout)
+    }
+}
+
+impl FilterChanger<Camera> for FilterWheel {
+    type Error = CameraError;
+
+    fn change_to(&mut self, camera: &mut Camera, name: &str) -> Result<()> {
+        self.move_to_filter(camera, name, Duration::from_secs(30))
+    }
+}
+
+/// `names` with the slots in `renamed` given their new names, as long as every slot is on the
+/// wheel and no two slots end up with the same name, a default `slotN` one included: a
+/// sequence asking for that name would get whichever came first.
+fn rename_slots(names: &[String], renamed: Vec<(u32, String)>) -> std::result::Result<Vec<String>, String> {
+    let mut names = names.to_vec();
+    for (slot, name) in renamed {
+        if slot as usize >= names.len() {
+            return Err(format!("filter {} is in slot {}, but the wheel only has {} slots", name, slot, names.len()));
+        }
+        names[slot as usize] = name;
+    }
+    for (slot, name) in names.iter().enumerate() {
+        if let Some(other) = names[..slot].iter().position(|n| n == name) {
+            return Err(format!("slots {} and {} are both named {}", other, slot, name));
+        }
+    }
+    Ok(names)
+}
+
+/// Parse filter names from text with one `<slot> <name>` pair per line, `#` starting a
+/// comment, e.g.:
+///
+/// ```text
+/// # slot  filter
+/// 0       L
+/// 1       R
+/// 4       Ha
+/// ```
+pub fn parse_filter_names(text: &str) -> std::result::Result<Vec<(u32, String)>, String> {
+    let mut names: Vec<(u32, String)> = Vec::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(idx) => &line[..idx],
+            None => line
+        }.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let slot = parts.next().unwrap();
+        let name = match parts.next() {
+            Some(name) => name,
+            None => { return Err(format!("line {}: expected `<slot> <name>`", lineno + 1)); }
+        };
+        if parts.next().is_some() {
+            return Err(format!("line {}: filter names can't contain spaces", lineno + 1));
+        }
+        let slot: u32 = slot.parse()
+            .map_err(|_| format!("line {}: bad slot number {:?}", lineno + 1, slot))?;
+        if names.iter().any(|(_, n)| n == name) {
+            return Err(format!("line {}: filter {} is named twice", lineno + 1, name));
+        }
+        names.push((slot, name.to_owned()));
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults(slots: u32) -> Vec<String> {
+        (0..slots).map(|slot| format!("slot{}", slot)).collect()
+    }
+
+    #[test]
+    fn names_are_read_around_comments_and_blank_lines() {
+        let text = "# slot  filter\n\n0 L   # luminance\n  1\tR\n\n4 Ha\n";
+        assert_eq!(
+            parse_filter_names(text).unwrap(),
+            vec![(0, "L".to_owned()), (1, "R".to_owned()), (4, "Ha".to_owned())]
+        );
+        assert_eq!(parse_filter_names("# nothing named\n\n").unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn bad_lines_are_reported_by_number() {
+        let error = parse_filter_names("0 L\n1\n").unwrap_err();
+        assert!(error.starts_with("line 2:"), "{}", error);
+        let error = parse_filter_names("0 L\n1 O III\n").unwrap_err();
+        assert!(error.contains("line 2") && error.contains("spaces"), "{}", error);
+        let error = parse_filter_names("x L\n").unwrap_err();
+        assert!(error.contains("line 1") && error.contains("bad slot"), "{}", error);
+        assert!(parse_filter_names("-1 L\n").is_err());
+        let error = parse_filter_names("0 Ha\n1 R\n2 Ha\n").unwrap_err();
+        assert!(error.contains("line 3") && error.contains("named twice"), "{}", error);
+    }
+
+    #[test]
+    fn slots_are_renamed_only_on_the_wheel_and_never_to_another_slots_name() {
+        let names = rename_slots(&defaults(5), vec![(0, "L".to_owned()), (4, "Ha".to_owned())]).unwrap();
+        assert_eq!(names, vec!["L", "slot1", "slot2", "slot3", "Ha"]);
+
+        let error = rename_slots(&defaults(5), vec![(5, "SII".to_owned())]).unwrap_err();
+        assert!(error.contains("slot 5") && error.contains("5 slots"), "{}", error);
+
+        // slot 0 keeps its default name, so slot 3 can't take it
+        let error = rename_slots(&defaults(5), vec![(3, "slot0".to_owned())]).unwrap_err();
+        assert!(error.contains("slots 0 and 3"), "{}", error);
+        // unless slot 0 is renamed too
+        let names = rename_slots(&defaults(5), vec![(3, "slot0".to_owned()), (0, "L".to_owned())]).unwrap();
+        assert_eq!(names[3], "slot0");
+    }
+
+    #[test]
+    fn slots_past_nine_go_out_and_come_back_as_hex_digits() {
+        assert_eq!(slot_order(0), Some(b'0'));
+        assert_eq!(slot_order(9), Some(b'9'));
+        assert_eq!(slot_order(10), Some(b'A'));
+        assert_eq!(slot_order(15), Some(b'F'));
+        assert_eq!(slot_order(MAX_SLOTS), None);
+        for slot in 0..MAX_SLOTS {
+            assert_eq!(reported_slot(slot_order(slot).unwrap()), Some(slot));
+        }
+        assert_eq!(reported_slot(b'a'), Some(10));
+        assert_eq!(reported_slot(b'N'), None);
+        assert_eq!(reported_slot(0), None);
+    }
+}
@@ -1,45 +1,216 @@
 pub mod QHYCCDCam;
+pub mod cfw;
 
-pub use self::QHYCCDCam::Control;
+pub use self::QHYCCDCam::{Control, TriggerMode};
+pub use self::cfw::FilterWheel;
 
 use self::QHYCCDCam::*;
 
-use std::alloc::{alloc, dealloc, Layout};
-use std::collections::HashMap;
+use crate::dynlib::{self, LoadError};
+use crate::fits;
+use crate::frame::{Cfa, Frame, FrameType, HeaderValue, Setup, Shutter};
+use crate::gps::GpsHeader;
+use crate::hotplug::Presence;
+use crate::imaging::{Imager, Reopen, Subframe};
+use crate::preview::Preview;
+
+use std::cell::RefCell;
 use std::ffi::CStr;
 use std::os;
-use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
-
-use png::HasParameters;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Debug)]
 pub struct Camera {
-    handle: *mut os::raw::c_void
+    idx: i32,
+    /// The SDK's ID for the camera, its model and serial number.
+    pub id: String,
+    handle: *mut os::raw::c_void,
+    trigger_enabled: bool,
+    gps_enabled: bool,
+    /// The Bayer pattern of a color camera, for frames that aren't debayered.
+    bayer: Option<Cfa>,
+    settings: RefCell<Settings>,
+    presence: Presence
+}
+
+/// The sensor, as `GetQHYCCDChipInfo` describes it.
+#[derive(Debug, Copy, Clone)]
+pub struct ChipInfo {
+    /// Width and height in mm.
+    pub chip_size: (f64, f64),
+    /// Width and height in pixels.
+    pub image_size: (u32, u32),
+    /// Width and height in um.
+    pub pixel_size: (f64, f64),
+    /// Bits per pixel the SDK transfers at.
+    pub bpp: u32
+}
+
+/// What's been set through a `Camera`, so `reopen` can put it back.
+#[derive(Debug, Default, Clone)]
+struct Settings {
+    defaults: bool,
+    bin: Option<u8>,
+    /// Start and size of the region read out, in binned pixels.
+    resolution: Option<(u32, u32, u32, u32)>,
+    params: Vec<(Control, f64)>,
+    target_temp: Option<f64>,
+    trigger_mode: Option<TriggerMode>
 }
 
 #[derive(Debug, Copy, Clone)]
 pub enum CameraError {
     QHYError, // unspecified error from the qhy sdk
-    InvalidControl
+    InvalidControl,
+    NoFilterWheel,
+    InvalidFilter,
+    Timeout,
+    Removed,
+    SdkMissing
+}
+
+pub type Result<T> = std::result::Result<T, CameraError>;
+
+/// Call `read` every `interval` until it comes back with something or fails, or `timeout`
+/// passes, which gives `None`.
+fn poll<T, F>(timeout: Duration, interval: Duration, mut read: F) -> Result<Option<T>>
+    where F: FnMut() -> Result<Option<T>>
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(value) = read()? {
+            return Ok(Some(value));
+        }
+        if Instant::now() + interval > deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Keeps the shutter closed while it's alive and lets it go free again when dropped, so an
+/// error partway through a dark doesn't leave it shut.
+struct ShutterHeld<'a> {
+    camera: &'a Camera
 }
 
-type Result<T> = std::result::Result<T, CameraError>;
+impl<'a> Drop for ShutterHeld<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.camera.control_shutter(QHYCCDCam::MACHANICALSHUTTER_FREE) {
+            println!("Couldn't release the shutter: {:?}", e);
+        }
+    }
+}
+
+/// What a frame taken while the camera reported `status` saw of its shutter. A status that
+/// can't be read is taken as open, since a dark it spoils is better known about.
+fn shutter_state(status: Result<u8>) -> Shutter {
+    match status {
+        Ok(QHYCCDCam::MACHANICALSHUTTER_CLOSE) => Shutter::Closed,
+        _ => Shutter::Open
+    }
+}
 
 fn check(result: os::raw::c_int) -> Result<()> {
     match QHYResult::from(result as u32) {
         QHYResult::QHYCCD_SUCCESS => Ok(()),
         QHYResult::QHYCCD_ERROR => Err(CameraError::QHYError),
-        a @ _ => {
-            panic!("Unexpected result code from qhy sdk: {:?}", a);
+        a => {
+            println!("Unexpected result code from qhy sdk: {:?}", a);
+            Err(CameraError::QHYError)
         }
     }
 }
 
+/// Fail with `SdkMissing` if the loaded SDK is too old to have the optional function `name`.
+fn require(name: &str) -> Result<()> {
+    if QHYCCDCam::has(name) {
+        Ok(())
+    } else {
+        println!("The QHY SDK is too old to have {}", name);
+        Err(CameraError::SdkMissing)
+    }
+}
+
+/// The Bayer pattern `IsQHYCCDControlAvailable` gives for `Control::Color`, in place of
+/// success, or `None` for a mono camera.
+fn bayer_pattern(available: u32) -> Option<Cfa> {
+    match available {
+        1 => Some(Cfa::GBRG),
+        2 => Some(Cfa::GRBG),
+        3 => Some(Cfa::BGGR),
+        4 => Some(Cfa::RGGB),
+        _ => None
+    }
+}
+
+impl Imager for Camera {
+    type Error = CameraError;
+
+    fn set_exposure(&mut self, exposure: Duration) -> Result<()> {
+        self.set_param(Control::Exposure, exposure.as_micros() as f64)
+    }
+
+    fn set_gain(&mut self, gain: f64) -> Result<()> {
+        self.set_param(Control::Gain, gain)
+    }
+
+    fn exposure_range(&self) -> Result<(Duration, Duration)> {
+        let (min, max, _) = self.get_param_range(Control::Exposure)?;
+        Ok((Duration::from_micros(min as u64), Duration::from_micros(max as u64)))
+    }
+
+    fn set_subframe(&mut self, subframe: Option<Subframe>) -> Result<()> {
+        let bin = self.settings.borrow().bin.unwrap_or(1).max(1) as u32;
+        let subframe = match subframe {
+            Some(subframe) => subframe,
+            None => {
+                let (width, height) = self.get_dimensions()?.image_size;
+                Subframe { x: 0, y: 0, width: width, height: height }
+            }
+        };
+        self.set_resolution(subframe.x / bin, subframe.y / bin, subframe.width / bin, subframe.height / bin)
+    }
+
+    fn serial(&self) -> String {
+        self.id.clone()
+    }
+
+    fn binning(&self) -> u32 {
+        self.settings.borrow().bin.unwrap_or(1) as u32
+    }
+
+    fn capture(&mut self, frame_type: FrameType) -> Result<Frame> {
+        Camera::capture(self, frame_type)
+    }
+}
+
+impl Reopen for Camera {
+    fn presence(&self) -> Presence {
+        Camera::presence(self)
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        Camera::reopen(self)
+    }
+}
+
 static mut INITIALIZED: bool = false;
 
-pub fn acquire(camera_idx: i32) -> Result<Camera> {
+/// Load the QHY SDK, returning any functions it's missing. `acquire` does this if it hasn't
+/// been done already.
+pub fn load_sdk() -> std::result::Result<Vec<&'static str>, LoadError> {
+    // nothing's vendored for QHY, so there's no directory to fall back on
+    QHYCCDCam::load(&dynlib::candidates("QHY_SDK", None, &["libqhyccd.so", "libqhyccd.so.20"]))
+}
+
+/// Load the SDK and set it up if that hasn't been done yet, then scan for cameras, returning
+/// how many there are.
+fn scan() -> Result<i32> {
+    if let Err(e) = load_sdk() {
+        println!("QHY SDK: {}", e);
+        return Err(CameraError::SdkMissing);
+    }
     unsafe {
         if !INITIALIZED {
             println!("Initializing QHYCCDResource");
@@ -48,25 +219,62 @@ pub fn acquire(camera_idx: i32) -> Result<Camera> {
         }
         let cameracount = QHYCCDCam::ScanQHYCCD();
         println!("Detected {} cameras", cameracount);
-        if camera_idx >= cameracount {
-            panic!("Camera id is invalid (detected {} cameras)", cameracount);
-        }
+        Ok(cameracount)
+    }
+}
 
-        let mut id_space: [os::raw::c_char; 32] = [0; 32];
+/// The ID of the camera at `camera_idx` in the last scan: its model and serial number.
+fn id_at(camera_idx: i32) -> Result<[os::raw::c_char; 32]> {
+    let mut id_space: [os::raw::c_char; 32] = [0; 32];
+    unsafe {
         check(QHYCCDCam::GetQHYCCDId(camera_idx, id_space.as_mut_ptr()))?;
-        println!("Got camera id: {:?}", id_space);
-        println!("One sec, trying again...");
-        println!("How's this: {}", CStr::from_ptr(id_space.as_ptr()).to_str().unwrap());
+    }
+    Ok(id_space)
+}
+
+pub fn acquire(camera_idx: i32) -> Result<Camera> {
+    let cameracount = scan()?;
+    if camera_idx >= cameracount {
+        panic!("Camera id is invalid (detected {} cameras)", cameracount);
+    }
+    open(camera_idx, id_at(camera_idx)?)
+}
+
+/// Open the camera with the ID `id`, wherever it is in the scan. Indexes shift when cameras
+/// are plugged in or re-enumerate, so this is how to find one again.
+pub fn acquire_id(id: &str) -> Result<Camera> {
+    let cameracount = scan()?;
+    for camera_idx in 0..cameracount {
+        let id_space = id_at(camera_idx)?;
+        let found = unsafe { CStr::from_ptr(id_space.as_ptr()) };
+        if found.to_string_lossy() == id {
+            return open(camera_idx, id_space);
+        }
+    }
+    Err(CameraError::Removed)
+}
+
+fn open(camera_idx: i32, mut id_space: [os::raw::c_char; 32]) -> Result<Camera> {
+    unsafe {
+        println!("Opening {}", CStr::from_ptr(id_space.as_ptr()).to_string_lossy());
         let handle: *mut os::raw::c_void = QHYCCDCam::OpenQHYCCD(id_space.as_mut_ptr());
-        if handle == std::ptr::null_mut() {
+        if handle.is_null() {
             println!("Failed to open the device");
             return Err(CameraError::QHYError);
         }
         check(QHYCCDCam::SetQHYCCDStreamMode(handle, 0))?; // 0 means single frame mode...
         check(QHYCCDCam::InitQHYCCD(handle))?;
         check(QHYCCDCam::CancelQHYCCDExposingAndReadout(handle))?;
+        let bayer = bayer_pattern(QHYCCDCam::IsQHYCCDControlAvailable(handle, Control::Color as i32) as u32);
         Ok(Camera {
-            handle: handle
+            idx: camera_idx,
+            id: CStr::from_ptr(id_space.as_ptr()).to_string_lossy().into_owned(),
+            handle: handle,
+            trigger_enabled: false,
+            gps_enabled: false,
+            bayer: bayer,
+            settings: RefCell::new(Settings::default()),
+            presence: Presence::new()
         })
     }
 }
@@ -77,28 +285,26 @@ impl Camera {
     }
     pub fn set_target_temp(&self, temp: f64) -> Result<()> {
         unsafe {
-            check(QHYCCDCam::ControlQHYCCDTemp(self.handle, temp))
+            check(QHYCCDCam::ControlQHYCCDTemp(self.handle, temp))?;
         }
+        self.settings.borrow_mut().target_temp = Some(temp);
+        Ok(())
     }
+    /// Whether the camera has `control`. This looks at the raw result rather than going
+    /// through `QHYResult`: some controls answer with something other than success when
+    /// they're there, like `Control::Color` with the Bayer pattern.
     pub fn has_param(&self, control: Control) -> bool {
-        unsafe {
-            match QHYResult::from(QHYCCDCam::IsQHYCCDControlAvailable(self.handle, control as i32) as u32) {
-                QHYResult::QHYCCD_ERROR => {
-                    false
-                },
-                QHYResult::QHYCCD_SUCCESS => {
-                    true
-                }
-                a @ _ => {
-                    panic!("Unexpected response when querying if control '{:?}' is available: {:?}", control, a);
-                }
-            }
-        }
+        let available = unsafe { QHYCCDCam::IsQHYCCDControlAvailable(self.handle, control as i32) as u32 };
+        available != QHYResult::QHYCCD_ERROR as u32
     }
     pub fn set_param(&self, control: Control, value: f64) -> Result<()> {
         unsafe {
         if self.has_param(control) {
-            check(QHYCCDCam::SetQHYCCDParam(self.handle, control as i32, value))
+            check(QHYCCDCam::SetQHYCCDParam(self.handle, control as i32, value))?;
+            let mut settings = self.settings.borrow_mut();
+            settings.params.retain(|(c, _)| *c as u32 != control as u32);
+            settings.params.push((control, value));
+            Ok(())
         } else {
             println!("Cannot set control: {:?}", control);
             Ok(())
@@ -110,6 +316,16 @@ impl Camera {
             QHYCCDCam::GetQHYCCDParam(self.handle, control as i32)
         }
     }
+    /// The (min, max, step) range of a control.
+    pub fn get_param_range(&self, control: Control) -> Result<(f64, f64, f64)> {
+        let mut min: f64 = 0.0;
+        let mut max: f64 = 0.0;
+        let mut step: f64 = 0.0;
+        unsafe {
+        check(QHYCCDCam::GetQHYCCDParamMinMaxStep(self.handle, control as i32, &mut min, &mut max, &mut step))?;
+        }
+        Ok((min, max, step))
+    }
     pub fn release(self) -> Result<()> {
         unsafe {
         check(QHYCCDCam::CloseQHYCCD(self.handle))
@@ -118,15 +334,15 @@ impl Camera {
     pub fn set_defaults(&self) -> Result<()> {
         unsafe {
         println!("Hey wait gotta get dimensions first");
-        let ((chipw, chiph), (imagew, imageh), (pixelw, pixelh), bpp) = self.get_dimensions()?;
+        let (imagew, imageh) = self.get_dimensions()?.image_size;
         match QHYCCDCam::IsQHYCCDControlAvailable(self.handle, Control::Color as i32) {
-            1 | 2 | 3 | 4 => {
+            1..=4 => {
                 check(QHYCCDCam::SetQHYCCDDebayerOnOff(self.handle, 1))?;
                 self.set_param(Control::CONTROL_WBR, 20.0)?;
                 self.set_param(Control::CONTROL_WBG, 20.0)?;
                 self.set_param(Control::CONTROL_WBB, 20.0)?;
             },
-            a @ _ => {
+            a => {
                 println!("unexpected response when querying color setting: {}", a);
                 return Err(CameraError::QHYError)
             }
@@ -136,6 +352,10 @@ impl Camera {
         if self.has_param(Control::TransferBit) {
             check(QHYCCDCam::SetQHYCCDBitsMode(self.handle, 16))?;
         }
+        let mut settings = self.settings.borrow_mut();
+        settings.defaults = true;
+        settings.bin = None;
+        settings.resolution = None;
         Ok(())
         }
     }
@@ -149,10 +369,143 @@ impl Camera {
             _ => { return Err(CameraError::InvalidControl); }
         }
         unsafe {
-        check(QHYCCDCam::SetQHYCCDBinMode(self.handle, bin as i32, bin as i32))
+        check(QHYCCDCam::SetQHYCCDBinMode(self.handle, bin as i32, bin as i32))?;
+        }
+        self.settings.borrow_mut().bin = Some(bin);
+        Ok(())
+    }
+
+    /// Keep the overscan area in frames, to correct each one's bias level from, or have the
+    /// SDK crop it off. Frames that keep it record where it is in `BIASSEC` and `DATASEC`.
+    pub fn set_keep_overscan(&self, keep: bool) -> Result<()> {
+        if !self.has_param(Control::CAM_IGNOREOVERSCAN_INTERFACE) {
+            return Err(CameraError::InvalidControl);
+        }
+        self.set_param(Control::CAM_IGNOREOVERSCAN_INTERFACE, if keep { 0.0 } else { 1.0 })
+    }
+
+    /// Where the overscan and the exposed pixels are in frames as they're read out now, in
+    /// binned pixels, or `None` if the overscan isn't being kept or only a region is being
+    /// read out.
+    pub fn overscan_sections(&self) -> Result<Option<(Subframe, Subframe)>> {
+        let settings = self.settings.borrow();
+        let keep = settings.params.iter()
+            .any(|(control, value)| *control as u32 == Control::CAM_IGNOREOVERSCAN_INTERFACE as u32 && *value == 0.0);
+        if !keep || settings.resolution.is_some() {
+            return Ok(None);
+        }
+        let bin = settings.bin.unwrap_or(1).max(1) as u32;
+        let binned = |(x, y, width, height): (u32, u32, u32, u32)| Subframe {
+            x: x.div_ceil(bin),
+            y: y.div_ceil(bin),
+            width: width / bin,
+            height: height / bin
+        };
+        let overscan = binned(self.get_overscan_area()?);
+        let effective = binned(self.get_effective_area()?);
+        if overscan.width == 0 || overscan.height == 0 || effective.width == 0 || effective.height == 0 {
+            return Ok(None);
+        }
+        Ok(Some((overscan, effective)))
+    }
+
+    /// Read out only the `width` by `height` region starting at (`x`, `y`), in binned pixels.
+    pub fn set_resolution(&self, x: u32, y: u32, width: u32, height: u32) -> Result<()> {
+        unsafe {
+        check(QHYCCDCam::SetQHYCCDResolution(self.handle, x, y, width, height))?;
+        }
+        self.settings.borrow_mut().resolution = Some((x, y, width, height));
+        Ok(())
+    }
+
+    /// Close and reopen the camera, found again by its ID, then restore everything set through
+    /// this handle. This is how to pick a camera back up after it fell off the bus and was
+    /// reset.
+    pub fn reopen(&mut self) -> Result<()> {
+        unsafe {
+            // the camera is likely already gone as far as the SDK is concerned
+            let _ = QHYCCDCam::CloseQHYCCD(self.handle);
+        }
+        let mut fresh = acquire_id(&self.id)?;
+        let settings = self.settings.borrow().clone();
+        if settings.defaults {
+            fresh.set_defaults()?;
+        }
+        if let Some(bin) = settings.bin {
+            fresh.set_bin_mode(bin)?;
+        }
+        if let Some((x, y, width, height)) = settings.resolution {
+            fresh.set_resolution(x, y, width, height)?;
+        }
+        for (control, value) in settings.params.iter() {
+            fresh.set_param(*control, *value)?;
+        }
+        if let Some(temp) = settings.target_temp {
+            fresh.set_target_temp(temp)?;
+        }
+        if let Some(mode) = settings.trigger_mode {
+            fresh.enable_trigger(mode)?;
+        }
+        if self.gps_enabled {
+            fresh.enable_gps(true)?;
+        }
+        fresh.presence = self.presence.clone();
+        fresh.presence.set(true);
+        *self = fresh;
+        Ok(())
+    }
+
+    /// Shared with a hotplug `Watcher`, which marks it absent when the camera is unplugged.
+    pub fn presence(&self) -> Presence {
+        self.presence.clone()
+    }
+
+    fn check_present(&self) -> Result<()> {
+        if self.presence.is_present() {
+            Ok(())
+        } else {
+            Err(CameraError::Removed)
+        }
+    }
+
+    pub fn has_filter_wheel(&self) -> bool {
+        if !self.has_param(Control::CFWPort) {
+            return false;
+        }
+        unsafe {
+            QHYCCDCam::IsQHYCCDCFWPlugged(self.handle) == QHYResult::QHYCCD_SUCCESS as i32
+        }
+    }
+
+    pub fn filter_slot_count(&self) -> Result<u32> {
+        if !self.has_param(Control::CONTROL_CFWSLOTSNUM) {
+            return Err(CameraError::NoFilterWheel);
+        }
+        Ok(self.get_param(Control::CONTROL_CFWSLOTSNUM) as u32)
+    }
+
+    /// Start moving the filter wheel to `slot` (zero-based, below `cfw::MAX_SLOTS`). This
+    /// returns as soon as the wheel has the order, see `filter_position` to find out when it
+    /// got there.
+    pub fn move_filter_wheel(&self, slot: u32) -> Result<()> {
+        if slot >= self.filter_slot_count()? {
+            return Err(CameraError::InvalidFilter);
+        }
+        let mut order = [cfw::slot_order(slot).ok_or(CameraError::InvalidFilter)? as os::raw::c_char];
+        unsafe {
+            check(QHYCCDCam::SendOrder2QHYCCDCFW(self.handle, order.as_mut_ptr(), 1))
         }
     }
 
+    /// The slot the filter wheel is in, or `None` while it is still moving.
+    pub fn filter_position(&self) -> Result<Option<u32>> {
+        let mut status: [os::raw::c_char; 64] = [0; 64];
+        unsafe {
+            check(QHYCCDCam::GetQHYCCDCFWStatus(self.handle, status.as_mut_ptr()))?;
+        }
+        Ok(cfw::reported_slot(status[0] as u8))
+    }
+
     pub fn get_exposure_remaining(&self) -> u32 {
         unsafe {
             QHYCCDCam::GetQHYCCDExposureRemaining(self.handle)
@@ -168,41 +521,131 @@ impl Camera {
         println!("Effective area:");
         println!("  startX x startY : {:05} x {:05}", effective_start_X, effective_start_Y);
         println!("  sizeX  x sizeY  : {:05} x {:05}", effective_size_X, effective_size_Y);
-        let ((chipw, chiph), (imagew, imageh), (pixelw, pixelh), bpp) = self.get_dimensions()?;
+        let chip = self.get_dimensions()?;
         println!("Chip dimensions:");
-        println!("Chip size (w/h):      {:05} x {:05} [mm]", chipw, chiph);
-        println!("Pixel size (w/h):     {:05} x {:05} [um]", pixelw, pixelh);
-        println!("Image size (w/h):     {:05} x {:05} [pixels]", imagew, imageh);
-        println!("   bpp:               {}", bpp);
+        println!("Chip size (w/h):      {:05} x {:05} [mm]", chip.chip_size.0, chip.chip_size.1);
+        println!("Pixel size (w/h):     {:05} x {:05} [um]", chip.pixel_size.0, chip.pixel_size.1);
+        println!("Image size (w/h):     {:05} x {:05} [pixels]", chip.image_size.0, chip.image_size.1);
+        println!("   bpp:               {}", chip.bpp);
         Ok(())
     }
 
+    /// Take a frame and save it at `path`, as FITS for a `.fits` path, with a preview next
+    /// to it.
     pub fn take_image(&self, path: &str) -> Result<()> {
+        let frame = self.capture_frame()?;
+        frame.save(path).unwrap();
+        Preview::thumbnail().write_for(&frame, path).unwrap();
+        Ok(())
+    }
+
+    pub fn has_mechanical_shutter(&self) -> bool {
+        self.has_param(Control::CAM_MECHANICALSHUTTER)
+    }
+
+    /// Hold the shutter open or closed, or with `MACHANICALSHUTTER_FREE` let the camera
+    /// operate it with each exposure.
+    pub fn control_shutter(&self, state: u8) -> Result<()> {
+        if !self.has_mechanical_shutter() {
+            return Err(CameraError::InvalidControl);
+        }
+        unsafe {
+        check(QHYCCDCam::ControlQHYCCDShutter(self.handle, state))
+        }
+    }
+
+    pub fn shutter_status(&self) -> Result<u8> {
+        if !self.has_mechanical_shutter() {
+            return Err(CameraError::InvalidControl);
+        }
+        let status = unsafe { QHYCCDCam::GetQHYCCDShutterStatus(self.handle) };
+        if status < 0 {
+            return Err(CameraError::QHYError);
+        }
+        Ok(status as u8)
+    }
+
+    /// Take a frame of the given type. Darks and bias frames are taken with the shutter held
+    /// closed if the camera has one, and bias frames at the shortest exposure the camera
+    /// allows. The frame records its type and whether the shutter was actually closed.
+    pub fn capture(&self, frame_type: FrameType) -> Result<Frame> {
+        let has_shutter = self.has_mechanical_shutter();
+        let held = if frame_type.wants_dark() && has_shutter {
+            self.control_shutter(QHYCCDCam::MACHANICALSHUTTER_CLOSE)?;
+            Some(ShutterHeld { camera: self })
+        } else {
+            None
+        };
+        let exposure = self.get_param(Control::Exposure);
+        let bias_exposure = if frame_type == FrameType::Bias {
+            let (min_exposure, _, _) = self.get_param_range(Control::Exposure)?;
+            self.set_param(Control::Exposure, min_exposure)?;
+            Some(min_exposure)
+        } else {
+            None
+        };
+        let frame = self.capture_frame();
+        // ask while the shutter is still held, so a shutter that never closed shows up
+        let shutter = if has_shutter {
+            shutter_state(self.shutter_status())
+        } else {
+            Shutter::None
+        };
+        // let go of the shutter before anything else can fail and leave it closed
+        drop(held);
+        if bias_exposure.is_some() {
+            self.set_param(Control::Exposure, exposure)?;
+        }
+        let exposure = bias_exposure.unwrap_or(exposure);
+        let mut frame = frame?;
+        frame.set_frame_type(frame_type, shutter);
+        if frame.gps.is_none() {
+            frame.set_header("EXPTIME", HeaderValue::Float(exposure / 1e6));
+        }
+        frame.set_setup(&self.setup());
+        if let Some((overscan, effective)) = self.overscan_sections()? {
+            frame.set_header("BIASSEC", HeaderValue::Str(fits::section(&overscan)));
+            frame.set_header("DATASEC", HeaderValue::Str(fits::section(&effective)));
+        }
+        Ok(frame)
+    }
+
+    /// How frames are being taken, for matching them with masters.
+    pub fn setup(&self) -> Setup {
+        let settings = self.settings.borrow();
+        Setup {
+            serial: self.id.clone(),
+            gain: Some(self.get_param(Control::Gain)),
+            offset: Some(self.get_param(Control::Offset)),
+            binning: settings.bin.unwrap_or(1).max(1) as u32,
+            origin: settings.resolution.map(|(x, y, _, _)| (x, y)).unwrap_or((0, 0)),
+            temperature: Some(self.get_param(Control::CurTemp))
+        }
+    }
+
+    pub fn capture_frame(&self) -> Result<Frame> {
+        self.check_present()?;
         unsafe {
         let exposure_duration = self.get_param(Control::Exposure);
         let exposure_ms = exposure_duration / 1000.0;
         println!("Exposure duration: {}", exposure_ms);
+        let started = SystemTime::now();
         let result = QHYCCDCam::ExpQHYCCDSingleFrame(self.handle);
         match QHYCCDCam::QHYResult::from(result as u32) {
             QHYResult::QHYCCD_SUCCESS => {
                 println!("Didn't expect this result...");
                 std::thread::sleep(std::time::Duration::from_millis(1000));
             },
-            QHYResult::QHYCCD_READ_DIRECTLY => {
+            QHYResult::QHYCCD_READ_DIRECTLY | QHYResult::QHYCCD_DELAY_200MS => {
                 println!("Exp complete, example sleeps so i'll sleep too");
                 std::thread::sleep(std::time::Duration::from_millis(1000));
             },
-            a @ _ =>{
+            a =>{
                 println!("exp err: {:?}", a);
                 return Err(CameraError::QHYError);
             }
         }
 
-        let bufsize = QHYCCDCam::GetQHYCCDMemLength(self.handle);
-        println!("Ok, we'll need {} bytes...", bufsize);
-        let data_layout = Layout::from_size_align(bufsize as usize, 8).unwrap();
-        let data = alloc(data_layout);
-
         let mut counter: i64 = (self.get_param(Control::Exposure) as u64 / 1000) as i64;
 
         while counter > 0 {
@@ -212,35 +655,192 @@ impl Camera {
             counter -= 500;
         }
 
+        println!("Getting data...");
+        self.check_present()?;
+        let mut data = self.frame_buffer()?;
+        match self.read_frame(&mut data)? {
+            Some(mut frame) => {
+                if frame.started.is_none() {
+                    frame.started = Some(started);
+                }
+                Ok(frame)
+            }
+            None => Err(CameraError::QHYError)
+        }
+        }
+    }
+
+    /// A buffer big enough for any frame the camera reads out in its current mode.
+    fn frame_buffer(&self) -> Result<Vec<u8>> {
+        let bufsize = unsafe { QHYCCDCam::GetQHYCCDMemLength(self.handle) };
+        if bufsize <= 0 {
+            return Err(CameraError::QHYError);
+        }
+        Ok(vec![0u8; bufsize as usize])
+    }
+
+    /// Read out the frame of the current exposure into `data`, from `frame_buffer`, or `None`
+    /// if it isn't ready. The SDK only has the one error code, so not ready is told apart from
+    /// a camera that went away by checking it's still there.
+    fn read_frame(&self, data: &mut [u8]) -> Result<Option<Frame>> {
         let mut castediw = 0i32;
         let mut castedih = 0i32;
         let mut castedbpp = 0i32;
         let mut channels = 0;
-        println!("Getting data...");
-        check(QHYCCDCam::GetQHYCCDSingleFrame(self.handle, &mut castediw, &mut castedih, &mut castedbpp, &mut channels, data))?;
-        println!("Ok, guess we got it?");
-        println!("image: {} x {}", castediw, castedih);
-        println!("bpp: {}", castedbpp);
-        println!("channels: {}", channels);
-
-        let dest = Path::new(path);
-        let file = File::create(dest).unwrap();
-        let ref mut w = BufWriter::new(file);
-        let mut encoder = png::Encoder::new(w, castediw as u32, castedih as u32);
-        encoder.set(png::ColorType::RGB).set(png::BitDepth::Sixteen);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(
-            unsafe {
-                std::slice::from_raw_parts(
-                    data,
-                    bufsize as usize
-                )
+        let res = unsafe {
+            QHYCCDCam::GetQHYCCDSingleFrame(self.handle, &mut castediw, &mut castedih, &mut castedbpp, &mut channels, data.as_mut_ptr())
+        };
+        match QHYResult::from(res as u32) {
+            QHYResult::QHYCCD_SUCCESS => {}
+            QHYResult::QHYCCD_ERROR => {
+                self.check_present()?;
+                return Ok(None);
+            }
+            other => {
+                println!("Unexpected result reading out a frame: {:?}", other);
+                return Err(CameraError::QHYError);
+            }
+        }
+        println!("image: {} x {}, bpp: {}, channels: {}", castediw, castedih, castedbpp, channels);
+        let bytes = &data[..];
+        let mut frame = Frame::from_bytes(
+            castediw as u32,
+            castedih as u32,
+            channels as u32,
+            castedbpp as u32,
+            bytes
+        );
+        if self.gps_enabled {
+            match GpsHeader::parse(bytes) {
+                Some(gps) => frame.set_gps(gps),
+                None => println!("GPS is enabled but the frame has no GPS header")
             }
-        ).unwrap();
-        dealloc(data as *mut u8, data_layout);
+        }
+        if frame.channels == 1 {
+            frame.cfa = self.bayer;
+        }
+        frame.compute_stats();
+        Ok(Some(frame))
+    }
+
+    pub fn has_gps(&self) -> bool {
+        self.has_param(Control::CAM_GPS)
+    }
+
+    /// Turn on the GPS header the camera writes over the start of each frame. Frames read
+    /// while it is on carry the parsed header and are timestamped from it.
+    pub fn enable_gps(&mut self, enable: bool) -> Result<()> {
+        if !self.has_gps() {
+            return Err(CameraError::InvalidControl);
+        }
+        self.set_param(Control::CAM_GPS, if enable { 1.0 } else { 0.0 })?;
+        self.gps_enabled = enable;
+        Ok(())
+    }
+
+    /// Trim the GPS board's 10MHz VCXO.
+    pub fn set_gps_vcox_freq(&self, freq: u16) -> Result<()> {
+        require("SetQHYCCDGPSVCOXFreq")?;
+        unsafe {
+        check(QHYCCDCam::SetQHYCCDGPSVCOXFreq(self.handle, freq))
+        }
+    }
+
+    /// Flash the calibration LED at `pos` (in clock ticks after the PPS) for `width` so the
+    /// shutter timing can be checked against the recorded timestamps.
+    pub fn set_gps_led_calibration(&self, enable: bool, pos: u32, width: u8) -> Result<()> {
+        require("SetQHYCCDGPSLedCalMode")?;
+        require("SetQHYCCDGPSLedCal")?;
+        unsafe {
+        check(QHYCCDCam::SetQHYCCDGPSLedCalMode(self.handle, enable as u8))?;
+        if enable {
+            QHYCCDCam::SetQHYCCDGPSLedCal(self.handle, pos, width);
+        }
+        }
+        Ok(())
+    }
+
+    /// Where the shutter open (A) and close (B) measurement points sit, for master or slave
+    /// mode respectively.
+    pub fn set_gps_measure_points(&self, slave: bool, pos_a: (u32, u8), pos_b: (u32, u8)) -> Result<()> {
+        require("SetQHYCCDGPSPOSA")?;
+        require("SetQHYCCDGPSPOSB")?;
+        unsafe {
+        QHYCCDCam::SetQHYCCDGPSPOSA(self.handle, slave as u8, pos_a.0, pos_a.1);
+        QHYCCDCam::SetQHYCCDGPSPOSB(self.handle, slave as u8, pos_b.0, pos_b.1);
+        }
+        Ok(())
+    }
+
+    /// In master mode the camera runs freely and timestamps exposures; in slave mode it starts
+    /// exposures at GPS times given by `set_gps_slave_schedule`.
+    pub fn set_gps_master(&self, master: bool) -> Result<()> {
+        require("SetQHYCCDGPSMasterSlave")?;
+        unsafe {
+        check(QHYCCDCam::SetQHYCCDGPSMasterSlave(self.handle, if master { 0 } else { 1 }))
+        }
+    }
+
+    /// Start the first exposure at GPS time `target` (seconds since the GPS epoch and
+    /// microseconds), then every `interval` after, each exposing for `exposure_us`.
+    pub fn set_gps_slave_schedule(&self, target: (u32, u32), interval: (u32, u32), exposure_us: u32) -> Result<()> {
+        require("SetQHYCCDGPSSlaveModeParameter")?;
+        unsafe {
+        QHYCCDCam::SetQHYCCDGPSSlaveModeParameter(self.handle, target.0, target.1, interval.0, interval.1, exposure_us);
+        }
+        Ok(())
+    }
+
+    /// Arm the camera's trigger input. Which modes past `TriggerMode::TriggerIn` a camera has
+    /// varies by model.
+    pub fn enable_trigger(&mut self, mode: TriggerMode) -> Result<()> {
+        if !self.has_param(Control::CAM_TRIGER_INTERFACE) {
+            return Err(CameraError::InvalidControl);
+        }
+        unsafe {
+        check(QHYCCDCam::SetQHYCCDTrigerFunction(self.handle, true))?;
+        check(QHYCCDCam::SetQHYCCDTrigerMode(self.handle, mode.raw()))?;
+        }
+        self.trigger_enabled = true;
+        self.settings.borrow_mut().trigger_mode = Some(mode);
+        Ok(())
+    }
+
+    pub fn disable_trigger(&mut self) -> Result<()> {
+        if !self.trigger_enabled {
+            return Ok(());
+        }
+        unsafe {
+        check(QHYCCDCam::SetQHYCCDTrigerFunction(self.handle, false))?;
+        }
+        self.trigger_enabled = false;
+        self.settings.borrow_mut().trigger_mode = None;
         Ok(())
     }
+
+    /// Arm an exposure and block until the external trigger fires and the frame is read out,
+    /// or `timeout` passes, in which case the armed exposure is cancelled.
+    pub fn wait_for_triggered_frame(&self, timeout: Duration) -> Result<Frame> {
+        if !self.trigger_enabled {
+            return Err(CameraError::InvalidControl);
+        }
+        unsafe {
+        if let QHYResult::QHYCCD_ERROR = QHYResult::from(QHYCCDCam::ExpQHYCCDSingleFrame(self.handle) as u32) {
+            return Err(CameraError::QHYError);
+        }
+        }
+        let mut data = self.frame_buffer()?;
+        match poll(timeout, Duration::from_millis(10), || self.read_frame(&mut data))? {
+            Some(frame) => Ok(frame),
+            None => {
+                unsafe {
+                check(QHYCCDCam::CancelQHYCCDExposingAndReadout(self.handle))?;
+                }
+                Err(CameraError::Timeout)
+            }
+        }
     }
+
     pub fn get_overscan_area(&self) -> Result<(u32, u32, u32, u32)> {
         unsafe {
         let mut startX: i32 = 0;
@@ -273,7 +873,7 @@ impl Camera {
         Ok((startX as u32, startY as u32, sizeX as u32, sizeY as u32))
         }
     }
-    pub fn get_dimensions(&self) -> Result<((f64, f64), (u32, u32), (f64, f64), u32)> {
+    pub fn get_dimensions(&self) -> Result<ChipInfo> {
         unsafe {
         let mut chipw: f64 = 0.0;
         let mut chiph: f64 = 0.0;
@@ -291,12 +891,79 @@ impl Camera {
             &mut pixelw as *mut os::raw::c_double,
             &mut pixelh as *mut os::raw::c_double,
             &mut bpp as *mut os::raw::c_int))?;
-        Ok((
-            (chipw, chiph),
-            (imagew as u32, imageh as u32),
-            (pixelw, pixelh),
-            bpp as u32
-        ))
+        Ok(ChipInfo {
+            chip_size: (chipw, chiph),
+            image_size: (imagew as u32, imageh as u32),
+            pixel_size: (pixelw, pixelh),
+            bpp: bpp as u32
+        })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn trigger_modes_by_name() {
+        assert_eq!(TriggerMode::from_name("in"), Some(TriggerMode::TriggerIn));
+        assert_eq!(TriggerMode::from_name("0"), Some(TriggerMode::TriggerIn));
+        assert_eq!(TriggerMode::from_name("2"), Some(TriggerMode::Model(2)));
+        assert_eq!(TriggerMode::from_name("rise"), None);
+        assert_eq!(TriggerMode::Model(2).raw(), 2);
+        assert_eq!(TriggerMode::TriggerIn.raw(), 0);
+    }
+
+    #[test]
+    fn result_codes_and_bayer_patterns() {
+        assert!(matches!(QHYResult::from(0x2001), QHYResult::QHYCCD_READ_DIRECTLY));
+        assert!(matches!(QHYResult::from(0x2000), QHYResult::QHYCCD_DELAY_200MS));
+        assert!(matches!(QHYResult::from(0xffff_ffff), QHYResult::QHYCCD_ERROR));
+        assert!(matches!(QHYResult::from(3), QHYResult::QHYCCD_ERROR));
+        assert_eq!(bayer_pattern(0), None);
+        assert_eq!(bayer_pattern(1), Some(Cfa::GBRG));
+        assert_eq!(bayer_pattern(4), Some(Cfa::RGGB));
+        assert_eq!(bayer_pattern(QHYResult::QHYCCD_ERROR as u32), None);
+    }
+
+    #[test]
+    fn only_a_shutter_reported_closed_makes_a_closed_dark() {
+        assert_eq!(shutter_state(Ok(MACHANICALSHUTTER_CLOSE)), Shutter::Closed);
+        assert_eq!(shutter_state(Ok(MACHANICALSHUTTER_OPEN)), Shutter::Open);
+        assert_eq!(shutter_state(Ok(MACHANICALSHUTTER_FREE)), Shutter::Open);
+        assert_eq!(shutter_state(Err(CameraError::QHYError)), Shutter::Open);
+    }
+
+    #[test]
+    fn polling_returns_the_frame_once_ready() {
+        let calls = Cell::new(0);
+        let result = poll(Duration::from_secs(5), Duration::from_millis(1), || {
+            calls.set(calls.get() + 1);
+            Ok(if calls.get() == 3 { Some("frame") } else { None })
+        });
+        assert_eq!(result.unwrap(), Some("frame"));
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn polling_stops_at_the_first_error() {
+        let calls = Cell::new(0);
+        let result: Result<Option<()>> = poll(Duration::from_secs(5), Duration::from_millis(1), || {
+            calls.set(calls.get() + 1);
+            if calls.get() == 2 { Err(CameraError::Removed) } else { Ok(None) }
+        });
+        assert!(matches!(result, Err(CameraError::Removed)));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn polling_gives_up_at_the_timeout() {
+        let started = Instant::now();
+        let result: Result<Option<()>> = poll(Duration::from_millis(50), Duration::from_millis(5), || Ok(None));
+        assert_eq!(result.unwrap(), None);
+        assert!(started.elapsed() >= Duration::from_millis(45));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,244 @@
+// Getting a camera back after it wedges mid-readout: reset it on the bus, wait for it to
+// enumerate again (loading firmware if it comes back as a bare loader), then reopen it with
+// the settings it had.
+
+#[cfg(feature = "asi")]
+use crate::asicam;
+#[cfg(feature = "qhy")]
+use crate::firmware::{self, FirmwareError};
+use crate::hotplug;
+#[cfg(any(feature = "asi", feature = "qhy"))]
+use crate::imaging::Imager;
+#[cfg(feature = "qhy")]
+use crate::qhyccd;
+use crate::usb::{self, UsbDevice};
+
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum RecoveryError {
+    Io(io::Error),
+    NotFound,
+    /// The camera couldn't be told apart from others from the same maker on the bus, so
+    /// resetting one could take down the wrong camera, a guide camera say.
+    Unidentified,
+    Timeout,
+    #[cfg(feature = "qhy")]
+    Firmware(FirmwareError),
+    #[cfg(feature = "asi")]
+    Asi(asicam::CameraError),
+    #[cfg(feature = "qhy")]
+    Qhy(qhyccd::CameraError)
+}
+
+impl From<io::Error> for RecoveryError {
+    fn from(err: io::Error) -> RecoveryError {
+        RecoveryError::Io(err)
+    }
+}
+
+#[cfg(feature = "qhy")]
+impl From<FirmwareError> for RecoveryError {
+    fn from(err: FirmwareError) -> RecoveryError {
+        RecoveryError::Firmware(err)
+    }
+}
+
+#[cfg(feature = "asi")]
+impl From<asicam::CameraError> for RecoveryError {
+    fn from(err: asicam::CameraError) -> RecoveryError {
+        RecoveryError::Asi(err)
+    }
+}
+
+#[cfg(feature = "qhy")]
+impl From<qhyccd::CameraError> for RecoveryError {
+    fn from(err: qhyccd::CameraError) -> RecoveryError {
+        RecoveryError::Qhy(err)
+    }
+}
+
+type Result<T> = std::result::Result<T, RecoveryError>;
+
+/// Whether an ASI error means the camera has dropped off and is worth resetting.
+#[cfg(feature = "asi")]
+pub fn asi_recoverable(err: &asicam::CameraError) -> bool {
+    matches!(err, asicam::CameraError::CameraRemoved | asicam::CameraError::Timeout)
+}
+
+/// Whether a QHY error means the camera is worth resetting. The SDK doesn't say why a call
+/// failed, so anything other than a bad argument counts.
+#[cfg(feature = "qhy")]
+pub fn qhy_recoverable(err: &qhyccd::CameraError) -> bool {
+    matches!(err, qhyccd::CameraError::QHYError | qhyccd::CameraError::Timeout | qhyccd::CameraError::Removed)
+}
+
+/// Whether `device` is a QHY camera waiting for its firmware.
+#[cfg(feature = "qhy")]
+fn needs_firmware(device: &UsbDevice) -> bool {
+    firmware::lookup(device.vid, device.pid).is_some()
+}
+
+#[cfg(not(feature = "qhy"))]
+fn needs_firmware(_device: &UsbDevice) -> bool {
+    false
+}
+
+/// Whether `device` is the camera coming back after a reset of the one on `port`: either
+/// the camera itself, or a bare loader for one on the same port. A loader elsewhere is some
+/// other camera waiting for its firmware. Without a USB serial to go by, only the same
+/// port will do, or another camera of the same model would count.
+fn came_back(device: &UsbDevice, port: &str, vid: u16, pid: Option<u16>, serial: Option<&str>) -> bool {
+    (device.matches(vid, pid, serial) && (serial.is_some() || device.port() == port))
+        || (needs_firmware(device) && device.port() == port)
+}
+
+/// The USB device of the camera from `vid` its SDK knows by `serial`, picked out as the
+/// hotplug watcher does it (see `hotplug::port_of`): SDK serials aren't USB serials, so
+/// `UsbDevice::matches` can't be used. `None` if that doesn't single out one device.
+fn device_of(devices: &[UsbDevice], vid: u16, serial: &str) -> Option<UsbDevice> {
+    let port = hotplug::port_of(devices, vid, serial)?;
+    devices.iter().find(|device| device.vid == vid && device.port() == port).cloned()
+}
+
+#[derive(Debug, Clone)]
+pub struct Recovery {
+    pub sysfs_root: PathBuf,
+    pub dev_root: PathBuf,
+    pub firmware_dir: PathBuf,
+    /// How long to wait for the camera to come back after the reset.
+    pub timeout: Duration
+}
+
+impl Default for Recovery {
+    fn default() -> Recovery {
+        Recovery {
+            sysfs_root: PathBuf::from(usb::SYSFS_ROOT),
+            dev_root: PathBuf::from(usb::DEV_ROOT),
+            firmware_dir: PathBuf::from("firmware/qhy"),
+            timeout: Duration::from_secs(20)
+        }
+    }
+}
+
+impl Recovery {
+    /// Find the device matching `vid`/`pid`/`serial`, or `None` if it's not attached.
+    pub fn find(&self, vid: u16, pid: Option<u16>, serial: Option<&str>) -> Result<Option<UsbDevice>> {
+        Ok(usb::find(&self.sysfs_root, vid, pid, serial)?)
+    }
+
+    /// Reset the matching device and wait for it to enumerate again as a camera. A QHY camera
+    /// that comes back as a bare FX2/FX3 gets its firmware loaded first.
+    pub fn reset_and_wait(&self, vid: u16, pid: Option<u16>, serial: Option<&str>) -> Result<UsbDevice> {
+        let device = self.find(vid, pid, serial)?.ok_or(RecoveryError::NotFound)?;
+        self.reset_device_and_wait(&device)
+    }
+
+    /// Reset `device` and wait for it to enumerate again as a camera, as `reset_and_wait`.
+    pub fn reset_device_and_wait(&self, device: &UsbDevice) -> Result<UsbDevice> {
+        usb::reset(device.dev_node(&self.dev_root))?;
+        // give the old device time to go away before looking for the new one
+        std::thread::sleep(Duration::from_millis(500));
+
+        let (vid, pid, serial) = (device.vid, Some(device.pid), device.serial.as_deref());
+        let port = device.port();
+        let found = usb::wait_for(&self.sysfs_root, self.timeout, |dev| {
+            came_back(dev, &port, vid, pid, serial)
+        })?.ok_or(RecoveryError::Timeout)?;
+        if !needs_firmware(&found) {
+            return Ok(found);
+        }
+
+        #[cfg(feature = "qhy")]
+        firmware::load_attached(&self.firmware_dir)?;
+        usb::wait_for(&self.sysfs_root, self.timeout, |dev| dev.matches(vid, pid, serial))?
+            .ok_or(RecoveryError::Timeout)
+    }
+
+    /// The USB device of the camera from `vid` its SDK knows by `serial`.
+    pub fn camera_device(&self, vid: u16, serial: &str) -> Result<UsbDevice> {
+        device_of(&usb::list_devices(&self.sysfs_root)?, vid, serial).ok_or(RecoveryError::Unidentified)
+    }
+
+    /// Reset an ASI camera and reopen it with the settings it had. Only the camera's own
+    /// USB device is reset, never just the first ASI device on the bus.
+    #[cfg(feature = "asi")]
+    pub fn recover_asi(&self, camera: &mut asicam::Camera) -> Result<()> {
+        let device = self.camera_device(usb::ASI_VID, &camera.serial())?;
+        self.reset_device_and_wait(&device)?;
+        camera.reopen()?;
+        Ok(())
+    }
+
+    /// Reset a QHY camera and reopen it with the settings it had, as `recover_asi`.
+    #[cfg(feature = "qhy")]
+    pub fn recover_qhy(&self, camera: &mut qhyccd::Camera) -> Result<()> {
+        let device = self.camera_device(usb::QHY_VID, &camera.serial())?;
+        self.reset_device_and_wait(&device)?;
+        camera.reopen()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(port: &str, vid: u16, pid: u16, serial: Option<&str>) -> UsbDevice {
+        UsbDevice {
+            sysfs_path: PathBuf::from(usb::SYSFS_ROOT).join(port),
+            vid: vid,
+            pid: pid,
+            serial: serial.map(|serial| serial.to_owned()),
+            busnum: 4,
+            devnum: 7
+        }
+    }
+
+    #[test]
+    fn only_the_failed_cameras_own_device_is_picked_for_a_reset() {
+        let guider = device("4-1.1", usb::ASI_VID, 0x120d, Some("1a2b3c"));
+        let imager = device("4-1.2", usb::ASI_VID, 0x1604, Some("4d5e6f"));
+        let hub = device("4", 0x1d6b, 0x0002, Some("0000:00:14.0"));
+        let devices = [hub.clone(), guider.clone(), imager.clone()];
+        // the SDK's serial has the USB one in it, in its own case
+        assert_eq!(device_of(&devices, usb::ASI_VID, "ASI294MC-4D5E6F"), Some(imager.clone()));
+        assert_eq!(device_of(&devices, usb::ASI_VID, "1A2B3C00"), Some(guider.clone()));
+        // one that matches neither could be either, so neither is picked, not the first
+        assert_eq!(device_of(&devices, usb::ASI_VID, "ffff0000"), None);
+        // with no USB serials only a lone camera can be picked
+        let bare = |port: &str, pid: u16| device(port, usb::ASI_VID, pid, None);
+        assert_eq!(device_of(&[hub.clone(), bare("4-1.2", 0x1604)], usb::ASI_VID, "4d5e6f"), Some(bare("4-1.2", 0x1604)));
+        assert_eq!(device_of(&[bare("4-1.1", 0x120d), bare("4-1.2", 0x1604)], usb::ASI_VID, "4d5e6f"), None);
+        assert_eq!(device_of(&[hub], usb::ASI_VID, "4d5e6f"), None);
+    }
+
+    #[test]
+    fn a_camera_without_a_usb_serial_only_comes_back_on_its_own_port() {
+        let camera = device("4-1.2", usb::ASI_VID, 0x1604, None);
+        assert!(came_back(&camera, "4-1.2", usb::ASI_VID, Some(0x1604), None));
+        let twin = device("4-1.3", usb::ASI_VID, 0x1604, None);
+        assert!(!came_back(&twin, "4-1.2", usb::ASI_VID, Some(0x1604), None));
+        let guider = device("4-1.2", usb::ASI_VID, 0x120d, None);
+        assert!(!came_back(&guider, "4-1.2", usb::ASI_VID, Some(0x1604), None));
+    }
+
+    #[cfg(feature = "qhy")]
+    #[test]
+    fn only_a_loader_on_the_reset_port_counts_as_the_camera_coming_back() {
+        let camera = device("4-1.2", usb::QHY_VID, 0xc368, Some("abc"));
+        assert!(came_back(&camera, "4-1.2", usb::QHY_VID, Some(0xc368), Some("abc")));
+        // the camera re-enumerating on another port is still the camera
+        let moved = device("4-1.3", usb::QHY_VID, 0xc368, Some("abc"));
+        assert!(came_back(&moved, "4-1.2", usb::QHY_VID, Some(0xc368), Some("abc")));
+
+        let loader = device("4-1.2", usb::QHY_VID, 0xc367, None);
+        assert!(came_back(&loader, "4-1.2", usb::QHY_VID, Some(0xc368), Some("abc")));
+        let other_loader = device("3-2", usb::QHY_VID, 0xc367, None);
+        assert!(!came_back(&other_loader, "4-1.2", usb::QHY_VID, Some(0xc368), Some("abc")));
+        let other_camera = device("4-1.2", usb::QHY_VID, 0xc368, Some("def"));
+        assert!(!came_back(&other_camera, "3-2", usb::QHY_VID, Some(0xc368), Some("abc")));
+    }
+}
@@ -0,0 +1,705 @@
+// Imaging sequences: lights through several filters, interleaved or in blocks, each filter
+// with its own exposure, gain and focus offset, and the flats to go with them. They run on
+// any `Imager` with any `FilterChanger`, so the simulator can stand in for the rig.
+
+use crate::badpixels::BadPixelMap;
+use crate::focus::{self, AnyFocuser, FocusError};
+use crate::frame::{Frame, FrameType, HeaderValue};
+use crate::imaging::{Imager, Reopen};
+use crate::phd2;
+use crate::preview::Preview;
+use crate::session::SessionLog;
+use crate::stars::StarFinder;
+
+use std::fmt;
+use std::io::{self, BufRead};
+use std::time::Duration;
+
+/// How long a camera gets to finish enumerating after being plugged back in, before its SDK
+/// goes looking for it.
+const ENUMERATE_DELAY: Duration = Duration::from_secs(2);
+
+/// How long the focuser gets to make a filter's focus offset.
+const FOCUS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a sequence needs from a filter wheel. Wheels that hang off the camera are driven
+/// through it, so the camera is handed to each call.
+pub trait FilterChanger<C> {
+    type Error: fmt::Debug;
+
+    /// Put the filter called `name` in the light path, returning once it's there.
+    fn change_to(&mut self, camera: &mut C, name: &str) -> Result<(), Self::Error>;
+}
+
+/// Filters changed by hand, for a filter drawer or a rig without a wheel: asks on the console
+/// and waits for enter.
+#[derive(Debug, Default)]
+pub struct ManualFilters {
+    current: Option<String>
+}
+
+impl<C> FilterChanger<C> for ManualFilters {
+    type Error = io::Error;
+
+    fn change_to(&mut self, _camera: &mut C, name: &str) -> io::Result<()> {
+        if self.current.as_deref() == Some(name) {
+            return Ok(());
+        }
+        println!("Put the {} filter in and press enter", name);
+        io::stdin().lock().read_line(&mut String::new())?;
+        self.current = Some(name.to_owned());
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum SequenceError<W, C> {
+    FilterWheel(W),
+    Camera(C),
+    /// The camera was unplugged and didn't come back in time.
+    Removed,
+    /// The plan has a focus offset for this filter, but there's no focuser to apply it.
+    NoFocuser(String),
+    /// The focuser failed or didn't finish a move, as described.
+    Focuser(String)
+}
+
+/// Exposure settings for one filter in a sequence.
+#[derive(Debug, Clone)]
+pub struct FilterPlan {
+    pub filter: String,
+    pub count: u32,
+    pub exposure_ms: u32,
+    pub gain: f64,
+    /// Focuser steps relative to best focus for this filter.
+    pub focus_offset: i32,
+    /// Exposure for flats through this filter, if flats should be taken for it.
+    pub flat_exposure_ms: Option<u32>
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Interleave {
+    /// Cycle filters every frame: L R G B L R G B ...
+    PerFrame,
+    /// Take this many frames through a filter before moving to the next.
+    PerBlock(u32)
+}
+
+#[derive(Debug, Clone)]
+pub struct Sequence {
+    pub name: String,
+    pub filters: Vec<FilterPlan>,
+    pub interleave: Interleave,
+    /// Lights, or flats from `flats`.
+    pub frame_type: FrameType
+}
+
+/// One frame of a sequence: which filter plan it uses and its number within that filter.
+#[derive(Debug, Copy, Clone)]
+pub struct Step<'a> {
+    pub plan: &'a FilterPlan,
+    pub index: u32
+}
+
+impl Sequence {
+    /// Parse a sequence of lights from a plan with one filter per line, `#` starting a
+    /// comment, and an optional `interleave` line: `frame` cycles filters every frame, a
+    /// number takes that many through each filter in turn. E.g.:
+    ///
+    /// ```text
+    /// interleave 5
+    /// # filter  count  exposure ms  gain  focus offset  [flat exposure ms]
+    /// L         20     60000        100   0             1500
+    /// Ha        10     300000       200   -40
+    /// ```
+    pub fn parse(name: &str, text: &str) -> Result<Sequence, String> {
+        let mut sequence = Sequence {
+            name: name.to_owned(),
+            filters: Vec::new(),
+            interleave: Interleave::PerFrame,
+            frame_type: FrameType::Light
+        };
+        for (lineno, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(idx) => &line[..idx],
+                None => line
+            }.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let bad = |what: &str| format!("line {}: bad {} in {:?}", lineno + 1, what, line);
+            if parts[0] == "interleave" {
+                sequence.interleave = match parts.get(1).copied() {
+                    Some("frame") if parts.len() == 2 => Interleave::PerFrame,
+                    Some(block) if parts.len() == 2 => Interleave::PerBlock(block.parse().map_err(|_| bad("block size"))?),
+                    _ => return Err(format!("line {}: expected `interleave <frame|block size>`", lineno + 1))
+                };
+                continue;
+            }
+            if parts.len() != 5 && parts.len() != 6 {
+                return Err(format!("line {}: expected `<filter> <count> <exposure ms> <gain> <focus offset> [flat exposure ms]`", lineno + 1));
+            }
+            if sequence.filters.iter().any(|plan| plan.filter == parts[0]) {
+                return Err(format!("line {}: filter {} is planned twice", lineno + 1, parts[0]));
+            }
+            sequence.filters.push(FilterPlan {
+                filter: parts[0].to_owned(),
+                count: parts[1].parse().map_err(|_| bad("count"))?,
+                exposure_ms: parts[2].parse().map_err(|_| bad("exposure"))?,
+                gain: parts[3].parse().map_err(|_| bad("gain"))?,
+                focus_offset: parts[4].parse().map_err(|_| bad("focus offset"))?,
+                flat_exposure_ms: match parts.get(5) {
+                    Some(exposure) => Some(exposure.parse().map_err(|_| bad("flat exposure"))?),
+                    None => None
+                }
+            });
+        }
+        Ok(sequence)
+    }
+
+    /// The frames of this sequence in capture order.
+    pub fn steps(&self) -> Vec<Step<'_>> {
+        let block = match self.interleave {
+            Interleave::PerFrame => 1,
+            Interleave::PerBlock(n) => std::cmp::max(n, 1)
+        };
+        let mut taken = vec![0u32; self.filters.len()];
+        let mut steps = Vec::new();
+        loop {
+            let mut progressed = false;
+            for (plan, taken) in self.filters.iter().zip(taken.iter_mut()) {
+                for _ in 0..block {
+                    if *taken >= plan.count {
+                        break;
+                    }
+                    steps.push(Step { plan: plan, index: *taken });
+                    *taken += 1;
+                    progressed = true;
+                }
+            }
+            if !progressed {
+                return steps;
+            }
+        }
+    }
+
+    /// A sequence of `count` flats through every filter that has a flat exposure, in blocks,
+    /// keeping each filter's gain and focus offset so the flats match the lights.
+    pub fn flats(&self, count: u32) -> Sequence {
+        Sequence {
+            name: format!("{}_flat", self.name),
+            filters: self.filters.iter()
+                .filter_map(|plan| {
+                    plan.flat_exposure_ms.map(|exposure_ms| FilterPlan {
+                        count: count,
+                        exposure_ms: exposure_ms,
+                        ..plan.clone()
+                    })
+                })
+                .collect(),
+            interleave: Interleave::PerBlock(count),
+            frame_type: FrameType::Flat
+        }
+    }
+}
+
+/// How far a sequence got: the next step to take and where the focuser is relative to best
+/// focus.
+#[derive(Debug, Copy, Clone, Default)]
+struct Progress {
+    next: usize,
+    focus_offset: i32
+}
+
+/// Runs sequences. On each filter change guiding is paused (if there is a guider) and
+/// `focuser` is moved by the difference between the two filters' focus offsets; plans with
+/// offsets are refused without one. Every frame is recorded in `log`.
+pub struct Runner<'a> {
+    pub guider: Option<&'a mut phd2::Guider>,
+    pub focuser: Option<&'a mut AnyFocuser>,
+    /// Steps past a focus position to go before moving in to it, as for autofocus.
+    pub focus_backlash: i32,
+    pub log: &'a mut SessionLog,
+    /// Patched into lights. Flats are left as they are: calibrating with them needs the
+    /// sensor's real response, bad pixels and all.
+    pub bad_pixels: Option<BadPixelMap>,
+    /// How long `run_reconnecting` waits for an unplugged camera to come back.
+    pub reconnect: Duration,
+    /// How the preview written next to each frame is rendered, and as what.
+    pub preview: Preview
+}
+
+impl<'a> Runner<'a> {
+    pub fn new(log: &'a mut SessionLog) -> Runner<'a> {
+        Runner {
+            guider: None,
+            focuser: None,
+            focus_backlash: 0,
+            log: log,
+            bad_pixels: None,
+            reconnect: Duration::from_secs(300),
+            preview: Preview::thumbnail()
+        }
+    }
+
+    /// Run `sequence` on `camera`, changing filters with `wheel`.
+    pub fn run<C, W>(&mut self, camera: &mut C, wheel: &mut W, sequence: &Sequence) -> Result<(), SequenceError<W::Error, C::Error>>
+        where C: Imager, W: FilterChanger<C>
+    {
+        self.check_focuser(sequence)?;
+        let mut progress = Progress::default();
+        let result = self.run_steps(camera, wheel, sequence, &mut progress);
+        let restored = self.restore_focus(&progress);
+        result.and(restored)
+    }
+
+    /// Like `run`, but if the camera is unplugged partway through, wait up to `reconnect`
+    /// for it to be plugged back in, reopen it and carry on from the frame that was lost. The
+    /// camera's `Presence` must be watched by a hotplug `Watcher`.
+    pub fn run_reconnecting<C, W>(&mut self, camera: &mut C, wheel: &mut W, sequence: &Sequence) -> Result<(), SequenceError<W::Error, C::Error>>
+        where C: Reopen, W: FilterChanger<C>
+    {
+        self.check_focuser(sequence)?;
+        let mut progress = Progress::default();
+        let presence = camera.presence();
+        let result = loop {
+            match self.run_steps(camera, wheel, sequence, &mut progress) {
+                Err(_) if !presence.is_present() => {
+                    println!("Camera was unplugged, waiting up to {}s for it to come back", self.reconnect.as_secs());
+                    if !presence.wait_present(self.reconnect) {
+                        break Err(SequenceError::Removed);
+                    }
+                    std::thread::sleep(ENUMERATE_DELAY);
+                    if let Err(e) = camera.reopen() {
+                        break Err(SequenceError::Camera(e));
+                    }
+                    println!("Camera is back, resuming at frame {}", progress.next + 1);
+                }
+                result => break result
+            }
+        };
+        let restored = self.restore_focus(&progress);
+        result.and(restored)
+    }
+
+    /// Refuse a plan with focus offsets when there's nothing to apply them with, rather than
+    /// take every frame through those filters out of focus.
+    fn check_focuser<W, C>(&self, sequence: &Sequence) -> Result<(), SequenceError<W, C>> {
+        match sequence.filters.iter().find(|plan| plan.focus_offset != 0) {
+            Some(plan) if self.focuser.is_none() => Err(SequenceError::NoFocuser(plan.filter.clone())),
+            _ => Ok(())
+        }
+    }
+
+    fn restore_focus<W, C>(&mut self, progress: &Progress) -> Result<(), SequenceError<W, C>> {
+        // put the focuser back where we found it
+        if progress.focus_offset != 0 {
+            self.move_focus(-progress.focus_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Move the focuser `steps` from where it is, if there is one.
+    fn move_focus<W, C>(&mut self, steps: i32) -> Result<(), SequenceError<W, C>> {
+        let focuser = match self.focuser.as_mut() {
+            Some(focuser) => focuser,
+            None => return Ok(())
+        };
+        let position = focuser.position().map_err(|e| SequenceError::Focuser(format!("{:?}", e)))?;
+        match focus::move_to::<_, ()>(&mut **focuser, position + steps, self.focus_backlash, FOCUS_TIMEOUT) {
+            Ok(()) => Ok(()),
+            Err(FocusError::Focuser(e)) => Err(SequenceError::Focuser(format!("{:?}", e))),
+            Err(_) => Err(SequenceError::Focuser(format!("still moving to {} after {}s", position + steps, FOCUS_TIMEOUT.as_secs())))
+        }
+    }
+
+    /// Finish a frame off the camera for saving.
+    fn prepare(&self, frame: &mut Frame, frame_type: FrameType, filter: &str) {
+        frame.set_header("FILTER", HeaderValue::Str(filter.to_owned()));
+        if frame_type == FrameType::Light {
+            if let Some(map) = self.bad_pixels.as_ref() {
+                if !map.correct_frame(frame) {
+                    println!("The bad pixel map is for {}x{} frames, not {}x{}", map.width, map.height, frame.width, frame.height);
+                }
+            }
+        }
+    }
+
+    fn run_steps<C, W>(&mut self, camera: &mut C, wheel: &mut W, sequence: &Sequence, progress: &mut Progress) -> Result<(), SequenceError<W::Error, C::Error>>
+        where C: Imager, W: FilterChanger<C>
+    {
+        let mut current: Option<&FilterPlan> = None;
+        for step in sequence.steps().into_iter().skip(progress.next) {
+            let plan = step.plan;
+            if current.map(|c| c.filter != plan.filter).unwrap_or(true) {
+                if let Some(guider) = self.guider.as_mut() {
+                    if let Err(e) = guider.pause() {
+                        println!("Couldn't pause guiding for the filter change: {:?}", e);
+                    }
+                }
+                wheel.change_to(camera, &plan.filter).map_err(SequenceError::FilterWheel)?;
+                if plan.focus_offset != progress.focus_offset {
+                    self.move_focus(plan.focus_offset - progress.focus_offset)?;
+                    progress.focus_offset = plan.focus_offset;
+                }
+                camera.set_exposure(Duration::from_millis(plan.exposure_ms as u64)).map_err(SequenceError::Camera)?;
+                camera.set_gain(plan.gain).map_err(SequenceError::Camera)?;
+                if let Some(guider) = self.guider.as_mut() {
+                    if let Err(e) = guider.resume() {
+                        println!("Couldn't resume guiding after the filter change: {:?}", e);
+                    }
+                }
+                current = Some(plan);
+            }
+
+            let path = format!("{}_{}_{:06}.fits", sequence.name, plan.filter, step.index);
+            println!("{} ({} of {})", path, step.index + 1, plan.count);
+            let mut frame = camera.capture(sequence.frame_type).map_err(SequenceError::Camera)?;
+            self.prepare(&mut frame, sequence.frame_type, &plan.filter);
+            if let Err(e) = frame.save(&path) {
+                println!("Failed to write {}: {}", path, e);
+            }
+            if let Err(e) = self.preview.write_for(&frame, &path) {
+                println!("Failed to write a preview of {}: {}", path, e);
+            }
+            let mut fields = vec![
+                ("IMAGETYP".to_owned(), sequence.frame_type.imagetyp().to_owned()),
+                ("FILTER".to_owned(), plan.filter.clone()),
+                ("EXPTIME".to_owned(), format!("{:.3}", plan.exposure_ms as f64 / 1000.0)),
+                ("GAIN".to_owned(), format!("{}", plan.gain)),
+                ("FOCUSOFS".to_owned(), format!("{}", plan.focus_offset))
+            ];
+            if let Some(temperature) = frame.setup().temperature {
+                fields.push(("CCD-TEMP".to_owned(), format!("{:.1}", temperature)));
+            }
+            if let Some(stats) = frame.stats.as_ref() {
+                fields.extend(stats.log_fields());
+            }
+            if sequence.frame_type == FrameType::Light {
+                fields.extend(StarFinder::default().find(&frame).log_fields());
+            }
+            if let Err(e) = self.log.record(&path, &fields) {
+                println!("Failed to record {} in the session log: {}", path, e);
+            }
+            progress.next += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::fits;
+    use crate::focus::{Boxed, Focuser};
+    use crate::hotplug::Presence;
+    use crate::sim;
+    use crate::testing::TempDir;
+    use std::convert::Infallible;
+
+    const PLAN: &str = "
+        # filter  count  exposure ms  gain  focus offset  [flat exposure ms]
+        interleave 2
+        L   3  1000  1  0    200
+        Ha  2  3000  1  -40
+        R   1  1000  1  15   400
+    ";
+
+    /// A wheel that changes instantly and remembers where it went.
+    #[derive(Default)]
+    struct FakeWheel {
+        moves: Vec<String>
+    }
+
+    impl<C> FilterChanger<C> for FakeWheel {
+        type Error = Infallible;
+
+        fn change_to(&mut self, _camera: &mut C, name: &str) -> Result<(), Infallible> {
+            self.moves.push(name.to_owned());
+            Ok(())
+        }
+    }
+
+    /// A focuser that gets where it's sent instantly and remembers each move.
+    #[derive(Default)]
+    struct FakeFocuser {
+        position: i32,
+        moves: Vec<i32>
+    }
+
+    impl Focuser for FakeFocuser {
+        type Error = Infallible;
+
+        fn position(&self) -> Result<i32, Infallible> {
+            Ok(self.position)
+        }
+
+        fn move_to(&mut self, position: i32) -> Result<(), Infallible> {
+            self.position = position;
+            self.moves.push(position);
+            Ok(())
+        }
+
+        fn is_moving(&self) -> Result<bool, Infallible> {
+            Ok(false)
+        }
+
+        fn temperature(&self) -> Result<Option<f64>, Infallible> {
+            Ok(None)
+        }
+    }
+
+    fn log_lines(path: &std::path::Path) -> Vec<String> {
+        std::fs::read_to_string(path).unwrap().lines().map(|line| line.to_owned()).collect()
+    }
+
+    fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+        line.split('\t').find_map(|field| field.strip_prefix(key).and_then(|rest| rest.strip_prefix('=')))
+    }
+
+    #[test]
+    fn plans_parse() {
+        let sequence = Sequence::parse("M31", PLAN).unwrap();
+        assert_eq!(sequence.filters.len(), 3);
+        assert_eq!(sequence.filters[1].filter, "Ha");
+        assert_eq!(sequence.filters[1].exposure_ms, 3000);
+        assert_eq!(sequence.filters[1].focus_offset, -40);
+        assert_eq!(sequence.filters[1].flat_exposure_ms, None);
+        assert_eq!(sequence.filters[2].flat_exposure_ms, Some(400));
+        assert!(matches!(sequence.interleave, Interleave::PerBlock(2)));
+        assert_eq!(sequence.frame_type, FrameType::Light);
+
+        assert!(Sequence::parse("x", "L 3 1000 1").is_err());
+        assert!(Sequence::parse("x", "L 3 1000 1 0\nL 2 1000 1 0").is_err());
+        assert!(Sequence::parse("x", "interleave sometimes").is_err());
+        assert!(Sequence::parse("x", "L three 1000 1 0").is_err());
+    }
+
+    #[test]
+    fn steps_follow_the_interleave() {
+        let mut sequence = Sequence::parse("M31", PLAN).unwrap();
+        let order = |sequence: &Sequence| -> Vec<String> {
+            sequence.steps().iter().map(|step| format!("{}{}", step.plan.filter, step.index)).collect()
+        };
+        assert_eq!(order(&sequence), ["L0", "L1", "Ha0", "Ha1", "R0", "L2"]);
+        sequence.interleave = Interleave::PerFrame;
+        assert_eq!(order(&sequence), ["L0", "Ha0", "R0", "L1", "Ha1", "L2"]);
+
+        let flats = sequence.flats(2);
+        assert_eq!(flats.frame_type, FrameType::Flat);
+        assert_eq!(order(&flats), ["L0", "L1", "R0", "R1"]);
+        assert_eq!(flats.filters[1].exposure_ms, 400);
+    }
+
+    #[test]
+    fn lights_get_their_filter_and_bad_pixels_patched_but_flats_keep_them() {
+        let mut camera = sim::Camera::new(64, 48);
+        camera.stars.clear();
+        camera.hot_pixels = vec![(10, 10, 5000.0)];
+        let dir = TempDir::new("sequence_prepare");
+        let mut log = SessionLog::open(dir.join("session.log")).unwrap();
+        let mut runner = Runner::new(&mut log);
+        runner.bad_pixels = Some(BadPixelMap {
+            serial: "SIM-0001".to_owned(),
+            binning: 1,
+            width: 64,
+            height: 48,
+            hot: vec![(10, 10)],
+            cold: Vec::new(),
+            columns: Vec::new()
+        });
+
+        let mut light = camera.capture(FrameType::Light);
+        assert!(light.sample(10, 10, 0) > 3000);
+        runner.prepare(&mut light, FrameType::Light, "Ha");
+        assert_eq!(light.header("FILTER"), Some(&HeaderValue::Str("Ha".to_owned())));
+        assert!(light.sample(10, 10, 0) < 1000);
+
+        let mut flat = camera.capture(FrameType::Flat);
+        let hot = flat.sample(10, 10, 0);
+        runner.prepare(&mut flat, FrameType::Flat, "Ha");
+        assert_eq!(flat.header("FILTER"), Some(&HeaderValue::Str("Ha".to_owned())));
+        assert_eq!(flat.sample(10, 10, 0), hot);
+    }
+
+    #[test]
+    fn runs_change_filters_and_focus_and_log_every_frame() {
+        let dir = TempDir::new("sequence_run");
+        let mut sequence = Sequence::parse(dir.join("M31").to_str().unwrap(), PLAN).unwrap();
+        sequence.interleave = Interleave::PerFrame;
+        let mut camera = sim::Camera::new(64, 48);
+        let mut wheel = FakeWheel::default();
+        let mut focuser = Boxed(FakeFocuser { position: 1000, moves: Vec::new() });
+        let mut log = SessionLog::open(dir.join("session.log")).unwrap();
+        {
+            let mut runner = Runner::new(&mut log);
+            runner.focuser = Some(&mut focuser);
+            runner.run(&mut camera, &mut wheel, &sequence).unwrap();
+            runner.run(&mut camera, &mut wheel, &sequence.flats(1)).unwrap();
+        }
+
+        assert_eq!(wheel.moves, ["L", "Ha", "R", "L", "Ha", "L", "L", "R"]);
+        // out to Ha, over to R, back to L each round; then back to best focus once done, and
+        // the same for the flats
+        assert_eq!(focuser.0.moves, [960, 1015, 1000, 960, 1000, 1015, 1000]);
+
+        let lines = log_lines(&dir.join("session.log"));
+        assert_eq!(lines.len(), 8);
+        assert_eq!(field(&lines[1], "FILTER"), Some("Ha"));
+        assert_eq!(field(&lines[1], "EXPTIME"), Some("3.000"));
+        assert_eq!(field(&lines[1], "IMAGETYP"), Some("Light Frame"));
+        assert_eq!(field(&lines[7], "IMAGETYP"), Some("Flat Field"));
+        let (_, headers) = fits::read(dir.join("M31_Ha_000001.fits")).unwrap();
+        assert!(headers.contains(&("FILTER".to_owned(), HeaderValue::Str("Ha".to_owned()))));
+        assert!(headers.contains(&("IMAGETYP".to_owned(), HeaderValue::Str("Light Frame".to_owned()))));
+        assert!(headers.iter().any(|(key, _)| key == "DATE-OBS"));
+        let (_, headers) = fits::read(dir.join("M31_flat_R_000000.fits")).unwrap();
+        assert!(headers.contains(&("FILTER".to_owned(), HeaderValue::Str("R".to_owned()))));
+        assert!(headers.contains(&("IMAGETYP".to_owned(), HeaderValue::Str("Flat Field".to_owned()))));
+        assert!(dir.join("M31_flat_R_000000_preview.png").exists());
+    }
+
+    #[test]
+    fn focus_offsets_finish_going_out() {
+        let dir = TempDir::new("sequence_backlash");
+        let sequence = Sequence::parse(dir.join("M31").to_str().unwrap(), PLAN).unwrap();
+        let mut camera = sim::Camera::new(64, 48);
+        let mut focuser = Boxed(FakeFocuser { position: 1000, moves: Vec::new() });
+        let mut log = SessionLog::open(dir.join("session.log")).unwrap();
+        let mut runner = Runner::new(&mut log);
+        runner.focuser = Some(&mut focuser);
+        runner.focus_backlash = 30;
+        runner.run(&mut camera, &mut FakeWheel::default(), &sequence).unwrap();
+        drop(runner);
+        // in to Ha past it, out to R, in to L past it
+        assert_eq!(focuser.0.moves, [930, 960, 1015, 970, 1000]);
+    }
+
+    #[test]
+    fn focus_offsets_without_a_focuser_are_refused() {
+        let dir = TempDir::new("sequence_no_focuser");
+        let sequence = Sequence::parse(dir.join("M31").to_str().unwrap(), PLAN).unwrap();
+        let mut camera = sim::Camera::new(64, 48);
+        let mut wheel = FakeWheel::default();
+        let mut log = SessionLog::open(dir.join("session.log")).unwrap();
+        let mut runner = Runner::new(&mut log);
+        match runner.run(&mut camera, &mut wheel, &sequence) {
+            Err(SequenceError::NoFocuser(filter)) => assert_eq!(filter, "Ha"),
+            other => panic!("{:?}", other)
+        }
+        assert!(matches!(runner.run_reconnecting(&mut camera, &mut wheel, &sequence), Err(SequenceError::NoFocuser(_))));
+        assert!(wheel.moves.is_empty());
+
+        // a plan that keeps every filter at best focus doesn't need one
+        let parfocal = Sequence::parse(dir.join("M31").to_str().unwrap(), "L 1 1000 1 0\nR 1 1000 1 0").unwrap();
+        runner.run(&mut camera, &mut wheel, &parfocal).unwrap();
+        assert_eq!(wheel.moves, ["L", "R"]);
+    }
+
+    /// A simulated camera that falls off the bus before the frame numbered `unplug_at`, and
+    /// comes back a moment later.
+    struct Flaky {
+        camera: sim::Camera,
+        presence: Presence,
+        taken: u32,
+        unplug_at: Option<u32>,
+        reopened: u32
+    }
+
+    impl Imager for Flaky {
+        type Error = &'static str;
+
+        fn set_exposure(&mut self, exposure: Duration) -> Result<(), &'static str> {
+            self.camera.exposure = exposure;
+            Ok(())
+        }
+
+        fn set_gain(&mut self, _gain: f64) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn exposure_range(&self) -> Result<(Duration, Duration), &'static str> {
+            Ok((Duration::from_millis(1), Duration::from_secs(3600)))
+        }
+
+        fn set_subframe(&mut self, _subframe: Option<crate::imaging::Subframe>) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn serial(&self) -> String {
+            "FLAKY".to_owned()
+        }
+
+        fn binning(&self) -> u32 {
+            1
+        }
+
+        fn capture(&mut self, frame_type: FrameType) -> Result<Frame, &'static str> {
+            if !self.presence.is_present() {
+                return Err("removed");
+            }
+            if self.unplug_at == Some(self.taken) {
+                self.unplug_at = None;
+                self.presence.set(false);
+                let presence = self.presence.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(100));
+                    presence.set(true);
+                });
+                return Err("removed");
+            }
+            self.taken += 1;
+            Ok(self.camera.capture(frame_type))
+        }
+    }
+
+    impl Reopen for Flaky {
+        fn presence(&self) -> Presence {
+            self.presence.clone()
+        }
+
+        fn reopen(&mut self) -> Result<(), &'static str> {
+            self.reopened += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn unplugged_cameras_are_waited_for_and_the_sequence_resumes() {
+        let dir = TempDir::new("sequence_reconnect");
+        let sequence = Sequence::parse(dir.join("M31").to_str().unwrap(), PLAN).unwrap();
+        let mut camera = Flaky {
+            camera: sim::Camera::new(64, 48),
+            presence: Presence::new(),
+            taken: 0,
+            unplug_at: Some(3),
+            reopened: 0
+        };
+        let mut wheel = FakeWheel::default();
+        let mut focuser = Boxed(FakeFocuser::default());
+        let mut log = SessionLog::open(dir.join("session.log")).unwrap();
+        {
+            let mut runner = Runner::new(&mut log);
+            runner.focuser = Some(&mut focuser);
+            runner.reconnect = Duration::from_secs(5);
+            runner.run_reconnecting(&mut camera, &mut wheel, &sequence).unwrap();
+        }
+        assert_eq!(camera.reopened, 1);
+        assert_eq!(camera.taken, 6);
+        // the filter is set again after reopening, in case the wheel lost its place
+        assert_eq!(wheel.moves, ["L", "Ha", "Ha", "R", "L"]);
+        let files: Vec<String> = log_lines(&dir.join("session.log")).iter()
+            .map(|line| field(line, "FILE").unwrap().rsplit('/').next().unwrap().to_owned())
+            .collect();
+        assert_eq!(files, ["M31_L_000000.fits", "M31_L_000001.fits", "M31_Ha_000000.fits", "M31_Ha_000001.fits", "M31_R_000000.fits", "M31_L_000002.fits"]);
+
+        // and if it never comes back, the sequence gives up
+        camera.unplug_at = Some(camera.taken);
+        camera.presence.set(false);
+        let mut runner = Runner::new(&mut log);
+        runner.focuser = Some(&mut focuser);
+        runner.reconnect = Duration::from_millis(50);
+        assert!(matches!(runner.run_reconnecting(&mut camera, &mut wheel, &sequence), Err(SequenceError::Removed)));
+    }
+}
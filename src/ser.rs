@@ -0,0 +1,108 @@
+// Writer for SER videos, the format planetary and occultation tools expect: a fixed header,
+// raw frames back to back, then a trailer with a UTC timestamp per frame.
+
+use crate::frame::Frame;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HEADER_LEN: u64 = 178;
+const FRAME_COUNT_OFFSET: u64 = 38;
+const DATE_OFFSET: u64 = 162;
+// the two dates are the last thing in the header
+const _: () = assert!(DATE_OFFSET + 16 == HEADER_LEN);
+
+const COLOR_MONO: i32 = 0;
+const COLOR_RGB: i32 = 100;
+
+/// SER timestamps are .NET `DateTime` ticks: 100ns since 0001-01-01 00:00:00.
+fn ser_ticks(time: SystemTime) -> i64 {
+    const UNIX_EPOCH_TICKS: i64 = 621_355_968_000_000_000;
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH_TICKS + since.as_secs() as i64 * 10_000_000 + (since.subsec_nanos() / 100) as i64,
+        Err(_) => UNIX_EPOCH_TICKS
+    }
+}
+
+fn fixed_str(s: &str) -> [u8; 40] {
+    let mut field = [0u8; 40];
+    for (dest, src) in field.iter_mut().zip(s.bytes()) {
+        *dest = src;
+    }
+    field
+}
+
+pub struct SerWriter {
+    w: BufWriter<File>,
+    width: u32,
+    height: u32,
+    channels: u32,
+    bpp: u32,
+    timestamps: Vec<i64>
+}
+
+impl SerWriter {
+    /// Start a video of frames shaped like `first`; later frames must match it.
+    pub fn create<P: AsRef<Path>>(path: P, first: &Frame, instrument: &str) -> io::Result<SerWriter> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(b"LUCAM-RECORDER")?;
+        w.write_all(&0i32.to_le_bytes())?; // LuID
+        w.write_all(&(if first.channels == 3 { COLOR_RGB } else { COLOR_MONO }).to_le_bytes())?;
+        // the spec says 1 means little-endian, but most readers and writers in use have it
+        // backwards and take 0 to mean little-endian.
+        w.write_all(&0i32.to_le_bytes())?;
+        w.write_all(&(first.width as i32).to_le_bytes())?;
+        w.write_all(&(first.height as i32).to_le_bytes())?;
+        w.write_all(&(first.bpp as i32).to_le_bytes())?;
+        w.write_all(&0i32.to_le_bytes())?; // frame count, patched in `finish`
+        w.write_all(&fixed_str(""))?; // observer
+        w.write_all(&fixed_str(instrument))?;
+        w.write_all(&fixed_str(""))?; // telescope
+        w.write_all(&0i64.to_le_bytes())?; // local start time, patched in `finish`
+        w.write_all(&0i64.to_le_bytes())?; // UTC start time, patched in `finish`
+        Ok(SerWriter {
+            w: w,
+            width: first.width,
+            height: first.height,
+            channels: first.channels,
+            bpp: first.bpp,
+            timestamps: Vec::new()
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if frame.width != self.width || frame.height != self.height ||
+           frame.channels != self.channels || frame.bpp != self.bpp {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame doesn't match the video's format"));
+        }
+        let mut bytes: Vec<u8> = Vec::with_capacity(frame.data.len() * 2);
+        for sample in frame.data.iter() {
+            if self.bpp <= 8 {
+                bytes.push(*sample as u8);
+            } else {
+                bytes.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        self.w.write_all(&bytes)?;
+        // the start of exposure where it's known, as occultation timing wants
+        self.timestamps.push(ser_ticks(frame.started.unwrap_or(frame.timestamp)));
+        Ok(())
+    }
+
+    /// Write the timestamp trailer and fill in the header fields that depend on the frames.
+    pub fn finish(mut self) -> io::Result<()> {
+        for ts in self.timestamps.iter() {
+            self.w.write_all(&ts.to_le_bytes())?;
+        }
+        let start = self.timestamps.first().cloned().unwrap_or(0);
+        self.w.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+        self.w.write_all(&(self.timestamps.len() as i32).to_le_bytes())?;
+        self.w.seek(SeekFrom::Start(DATE_OFFSET))?;
+        // we don't know the local timezone, so local time is UTC too
+        self.w.write_all(&start.to_le_bytes())?;
+        self.w.write_all(&start.to_le_bytes())?;
+        self.w.flush()
+    }
+}
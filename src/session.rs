@@ -0,0 +1,29 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Append-only log of what was captured in a session, one line per frame of tab-separated
+/// `KEY=value` fields, so that filter, exposure and such survive next to image formats that
+/// can't carry them.
+pub struct SessionLog {
+    file: File
+}
+
+impl SessionLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SessionLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(SessionLog { file: file })
+    }
+
+    pub fn record<K: AsRef<str>>(&mut self, path: &str, fields: &[(K, String)]) -> io::Result<()> {
+        let mut line = format!("FILE={}", path);
+        for (key, value) in fields {
+            line.push('\t');
+            line.push_str(key.as_ref());
+            line.push('=');
+            line.push_str(value);
+        }
+        line.push('\n');
+        self.file.write_all(line.as_bytes())
+    }
+}
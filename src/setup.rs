@@ -0,0 +1,337 @@
+// Checking that the machine is set up to talk to the cameras without root: udev rules that
+// open up the device nodes and raise usbfs's buffer limit, and that they've taken effect.
+// The /sys, /dev and /etc/udev roots are arguments so a copy of them can be checked.
+
+#[cfg(feature = "qhy")]
+use crate::firmware;
+use crate::usb::{self, ASI_VID, QHY_VID};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// usbfs buffer memory the SDKs want, in MB. ZWO recommend 200; the kernel default of 16 is
+/// too small for a full frame from the bigger sensors.
+pub const USBFS_MEMORY_MB: u32 = 200;
+
+pub const RULES_FILE: &str = "rules.d/99-astro-cameras.rules";
+
+#[derive(Debug, Clone)]
+pub struct Roots {
+    pub sys: PathBuf,
+    pub dev: PathBuf,
+    pub udev: PathBuf
+}
+
+impl Default for Roots {
+    fn default() -> Roots {
+        Roots {
+            sys: PathBuf::from("/sys"),
+            dev: PathBuf::from("/dev"),
+            udev: PathBuf::from("/etc/udev")
+        }
+    }
+}
+
+impl Roots {
+    fn usbfs_memory_mb(&self) -> PathBuf {
+        self.sys.join("module/usbcore/parameters/usbfs_memory_mb")
+    }
+
+    fn usb_devices(&self) -> PathBuf {
+        self.sys.join("bus/usb/devices")
+    }
+
+    fn dev_usb(&self) -> PathBuf {
+        self.dev.join("bus/usb")
+    }
+
+    pub fn rules_path(&self) -> PathBuf {
+        self.udev.join(RULES_FILE)
+    }
+}
+
+/// The udev rules for every camera we support: each vendor's devices, plus the bare
+/// FX2/FX3 loaders that QHY cameras show up as before their firmware is loaded.
+pub fn rules() -> String {
+    let mut rules = String::new();
+    rules.push_str("# Generated by `setup rules`. ZWO ASI and QHY cameras, filter wheels and\n");
+    rules.push_str("# firmware loaders, readable and writable without root.\n\n");
+    rules.push_str(&format!(
+        "ACTION==\"add\", SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{:04x}\", RUN+=\"/bin/sh -c '/bin/echo {} >/sys/module/usbcore/parameters/usbfs_memory_mb'\"\n",
+        ASI_VID, USBFS_MEMORY_MB
+    ));
+    rules.push_str(&format!(
+        "ACTION==\"add\", SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{:04x}\", RUN+=\"/bin/sh -c '/bin/echo {} >/sys/module/usbcore/parameters/usbfs_memory_mb'\"\n\n",
+        QHY_VID, USBFS_MEMORY_MB
+    ));
+    rules.push_str("# ZWO ASI cameras and filter wheels\n");
+    rules.push_str(&format!("SUBSYSTEMS==\"usb\", ATTR{{idVendor}}==\"{:04x}\", MODE=\"0666\"\n", ASI_VID));
+    rules.push_str("# QHY cameras and filter wheels\n");
+    rules.push_str(&format!("SUBSYSTEMS==\"usb\", ATTR{{idVendor}}==\"{:04x}\", MODE=\"0666\"\n", QHY_VID));
+    #[cfg(feature = "qhy")]
+    for device in firmware::DEVICES {
+        rules.push_str(&format!("# {} before its firmware ({}) is loaded\n", device.name, device.image));
+        rules.push_str(&format!(
+            "SUBSYSTEMS==\"usb\", ATTR{{idVendor}}==\"{:04x}\", ATTR{{idProduct}}==\"{:04x}\", MODE=\"0666\"\n",
+            device.vid, device.pid
+        ));
+    }
+    rules
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    /// No rules file at the path.
+    RulesMissing(PathBuf),
+    /// A rules file is there but lacks some of the rules `rules()` would write.
+    RulesOutdated(PathBuf),
+    /// usbfs_memory_mb is set to this, below `USBFS_MEMORY_MB`.
+    UsbfsMemory(u32),
+    /// usbfs_memory_mb couldn't be read.
+    UsbfsMemoryUnknown(io::Error),
+    /// A camera's device node isn't readable and writable by everyone.
+    Permissions { node: PathBuf, vid: u16, pid: u16, mode: u32 },
+    /// A camera's device node doesn't exist or can't be looked at.
+    NoDeviceNode { node: PathBuf, vid: u16, pid: u16 }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::RulesMissing(path) =>
+                write!(f, "no udev rules at {}, run `setup install`", path.display()),
+            Problem::RulesOutdated(path) =>
+                write!(f, "udev rules at {} are out of date, run `setup install`", path.display()),
+            Problem::UsbfsMemory(mb) =>
+                write!(f, "usbfs_memory_mb is {}, needs to be at least {} (or 0 for no limit)", mb, USBFS_MEMORY_MB),
+            Problem::UsbfsMemoryUnknown(e) =>
+                write!(f, "couldn't read usbfs_memory_mb: {}", e),
+            Problem::Permissions { node, vid, pid, mode } =>
+                write!(f, "{:04x}:{:04x} at {} has mode {:04o}, needs 0666; replug it after installing the rules",
+                    vid, pid, node.display(), mode),
+            Problem::NoDeviceNode { node, vid, pid } =>
+                write!(f, "{:04x}:{:04x} has no device node at {}", vid, pid, node.display())
+        }
+    }
+}
+
+/// Whether the `installed` rules file has every rule in `wanted`. Comments and blank lines
+/// don't count, and neither do extra rules, like the firmware loader ones a build with QHY
+/// support writes, so a file installed by a build with other features still passes.
+fn covers(installed: &str, wanted: &str) -> bool {
+    let installed: Vec<&str> = rule_lines(installed).collect();
+    rule_lines(wanted).all(|rule| installed.contains(&rule))
+}
+
+fn rule_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Everything that's misconfigured, or nothing if it's all fine.
+pub fn check(roots: &Roots) -> io::Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+
+    let rules_path = roots.rules_path();
+    match fs::read_to_string(&rules_path) {
+        Ok(existing) => if !covers(&existing, &rules()) {
+            problems.push(Problem::RulesOutdated(rules_path));
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => problems.push(Problem::RulesMissing(rules_path)),
+        Err(e) => return Err(e)
+    }
+
+    match fs::read_to_string(roots.usbfs_memory_mb()) {
+        Ok(value) => match value.trim().parse::<u32>() {
+            // 0 means no limit
+            Ok(mb) if mb != 0 && mb < USBFS_MEMORY_MB => problems.push(Problem::UsbfsMemory(mb)),
+            Ok(_) => {}
+            Err(_) => problems.push(Problem::UsbfsMemoryUnknown(
+                io::Error::new(io::ErrorKind::InvalidData, format!("{:?} isn't a number", value.trim()))
+            ))
+        },
+        Err(e) => problems.push(Problem::UsbfsMemoryUnknown(e))
+    }
+
+    // no USB bus in sysfs means nothing's attached to check
+    let devices = match usb::list_devices(roots.usb_devices()) {
+        Ok(devices) => devices,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e)
+    };
+    for device in devices.iter().filter(|device| device.vid == ASI_VID || device.vid == QHY_VID) {
+        let node = device.dev_node(roots.dev_usb());
+        match fs::metadata(&node) {
+            Ok(meta) => {
+                let mode = meta.permissions().mode() & 0o777;
+                if mode & 0o666 != 0o666 {
+                    problems.push(Problem::Permissions { node: node, vid: device.vid, pid: device.pid, mode: mode });
+                }
+            }
+            Err(_) => problems.push(Problem::NoDeviceNode { node: node, vid: device.vid, pid: device.pid })
+        }
+    }
+    Ok(problems)
+}
+
+/// Write the rules file, creating `rules.d` if needed.
+pub fn install(roots: &Roots) -> io::Result<PathBuf> {
+    let path = roots.rules_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, rules())?;
+    Ok(path)
+}
+
+/// Roots taken from `--sys`, `--dev` and `--udev` options, defaulting to the real ones.
+pub fn roots_from_args(args: &[String]) -> Roots {
+    let mut roots = Roots::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let root = match arg.as_str() {
+            "--sys" => &mut roots.sys,
+            "--dev" => &mut roots.dev,
+            "--udev" => &mut roots.udev,
+            _ => continue
+        };
+        if let Some(value) = args.next() {
+            *root = Path::new(value).to_owned();
+        }
+    }
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn roots(dir: &TempDir) -> Roots {
+        Roots { sys: dir.join("sys"), dev: dir.join("dev"), udev: dir.join("etc/udev") }
+    }
+
+    fn set_usbfs_memory(roots: &Roots, value: &str) {
+        fs::create_dir_all(roots.usbfs_memory_mb().parent().unwrap()).unwrap();
+        fs::write(roots.usbfs_memory_mb(), format!("{}\n", value)).unwrap();
+    }
+
+    /// A camera in sysfs with its device node in /dev at `mode`.
+    fn add_camera(roots: &Roots, port: &str, vid: u16, pid: u16, devnum: u32, mode: u32) {
+        let dir = roots.usb_devices().join(port);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("idVendor"), format!("{:04x}\n", vid)).unwrap();
+        fs::write(dir.join("idProduct"), format!("{:04x}\n", pid)).unwrap();
+        fs::write(dir.join("busnum"), "4\n").unwrap();
+        fs::write(dir.join("devnum"), format!("{}\n", devnum)).unwrap();
+        let node = roots.dev_usb().join("004").join(format!("{:03}", devnum));
+        fs::create_dir_all(node.parent().unwrap()).unwrap();
+        fs::write(&node, "").unwrap();
+        fs::set_permissions(&node, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn a_set_up_machine_has_no_problems() {
+        let dir = TempDir::new("setup_ok");
+        let roots = roots(&dir);
+        install(&roots).unwrap();
+        set_usbfs_memory(&roots, "200");
+        add_camera(&roots, "4-1", ASI_VID, 0x120a, 5, 0o666);
+        assert!(check(&roots).unwrap().is_empty());
+        // no limit at all is fine too
+        set_usbfs_memory(&roots, "0");
+        assert!(check(&roots).unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing_and_outdated_rules_are_reported() {
+        let dir = TempDir::new("setup_rules");
+        let roots = roots(&dir);
+        set_usbfs_memory(&roots, "200");
+        match check(&roots).unwrap().as_slice() {
+            [Problem::RulesMissing(path)] => assert_eq!(*path, roots.rules_path()),
+            other => panic!("expected the rules to be missing, got {:?}", other)
+        }
+        fs::create_dir_all(roots.rules_path().parent().unwrap()).unwrap();
+        fs::write(roots.rules_path(), "SUBSYSTEMS==\"usb\", ATTR{idVendor}==\"03c3\", MODE=\"0664\"\n").unwrap();
+        match check(&roots).unwrap().as_slice() {
+            [Problem::RulesOutdated(path)] => assert_eq!(*path, roots.rules_path()),
+            other => panic!("expected the rules to be out of date, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn rules_from_a_build_with_other_features_still_count() {
+        let dir = TempDir::new("setup_features");
+        let roots = roots(&dir);
+        set_usbfs_memory(&roots, "200");
+        fs::create_dir_all(roots.rules_path().parent().unwrap()).unwrap();
+        // what a build with other features would have written: other comments, another rule
+        let other_build = rules().replace("# ", "## ")
+            + "SUBSYSTEMS==\"usb\", ATTR{idVendor}==\"1618\", ATTR{idProduct}==\"c999\", MODE=\"0666\"\n";
+        fs::write(roots.rules_path(), &other_build).unwrap();
+        assert!(check(&roots).unwrap().is_empty());
+
+        let last_rule = rules().lines().rfind(|line| line.starts_with("SUBSYSTEMS")).unwrap().to_owned();
+        fs::write(roots.rules_path(), other_build.replace(&last_rule, "")).unwrap();
+        match check(&roots).unwrap().as_slice() {
+            [Problem::RulesOutdated(_)] => {}
+            other => panic!("expected a missing rule to be reported, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn a_low_or_unreadable_usbfs_limit_is_reported() {
+        let dir = TempDir::new("setup_usbfs");
+        let roots = roots(&dir);
+        install(&roots).unwrap();
+        match check(&roots).unwrap().as_slice() {
+            [Problem::UsbfsMemoryUnknown(_)] => {}
+            other => panic!("expected usbfs_memory_mb to be unreadable, got {:?}", other)
+        }
+        set_usbfs_memory(&roots, "16");
+        match check(&roots).unwrap().as_slice() {
+            [Problem::UsbfsMemory(16)] => {}
+            other => panic!("expected usbfs_memory_mb to be too low, got {:?}", other)
+        }
+        set_usbfs_memory(&roots, "lots");
+        match check(&roots).unwrap().as_slice() {
+            [Problem::UsbfsMemoryUnknown(e)] => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            other => panic!("expected usbfs_memory_mb not to parse, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn camera_nodes_need_to_be_open_to_everyone() {
+        let dir = TempDir::new("setup_nodes");
+        let roots = roots(&dir);
+        install(&roots).unwrap();
+        set_usbfs_memory(&roots, "200");
+        add_camera(&roots, "4-1", ASI_VID, 0x120a, 5, 0o664);
+        add_camera(&roots, "4-2", QHY_VID, 0xc368, 6, 0o666);
+        // something that isn't a camera is left alone, whatever its mode
+        add_camera(&roots, "4-3", 0x046d, 0xc52b, 7, 0o600);
+        match check(&roots).unwrap().as_slice() {
+            [Problem::Permissions { node, vid, pid, mode }] => {
+                assert_eq!(*node, roots.dev_usb().join("004/005"));
+                assert_eq!((*vid, *pid, *mode), (ASI_VID, 0x120a, 0o664));
+            }
+            other => panic!("expected the ASI camera's mode to be wrong, got {:?}", other)
+        }
+
+        fs::remove_file(roots.dev_usb().join("004/006")).unwrap();
+        let problems = check(&roots).unwrap();
+        assert!(matches!(problems.last(), Some(Problem::NoDeviceNode { pid: 0xc368, .. })), "{:?}", problems);
+    }
+
+    #[test]
+    fn roots_come_from_options() {
+        let args: Vec<String> = ["install", "--sys", "/tmp/sys", "--udev", "/tmp/udev"].iter().map(|arg| arg.to_string()).collect();
+        let roots = roots_from_args(&args);
+        assert_eq!(roots.sys, PathBuf::from("/tmp/sys"));
+        assert_eq!(roots.dev, PathBuf::from("/dev"));
+        assert_eq!(roots.rules_path(), PathBuf::from("/tmp/udev").join(RULES_FILE));
+    }
+}
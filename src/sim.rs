@@ -0,0 +1,426 @@
+// A simulated camera, for trying things out (and running on CI) without any hardware or
+// vendor SDK. Frames are bias plus dark current plus whatever light the frame type lets in,
+// with shot and read noise, so calibration code gets something realistic to chew on, and
+// optionally overscan columns to measure each row's bias from. It keeps a clock of its own,
+// advanced by each exposure, so the sky can brighten or fade over a session without anything
+// actually waiting. A simulated focuser shares the camera's focus position, and the stars
+// blur as it moves away from best focus.
+
+use crate::fits;
+use crate::focus;
+use crate::frame::{Frame, FrameType, HeaderValue, Setup, Shutter};
+use crate::hotplug::Presence;
+use crate::imaging::{Imager, Reopen, Subframe};
+
+use std::cell::Cell;
+use std::convert::Infallible;
+use std::rc::Rc;
+
+use std::time::{Duration, SystemTime};
+
+/// xorshift64*, which is plenty for noise.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: if seed == 0 { 0x9e3779b97f4a7c15 } else { seed } }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal, by Box-Muller.
+    pub fn normal(&mut self) -> f64 {
+        let u1 = 1.0 - self.uniform();
+        let u2 = self.uniform();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    /// Poisson-ish: exact enough for small means, normal for large ones.
+    pub fn poisson(&mut self, mean: f64) -> f64 {
+        if mean <= 0.0 {
+            return 0.0;
+        }
+        if mean < 30.0 {
+            let limit = (-mean).exp();
+            let mut k = 0.0;
+            let mut p = self.uniform();
+            while p > limit {
+                k += 1.0;
+                p *= self.uniform();
+            }
+            return k;
+        }
+        (mean + mean.sqrt() * self.normal()).max(0.0)
+    }
+}
+
+/// A star in the simulated sky.
+#[derive(Debug, Clone)]
+pub struct Star {
+    pub x: f64,
+    pub y: f64,
+    /// Electrons per second, over the whole star.
+    pub flux: f64
+}
+
+/// `count` stars scattered over a `width` by `height` frame, mostly faint like the real sky.
+pub fn scatter_stars(width: u32, height: u32, count: usize, seed: u64) -> Vec<Star> {
+    let mut rng = Rng::new(seed);
+    (0..count).map(|_| Star {
+        x: rng.uniform() * width as f64,
+        y: rng.uniform() * height as f64,
+        flux: 1000.0 * (50.0f64).powf(rng.uniform().powi(2))
+    }).collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Camera {
+    pub width: u32,
+    pub height: u32,
+    pub exposure: Duration,
+    /// Electrons per ADU.
+    pub gain: f64,
+    /// Bias level in ADU.
+    pub offset: f64,
+    /// Read noise in electrons.
+    pub read_noise: f64,
+    /// Dark current in electrons per pixel per second.
+    pub dark_current: f64,
+    /// Pixels with extra dark current, in electrons per second.
+    pub hot_pixels: Vec<(u32, u32, f64)>,
+    /// Columns with extra dark current in every pixel, in electrons per second.
+    pub hot_columns: Vec<(u32, f64)>,
+    /// Pixels that don't respond to light.
+    pub dead_pixels: Vec<(u32, u32)>,
+    /// Light reaching each pixel with the shutter open, in electrons per second.
+    pub sky: f64,
+    pub stars: Vec<Star>,
+    /// Where the mount points, as a shift of the stars in pixels and a rotation about the
+    /// centre of the sensor in radians, for dithering.
+    pub shift: (f64, f64),
+    pub rotation: f64,
+    /// The sigma of the stars' Gaussian profile at best focus, in pixels.
+    pub psf_sigma: f64,
+    /// Where the optics are in focus, in focuser steps.
+    pub best_focus: i32,
+    /// How much the stars' sigma grows per focuser step away from best focus.
+    pub blur_per_step: f64,
+    /// Where the optics actually are, shared with the `Focuser`s from `focuser`.
+    focus: Rc<Cell<i32>>,
+    subframe: Option<Subframe>,
+    /// How much dimmer the corners are than the centre, as a fraction, for light through
+    /// the optics.
+    pub vignetting: f64,
+    /// Columns of overscan read out to the right of the sensor, for full frames.
+    pub overscan: u32,
+    /// How much the bias wanders from row to row, different in every frame, in ADU.
+    pub row_noise: f64,
+    /// Light from the flat panel, in electrons per second.
+    pub flat_level: f64,
+    /// How fast the flat light changes, as a fraction per second: negative for sky flats at
+    /// dusk, positive at dawn, 0 for a panel.
+    pub twilight: f64,
+    /// Time taken to read out each frame, on top of its exposure.
+    pub readout: Duration,
+    pub temperature: f64,
+    /// When the simulated session started, and how far into it the next exposure starts.
+    start: SystemTime,
+    elapsed: Duration,
+    rng: Rng
+}
+
+impl Camera {
+    pub fn new(width: u32, height: u32) -> Camera {
+        Camera {
+            width: width,
+            height: height,
+            exposure: Duration::from_secs(1),
+            gain: 1.0,
+            offset: 500.0,
+            read_noise: 3.0,
+            dark_current: 0.05,
+            hot_pixels: Vec::new(),
+            hot_columns: Vec::new(),
+            dead_pixels: Vec::new(),
+            sky: 2.0,
+            stars: scatter_stars(width, height, 40, 2),
+            shift: (0.0, 0.0),
+            rotation: 0.0,
+            psf_sigma: 1.5,
+            best_focus: 5000,
+            blur_per_step: 0.01,
+            focus: Rc::new(Cell::new(5000)),
+            subframe: None,
+            vignetting: 0.0,
+            overscan: 0,
+            row_noise: 0.0,
+            flat_level: 3000.0,
+            twilight: 0.0,
+            readout: Duration::from_secs(2),
+            temperature: -10.0,
+            start: SystemTime::now(),
+            elapsed: Duration::from_secs(0),
+            rng: Rng::new(1)
+        }
+    }
+
+    pub fn set_exposure_ms(&mut self, ms: u32) {
+        self.exposure = Duration::from_millis(ms as u64);
+    }
+
+    /// A focuser driving this camera's focus, with `backlash` steps of play in its gears.
+    pub fn focuser(&self, backlash: i32) -> Focuser {
+        Focuser {
+            motor: self.focus.get(),
+            backlash: backlash,
+            optics: self.focus.clone(),
+            temperature: 5.0
+        }
+    }
+
+    /// Scatter `hot` hot pixels and `dead` dead pixels over the sensor, at random but the same
+    /// ones for the same `seed`.
+    pub fn scatter_defects(&mut self, hot: usize, dead: usize, seed: u64) {
+        let mut rng = Rng::new(seed);
+        let (width, height) = (self.width as f64, self.height as f64);
+        let pick = |rng: &mut Rng| ((rng.uniform() * width) as u32, (rng.uniform() * height) as u32);
+        for _ in 0..hot {
+            let (x, y) = pick(&mut rng);
+            // from warm to saturating over a long dark
+            let current = 2.0 * 10f64.powf(3.0 * rng.uniform());
+            self.hot_pixels.push((x, y, current));
+        }
+        for _ in 0..dead {
+            let (x, y) = pick(&mut rng);
+            self.dead_pixels.push((x, y));
+        }
+    }
+
+    /// The stars' sigma where the focuser has the optics now.
+    fn star_sigma(&self) -> f64 {
+        let blur = self.blur_per_step * (self.focus.get() - self.best_focus) as f64;
+        (self.psf_sigma * self.psf_sigma + blur * blur).sqrt()
+    }
+
+    /// Light falling on pixel (`x`, `y`) in electrons per second, for `Light` frames with
+    /// stars of `sigma`.
+    fn light_at(&self, x: u32, y: u32, stars: &[Star], sigma: f64) -> f64 {
+        let reach = 5.0 * sigma;
+        let spread = 2.0 * sigma * sigma;
+        let mut light = self.sky;
+        for star in stars.iter() {
+            let dx = x as f64 - star.x;
+            let dy = y as f64 - star.y;
+            if dx.abs() < reach && dy.abs() < reach {
+                light += star.flux * (-(dx * dx + dy * dy) / spread).exp() / (std::f64::consts::PI * spread);
+            }
+        }
+        light
+    }
+
+    /// Where the stars fall on the sensor with the mount pointing where it is.
+    fn pointed_stars(&self) -> Vec<Star> {
+        let (cx, cy) = (self.width as f64 / 2.0, self.height as f64 / 2.0);
+        let (sin, cos) = self.rotation.sin_cos();
+        self.stars.iter().map(|star| {
+            let (dx, dy) = (star.x - cx, star.y - cy);
+            Star {
+                x: cx + cos * dx - sin * dy + self.shift.0,
+                y: cy + sin * dx + cos * dy + self.shift.1,
+                flux: star.flux
+            }
+        }).collect()
+    }
+
+    /// The fraction of the light through the optics reaching pixel (`x`, `y`).
+    fn illumination(&self, x: u32, y: u32) -> f64 {
+        let dx = x as f64 - self.width as f64 / 2.0;
+        let dy = y as f64 - self.height as f64 / 2.0;
+        let corner = (self.width as f64 * self.width as f64 + self.height as f64 * self.height as f64) / 4.0;
+        1.0 - self.vignetting * (dx * dx + dy * dy) / corner
+    }
+
+    /// Light from the flat panel or twilight sky at the start of the next exposure.
+    fn flat_light(&self) -> f64 {
+        let elapsed = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+        self.flat_level * (self.twilight * elapsed).exp()
+    }
+
+    /// Take a frame of `frame_type`. Bias frames use no exposure time at all; nothing
+    /// actually waits for the exposure.
+    pub fn capture(&mut self, frame_type: FrameType) -> Frame {
+        let seconds = match frame_type {
+            FrameType::Bias => 0.0,
+            _ => self.exposure.as_secs() as f64 + self.exposure.subsec_nanos() as f64 / 1e9
+        };
+        let flat_light = self.flat_light();
+        let sigma = self.star_sigma();
+        let stars = self.pointed_stars();
+        let region = self.subframe.unwrap_or(Subframe { x: 0, y: 0, width: self.width, height: self.height });
+        let mut dark = vec![self.dark_current; self.width as usize * self.height as usize];
+        let mut dead = vec![false; self.width as usize * self.height as usize];
+        for (x, y, current) in self.hot_pixels.iter() {
+            dark[(*y * self.width + *x) as usize] += current;
+        }
+        for (x, current) in self.hot_columns.iter() {
+            for y in 0..self.height {
+                dark[(y * self.width + *x) as usize] += current;
+            }
+        }
+        for (x, y) in self.dead_pixels.iter() {
+            dead[(*y * self.width + *x) as usize] = true;
+        }
+        let overscan = if self.subframe.is_none() { self.overscan } else { 0 };
+        let mut data = Vec::with_capacity((region.width + overscan) as usize * region.height as usize);
+        for y in region.y..region.y + region.height {
+            let row_bias = self.offset + self.row_noise * self.rng.normal();
+            for x in region.x..region.x + region.width {
+                let i = (y * self.width + x) as usize;
+                let light = match frame_type {
+                    _ if dead[i] => 0.0,
+                    FrameType::Light => self.light_at(x, y, &stars, sigma) * self.illumination(x, y),
+                    FrameType::Flat => flat_light * self.illumination(x, y),
+                    FrameType::Dark | FrameType::Bias => 0.0
+                };
+                let electrons = self.rng.poisson((light + dark[i]) * seconds)
+                    + self.read_noise * self.rng.normal();
+                let adu = row_bias + electrons / self.gain;
+                data.push(adu.round().clamp(0.0, 65535.0) as u16);
+            }
+            for _ in 0..overscan {
+                let adu = row_bias + self.read_noise * self.rng.normal() / self.gain;
+                data.push(adu.round().clamp(0.0, 65535.0) as u16);
+            }
+        }
+        let mut frame = Frame {
+            width: region.width + overscan,
+            height: region.height,
+            channels: 1,
+            bpp: 16,
+            data: data,
+            timestamp: self.start + self.elapsed + Duration::from_secs_f64(seconds),
+            started: Some(self.start + self.elapsed),
+            gps: None,
+            headers: Vec::new(),
+            cfa: None,
+            stats: None
+        };
+        let shutter = if frame_type.wants_dark() { Shutter::Closed } else { Shutter::Open };
+        frame.set_frame_type(frame_type, shutter);
+        frame.set_header("EXPTIME", HeaderValue::Float(seconds));
+        frame.set_setup(&Setup {
+            serial: Imager::serial(self),
+            gain: Some(self.gain),
+            offset: Some(self.offset),
+            binning: 1,
+            origin: (region.x, region.y),
+            temperature: Some(self.temperature)
+        });
+        if overscan > 0 {
+            let bias = Subframe { x: region.width, y: 0, width: overscan, height: region.height };
+            let data = Subframe { x: 0, y: 0, width: region.width, height: region.height };
+            frame.set_header("BIASSEC", HeaderValue::Str(fits::section(&bias)));
+            frame.set_header("DATASEC", HeaderValue::Str(fits::section(&data)));
+        }
+        frame.compute_stats();
+        self.elapsed += Duration::from_secs_f64(seconds) + self.readout;
+        frame
+    }
+}
+
+impl Imager for Camera {
+    type Error = Infallible;
+
+    fn set_exposure(&mut self, exposure: Duration) -> Result<(), Infallible> {
+        self.exposure = exposure;
+        Ok(())
+    }
+
+    /// The simulator takes the gain as electrons per ADU.
+    fn set_gain(&mut self, gain: f64) -> Result<(), Infallible> {
+        self.gain = gain;
+        Ok(())
+    }
+
+    fn exposure_range(&self) -> Result<(Duration, Duration), Infallible> {
+        Ok((Duration::from_micros(1), Duration::from_secs(3600)))
+    }
+
+    fn set_subframe(&mut self, subframe: Option<Subframe>) -> Result<(), Infallible> {
+        self.subframe = subframe.map(|subframe| {
+            let x = subframe.x.min(self.width - 1);
+            let y = subframe.y.min(self.height - 1);
+            Subframe { x: x, y: y, width: subframe.width.min(self.width - x), height: subframe.height.min(self.height - y) }
+        });
+        Ok(())
+    }
+
+    fn serial(&self) -> String {
+        "SIM-0001".to_owned()
+    }
+
+    fn binning(&self) -> u32 {
+        1
+    }
+
+    fn capture(&mut self, frame_type: FrameType) -> Result<Frame, Infallible> {
+        Ok(Camera::capture(self, frame_type))
+    }
+}
+
+/// A simulated camera is never unplugged, so reconnecting runs go straight through.
+impl Reopen for Camera {
+    fn presence(&self) -> Presence {
+        Presence::new()
+    }
+
+    fn reopen(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// A focuser for a simulated `Camera`. Moves finish instantly, but the gears have play: after
+/// moving out the optics trail the motor by `backlash` steps, and after moving in they're
+/// where the motor says.
+#[derive(Debug, Clone)]
+pub struct Focuser {
+    motor: i32,
+    pub backlash: i32,
+    optics: Rc<Cell<i32>>,
+    pub temperature: f64
+}
+
+impl focus::Focuser for Focuser {
+    type Error = Infallible;
+
+    fn position(&self) -> Result<i32, Infallible> {
+        Ok(self.motor)
+    }
+
+    fn move_to(&mut self, position: i32) -> Result<(), Infallible> {
+        self.motor = position;
+        self.optics.set(self.optics.get().clamp(position - self.backlash, position));
+        Ok(())
+    }
+
+    fn is_moving(&self) -> Result<bool, Infallible> {
+        Ok(false)
+    }
+
+    fn temperature(&self) -> Result<Option<f64>, Infallible> {
+        Ok(Some(self.temperature))
+    }
+}
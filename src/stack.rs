@@ -0,0 +1,690 @@
+// Registering and stacking lights in-process, for a quick look at the night's data on a rig
+// with no screen. Stars are matched between frames by the shapes of the triangles the
+// brightest ones make, which don't change as the frame shifts and rotates, and a transform is
+// fitted through the matches. Each frame is resampled onto the reference, and the frames are
+// averaged per pixel with outliers clipped and the sharper, cleaner frames counting for more.
+//
+// Raw Bayer frames are debayered first, since resampling would mix up the CFA's colors.
+
+use crate::frame::Frame;
+use crate::image::{self, Image};
+use crate::stars::{Star, StarFinder};
+
+use std::f64::consts::PI;
+
+/// MAD * this estimates sigma for Gaussian noise.
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+#[derive(Debug)]
+pub enum StackError {
+    NoFrames,
+    /// The reference frame has too few stars to register anything against.
+    TooFewStars(usize),
+    /// Frames have different sizes or channel counts.
+    MixedFrames,
+    /// No frame but the reference could be registered.
+    NothingRegistered
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Model {
+    /// Shift, rotation, scale and shear; enough for anything through the same optics.
+    Affine,
+    /// Affine plus perspective, for frames whose tilt differs.
+    Homography
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interpolation {
+    Bilinear,
+    /// Sharper, at the cost of some ringing around bright stars.
+    Lanczos3
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Weighting {
+    Equal,
+    /// By the square of the stars' median SNR.
+    Snr,
+    /// By one over the square of the stars' median FWHM.
+    Fwhm,
+    /// Both.
+    SnrFwhm
+}
+
+/// A projective transform of pixel coordinates, as a 3x3 matrix in row order.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub matrix: [f64; 9]
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform { matrix: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0] }
+    }
+
+    pub fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        let m = &self.matrix;
+        let w = m[6] * x + m[7] * y + m[8];
+        ((m[0] * x + m[1] * y + m[2]) / w, (m[3] * x + m[4] * y + m[5]) / w)
+    }
+
+    /// `self` then `other`.
+    fn then(&self, other: &Transform) -> Transform {
+        let (a, b) = (&other.matrix, &self.matrix);
+        let mut matrix = [0f64; 9];
+        for row in 0..3 {
+            for column in 0..3 {
+                matrix[row * 3 + column] = (0..3).map(|k| a[row * 3 + k] * b[k * 3 + column]).sum();
+            }
+        }
+        Transform { matrix: matrix }
+    }
+
+    /// Shift and scale `points` to be centred on 0 about √2 from it, which keeps the
+    /// homography's equations well conditioned.
+    fn normalizing(points: &[(f64, f64)]) -> Transform {
+        let n = points.len() as f64;
+        let (mx, my) = points.iter().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+        let (mx, my) = (mx / n, my / n);
+        let spread = points.iter().map(|(x, y)| ((x - mx).powi(2) + (y - my).powi(2)).sqrt()).sum::<f64>() / n;
+        let s = if spread > 0.0 { 2f64.sqrt() / spread } else { 1.0 };
+        Transform { matrix: [s, 0.0, -s * mx, 0.0, s, -s * my, 0.0, 0.0, 1.0] }
+    }
+
+    /// The least-squares transform taking each `from` point to its `to` point.
+    pub fn fit(model: Model, from: &[(f64, f64)], to: &[(f64, f64)]) -> Option<Transform> {
+        match model {
+            Model::Affine if from.len() >= 3 => {
+                // x' = a x + b y + c and y' = d x + e y + f are separate linear fits
+                let rows: Vec<Vec<f64>> = from.iter().map(|(x, y)| vec![*x, *y, 1.0]).collect();
+                let xs: Vec<f64> = to.iter().map(|(x, _)| *x).collect();
+                let ys: Vec<f64> = to.iter().map(|(_, y)| *y).collect();
+                let abc = least_squares(&rows, &xs)?;
+                let def = least_squares(&rows, &ys)?;
+                Some(Transform { matrix: [abc[0], abc[1], abc[2], def[0], def[1], def[2], 0.0, 0.0, 1.0] })
+            }
+            Model::Homography if from.len() >= 4 => {
+                let (from_n, to_n) = (Transform::normalizing(from), Transform::normalizing(to));
+                let mut rows = Vec::with_capacity(from.len() * 2);
+                let mut values = Vec::with_capacity(from.len() * 2);
+                for (p, q) in from.iter().zip(to.iter()) {
+                    let (x, y) = from_n.apply(p.0, p.1);
+                    let (u, v) = to_n.apply(q.0, q.1);
+                    rows.push(vec![x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u]);
+                    values.push(u);
+                    rows.push(vec![0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v]);
+                    values.push(v);
+                }
+                let h = least_squares(&rows, &values)?;
+                let normalized = Transform { matrix: [h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.0] };
+                let s = to_n.matrix[0];
+                let to_back = Transform {
+                    matrix: [1.0 / s, 0.0, -to_n.matrix[2] / s, 0.0, 1.0 / s, -to_n.matrix[5] / s, 0.0, 0.0, 1.0]
+                };
+                Some(from_n.then(&normalized).then(&to_back))
+            }
+            _ => None
+        }
+    }
+}
+
+/// Solve `rows x = values` in the least-squares sense, through the normal equations.
+fn least_squares(rows: &[Vec<f64>], values: &[f64]) -> Option<Vec<f64>> {
+    let n = rows.first()?.len();
+    let mut a = vec![vec![0f64; n + 1]; n];
+    for (row, value) in rows.iter().zip(values.iter()) {
+        for i in 0..n {
+            for j in 0..n {
+                a[i][j] += row[i] * row[j];
+            }
+            a[i][n] += row[i] * value;
+        }
+    }
+    // Gaussian elimination with partial pivoting
+    for column in 0..n {
+        let pivot = (column..n).max_by(|i, j| a[*i][column].abs().total_cmp(&a[*j][column].abs()))?;
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(column, pivot);
+        let pivot_row = a[column].clone();
+        for (i, row) in a.iter_mut().enumerate() {
+            if i != column {
+                let factor = row[column] / pivot_row[column];
+                for (value, pivot) in row.iter_mut().zip(pivot_row.iter()).skip(column) {
+                    *value -= factor * pivot;
+                }
+            }
+        }
+    }
+    Some((0..n).map(|i| a[i][n] / a[i][i]).collect())
+}
+
+/// A triangle of stars, described by the ratios of its sides, which stay the same however
+/// the frame is shifted, rotated or scaled.
+#[derive(Debug, Copy, Clone)]
+struct Triangle {
+    /// The stars opposite the longest, middle and shortest sides.
+    stars: [usize; 3],
+    /// Middle side over longest, shortest over longest.
+    ratios: (f64, f64)
+}
+
+/// The triangles between `points`, leaving out ones too thin to describe reliably.
+fn triangles(points: &[(f64, f64)], max_ratio: f64) -> Vec<Triangle> {
+    let distance = |i: usize, j: usize| ((points[i].0 - points[j].0).powi(2) + (points[i].1 - points[j].1).powi(2)).sqrt();
+    let mut triangles = Vec::new();
+    for i in 0..points.len() {
+        for j in i + 1..points.len() {
+            for k in j + 1..points.len() {
+                // each side with the star opposite it
+                let mut sides = [(distance(j, k), i), (distance(i, k), j), (distance(i, j), k)];
+                sides.sort_by(|a, b| b.0.total_cmp(&a.0));
+                if sides[2].0 <= 0.0 || sides[0].0 / sides[2].0 > max_ratio {
+                    continue;
+                }
+                triangles.push(Triangle {
+                    stars: [sides[0].1, sides[1].1, sides[2].1],
+                    ratios: (sides[1].0 / sides[0].0, sides[2].0 / sides[0].0)
+                });
+            }
+        }
+    }
+    triangles.sort_by(|a, b| a.ratios.0.total_cmp(&b.ratios.0));
+    triangles
+}
+
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub model: Model,
+    /// How many of the brightest stars to make triangles from.
+    pub stars: usize,
+    /// How close two triangles' side ratios have to be to match.
+    pub tolerance: f64,
+    /// Triangles whose longest side is more than this times their shortest are left out.
+    pub max_ratio: f64,
+    /// How close, in pixels, a star has to land on its match for the two to count as the
+    /// same star.
+    pub radius: f64,
+    /// Fewer stars matched than this and the frame isn't registered.
+    pub min_matches: usize
+}
+
+impl Default for Registration {
+    fn default() -> Registration {
+        Registration {
+            model: Model::Affine,
+            stars: 25,
+            tolerance: 0.005,
+            max_ratio: 10.0,
+            radius: 2.0,
+            min_matches: 6
+        }
+    }
+}
+
+/// How one frame lines up with the reference.
+#[derive(Debug, Clone)]
+pub struct Registered {
+    /// From reference pixels to this frame's.
+    pub transform: Transform,
+    pub matches: usize,
+    /// RMS distance between matched stars after the transform, in pixels.
+    pub rms: f64
+}
+
+fn brightest(stars: &[Star], count: usize) -> Vec<(f64, f64)> {
+    let mut stars: Vec<&Star> = stars.iter().collect();
+    stars.sort_by(|a, b| b.flux.total_cmp(&a.flux));
+    stars.iter().take(count).map(|star| (star.x, star.y)).collect()
+}
+
+impl Registration {
+    /// Line `stars` up with `reference`'s.
+    pub fn register(&self, reference: &[Star], stars: &[Star]) -> Option<Registered> {
+        let from = brightest(reference, self.stars);
+        let to = brightest(stars, self.stars);
+        let from_triangles = triangles(&from, self.max_ratio);
+        let to_triangles = triangles(&to, self.max_ratio);
+
+        // every pair of matching triangles votes for its stars matching
+        let mut votes = vec![vec![0u32; to.len()]; from.len()];
+        for triangle in from_triangles.iter() {
+            let start = to_triangles.partition_point(|other| other.ratios.0 < triangle.ratios.0 - self.tolerance);
+            for other in to_triangles[start..].iter().take_while(|other| other.ratios.0 <= triangle.ratios.0 + self.tolerance) {
+                if (other.ratios.1 - triangle.ratios.1).abs() <= self.tolerance {
+                    for (i, j) in triangle.stars.iter().zip(other.stars.iter()) {
+                        votes[*i][*j] += 1;
+                    }
+                }
+            }
+        }
+        // pairs that are each other's best, most votes first
+        let mut pairs: Vec<(u32, usize, usize)> = Vec::new();
+        for (i, row) in votes.iter().enumerate() {
+            let (j, count) = match row.iter().enumerate().max_by_key(|(_, count)| **count) {
+                Some((j, count)) => (j, *count),
+                None => continue
+            };
+            let best_for_j = (0..from.len()).max_by_key(|k| votes[*k][j]).unwrap_or(i);
+            if count >= 2 && best_for_j == i {
+                pairs.push((count, i, j));
+            }
+        }
+        pairs.sort_by_key(|(votes, _, _)| std::cmp::Reverse(*votes));
+
+        // fit, throwing out the worst match until they all agree
+        let mut from_points: Vec<(f64, f64)> = pairs.iter().map(|(_, i, _)| from[*i]).collect();
+        let mut to_points: Vec<(f64, f64)> = pairs.iter().map(|(_, _, j)| to[*j]).collect();
+        let mut transform;
+        loop {
+            if from_points.len() < self.min_matches.max(3) {
+                return None;
+            }
+            transform = Transform::fit(self.model, &from_points, &to_points)?;
+            let (worst, error) = worst_match(&transform, &from_points, &to_points);
+            if error <= self.radius {
+                break;
+            }
+            from_points.remove(worst);
+            to_points.remove(worst);
+        }
+
+        // then match up every star the transform puts close to another
+        let all_from: Vec<(f64, f64)> = reference.iter().map(|star| (star.x, star.y)).collect();
+        let all_to: Vec<(f64, f64)> = stars.iter().map(|star| (star.x, star.y)).collect();
+        let (mut from_points, mut to_points) = (Vec::new(), Vec::new());
+        for point in all_from.iter() {
+            let (x, y) = transform.apply(point.0, point.1);
+            let nearest = all_to.iter().min_by(|a, b| {
+                ((a.0 - x).powi(2) + (a.1 - y).powi(2)).total_cmp(&((b.0 - x).powi(2) + (b.1 - y).powi(2)))
+            });
+            if let Some(nearest) = nearest {
+                if ((nearest.0 - x).powi(2) + (nearest.1 - y).powi(2)).sqrt() <= self.radius {
+                    from_points.push(*point);
+                    to_points.push(*nearest);
+                }
+            }
+        }
+        if from_points.len() < self.min_matches {
+            return None;
+        }
+        let transform = Transform::fit(self.model, &from_points, &to_points).unwrap_or(transform);
+        let squares: f64 = from_points.iter().zip(to_points.iter()).map(|(p, q)| {
+            let (x, y) = transform.apply(p.0, p.1);
+            (x - q.0).powi(2) + (y - q.1).powi(2)
+        }).sum();
+        Some(Registered {
+            transform: transform,
+            matches: from_points.len(),
+            rms: (squares / from_points.len() as f64).sqrt()
+        })
+    }
+}
+
+/// The index of the pair `transform` fits worst, and how far off it is.
+fn worst_match(transform: &Transform, from: &[(f64, f64)], to: &[(f64, f64)]) -> (usize, f64) {
+    from.iter().zip(to.iter()).enumerate().map(|(i, (p, q))| {
+        let (x, y) = transform.apply(p.0, p.1);
+        (i, ((x - q.0).powi(2) + (y - q.1).powi(2)).sqrt())
+    }).fold((0, 0.0), |worst, this| if this.1 > worst.1 { this } else { worst })
+}
+
+fn lanczos3(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else if x.abs() >= 3.0 {
+        0.0
+    } else {
+        let px = PI * x;
+        3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+    }
+}
+
+/// `image` sampled at (`x`, `y`), or `None` if that's off it.
+fn sample(image: &Image, x: f64, y: f64, channel: u32, interpolation: Interpolation) -> Option<f32> {
+    if x < 0.0 || y < 0.0 || x > (image.width - 1) as f64 || y > (image.height - 1) as f64 {
+        return None;
+    }
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let at = |x: i64, y: i64| {
+        let x = x.clamp(0, image.width as i64 - 1) as u32;
+        let y = y.clamp(0, image.height as i64 - 1) as u32;
+        image.at(x, y, channel) as f64
+    };
+    let value = match interpolation {
+        Interpolation::Bilinear => {
+            let top = at(x0, y0) * (1.0 - fx) + at(x0 + 1, y0) * fx;
+            let bottom = at(x0, y0 + 1) * (1.0 - fx) + at(x0 + 1, y0 + 1) * fx;
+            top * (1.0 - fy) + bottom * fy
+        }
+        Interpolation::Lanczos3 => {
+            let (mut sum, mut weights) = (0.0, 0.0);
+            for dy in -2..=3 {
+                let wy = lanczos3(dy as f64 - fy);
+                for dx in -2..=3 {
+                    let w = wy * lanczos3(dx as f64 - fx);
+                    sum += w * at(x0 + dx, y0 + dy);
+                    weights += w;
+                }
+            }
+            sum / weights
+        }
+    };
+    Some(value as f32)
+}
+
+/// `image` resampled onto the reference's pixels, with NaN where it doesn't reach.
+pub fn resample(image: &Image, transform: &Transform, interpolation: Interpolation) -> Image {
+    let mut resampled = Image::new(image.width, image.height, image.channels);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let (sx, sy) = transform.apply(x as f64, y as f64);
+            for channel in 0..image.channels {
+                resampled.set(x, y, channel, sample(image, sx, sy, channel, interpolation).unwrap_or(f32::NAN));
+            }
+        }
+    }
+    resampled
+}
+
+#[derive(Debug, Clone)]
+pub struct Stacker {
+    pub finder: StarFinder,
+    pub registration: Registration,
+    pub interpolation: Interpolation,
+    pub weighting: Weighting,
+    /// Samples this many sigmas below or above a pixel's median are left out.
+    pub low_sigma: f32,
+    pub high_sigma: f32,
+    /// Rounds of clipping per pixel.
+    pub iterations: u32
+}
+
+impl Default for Stacker {
+    fn default() -> Stacker {
+        Stacker {
+            finder: StarFinder::default(),
+            registration: Registration::default(),
+            interpolation: Interpolation::Bilinear,
+            weighting: Weighting::SnrFwhm,
+            low_sigma: 4.0,
+            high_sigma: 3.0,
+            iterations: 3
+        }
+    }
+}
+
+/// What each frame brought to a stack.
+#[derive(Debug, Clone)]
+pub struct StackedFrame {
+    /// `None` if it couldn't be registered and was left out.
+    pub registered: Option<Registered>,
+    /// Relative to the reference's.
+    pub weight: f64,
+    pub stars: usize,
+    pub fwhm: f64,
+    pub snr: f64
+}
+
+#[derive(Debug, Clone)]
+pub struct Stack {
+    pub image: Image,
+    /// Which frame everything was lined up on.
+    pub reference: usize,
+    pub frames: Vec<StackedFrame>,
+    /// The fraction of samples clipped.
+    pub rejected: f64
+}
+
+impl Stacker {
+    pub fn stack_frames(&self, frames: &[Frame]) -> Result<Stack, StackError> {
+        let images: Vec<Image> = frames.iter().map(Image::from_frame).collect();
+        self.stack(&images)
+    }
+
+    /// Register and stack `images`, which have to be the same size. Raw Bayer images are
+    /// debayered first.
+    pub fn stack(&self, images: &[Image]) -> Result<Stack, StackError> {
+        let first = images.first().ok_or(StackError::NoFrames)?;
+        if images.iter().any(|image| !image.same_shape(first) || image.cfa != first.cfa) {
+            return Err(StackError::MixedFrames);
+        }
+        let images: Vec<Image> = images.iter().map(Image::debayer).collect();
+
+        // measure every frame, and line them up on the best
+        let fields: Vec<_> = images.iter().map(|image| self.finder.find_image(image)).collect();
+        let mut frames: Vec<StackedFrame> = fields.iter().map(|field| {
+            let mut snrs: Vec<f32> = field.stars.iter().map(|star| star.snr as f32).collect();
+            let snr = image::median(&mut snrs) as f64;
+            StackedFrame {
+                registered: None,
+                weight: self.weight(snr, field.fwhm),
+                stars: field.stars.len(),
+                fwhm: field.fwhm,
+                snr: snr
+            }
+        }).collect();
+        let reference = (0..frames.len()).max_by(|a, b| frames[*a].weight.total_cmp(&frames[*b].weight)).unwrap_or(0);
+        let best = frames[reference].weight;
+        for frame in frames.iter_mut() {
+            frame.weight = if best > 0.0 { frame.weight / best } else { 1.0 };
+        }
+        if fields[reference].stars.len() < self.registration.min_matches {
+            return Err(StackError::TooFewStars(fields[reference].stars.len()));
+        }
+        for (i, field) in fields.iter().enumerate() {
+            frames[i].registered = if i == reference {
+                Some(Registered { transform: Transform::identity(), matches: field.stars.len(), rms: 0.0 })
+            } else {
+                self.registration.register(&fields[reference].stars, &field.stars)
+            };
+        }
+        if frames.iter().filter(|frame| frame.registered.is_some()).count() < 2 {
+            return Err(StackError::NothingRegistered);
+        }
+
+        // resample onto the reference, taking out differences in sky level
+        let sky = |image: &Image| {
+            let mut values: Vec<f32> = image.data.iter().cloned().filter(|v| !v.is_nan()).collect();
+            image::median(&mut values)
+        };
+        let target = sky(&images[reference]);
+        let mut layers = Vec::new();
+        let mut weights = Vec::new();
+        for (image, frame) in images.iter().zip(frames.iter()) {
+            if let Some(registered) = frame.registered.as_ref() {
+                let mut layer = resample(image, &registered.transform, self.interpolation);
+                let offset = target - sky(&layer);
+                for sample in layer.data.iter_mut() {
+                    *sample += offset;
+                }
+                layers.push(layer);
+                weights.push(frame.weight);
+            }
+        }
+
+        let (image, rejected) = self.integrate(&layers, &weights);
+        Ok(Stack { image: image, reference: reference, frames: frames, rejected: rejected })
+    }
+
+    fn weight(&self, snr: f64, fwhm: f64) -> f64 {
+        let by_snr = snr * snr;
+        let by_fwhm = if fwhm > 0.0 { 1.0 / (fwhm * fwhm) } else { 0.0 };
+        match self.weighting {
+            Weighting::Equal => 1.0,
+            Weighting::Snr => by_snr,
+            Weighting::Fwhm => by_fwhm,
+            Weighting::SnrFwhm => by_snr * by_fwhm
+        }
+    }
+
+    /// The weighted mean of each sample across `layers`, after sigma clipping. Returns the
+    /// stack and the fraction of samples clipped.
+    fn integrate(&self, layers: &[Image], weights: &[f64]) -> (Image, f64) {
+        let first = &layers[0];
+        let mut stacked = Image::new(first.width, first.height, first.channels);
+        let mut values: Vec<(f32, f64)> = Vec::with_capacity(layers.len());
+        let mut deviations = Vec::with_capacity(layers.len());
+        let (mut rejected, mut total) = (0usize, 0usize);
+        for (i, out) in stacked.data.iter_mut().enumerate() {
+            values.clear();
+            values.extend(layers.iter().zip(weights.iter()).map(|(layer, w)| (layer.data[i], *w)).filter(|(v, _)| !v.is_nan()));
+            let count = values.len();
+            for _ in 0..self.iterations {
+                if values.len() < 3 {
+                    break;
+                }
+                let mut samples: Vec<f32> = values.iter().map(|(v, _)| *v).collect();
+                let median = image::median(&mut samples);
+                deviations.clear();
+                deviations.extend(values.iter().map(|(v, _)| (v - median).abs()));
+                let sigma = image::median(&mut deviations) * MAD_TO_SIGMA;
+                if sigma <= 0.0 {
+                    break;
+                }
+                let before = values.len();
+                values.retain(|(v, _)| *v >= median - self.low_sigma * sigma && *v <= median + self.high_sigma * sigma);
+                if values.len() == before {
+                    break;
+                }
+            }
+            rejected += count - values.len();
+            total += count;
+            let weight: f64 = values.iter().map(|(_, w)| w).sum();
+            *out = if weight > 0.0 {
+                (values.iter().map(|(v, w)| *v as f64 * w).sum::<f64>() / weight) as f32
+            } else {
+                values.iter().map(|(v, _)| *v).sum::<f32>() / values.len().max(1) as f32
+            };
+        }
+        (stacked, rejected as f64 / total.max(1) as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A rotation by `angle` about (`cx`, `cy`) and then a shift by (`dx`, `dy`).
+    fn rotated(angle: f64, (cx, cy): (f64, f64), (dx, dy): (f64, f64)) -> impl Fn(f64, f64) -> (f64, f64) {
+        let (sin, cos) = angle.sin_cos();
+        move |x, y| (cx + cos * (x - cx) - sin * (y - cy) + dx, cy + sin * (x - cx) + cos * (y - cy) + dy)
+    }
+
+    #[test]
+    fn transforms_are_fitted_through_matched_points() {
+        let to = rotated(0.02, (320.0, 240.0), (12.5, -7.25));
+        let from: Vec<(f64, f64)> = [(10.0, 20.0), (600.0, 40.0), (300.0, 460.0), (50.0, 400.0), (330.0, 250.0)].to_vec();
+        let moved: Vec<(f64, f64)> = from.iter().map(|(x, y)| to(*x, *y)).collect();
+        for model in [Model::Affine, Model::Homography].iter() {
+            let transform = Transform::fit(*model, &from, &moved).unwrap();
+            for ((x, y), (mx, my)) in from.iter().zip(moved.iter()) {
+                let (tx, ty) = transform.apply(*x, *y);
+                assert!((tx - mx).abs() < 1e-6 && (ty - my).abs() < 1e-6, "{:?}: ({}, {}) went to ({}, {})", model, x, y, tx, ty);
+            }
+        }
+        assert!(Transform::fit(Model::Affine, &from[..2], &moved[..2]).is_none());
+    }
+
+    #[cfg(feature = "sim")]
+    mod sim {
+        use super::super::*;
+        use super::rotated;
+        use crate::frame::FrameType;
+        use crate::imaging::Subframe;
+        use crate::sim;
+
+        /// The sky noise over the middle of `image`, away from the edges dithering leaves thin.
+        fn noise(image: &Image) -> f32 {
+            let mut middle = image.crop(&Subframe::centre(image.width, image.height, 2)).unwrap().data;
+            let level = image::median(&mut middle);
+            for value in middle.iter_mut() {
+                *value = (*value - level).abs();
+            }
+            image::median(&mut middle) * MAD_TO_SIGMA
+        }
+
+        #[test]
+        fn dithered_frames_are_registered_on_their_stars() {
+            let mut camera = sim::Camera::new(640, 480);
+            camera.set_exposure_ms(30000);
+            let reference = StarFinder::default().find(&camera.capture(FrameType::Light));
+            camera.shift = (9.0, -6.0);
+            camera.rotation = 0.3f64.to_radians();
+            let moved = StarFinder::default().find(&camera.capture(FrameType::Light));
+
+            let registered = Registration::default().register(&reference.stars, &moved.stars).unwrap();
+            assert!(registered.matches >= 15, "{} matches", registered.matches);
+            assert!(registered.rms < 0.2, "RMS {:.3}", registered.rms);
+            let expected = rotated(camera.rotation, (320.0, 240.0), camera.shift);
+            for (x, y) in [(100.0, 100.0), (500.0, 80.0), (320.0, 400.0)].iter() {
+                let (tx, ty) = registered.transform.apply(*x, *y);
+                let (ex, ey) = expected(*x, *y);
+                assert!((tx - ex).abs() < 0.3 && (ty - ey).abs() < 0.3, "({}, {}) went to ({:.2}, {:.2}), not ({:.2}, {:.2})", x, y, tx, ty, ex, ey);
+            }
+
+            camera.stars.clear();
+            let empty = StarFinder::default().find(&camera.capture(FrameType::Light));
+            assert!(Registration::default().register(&reference.stars, &empty.stars).is_none());
+        }
+
+        #[test]
+        fn stacks_are_quieter_than_a_frame_and_lose_satellites() {
+            let mut camera = sim::Camera::new(640, 480);
+            camera.set_exposure_ms(30000);
+            let mut rng = sim::Rng::new(7);
+            let mut frames = Vec::new();
+            for i in 0..8 {
+                camera.shift = (30.0 * rng.uniform() - 15.0, 30.0 * rng.uniform() - 15.0);
+                camera.rotation = (rng.uniform() - 0.5).to_radians();
+                let mut frame = camera.capture(FrameType::Light);
+                if i == 3 {
+                    // a satellite trail, for clipping to take out
+                    for x in 0..frame.width {
+                        let at = ((100 + x / 4) * frame.width + x) as usize;
+                        frame.data[at] = frame.data[at].saturating_add(3000);
+                    }
+                }
+                frames.push(frame);
+            }
+            // a frame of cloud, which can't be registered
+            camera.stars.clear();
+            frames.push(camera.capture(FrameType::Light));
+
+            let stack = Stacker::default().stack_frames(&frames).unwrap();
+            assert_eq!(stack.frames.len(), 9);
+            assert!(stack.frames[..8].iter().all(|frame| frame.registered.is_some()));
+            assert!(stack.frames[8].registered.is_none());
+            assert!(stack.rejected > 0.0);
+
+            let single = noise(&Image::from_frame(&frames[stack.reference]));
+            let stacked = noise(&stack.image);
+            // eight frames should cut the noise by nearly sqrt(8)
+            assert!(stacked < single / 2.2, "sky noise {:.2} in the stack against {:.2} in one frame", stacked, single);
+
+            // the trail is gone: the stack's pixels that frame 3's trail fell on are just sky
+            let sky = stack.image.median();
+            let to_frame3 = stack.frames[3].registered.as_ref().unwrap().transform;
+            let mut on_trail = Vec::new();
+            for y in 0..stack.image.height {
+                for x in 0..stack.image.width {
+                    let (tx, ty) = to_frame3.apply(x as f64, y as f64);
+                    let (tx, ty) = (tx.round(), ty.round());
+                    if (0.0..640.0).contains(&tx) && ty == (100 + tx as u32 / 4) as f64 {
+                        on_trail.push(stack.image.at(x, y, 0));
+                    }
+                }
+            }
+            assert!(on_trail.len() > 300, "{} trail pixels found", on_trail.len());
+            let trail = image::median(&mut on_trail);
+            assert!((trail - sky).abs() < 3.0 * stacked, "trail at {:.1} over a sky of {:.1}", trail, sky);
+
+            let fwhm = StarFinder::default().find_image(&stack.image).fwhm;
+            assert!(fwhm < 1.2 * stack.frames[stack.reference].fwhm, "FWHM {:.2} in the stack", fwhm);
+        }
+    }
+}
@@ -0,0 +1,520 @@
+// Finding stars in a frame and measuring them, for focusing, throwing out bad frames and
+// guiding. The frame is reduced to one luminance plane (raw Bayer frames are binned 2x2 so
+// the CFA doesn't look like noise), a background map is estimated in tiles, and connected
+// pixels above the noise are split at their peaks into stars and measured.
+
+use crate::frame::Frame;
+use crate::image::Image;
+
+/// sigma = FWHM / this, for a Gaussian.
+const FWHM_PER_SIGMA: f64 = 2.354_820_045;
+
+/// MAD * this estimates sigma for Gaussian noise.
+const MAD_TO_SIGMA: f64 = 1.4826;
+
+/// Two peaks are separate stars if the light between them dips below this fraction of the
+/// fainter one. Less of a dip than that is noise on one star's top.
+const DEBLEND_DIP: f32 = 0.8;
+
+/// One frame's samples as floats, one per pixel. `scale` is how many frame pixels each plane
+/// pixel covers on a side.
+struct Plane {
+    width: usize,
+    height: usize,
+    scale: usize,
+    data: Vec<f32>
+}
+
+impl Plane {
+    fn luminance(frame: &Frame) -> Plane {
+        Plane::of(frame.width, frame.height, frame.channels, frame.cfa.is_some(), |i| frame.data[i] as f32)
+    }
+
+    fn of_image(image: &Image) -> Plane {
+        Plane::of(image.width, image.height, image.channels, image.cfa.is_some(), |i| image.data[i])
+    }
+
+    fn of<F: Fn(usize) -> f32>(width: u32, height: u32, channels: u32, cfa: bool, sample: F) -> Plane {
+        let (width, height, channels) = (width as usize, height as usize, channels as usize);
+        if cfa && channels == 1 {
+            let (w, h) = (width / 2, height / 2);
+            let mut data = Vec::with_capacity(w * h);
+            for y in 0..h {
+                for x in 0..w {
+                    let at = |dx: usize, dy: usize| sample((2 * y + dy) * width + 2 * x + dx);
+                    data.push((at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) / 4.0);
+                }
+            }
+            Plane { width: w, height: h, scale: 2, data: data }
+        } else {
+            let data = (0..width * height).map(|pixel| {
+                (0..channels).map(|c| sample(pixel * channels + c)).sum::<f32>() / channels as f32
+            }).collect();
+            Plane { width: width, height: height, scale: 1, data: data }
+        }
+    }
+
+    fn at(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let middle = values.len() / 2;
+    *values.select_nth_unstable_by(middle, |a, b| a.total_cmp(b)).1
+}
+
+/// The sky level across a frame, from the median of each tile, and the noise on it.
+struct Background {
+    tile: usize,
+    columns: usize,
+    rows: usize,
+    levels: Vec<f32>,
+    noise: f32
+}
+
+impl Background {
+    fn estimate(plane: &Plane, tile: usize) -> Background {
+        let tile = tile.max(4);
+        let columns = plane.width.div_ceil(tile).max(1);
+        let rows = plane.height.div_ceil(tile).max(1);
+        let mut levels = Vec::with_capacity(columns * rows);
+        let mut sigmas = Vec::with_capacity(columns * rows);
+        let mut values = Vec::with_capacity(tile * tile);
+        for row in 0..rows {
+            for column in 0..columns {
+                values.clear();
+                for y in (row * tile)..((row + 1) * tile).min(plane.height) {
+                    for x in (column * tile)..((column + 1) * tile).min(plane.width) {
+                        values.push(plane.at(x, y));
+                    }
+                }
+                let level = median(&mut values);
+                for value in values.iter_mut() {
+                    *value = (*value - level).abs();
+                }
+                levels.push(level);
+                sigmas.push(median(&mut values) * MAD_TO_SIGMA as f32);
+            }
+        }
+        // stars and hot pixels push up some tiles' spread, the median tile's is the sky's
+        let noise = median(&mut sigmas).max(f32::EPSILON);
+        Background { tile: tile, columns: columns, rows: rows, levels: levels, noise: noise }
+    }
+
+    /// The background at (`x`, `y`), interpolated between the centres of the tiles around it.
+    fn at(&self, x: usize, y: usize) -> f32 {
+        let position = |p: usize, count: usize| {
+            let t = ((p as f32 + 0.5) / self.tile as f32 - 0.5).clamp(0.0, (count - 1) as f32);
+            let i = (t as usize).min(count.saturating_sub(2));
+            (i, (t - i as f32).min(1.0))
+        };
+        let (cx, fx) = position(x, self.columns);
+        let (cy, fy) = position(y, self.rows);
+        let level = |c: usize, r: usize| self.levels[r.min(self.rows - 1) * self.columns + c.min(self.columns - 1)];
+        let top = level(cx, cy) * (1.0 - fx) + level(cx + 1, cy) * fx;
+        let bottom = level(cx, cy + 1) * (1.0 - fx) + level(cx + 1, cy + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Star {
+    /// Flux-weighted centroid, in frame pixels.
+    pub x: f64,
+    pub y: f64,
+    /// Total signal over the background, in ADU.
+    pub flux: f64,
+    /// The brightest pixel, background included.
+    pub peak: f64,
+    pub background: f64,
+    /// Half-flux radius, in frame pixels.
+    pub hfr: f64,
+    /// Full width at half maximum of a Gaussian with the same second moments, in frame pixels.
+    pub fwhm: f64,
+    /// 0 for round, approaching 1 for trailed.
+    pub eccentricity: f64,
+    pub snr: f64,
+    /// Pixels making up the star.
+    pub area: usize,
+    /// Whether the peak is at full scale, which flattens the profile and makes its HFR and
+    /// FWHM too big.
+    pub saturated: bool
+}
+
+/// The stars found in a frame, with a summary for focusing and frame rejection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StarField {
+    pub stars: Vec<Star>,
+    /// Median sky level and its noise, in ADU.
+    pub background: f64,
+    pub noise: f64,
+    /// Medians over the unsaturated stars, 0 if there are none.
+    pub hfr: f64,
+    pub fwhm: f64,
+    pub eccentricity: f64
+}
+
+impl StarField {
+    /// `KEY=value` fields for the session log.
+    pub fn log_fields(&self) -> Vec<(String, String)> {
+        vec![
+            ("STARS".to_owned(), format!("{}", self.stars.len())),
+            ("HFR".to_owned(), format!("{:.2}", self.hfr)),
+            ("FWHM".to_owned(), format!("{:.2}", self.fwhm)),
+            ("ECCENTRICITY".to_owned(), format!("{:.2}", self.eccentricity))
+        ]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StarFinder {
+    /// How far above the background, in noise sigmas, a pixel has to be to be part of a star.
+    pub sigma: f64,
+    /// Stars smaller than this, in plane pixels, are hot pixels or noise.
+    pub min_area: usize,
+    /// Anything bigger than this is a galaxy, nebula or satellite trail, not a star.
+    pub max_area: usize,
+    /// Side of the tiles the background is estimated in, in plane pixels.
+    pub tile: usize
+}
+
+impl Default for StarFinder {
+    fn default() -> StarFinder {
+        StarFinder {
+            sigma: 5.0,
+            min_area: 4,
+            max_area: 2500,
+            tile: 64
+        }
+    }
+}
+
+impl StarFinder {
+    pub fn find(&self, frame: &Frame) -> StarField {
+        self.find_in(Plane::luminance(frame), frame.full_scale() as f32)
+    }
+
+    /// Find stars in an image off the camera, calibrated or stacked. Nothing in it counts as
+    /// saturated.
+    pub fn find_image(&self, image: &Image) -> StarField {
+        self.find_in(Plane::of_image(image), f32::INFINITY)
+    }
+
+    fn find_in(&self, plane: Plane, full_scale: f32) -> StarField {
+        let background = Background::estimate(&plane, self.tile);
+        let (width, height) = (plane.width, plane.height);
+
+        // detect on a 3x3 mean, which cuts the noise by 3 and keeps single hot pixels out
+        let signal: Vec<f32> = (0..width * height).map(|i| plane.data[i] - background.at(i % width, i / width)).collect();
+        let mut smoothed = vec![0f32; width * height];
+        for y in 1..height.saturating_sub(1) {
+            for x in 1..width.saturating_sub(1) {
+                let mut sum = 0.0;
+                for dy in 0..3 {
+                    for dx in 0..3 {
+                        sum += signal[(y + dy - 1) * width + x + dx - 1];
+                    }
+                }
+                smoothed[y * width + x] = sum / 9.0;
+            }
+        }
+        let threshold = (self.sigma * background.noise as f64 / 3.0) as f32;
+
+        let mut labelled = vec![false; width * height];
+        let mut stars = Vec::new();
+        let mut pending = Vec::new();
+        for start in 0..width * height {
+            if labelled[start] || smoothed[start] <= threshold {
+                continue;
+            }
+            // flood fill the connected pixels above the threshold
+            let mut component = Vec::new();
+            let mut touches_edge = false;
+            labelled[start] = true;
+            pending.push(start);
+            while let Some(i) = pending.pop() {
+                component.push(i);
+                let (x, y) = (i % width, i / width);
+                if x <= 1 || y <= 1 || x + 2 >= width || y + 2 >= height {
+                    touches_edge = true;
+                }
+                for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                        let n = ny * width + nx;
+                        if !labelled[n] && smoothed[n] > threshold {
+                            labelled[n] = true;
+                            pending.push(n);
+                        }
+                    }
+                }
+            }
+            // partial stars at the edge would be measured wrong
+            if touches_edge || component.len() > self.max_area {
+                continue;
+            }
+            for pixels in deblend(&component, &smoothed, width, threshold) {
+                // smoothing spreads a lone hot pixel over 3x3, so count the pixels that clear
+                // the threshold on their own
+                let lit = pixels.iter().filter(|i| signal[**i] > threshold).count();
+                if lit >= self.min_area {
+                    if let Some(star) = measure(&pixels, &plane, &signal, &background, full_scale) {
+                        stars.push(star);
+                    }
+                }
+            }
+        }
+
+        let mut unsaturated: Vec<&Star> = stars.iter().filter(|star| !star.saturated).collect();
+        if unsaturated.is_empty() {
+            unsaturated = stars.iter().collect();
+        }
+        let median_of = |f: fn(&Star) -> f64| {
+            let mut values: Vec<f32> = unsaturated.iter().map(|star| f(star) as f32).collect();
+            median(&mut values) as f64
+        };
+        let (hfr, fwhm, eccentricity) = (median_of(|s| s.hfr), median_of(|s| s.fwhm), median_of(|s| s.eccentricity));
+        let mut levels = background.levels.clone();
+        StarField {
+            background: median(&mut levels) as f64,
+            noise: background.noise as f64,
+            stars: stars,
+            hfr: hfr,
+            fwhm: fwhm,
+            eccentricity: eccentricity
+        }
+    }
+}
+
+/// Split a component at its peaks, giving each pixel to the nearest one, so stars close
+/// enough to touch are measured separately. Only peaks well clear of the detection threshold
+/// and with a real dip between them count, so noise on a bright or out-of-focus star doesn't
+/// split it up.
+fn deblend(component: &[usize], smoothed: &[f32], width: usize, threshold: f32) -> Vec<Vec<usize>> {
+    let mut peaks = Vec::new();
+    for &i in component {
+        let (x, y) = (i % width, i / width);
+        let value = smoothed[i];
+        if value < 2.0 * threshold {
+            continue;
+        }
+        let mut highest = true;
+        for ny in y - 1..=y + 1 {
+            for nx in x - 1..=x + 1 {
+                let n = ny * width + nx;
+                // ties go to the pixel scanned first, so a flat top is one peak
+                if n != i && (smoothed[n] > value || (smoothed[n] == value && n < i)) {
+                    highest = false;
+                }
+            }
+        }
+        if highest {
+            peaks.push((x as isize, y as isize));
+        }
+    }
+    peaks.sort_by(|a, b| {
+        let value = |(x, y): &(isize, isize)| smoothed[*y as usize * width + *x as usize];
+        value(b).total_cmp(&value(a))
+    });
+    let mut separate: Vec<(isize, isize)> = Vec::new();
+    for peak in peaks {
+        let height = smoothed[peak.1 as usize * width + peak.0 as usize];
+        if separate.iter().all(|other| lowest_between(smoothed, width, peak, *other) < DEBLEND_DIP * height) {
+            separate.push(peak);
+        }
+    }
+    let peaks = separate;
+    if peaks.len() <= 1 {
+        return vec![component.to_vec()];
+    }
+    let mut parts = vec![Vec::new(); peaks.len()];
+    for &i in component {
+        let (x, y) = ((i % width) as isize, (i / width) as isize);
+        let nearest = peaks.iter().enumerate()
+            .min_by_key(|(_, (px, py))| (px - x) * (px - x) + (py - y) * (py - y))
+            .map(|(p, _)| p)
+            .unwrap();
+        parts[nearest].push(i);
+    }
+    parts
+}
+
+/// The lowest value on the straight line between two pixels.
+fn lowest_between(values: &[f32], width: usize, from: (isize, isize), to: (isize, isize)) -> f32 {
+    let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).max(1);
+    (0..=steps).map(|i| {
+        let x = from.0 + (to.0 - from.0) * i / steps;
+        let y = from.1 + (to.1 - from.1) * i / steps;
+        values[y as usize * width + x as usize]
+    }).fold(f32::INFINITY, f32::min)
+}
+
+fn measure(pixels: &[usize], plane: &Plane, signal: &[f32], background: &Background, full_scale: f32) -> Option<Star> {
+    let width = plane.width;
+    let mut flux = 0.0;
+    let mut cx = 0.0;
+    let mut cy = 0.0;
+    let mut peak = 0f32;
+    let mut sky = 0.0;
+    for &i in pixels {
+        // negative pixels in the wings are noise, and would throw the moments off
+        let s = signal[i].max(0.0) as f64;
+        flux += s;
+        cx += s * (i % width) as f64;
+        cy += s * (i / width) as f64;
+        peak = peak.max(plane.data[i]);
+        sky += (plane.data[i] - signal[i]) as f64;
+    }
+    if flux <= 0.0 {
+        return None;
+    }
+    cx /= flux;
+    cy /= flux;
+
+    // the core's shape is what tells round from trailed; the wings are mostly noise
+    let (mut xx, mut yy, mut xy) = (0.0, 0.0, 0.0);
+    for &i in pixels {
+        let s = signal[i].max(0.0) as f64;
+        let dx = (i % width) as f64 - cx;
+        let dy = (i / width) as f64 - cy;
+        xx += s * dx * dx;
+        yy += s * dy * dy;
+        xy += s * dx * dy;
+    }
+    let (xx, yy, xy) = (xx / flux, yy / flux, xy / flux);
+    // the eigenvalues of the second moments are the squared axes of the star's ellipse
+    let spread = (((xx - yy) / 2.0).powi(2) + xy * xy).sqrt();
+    let major = (xx + yy) / 2.0 + spread;
+    let minor = ((xx + yy) / 2.0 - spread).max(0.0);
+    let eccentricity = if major > 0.0 { (1.0 - minor / major).sqrt() } else { 0.0 };
+
+    // Only the star's core clears the threshold, and less of it the more spread out it is, so
+    // measuring just the detected pixels would make out-of-focus stars look smaller. Measure
+    // in a circle well past them instead, where the noise in the wings averages out.
+    let aperture = 2.0 * (pixels.len() as f64 / std::f64::consts::PI).sqrt() + 1.5;
+    let (mut flux, mut second, mut radius, mut area) = (0.0, 0.0, 0.0, 0);
+    let rows = (cy - aperture).max(0.0) as usize..((cy + aperture) as usize + 1).min(plane.height);
+    for y in rows {
+        for x in (cx - aperture).max(0.0) as usize..((cx + aperture) as usize + 1).min(width) {
+            let dx = x as f64 - cx;
+            let dy = y as f64 - cy;
+            let r = (dx * dx + dy * dy).sqrt();
+            if r > aperture {
+                continue;
+            }
+            let s = signal[y * width + x] as f64;
+            flux += s;
+            second += s * r * r;
+            radius += s * r;
+            area += 1;
+        }
+    }
+    if flux <= 0.0 {
+        return None;
+    }
+    let noise = background.noise as f64;
+    let scale = plane.scale as f64;
+    Some(Star {
+        // the centre of plane pixel x covers frame pixels scale*x .. scale*x + scale - 1
+        x: cx * scale + (scale - 1.0) / 2.0,
+        y: cy * scale + (scale - 1.0) / 2.0,
+        flux: flux * scale * scale,
+        peak: peak as f64,
+        background: sky / pixels.len() as f64,
+        hfr: (radius / flux).max(0.0) * scale,
+        // a Gaussian's mean squared radius is 2 sigma^2
+        fwhm: FWHM_PER_SIGMA * (second / flux / 2.0).max(0.0).sqrt() * scale,
+        eccentricity: eccentricity,
+        // the star's own shot noise, in ADU, plus the sky's under every pixel of it
+        snr: flux / (flux + area as f64 * noise * noise).sqrt(),
+        area: pixels.len(),
+        saturated: peak >= full_scale * 0.98
+    })
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::frame::FrameType;
+    use crate::sim;
+
+    /// The simulated star nearest (`x`, `y`), and how far away it is.
+    fn nearest(stars: &[sim::Star], x: f64, y: f64) -> (&sim::Star, f64) {
+        stars.iter().map(|star| (star, ((star.x - x).powi(2) + (star.y - y).powi(2)).sqrt()))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn stars_are_found_where_they_are_with_their_size() {
+        let mut camera = sim::Camera::new(320, 240);
+        camera.stars = sim::scatter_stars(320, 240, 30, 5);
+        camera.set_exposure_ms(10000);
+        let frame = camera.capture(FrameType::Light);
+        let field = StarFinder::default().find(&frame);
+
+        // every star found is a real one, pinned down well inside a pixel
+        assert!(field.stars.len() >= 20, "{} stars", field.stars.len());
+        for star in field.stars.iter() {
+            let (_, distance) = nearest(&camera.stars, star.x, star.y);
+            assert!(distance < 0.3, "star at ({:.1}, {:.1}) is {:.2} off", star.x, star.y, distance);
+            assert!(star.snr > 5.0);
+        }
+        // and none of the bright ones away from the edges are missed
+        for star in camera.stars.iter().filter(|star| star.flux > 5000.0) {
+            if star.x > 8.0 && star.x < 312.0 && star.y > 8.0 && star.y < 232.0 {
+                assert!(field.stars.iter().any(|found| (found.x - star.x).abs() < 1.0 && (found.y - star.y).abs() < 1.0), "missed {:?}", star);
+            }
+        }
+
+        let expected = FWHM_PER_SIGMA * camera.psf_sigma;
+        assert!((field.fwhm - expected).abs() < 0.1 * expected, "FWHM {:.2}, expected {:.2}", field.fwhm, expected);
+        // a Gaussian's half-flux radius is 1.177 sigma
+        assert!((field.hfr - 1.177 * camera.psf_sigma).abs() < 0.25, "HFR {:.2}", field.hfr);
+        assert!(field.eccentricity < 0.35, "eccentricity {:.2}", field.eccentricity);
+        assert!((field.background - (camera.offset + camera.sky * 10.0)).abs() < 2.0, "background {:.1}", field.background);
+    }
+
+    #[test]
+    fn defocused_stars_grow() {
+        use crate::focus::Focuser;
+        let mut camera = sim::Camera::new(320, 240);
+        camera.set_exposure_ms(10000);
+        let mut focuser = camera.focuser(0);
+        let mut hfr = Vec::new();
+        for offset in [0, 200, 400].iter() {
+            focuser.move_to(camera.best_focus + offset).unwrap();
+            hfr.push(StarFinder::default().find(&camera.capture(FrameType::Light)).hfr);
+        }
+        assert!(hfr[0] < hfr[1] && hfr[1] < hfr[2], "{:?}", hfr);
+    }
+
+    #[test]
+    fn hot_pixels_are_not_stars_and_saturated_stars_say_so() {
+        let mut camera = sim::Camera::new(160, 120);
+        camera.stars = vec![sim::Star { x: 40.0, y: 40.0, flux: 5e6 }, sim::Star { x: 110.0, y: 70.0, flux: 20000.0 }];
+        camera.hot_pixels = vec![(80, 20, 5000.0), (20, 100, 5000.0)];
+        camera.set_exposure_ms(10000);
+        let field = StarFinder::default().find(&camera.capture(FrameType::Light));
+        assert_eq!(field.stars.len(), 2, "{:?}", field.stars);
+        let bright = field.stars.iter().find(|star| star.x < 80.0).unwrap();
+        let faint = field.stars.iter().find(|star| star.x > 80.0).unwrap();
+        assert!(bright.saturated && !faint.saturated);
+        // the summary leaves out the flattened profile
+        assert!((field.hfr - faint.hfr).abs() < 1e-4, "HFR {:.3}", field.hfr);
+    }
+
+    #[test]
+    fn close_pairs_are_split() {
+        let mut camera = sim::Camera::new(120, 120);
+        camera.stars = vec![sim::Star { x: 57.0, y: 60.0, flux: 40000.0 }, sim::Star { x: 64.0, y: 60.0, flux: 30000.0 }];
+        camera.set_exposure_ms(10000);
+        let field = StarFinder::default().find(&camera.capture(FrameType::Light));
+        assert_eq!(field.stars.len(), 2, "{:?}", field.stars);
+        for star in field.stars.iter() {
+            assert!(nearest(&camera.stars, star.x, star.y).1 < 0.6, "star at ({:.1}, {:.1})", star.x, star.y);
+        }
+    }
+}
@@ -0,0 +1,336 @@
+// Statistics of a frame's pixel values, computed as frames come off the camera so bad
+// calibration frames can be caught while there's still time to retake them.
+
+use crate::frame::Frame;
+
+/// Histogram bins kept in `Stats`, spread evenly over the frame's full range.
+pub const HISTOGRAM_BINS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub count: usize,
+    pub mean: f64,
+    pub median: f64,
+    pub std_dev: f64,
+    /// Median absolute deviation from the median, unscaled.
+    pub mad: f64,
+    pub min: u16,
+    pub max: u16,
+    /// Pixels at the top of the frame's range.
+    pub saturated: usize,
+    /// The largest value a pixel can take.
+    pub full_scale: u16,
+    pub histogram: Vec<u32>
+}
+
+/// The value at `rank` (0-based) in the sorted samples a 65536-bin histogram counts.
+fn rank_in(counts: &[u32], rank: usize) -> u16 {
+    let mut seen = 0;
+    for (value, count) in counts.iter().enumerate() {
+        seen += *count as usize;
+        if seen > rank {
+            return value as u16;
+        }
+    }
+    0
+}
+
+/// The median of what a 65536-bin histogram counts, averaging the middle two for an even
+/// count.
+fn median_of(counts: &[u32], total: usize) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    if total % 2 == 1 {
+        rank_in(counts, total / 2) as f64
+    } else {
+        (rank_in(counts, total / 2 - 1) as f64 + rank_in(counts, total / 2) as f64) / 2.0
+    }
+}
+
+impl Stats {
+    /// Statistics of `samples`, which go up to `full_scale`.
+    pub fn of<I: Iterator<Item = u16>>(samples: I, full_scale: u16) -> Stats {
+        // everything's a u16, so a full histogram gives exact medians in linear time
+        let mut counts = vec![0u32; 65536];
+        let mut count = 0usize;
+        let mut sum = 0f64;
+        let mut sum_sq = 0f64;
+        for sample in samples {
+            counts[sample as usize] += 1;
+            count += 1;
+            sum += sample as f64;
+            sum_sq += sample as f64 * sample as f64;
+        }
+        if count == 0 {
+            return Stats {
+                count: 0,
+                mean: 0.0,
+                median: 0.0,
+                std_dev: 0.0,
+                mad: 0.0,
+                min: 0,
+                max: 0,
+                saturated: 0,
+                full_scale: full_scale,
+                histogram: vec![0; HISTOGRAM_BINS]
+            };
+        }
+        let mean = sum / count as f64;
+        let variance = (sum_sq / count as f64 - mean * mean).max(0.0);
+        let median = median_of(&counts, count);
+
+        let mut deviations = vec![0u32; 65536];
+        for (value, n) in counts.iter().enumerate().filter(|(_, n)| **n > 0) {
+            deviations[(value as f64 - median).abs() as usize] += *n;
+        }
+
+        let bin_width = (full_scale as usize + 1).div_ceil(HISTOGRAM_BINS).max(1);
+        let mut histogram = vec![0u32; HISTOGRAM_BINS];
+        for (value, n) in counts.iter().enumerate().take(full_scale as usize + 1) {
+            histogram[(value / bin_width).min(HISTOGRAM_BINS - 1)] += *n;
+        }
+
+        Stats {
+            count: count,
+            mean: mean,
+            median: median,
+            std_dev: variance.sqrt(),
+            mad: median_of(&deviations, count),
+            min: counts.iter().position(|n| *n > 0).unwrap_or(0) as u16,
+            max: counts.iter().rposition(|n| *n > 0).unwrap_or(0) as u16,
+            saturated: counts[full_scale as usize..].iter().map(|n| *n as usize).sum(),
+            full_scale: full_scale,
+            histogram: histogram
+        }
+    }
+
+    pub fn saturated_fraction(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.saturated as f64 / self.count as f64 }
+    }
+
+    /// The median as a fraction of full scale.
+    pub fn median_fraction(&self) -> f64 {
+        self.median / self.full_scale as f64
+    }
+}
+
+/// Statistics of a whole frame, plus each color's: the CFA positions of a raw Bayer frame or
+/// the channels of an RGB one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameStats {
+    pub all: Stats,
+    pub channels: Vec<(&'static str, Stats)>
+}
+
+impl FrameStats {
+    pub fn of(frame: &Frame) -> FrameStats {
+        let full_scale = frame.full_scale();
+        let all = Stats::of(frame.data.iter().cloned(), full_scale);
+        let channels = if frame.channels == 3 {
+            ["R", "G", "B"].iter().enumerate().map(|(c, name)| {
+                (*name, Stats::of(frame.data.iter().skip(c).step_by(3).cloned(), full_scale))
+            }).collect()
+        } else if let Some(cfa) = frame.cfa {
+            // the four positions of the 2x2 pattern, so the two greens are kept apart
+            (0..2u32).flat_map(|dy| (0..2u32).map(move |dx| (dx, dy))).map(|(dx, dy)| {
+                let samples = (dy..frame.height).step_by(2).flat_map(|y| {
+                    (dx..frame.width).step_by(2).map(move |x| frame.sample(x, y, 0))
+                });
+                (cfa.site_name(dx, dy), Stats::of(samples, full_scale))
+            }).collect()
+        } else {
+            Vec::new()
+        };
+        FrameStats { all: all, channels: channels }
+    }
+
+    /// `KEY=value` fields for the session log.
+    pub fn log_fields(&self) -> Vec<(String, String)> {
+        let mut fields = stat_fields("", &self.all);
+        for (name, stats) in self.channels.iter() {
+            fields.extend(stat_fields(&format!("{}_", name), stats));
+        }
+        fields
+    }
+}
+
+fn stat_fields(prefix: &str, stats: &Stats) -> Vec<(String, String)> {
+    vec![
+        (format!("{}MEAN", prefix), format!("{:.2}", stats.mean)),
+        (format!("{}MEDIAN", prefix), format!("{:.1}", stats.median)),
+        (format!("{}STDDEV", prefix), format!("{:.2}", stats.std_dev)),
+        (format!("{}MAD", prefix), format!("{:.1}", stats.mad)),
+        (format!("{}MIN", prefix), format!("{}", stats.min)),
+        (format!("{}MAX", prefix), format!("{}", stats.max)),
+        (format!("{}SATURATED", prefix), format!("{}", stats.saturated))
+    ]
+}
+
+/// Limits for calling a calibration frame bad.
+#[derive(Debug, Clone)]
+pub struct QualityLimits {
+    /// A dark whose median is more than this many MADs above the reference dark's has
+    /// probably got light on it.
+    pub dark_level_mads: f64,
+    /// A dark whose standard deviation has grown by more than this factor over the reference
+    /// dark's has picked up amp glow or a light leak in one corner.
+    pub dark_noise_ratio: f64,
+    /// Flats should have every channel's median between these fractions of full scale.
+    pub flat_min: f64,
+    pub flat_max: f64,
+    /// More than this fraction of saturated pixels is too many for any calibration frame.
+    pub max_saturated: f64
+}
+
+impl Default for QualityLimits {
+    fn default() -> QualityLimits {
+        QualityLimits {
+            dark_level_mads: 5.0,
+            dark_noise_ratio: 1.5,
+            flat_min: 0.2,
+            flat_max: 0.8,
+            max_saturated: 0.001
+        }
+    }
+}
+
+impl QualityLimits {
+    /// Problems with a dark, compared to the first good dark of the series if there is one.
+    pub fn check_dark(&self, stats: &FrameStats, reference: Option<&FrameStats>) -> Vec<String> {
+        let mut problems = Vec::new();
+        if stats.all.saturated_fraction() > self.max_saturated {
+            problems.push(format!("{} saturated pixels", stats.all.saturated));
+        }
+        if let Some(reference) = reference {
+            // MAD can be 0 on very clean sensors; don't let that make every dark a leak
+            let spread = reference.all.mad.max(1.0);
+            if stats.all.median > reference.all.median + self.dark_level_mads * spread {
+                problems.push(format!(
+                    "median {:.1} is well above the reference dark's {:.1}, light leak?",
+                    stats.all.median, reference.all.median
+                ));
+            }
+            if stats.all.std_dev > reference.all.std_dev * self.dark_noise_ratio {
+                problems.push(format!(
+                    "std dev {:.2} is up from the reference dark's {:.2}, amp glow or light leak?",
+                    stats.all.std_dev, reference.all.std_dev
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Problems with a flat: any channel under- or over-exposed.
+    pub fn check_flat(&self, stats: &FrameStats) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut channels: Vec<(&str, &Stats)> = stats.channels.iter().map(|(name, s)| (*name, s)).collect();
+        if channels.is_empty() {
+            channels.push(("all", &stats.all));
+        }
+        for (name, s) in channels {
+            let level = s.median_fraction();
+            if level < self.flat_min {
+                problems.push(format!("{} is underexposed at {:.0}% of full scale", name, level * 100.0));
+            } else if level > self.flat_max {
+                problems.push(format!("{} is overexposed at {:.0}% of full scale", name, level * 100.0));
+            }
+        }
+        if stats.all.saturated_fraction() > self.max_saturated {
+            problems.push(format!("{} saturated pixels", stats.all.saturated));
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn medians_and_mads_are_exact() {
+        let stats = Stats::of([1u16, 2, 2, 3, 10, 100].iter().cloned(), 255);
+        assert_eq!(stats.count, 6);
+        assert_eq!(stats.median, 2.5);
+        // deviations 1.5 0.5 0.5 0.5 7.5 97.5, truncated to whole ADU
+        assert_eq!(stats.mad, 0.5);
+        assert_eq!((stats.min, stats.max), (1, 100));
+        assert!((stats.mean - 118.0 / 6.0).abs() < 1e-9);
+
+        let stats = Stats::of([7u16, 3, 255, 255, 9].iter().cloned(), 255);
+        assert_eq!(stats.median, 9.0);
+        assert_eq!(stats.saturated, 2);
+        assert_eq!(stats.histogram.iter().sum::<u32>(), 5);
+        assert_eq!(stats.histogram[HISTOGRAM_BINS - 1], 2);
+
+        let empty = Stats::of(std::iter::empty(), 65535);
+        assert_eq!((empty.count, empty.median, empty.saturated_fraction()), (0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn bayer_frames_get_a_channel_per_site() {
+        // RGGB with each site its own level
+        let levels = [100u16, 200, 300, 400];
+        let mut frame = Frame::from_bytes(4, 4, 1, 16, &[0; 32]);
+        for y in 0..4 {
+            for x in 0..4 {
+                frame.data[(y * 4 + x) as usize] = levels[((y % 2) * 2 + x % 2) as usize];
+            }
+        }
+        frame.cfa = crate::frame::Cfa::parse("RGGB");
+        let stats = FrameStats::of(&frame);
+        let names: Vec<&str> = stats.channels.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names.len(), 4);
+        for ((_, channel), level) in stats.channels.iter().zip(levels.iter()) {
+            assert_eq!(channel.count, 4);
+            assert_eq!(channel.median, *level as f64);
+            assert_eq!(channel.mad, 0.0);
+        }
+        let fields = stats.log_fields();
+        assert!(fields.iter().any(|(key, value)| key == "MEDIAN" && value == "250.0"));
+        assert!(fields.iter().any(|(key, _)| *key == format!("{}_MEDIAN", names[3])));
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn leaky_darks_and_badly_exposed_flats_are_flagged() {
+        use crate::frame::FrameType;
+        let limits = QualityLimits::default();
+        let mut camera = crate::sim::Camera::new(200, 150);
+        camera.set_exposure_ms(30000);
+        let reference = camera.capture(FrameType::Dark).stats.unwrap();
+        let good = camera.capture(FrameType::Dark);
+        assert_eq!(limits.check_dark(good.stats.as_ref().unwrap(), Some(&reference)), Vec::<String>::new());
+
+        // light getting in everywhere lifts the median
+        let mut leak = good.clone();
+        for sample in leak.data.iter_mut() {
+            *sample += 100;
+        }
+        let problems = limits.check_dark(leak.compute_stats(), Some(&reference));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("light leak"), "{:?}", problems);
+
+        // amp glow brightens one corner, which shows in the spread before the median
+        let mut glow = good.clone();
+        for y in 0..40 {
+            for x in 0..40 {
+                glow.data[y * 200 + x] += (40 * (80 - x - y)) as u16;
+            }
+        }
+        let problems = limits.check_dark(glow.compute_stats(), Some(&reference));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("amp glow"), "{:?}", problems);
+
+        // a 3000 e-/s panel: a second is too short, 5 s about right, 25 s saturates
+        let flat = |camera: &mut crate::sim::Camera, ms: u32| {
+            camera.set_exposure_ms(ms);
+            limits.check_flat(camera.capture(FrameType::Flat).stats.as_ref().unwrap())
+        };
+        assert!(flat(&mut camera, 1000)[0].contains("underexposed"));
+        assert_eq!(flat(&mut camera, 5000), Vec::<String>::new());
+        let over = flat(&mut camera, 25000);
+        assert!(over[0].contains("overexposed"), "{:?}", over);
+        assert!(over[1].contains("saturated"), "{:?}", over);
+    }
+}
@@ -0,0 +1,176 @@
+// Finding USB devices through sysfs and resetting them through usbfs. Everything takes the
+// sysfs and /dev roots as arguments so it can be pointed at a copy of the tree.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const SYSFS_ROOT: &str = "/sys/bus/usb/devices";
+pub const DEV_ROOT: &str = "/dev/bus/usb";
+
+pub const ASI_VID: u16 = 0x03c3;
+pub const QHY_VID: u16 = 0x1618;
+
+/// `_IO('U', 20)`
+const USBDEVFS_RESET: os::raw::c_ulong = ((b'U' as os::raw::c_ulong) << 8) | 20;
+
+extern "C" {
+    fn ioctl(fd: os::raw::c_int, request: os::raw::c_ulong, ...) -> os::raw::c_int;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsbDevice {
+    /// The device's directory under the sysfs root, e.g. `.../4-1.2`.
+    pub sysfs_path: PathBuf,
+    pub vid: u16,
+    pub pid: u16,
+    pub serial: Option<String>,
+    pub busnum: u32,
+    pub devnum: u32
+}
+
+impl UsbDevice {
+    /// The usbfs node for this device, e.g. `/dev/bus/usb/004/006`.
+    pub fn dev_node<P: AsRef<Path>>(&self, dev_root: P) -> PathBuf {
+        dev_root.as_ref().join(format!("{:03}", self.busnum)).join(format!("{:03}", self.devnum))
+    }
+
+    /// The port the device is plugged into, e.g. `4-1.2`, which hotplug events are keyed on.
+    pub fn port(&self) -> String {
+        self.sysfs_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+
+    pub fn matches(&self, vid: u16, pid: Option<u16>, serial: Option<&str>) -> bool {
+        self.vid == vid &&
+            pid.map(|pid| pid == self.pid).unwrap_or(true) &&
+            serial.map(|serial| self.serial.as_ref().map(|s| s == serial).unwrap_or(false)).unwrap_or(true)
+    }
+}
+
+fn read_attr(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name)).ok().map(|value| value.trim().to_owned())
+}
+
+/// Read the device at `dir` in sysfs, or `None` if it isn't a device (interfaces and root
+/// hubs' ports live alongside devices, without `idVendor`).
+pub fn read_device(dir: &Path) -> Option<UsbDevice> {
+    Some(UsbDevice {
+        sysfs_path: dir.to_owned(),
+        vid: u16::from_str_radix(&read_attr(dir, "idVendor")?, 16).ok()?,
+        pid: u16::from_str_radix(&read_attr(dir, "idProduct")?, 16).ok()?,
+        serial: read_attr(dir, "serial"),
+        busnum: read_attr(dir, "busnum")?.parse().ok()?,
+        devnum: read_attr(dir, "devnum")?.parse().ok()?
+    })
+}
+
+pub fn list_devices<P: AsRef<Path>>(sysfs_root: P) -> io::Result<Vec<UsbDevice>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(sysfs_root)? {
+        if let Some(device) = read_device(&entry?.path()) {
+            devices.push(device);
+        }
+    }
+    devices.sort_by_key(|device| (device.busnum, device.devnum));
+    Ok(devices)
+}
+
+pub fn find<P: AsRef<Path>>(sysfs_root: P, vid: u16, pid: Option<u16>, serial: Option<&str>) -> io::Result<Option<UsbDevice>> {
+    Ok(list_devices(sysfs_root)?.into_iter().find(|device| device.matches(vid, pid, serial)))
+}
+
+/// Reset the device at usbfs node `dev_node`, as if it had been unplugged and plugged back in.
+pub fn reset<P: AsRef<Path>>(dev_node: P) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(dev_node)?;
+    let res = unsafe { ioctl(file.as_raw_fd(), USBDEVFS_RESET, 0) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Poll sysfs until a device satisfying `f` shows up, or `timeout` passes.
+pub fn wait_for<P: AsRef<Path>, F: Fn(&UsbDevice) -> bool>(sysfs_root: P, timeout: Duration, f: F) -> io::Result<Option<UsbDevice>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(device) = list_devices(sysfs_root.as_ref())?.into_iter().find(|device| f(device)) {
+            return Ok(Some(device));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// Lay out a device's sysfs directory under `root`, as the kernel does.
+    fn add_device(root: &Path, port: &str, vid: u16, pid: u16, serial: Option<&str>, busnum: u32, devnum: u32) {
+        let dir = root.join(port);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("idVendor"), format!("{:04x}\n", vid)).unwrap();
+        fs::write(dir.join("idProduct"), format!("{:04x}\n", pid)).unwrap();
+        fs::write(dir.join("busnum"), format!("{}\n", busnum)).unwrap();
+        fs::write(dir.join("devnum"), format!("{}\n", devnum)).unwrap();
+        if let Some(serial) = serial {
+            fs::write(dir.join("serial"), format!("{}\n", serial)).unwrap();
+        }
+    }
+
+    fn sysfs() -> TempDir {
+        let sysfs = TempDir::new("usb_sysfs");
+        add_device(sysfs.path(), "4-1.2", QHY_VID, 0xc368, Some("a1b2c3"), 4, 7);
+        add_device(sysfs.path(), "1-3", ASI_VID, 0x120a, None, 1, 12);
+        add_device(sysfs.path(), "4-1.1", QHY_VID, 0xc367, None, 4, 3);
+        // an interface and a root hub's port sit alongside devices without device attributes
+        fs::create_dir_all(sysfs.join("4-1.2:1.0")).unwrap();
+        fs::write(sysfs.join("4-1.2:1.0").join("bInterfaceClass"), "ff\n").unwrap();
+        fs::create_dir_all(sysfs.join("usb4")).unwrap();
+        sysfs
+    }
+
+    #[test]
+    fn devices_are_listed_in_bus_order_and_other_entries_skipped() {
+        let sysfs = sysfs();
+        let devices = list_devices(sysfs.path()).unwrap();
+        let found: Vec<(String, u16, u16, Option<&str>)> = devices.iter()
+            .map(|device| (device.port(), device.vid, device.pid, device.serial.as_deref()))
+            .collect();
+        assert_eq!(found, vec![
+            ("1-3".to_owned(), ASI_VID, 0x120a, None),
+            ("4-1.1".to_owned(), QHY_VID, 0xc367, None),
+            ("4-1.2".to_owned(), QHY_VID, 0xc368, Some("a1b2c3"))
+        ]);
+        assert_eq!(devices[2].dev_node(DEV_ROOT), PathBuf::from("/dev/bus/usb/004/007"));
+    }
+
+    #[test]
+    fn devices_are_found_by_vendor_product_and_serial() {
+        let sysfs = sysfs();
+        let port = |pid: Option<u16>, serial: Option<&str>| {
+            find(sysfs.path(), QHY_VID, pid, serial).unwrap().map(|device| device.port())
+        };
+        assert_eq!(port(None, None), Some("4-1.1".to_owned()));
+        assert_eq!(port(Some(0xc368), None), Some("4-1.2".to_owned()));
+        assert_eq!(port(None, Some("a1b2c3")), Some("4-1.2".to_owned()));
+        assert_eq!(port(None, Some("ffffff")), None);
+        assert_eq!(port(Some(0xc367), Some("a1b2c3")), None);
+        assert_eq!(find(sysfs.path(), 0x1234, None, None).unwrap(), None);
+    }
+
+    #[test]
+    fn waiting_gives_up_after_the_timeout() {
+        let sysfs = sysfs();
+        let found = wait_for(sysfs.path(), Duration::from_millis(0), |device| device.pid == 0xc368).unwrap();
+        assert_eq!(found.map(|device| device.devnum), Some(7));
+        let missing = wait_for(sysfs.path(), Duration::from_millis(300), |device| device.pid == 0xffff).unwrap();
+        assert_eq!(missing, None);
+    }
+}